@@ -31,7 +31,7 @@ fn main() {
 
     // MBR is always located in sector 0 of the disk
     let master_boot_record = MasterBootRecord::from(buf);
-    let mut vfat_fs = VfatFS::new(fbd, master_boot_record.partitions[0].start_sector).unwrap();
+    let vfat_fs = VfatFS::new(fbd, master_boot_record.partitions[0].start_sector).unwrap();
     let mut root = vfat_fs.get_root().unwrap();
     print_contents(root.contents());
     println!("Creating file 'my-file'");
@@ -1,19 +1,28 @@
-use alloc::format;
 use alloc::string::{String, ToString};
-use alloc::vec::{IntoIter, Vec};
+use alloc::vec;
+use alloc::vec::Vec;
 use core::mem;
+use core::ops::RangeInclusive;
 
 use log::{debug, error, info};
 use snafu::ensure;
 
 use crate::api::directory_entry::{
-    unknown_entry_convert_to_bytes_2, Attributes, EntryId, RegularDirectoryEntry,
-    UnknownDirectoryEntry, VfatDirectoryEntry,
+    eq_ignore_case, unknown_entry_convert_to_bytes_2, validate_name, Attributes, EntryId,
+    LongFileNameEntry, RegularDirectoryEntry, ShortName, UnknownDirectoryEntry, VfatDirectoryEntry,
 };
-use crate::api::{File, Metadata, VfatEntry};
+use crate::api::timestamp::VfatTimestamp;
+use crate::api::{DeletePolicy, File, Metadata, VfatEntry};
 use crate::cluster::cluster_reader::ClusterChainReader;
+use crate::diagnostics::{Diagnostic, LfnAnomaly};
+use crate::error::LogFailure;
 use crate::{error, Path};
-use crate::{ClusterId, VfatFS, VfatMetadataTrait};
+use crate::{ClusterId, VfatFS};
+
+/// A name takes at most 20 LFN entries (255 characters).
+const MAX_LFN_ENTRIES: u8 = 20;
+/// A directory can't be bigger than 2MiB, i.e. it holds at most 65,536 entries.
+const MAX_DIRECTORY_ENTRIES: usize = 65_536;
 
 // TODO: this assumes sector size
 const SECTOR_SIZE: usize = 512;
@@ -32,6 +41,52 @@ pub enum EntryType {
     // Link
 }
 
+/// Options used when creating a new entry, see `Directory::create_file_with`.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Only READ_ONLY, HIDDEN, SYSTEM and ARCHIVE can be set. Files are always created with
+    /// the ARCHIVE attribute.
+    pub attributes: Attributes,
+    /// Creation time, the current time if not set.
+    pub creation: Option<VfatTimestamp>,
+    /// Last modification time, the current time if not set.
+    pub last_update: Option<VfatTimestamp>,
+}
+
+/// Options used when opening a file relative to a directory, see `Directory::open_at`.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// Creates the file if it doesn't exist.
+    pub create: bool,
+    /// Creates the file, failing if it already exists.
+    pub create_new: bool,
+    /// Truncates the file to 0 bytes, if it exists.
+    pub truncate: bool,
+    /// Used if the file is created.
+    pub create_options: CreateOptions,
+}
+
+/// A regular entry bound to its name, as found while scanning a directory.
+pub(crate) struct ScannedEntry {
+    /// The long file name if present and valid, otherwise the 8.3 name.
+    pub(crate) name: String,
+    pub(crate) regular: RegularDirectoryEntry,
+    /// Index of the regular entry in the directory.
+    pub(crate) index: usize,
+    /// Index of the first entry (the first LFN entry, if any) of this entry.
+    pub(crate) first_index: usize,
+}
+
+/// A run of LFN entries, waiting for the regular entry it belongs to.
+struct LfnRun {
+    first_index: usize,
+    checksum: u8,
+    /// Position expected for the next LFN entry of the run. 0 when the run is complete.
+    next_position: u8,
+    /// Name parts in physical order (i.e. the last part of the name first).
+    parts: Vec<Vec<u16>>,
+}
+
 /// This is the public interface to the directory concept.
 /// A directory is composed of "DirectoryEntry" elements.
 /// The directory supports Long File Name (LFN)
@@ -41,8 +96,6 @@ pub enum EntryType {
 pub struct Directory {
     pub(crate) vfat_filesystem: VfatFS,
     pub metadata: Metadata,
-    // An optimization, if we already created an entry, we know the offset of the last position.
-    last_entry_spot: Option<usize>,
 }
 
 impl Directory {
@@ -50,56 +103,317 @@ impl Directory {
         Self {
             vfat_filesystem,
             metadata,
-            last_entry_spot: None,
         }
     }
 
-    /// Returns true if an entry called "name" is contained in this directory
-    ///
-    pub fn contains(&self, name: &str) -> error::Result<bool> {
-        for entry in self.contents()? {
-            if entry.name() == name {
-                return Ok(true);
+    /// A handle to the directory starting at `cluster`, used to access its entries when only
+    /// its location is known. Its own metadata (e.g. timestamps) is not read.
+    pub(crate) fn at(vfat_filesystem: VfatFS, cluster: ClusterId, path: Path) -> Self {
+        let metadata = Metadata::new(
+            VfatTimestamp::new(0),
+            VfatTimestamp::new(0),
+            path.file_name().unwrap_or("/"),
+            ShortName::empty(),
+            0,
+            path.clone(),
+            cluster,
+            path.parent().unwrap_or_default(),
+            cluster,
+            Attributes::new_directory(),
+        );
+        Self::new(vfat_filesystem, metadata)
+    }
+
+    /// Another handle to this directory.
+    fn handle(&self) -> Self {
+        Self::new(self.vfat_filesystem.clone(), self.metadata.clone())
+    }
+
+    /// Resolves `path` relative to this directory. Absolute paths are resolved from the root.
+    fn resolve(&self, path: &Path) -> error::Result<VfatEntry> {
+        self.vfat_filesystem.resolve(self.handle(), path)
+    }
+
+    /// Resolves the directory containing the last component of `path`, relative to this
+    /// directory. Returns it, along with the last component.
+    fn resolve_parent<'a>(&self, path: &'a Path) -> error::Result<(Directory, &'a str)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| error::VfatRsError::InvalidName {
+                target: path.display().to_string(),
+                reason: "the path doesn't end with a name",
+            })?;
+        let parent = self
+            .resolve(&path.parent().unwrap_or_default())?
+            .try_into_directory()?;
+        Ok((parent, name))
+    }
+
+    /// Opens the entry at `path`, relative to this directory, without walking from the root.
+    /// Directories can be opened too, unless `truncate` is set.
+    pub fn open_at(&mut self, path: Path, options: OpenOptions) -> error::Result<VfatEntry> {
+        self.open_at_inner(&path, options)
+            .log_failure("open", path.display())
+    }
+
+    fn open_at_inner(&mut self, path: &Path, options: OpenOptions) -> error::Result<VfatEntry> {
+        let may_create = options.create || options.create_new;
+        let _guard = if may_create || options.truncate {
+            self.vfat_filesystem.write_lock()?
+        } else {
+            self.vfat_filesystem.read_lock()
+        };
+        let entry = match self.resolve(path) {
+            Ok(entry) => {
+                ensure!(
+                    !options.create_new,
+                    error::NameAlreadyInUseSnafu {
+                        target: path.display(),
+                    }
+                );
+                entry
+            }
+            Err(error::VfatRsError::EntryNotFound { .. }) if may_create => {
+                let (mut parent, name) = self.resolve_parent(path)?;
+                parent.create(name.to_string(), EntryType::File, options.create_options)?
             }
+            Err(err) => return Err(err),
+        };
+        if !options.truncate {
+            return Ok(entry);
         }
-        Ok(false)
+        let mut file = entry.try_into_file()?;
+        file.truncate_inner(0)?;
+        Ok(file.into())
+    }
+
+    /// Creates a directory at `path`, relative to this directory.
+    pub fn create_dir_at(&mut self, path: Path) -> error::Result<Directory> {
+        self.create_dir_at_inner(&path)
+            .log_failure("create", path.display())
+    }
+
+    fn create_dir_at_inner(&mut self, path: &Path) -> error::Result<Directory> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        let (mut parent, name) = self.resolve_parent(path)?;
+        Ok(parent
+            .create(
+                name.to_string(),
+                EntryType::Directory,
+                CreateOptions::default(),
+            )?
+            .into_directory_unchecked())
+    }
+
+    /// Deletes the entry at `path`, relative to this directory. Like `delete`, only empty
+    /// directories can be deleted.
+    pub fn remove_at(&mut self, path: Path) -> error::Result<()> {
+        self.remove_at_inner(&path)
+            .log_failure("remove", path.display())
+    }
+
+    fn remove_at_inner(&mut self, path: &Path) -> error::Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        let (mut parent, name) = self.resolve_parent(path)?;
+        parent.delete_inner(name.to_string())
+    }
+
+    /// Renames (or moves) the entry at `from` to `to`, both relative to this directory.
+    pub fn rename(&mut self, from: Path, to: Path) -> error::Result<()> {
+        let to_dir = &mut self.handle();
+        self.rename_at(from, to_dir, to)
+    }
+
+    /// Moves the entry at `from`, relative to this directory, to `to`, relative to `to_dir`.
+    /// The target must not exist, and a directory can't be moved inside itself.
+    pub fn rename_at(&mut self, from: Path, to_dir: &mut Directory, to: Path) -> error::Result<()> {
+        self.rename_at_inner(&from, to_dir, &to)
+            .log_failure("rename", from.display())
+    }
+
+    fn rename_at_inner(&mut self, from: &Path, to_dir: &Directory, to: &Path) -> error::Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        let (mut source, from_name) = self.resolve_parent(from)?;
+        let (mut target, to_name) = to_dir.resolve_parent(to)?;
+        Self::move_entry(&mut source, from_name, &mut target, to_name)
+    }
+
+    /// The entries for the new name are written before the old ones are deleted: if
+    /// interrupted, the entry is left with two names rather than lost.
+    fn move_entry(
+        source: &mut Directory,
+        from_name: &str,
+        target: &mut Directory,
+        to_name: &str,
+    ) -> error::Result<()> {
+        validate_name(to_name)?;
+        ensure!(
+            from_name != "." && from_name != "..",
+            error::CannotDeletePseudoDirSnafu { target: from_name }
+        );
+        let entry = source.get_entry(from_name.to_string())?;
+        let same_directory = source.metadata.cluster == target.metadata.cluster;
+        let contents = target.contents_inner()?;
+        // Changing the case of a name is a rename onto the entry itself.
+        let name_in_use = contents.iter().any(|other| {
+            other.metadata.matches_name(to_name)
+                && !(same_directory && other.metadata.name() == entry.metadata.name())
+        });
+        ensure!(
+            !name_in_use,
+            error::NameAlreadyInUseSnafu { target: to_name }
+        );
+        if entry.is_dir() && !same_directory {
+            let mut ancestor = target.metadata.cluster;
+            while ancestor != target.vfat_filesystem.root_cluster {
+                ensure!(
+                    ancestor != entry.metadata.cluster,
+                    error::InvalidMoveSnafu {
+                        target: entry.metadata.path().display(),
+                        reason: "a directory can't be moved inside itself",
+                    }
+                );
+                ancestor = target.vfat_filesystem.parent_cluster(ancestor)?;
+            }
+        }
+
+        let old = source.find_scanned_entry(entry.metadata.name().to_string())?;
+        let short_name = Self::short_name_for(to_name, &contents)?;
+        let offset = target.reserve_spot(to_name, short_name)?;
+        let mut metadata = entry.metadata.clone();
+        metadata.relocate(to_name, short_name, &target.metadata);
+        target.write_entries(offset, to_name, &metadata)?;
+        source.mark_deleted(old.first_index..=old.index)?;
+        target
+            .vfat_filesystem
+            .open_files
+            .relocate(&entry.metadata, &metadata);
+        if entry.is_dir() && !same_directory {
+            Directory::new(target.vfat_filesystem.clone(), metadata)
+                .set_parent_link(target.parent_link())?;
+        }
+        Ok(())
+    }
+
+    /// Points the ".." entry of this directory to `parent`.
+    fn set_parent_link(&self, parent: ClusterId) -> error::Result<()> {
+        let (index, mut regular) = self
+            .contents_direntry()?
+            .into_iter()
+            .enumerate()
+            .find_map(|(index, entry)| {
+                entry
+                    .into_regular()
+                    .filter(|regular| regular.short_name().to_string() == "..")
+                    .map(|regular| (index, regular))
+            })
+            .ok_or_else(|| error::VfatRsError::BrokenParentLink {
+                target: self.metadata.path().display().to_string(),
+            })?;
+        (regular.high_16bits, regular.low_16bits) = parent.into_high_low();
+        self.update_entry_by_index(
+            VfatDirectoryEntry::Regular(regular).transmute_into_unknown_dir_entry(),
+            index,
+        )
+    }
+
+    /// Returns true if an entry called "name" is contained in this directory.
+    /// Names are compared case-insensitively, against both long names and 8.3 aliases.
+    pub fn contains(&self, name: &str) -> error::Result<bool> {
+        Ok(self
+            .contents()?
+            .iter()
+            .any(|entry| entry.metadata.matches_name(name)))
     }
     /// Create a new file in this directory
     ///
     pub fn create_file(&mut self, name: String) -> error::Result<File> {
-        Ok(self.create(name, EntryType::File)?.into_file_unchecked())
+        self.create_file_with(name, CreateOptions::default())
+    }
+
+    /// Create a new file in this directory, using the given attributes and timestamps.
+    pub fn create_file_with(
+        &mut self,
+        name: String,
+        options: CreateOptions,
+    ) -> error::Result<File> {
+        let path = self.metadata.path().join(&name);
+        let _guard = self
+            .vfat_filesystem
+            .write_lock()
+            .log_failure("create", path.display())?;
+        self.create(name, EntryType::File, options)
+            .and_then(VfatEntry::try_into_file)
+            .log_failure("create", path.display())
     }
 
     /// Create a new directory in this directory
     ///
     pub fn create_directory(&mut self, name: String) -> error::Result<Directory> {
-        Ok(self
-            .create(name, EntryType::Directory)?
-            .into_directory_unchecked())
+        self.create_directory_with(name, CreateOptions::default())
+    }
+
+    /// Create a new directory in this directory, using the given attributes and timestamps.
+    pub fn create_directory_with(
+        &mut self,
+        name: String,
+        options: CreateOptions,
+    ) -> error::Result<Directory> {
+        let path = self.metadata.path().join(&name);
+        let _guard = self
+            .vfat_filesystem
+            .write_lock()
+            .log_failure("create", path.display())?;
+        self.create(name, EntryType::Directory, options)
+            .map(VfatEntry::into_directory_unchecked)
+            .log_failure("create", path.display())
+    }
+
+    /// Sets `attributes` (READ_ONLY, HIDDEN, SYSTEM, ARCHIVE) on this directory.
+    pub fn set_attributes(&mut self, attributes: Attributes) -> error::Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.vfat_filesystem
+            .change_attributes(&mut self.metadata, attributes, Attributes::empty())
+    }
+
+    /// Clears `attributes` (READ_ONLY, HIDDEN, SYSTEM, ARCHIVE) on this directory.
+    pub fn clear_attributes(&mut self, attributes: Attributes) -> error::Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.vfat_filesystem
+            .change_attributes(&mut self.metadata, Attributes::empty(), attributes)
     }
 
     /// Used to create a new entry in this directory
-    fn create(&mut self, name: String, entry_type: EntryType) -> error::Result<VfatEntry> {
-        if self.contains(&name)? {
+    pub(crate) fn create(
+        &mut self,
+        name: String,
+        entry_type: EntryType,
+        options: CreateOptions,
+    ) -> error::Result<VfatEntry> {
+        validate_name(&name)?;
+        ensure!(
+            Attributes::USER_SETTABLE.contains(options.attributes),
+            error::InvalidAttributesSnafu {
+                target: name,
+                reason: "only READ_ONLY, HIDDEN, SYSTEM and ARCHIVE can be set",
+            }
+        );
+        let contents = self.contents_inner()?;
+        if contents
+            .iter()
+            .any(|entry| entry.metadata.matches_name(&name))
+        {
             return Err(error::VfatRsError::NameAlreadyInUse { target: name });
         }
+        let short_name = Self::short_name_for(&name, &contents)?;
+        // The spot is checked before allocating anything for the new entry.
+        let first_empty_spot_offset = self.reserve_spot(&name, short_name)?;
 
         // 1. Create metadata:
-        let metadata = self.create_metadata_for_new_entry(name.as_str(), &entry_type)?;
+        let metadata =
+            self.create_metadata_for_new_entry(name.as_str(), short_name, &entry_type, options)?;
 
         // 2. Based on the name, create one or more LFN and the Regular entry.
-        let entries: Vec<UnknownDirectoryEntry> = VfatDirectoryEntry::new_vfat_entry(
-            name.as_str(),
-            metadata.cluster,
-            Self::attributes_from_entry(&entry_type),
-        );
-        let entries_len = entries.len();
-        let first_empty_spot_offset = if self.last_entry_spot.is_none() {
-            self.find_first_empty_spot_offset()?
-        } else {
-            self.last_entry_spot.unwrap()
-        };
-
         info!(
             "Going to use as metadata: {:?}. self metadatapath= '{}', selfmetadata name = '{}'. My attributes: {:?}, cluster: {:?}",
             metadata,
@@ -108,31 +422,15 @@ impl Directory {
             self.metadata.attributes,
             self.metadata.cluster
         );
-        info!(
-            "Found spot: {:?}, Going to append entries: {:?}",
-            first_empty_spot_offset, entries
-        );
-
-        let mut ccw = self
-            .vfat_filesystem
-            .cluster_chain_writer(self.metadata.cluster);
-        ccw.seek(first_empty_spot_offset)?;
-
-        for unknown_entry in entries.into_iter() {
-            let entry: [u8; mem::size_of::<UnknownDirectoryEntry>()] = unknown_entry.into();
-            ccw.write(&entry)?;
-        }
+        self.write_entries(first_empty_spot_offset, &name, &metadata)?;
 
         if let EntryType::Directory = entry_type {
             let entries =
-                VfatDirectoryEntry::create_pseudo_dir_entries(metadata.cluster, ClusterId::new(0));
+                VfatDirectoryEntry::create_pseudo_dir_entries(metadata.cluster, self.parent_link());
             let mut cw = self.vfat_filesystem.cluster_chain_writer(metadata.cluster);
             let buf = unknown_entry_convert_to_bytes_2(entries);
             cw.write(&buf)?;
         }
-        // finally, update entries:
-        self.last_entry_spot =
-            Some(first_empty_spot_offset + entries_len * mem::size_of::<UnknownDirectoryEntry>());
 
         Ok(match entry_type {
             EntryType::Directory => {
@@ -142,6 +440,80 @@ impl Directory {
         })
     }
 
+    /// The cluster stored in the ".." entry of this directory's subdirectories.
+    /// The root directory is referred to as cluster 0.
+    fn parent_link(&self) -> ClusterId {
+        if self.metadata.cluster == self.vfat_filesystem.root_cluster {
+            ClusterId::new(0)
+        } else {
+            self.metadata.cluster
+        }
+    }
+
+    fn short_name_for(name: &str, contents: &[VfatEntry]) -> error::Result<ShortName> {
+        match ShortName::from_plain_name(name) {
+            Some(short_name) => Ok(short_name),
+            None => Self::unique_short_name(name, contents),
+        }
+    }
+
+    /// Returns the offset where the entries for `name` will be written, making sure the
+    /// directory can hold them.
+    fn reserve_spot(&self, name: &str, short_name: ShortName) -> error::Result<usize> {
+        let first_empty_spot_offset = self.find_first_empty_spot_offset()?;
+        let entries_len = VfatDirectoryEntry::required_entries(name, short_name);
+        let first_empty_spot = first_empty_spot_offset / mem::size_of::<UnknownDirectoryEntry>();
+        ensure!(
+            first_empty_spot + entries_len <= MAX_DIRECTORY_ENTRIES,
+            error::DirectoryFullSnafu {
+                target: self.metadata.path().display().to_string(),
+                max: MAX_DIRECTORY_ENTRIES,
+            }
+        );
+        Ok(first_empty_spot_offset)
+    }
+
+    /// Writes the LFN entries and the regular entry describing `metadata` at `offset`.
+    fn write_entries(&self, offset: usize, name: &str, metadata: &Metadata) -> error::Result<()> {
+        let entries: Vec<UnknownDirectoryEntry> =
+            VfatDirectoryEntry::new_vfat_entry(name, metadata.clone().into());
+        info!(
+            "Found spot: {:?}, Going to append entries: {:?}",
+            offset, entries
+        );
+
+        let mut ccw = self
+            .vfat_filesystem
+            .cluster_chain_writer(self.metadata.cluster)
+            .zeroing_new_clusters();
+        ccw.seek(offset)?;
+
+        for unknown_entry in entries.into_iter() {
+            let entry: [u8; mem::size_of::<UnknownDirectoryEntry>()] = unknown_entry.into();
+            ccw.write(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Picks the first `~N` numeric tail whose alias is not used by any entry in `contents`.
+    pub(crate) fn unique_short_name(
+        name: &str,
+        contents: &[VfatEntry],
+    ) -> error::Result<ShortName> {
+        const MAX_NUMERIC_TAIL: u32 = 999_999;
+        (1..=MAX_NUMERIC_TAIL)
+            .map(|tail| ShortName::from_long_name(name, tail))
+            .find(|candidate| {
+                let candidate = candidate.to_string();
+                !contents
+                    .iter()
+                    .any(|entry| entry.metadata.matches_name(&candidate))
+            })
+            .ok_or_else(|| error::VfatRsError::NameAlreadyInUse {
+                target: name.into(),
+            })
+    }
+
     /// Searches for `spots_needed` in all the clusters allocated to this directory
     /// it only searches for empty spots, it won't allow (for now? TODO) replacing deleted entries.
     ///
@@ -170,42 +542,47 @@ impl Directory {
     fn create_metadata_for_new_entry(
         &mut self,
         entry_name: &str,
+        short_name: ShortName,
         entry_type: &EntryType,
+        options: CreateOptions,
     ) -> error::Result<Metadata> {
-        let path = Path::from(format!("{}{}", self.metadata.path().display(), entry_name));
-        let attributes = Self::attributes_from_entry(entry_type);
+        let path = self.metadata.path().join(entry_name);
+        let attributes = Self::attributes_from_entry(entry_type) | options.attributes;
         let cluster_id = match entry_type {
             // No need to allocate a new cluster
             EntryType::File => ClusterId::new(0),
             // Allocate for directory
-            EntryType::Directory => self.vfat_filesystem.allocate_cluster_new_entry()?,
+            EntryType::Directory => {
+                let cluster_id = self.vfat_filesystem.allocate_cluster_new_entry()?;
+                self.vfat_filesystem.zero_cluster(cluster_id)?;
+                cluster_id
+            }
         };
         info!("Going to use as cluster id: {}", cluster_id);
         let size = 0;
-        let metadata = Metadata::new(
-            self.vfat_filesystem
-                .time_manager
-                .get_current_vfat_timestamp(),
+        let now = || {
             self.vfat_filesystem
                 .time_manager
-                .get_current_vfat_timestamp(),
+                .get_current_vfat_timestamp()
+        };
+        let metadata = Metadata::new(
+            options.creation.unwrap_or_else(now),
+            options.last_update.unwrap_or_else(now),
             entry_name,
+            short_name,
             size,
             path,
             cluster_id,
             self.metadata.path().clone(),
+            self.metadata.cluster,
             attributes,
         );
         Ok(metadata)
     }
 
-    pub(crate) fn iter(&self) -> error::Result<IntoIter<VfatEntry>> {
-        self.contents().map(|v| v.into_iter())
-    }
-
     /// Returns an entry from inside this directory.
     fn get_entry(&mut self, target_filename: String) -> error::Result<VfatEntry> {
-        self.contents()?
+        self.contents_inner()?
             .into_iter()
             .find(|name| {
                 debug!(
@@ -213,7 +590,7 @@ impl Directory {
                     name.metadata.name(),
                     target_filename
                 );
-                name.metadata.name() == target_filename
+                name.metadata.matches_name(&target_filename)
             })
             .ok_or(error::VfatRsError::FileNotFound {
                 target: target_filename,
@@ -222,8 +599,18 @@ impl Directory {
 
     //TOOD: test pseudo dir deletion.
     pub fn delete(&mut self, target_name: String) -> error::Result<()> {
+        let path = self.metadata.path().join(&target_name);
+        let _guard = self
+            .vfat_filesystem
+            .write_lock()
+            .log_failure("remove", path.display())?;
+        self.delete_inner(target_name)
+            .log_failure("remove", path.display())
+    }
+
+    fn delete_inner(&mut self, target_name: String) -> error::Result<()> {
         info!("Starting delete routine for entry: '{}'. ", target_name);
-        info!("Directory contents: {:?}", self.contents()?);
+        info!("Directory contents: {:?}", self.contents_inner()?);
 
         const PSEUDO_CURRENT_FOLDER: &str = ".";
         const PSEUDO_PARENT_FOLDER: &str = "..";
@@ -237,99 +624,253 @@ impl Directory {
         );
 
         let target_entry = self.get_entry(target_name)?;
+        ensure!(
+            !target_entry.metadata.attributes.is_read_only(),
+            error::ReadOnlyEntrySnafu {
+                target: target_entry.metadata.name(),
+            }
+        );
 
         info!("Found target entry: {:?}", target_entry);
-        self.delete_entry(target_entry)
+        self.delete_entry(target_entry)?;
+        if self.vfat_filesystem.auto_shrink {
+            self.shrink_inner(true)?;
+        }
+        Ok(())
     }
 
+    /// Packs the live entries of this directory toward its beginning, and frees the clusters
+    /// which are not needed anymore. Deleted and orphaned LFN entries are dropped along the way.
+    /// The first cluster of the directory (e.g. the root cluster) is never freed.
+    ///
+    /// Returns how many clusters were freed.
+    pub fn shrink(&mut self) -> error::Result<usize> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.shrink_inner(false)
+    }
+
+    /// If `only_if_frees` is set, the directory is left untouched unless shrinking it frees
+    /// at least one cluster.
+    fn shrink_inner(&mut self, only_if_frees: bool) -> error::Result<usize> {
+        let dir_entries = self.contents_direntry()?;
+        let packed: Vec<UnknownDirectoryEntry> = self
+            .scan_entries(dir_entries.clone())
+            .into_iter()
+            .flat_map(|scanned| dir_entries[scanned.first_index..=scanned.index].iter())
+            .cloned()
+            .map(VfatDirectoryEntry::transmute_into_unknown_dir_entry)
+            .collect();
+
+        let cluster_size = self.vfat_filesystem.cluster_size();
+        let entries_size = packed.len() * mem::size_of::<UnknownDirectoryEntry>();
+        let needed_clusters = entries_size.div_ceil(cluster_size).max(1);
+        if only_if_frees
+            && self
+                .vfat_filesystem
+                .cluster_chain_len(self.metadata.cluster)?
+                <= needed_clusters
+        {
+            return Ok(0);
+        }
+        info!(
+            "Shrinking directory {}: {} entries, {} clusters needed",
+            self.metadata.path().display(),
+            packed.len(),
+            needed_clusters
+        );
+
+        // The rest of the last cluster is zeroed, this also writes the EndOfEntries marker.
+        let mut buf = vec![0u8; needed_clusters * cluster_size];
+        for (entry, chunk) in packed
+            .into_iter()
+            .zip(buf.chunks_mut(mem::size_of::<UnknownDirectoryEntry>()))
+        {
+            let entry: [u8; mem::size_of::<UnknownDirectoryEntry>()] = entry.into();
+            chunk.copy_from_slice(&entry);
+        }
+        self.vfat_filesystem
+            .cluster_chain_writer(self.metadata.cluster)
+            .write(&buf)?;
+
+        self.vfat_filesystem
+            .truncate_cluster_chain(self.metadata.cluster, needed_clusters)
+    }
+
+    /// Returns the raw entries of this directory, up to the first EndOfEntries marker.
     fn contents_direntry(&self) -> error::Result<Vec<VfatDirectoryEntry>> {
         info!("Directory contents, cluster: {:?}", self.metadata.cluster);
 
         let mut buf = [0; BUF_SIZE];
-        let filter_invalid =
-            |entry: &VfatDirectoryEntry| !matches!(*entry, VfatDirectoryEntry::EndOfEntries(_));
         let mut cluster_chain_reader = self.cluster_chain_reader();
 
         let mut entries = Vec::new();
         while cluster_chain_reader.read(&mut buf)? > 0 {
             let unknown_entries: [UnknownDirectoryEntry; ENTRIES_AMOUNT] =
                 unknown_entry_convert_from_bytes_entries(buf);
-            //debug!("Unknown entries: {:?}", unknown_entries);
-            /*#[cfg(debug_assertions)]
-            unknown_entries
-                .iter()
-                .map(VfatDirectoryEntry::from)
-                //.take_while(filter_invalid)
-                .for_each(|entry| info!("unknown entry to vfat directory entry: {:?}", entry));
-            */
-            entries.extend(
-                unknown_entries
-                    .iter()
-                    .map(VfatDirectoryEntry::from)
-                    .filter(filter_invalid),
-            );
+            for entry in unknown_entries.iter().map(VfatDirectoryEntry::from) {
+                if let VfatDirectoryEntry::EndOfEntries(_) = entry {
+                    return Ok(entries);
+                }
+                entries.push(entry);
+            }
         }
         Ok(entries)
     }
 
-    pub fn contents(&self) -> error::Result<Vec<VfatEntry>> {
-        info!("Directory contents, cluster: {:?}", self.metadata.cluster);
+    /// Reads this directory, and binds every regular entry to its long file name.
+    /// VFAT rules are applied: LFN runs that are orphaned, not contiguous or whose checksum
+    /// doesn't match the following 8.3 entry are discarded (and reported), and the 8.3 name
+    /// is used instead.
+    fn scan(&self) -> error::Result<Vec<ScannedEntry>> {
+        Ok(self.scan_entries(self.contents_direntry()?))
+    }
 
-        let entries = self.contents_direntry()?;
-        let mut contents = Vec::new();
+    /// See `scan`. `dir_entries` are the raw entries of this directory.
+    pub(crate) fn scan_entries(&self, dir_entries: Vec<VfatDirectoryEntry>) -> Vec<ScannedEntry> {
+        let mut scanned = Vec::new();
+        let mut lfn_run: Option<LfnRun> = None;
+        // Set after a broken run: the remaining entries of that run are silently skipped.
+        let mut discarding = false;
+        let report = |entry_index, anomaly| {
+            self.vfat_filesystem.report(Diagnostic::DiscardedLfn {
+                directory: self.metadata.path().display().to_string(),
+                entry_index,
+                anomaly,
+            })
+        };
 
-        let mut lfn_buff: Vec<(u8, String)> = Vec::new();
-        for dir_entry in entries {
-            info!("Found entry: {:?}", dir_entry);
+        for (index, dir_entry) in dir_entries.into_iter().enumerate() {
             match dir_entry {
                 VfatDirectoryEntry::LongFileName(lfn) => {
-                    lfn_buff.push((lfn.sequence_number.get_position(), lfn.collect_name()))
+                    let sequence_number = lfn.sequence_number;
+                    let position = sequence_number.get_position();
+                    if sequence_number.is_last_logical() {
+                        if let Some(run) = lfn_run.take() {
+                            report(run.first_index, LfnAnomaly::NonContiguous);
+                        }
+                        discarding = false;
+                        if position == 0 || position > MAX_LFN_ENTRIES {
+                            report(index, LfnAnomaly::NonContiguous);
+                            discarding = true;
+                            continue;
+                        }
+                        lfn_run = Some(LfnRun {
+                            first_index: index,
+                            checksum: lfn.checksum_dos_filename,
+                            next_position: position - 1,
+                            parts: vec![lfn.collect_name()],
+                        });
+                        continue;
+                    }
+                    match lfn_run.as_mut() {
+                        Some(run)
+                            if position != 0
+                                && position == run.next_position
+                                && lfn.checksum_dos_filename == run.checksum =>
+                        {
+                            run.next_position -= 1;
+                            run.parts.push(lfn.collect_name());
+                        }
+                        Some(run) => {
+                            let anomaly = if lfn.checksum_dos_filename != run.checksum {
+                                LfnAnomaly::ChecksumMismatch
+                            } else {
+                                LfnAnomaly::NonContiguous
+                            };
+                            report(run.first_index, anomaly);
+                            lfn_run = None;
+                            discarding = true;
+                        }
+                        None if discarding => {}
+                        None => {
+                            report(index, LfnAnomaly::MissingLastLogical);
+                            discarding = true;
+                        }
+                    }
                 }
                 VfatDirectoryEntry::Deleted(_) => {
-                    lfn_buff.clear();
+                    if let Some(run) = lfn_run.take() {
+                        report(run.first_index, LfnAnomaly::NonContiguous);
+                    }
+                    discarding = false;
                 }
                 VfatDirectoryEntry::Regular(regular) => {
-                    let name = if !lfn_buff.is_empty() {
-                        let ret = Self::string_from_lfn(lfn_buff);
-                        lfn_buff = Vec::new();
-                        ret
-                    } else {
-                        regular.full_name()
+                    discarding = false;
+                    let short_name = regular.short_name();
+                    let checksum = VfatDirectoryEntry::checksum(&short_name.name, &short_name.ext);
+                    let (name, first_index) = match lfn_run.take() {
+                        Some(run) if run.next_position == 0 && run.checksum == checksum => {
+                            // Physically, the last part of the name comes first.
+                            let name = LongFileNameEntry::decode_name(run.parts);
+                            (name, run.first_index)
+                        }
+                        Some(run) => {
+                            let anomaly = if run.next_position == 0 {
+                                LfnAnomaly::ChecksumMismatch
+                            } else {
+                                LfnAnomaly::NonContiguous
+                            };
+                            report(run.first_index, anomaly);
+                            (regular.full_name(), index)
+                        }
+                        None => (regular.full_name(), index),
                     };
-
-                    let path = Path::from(format!(
-                        "{}{name}{}",
-                        self.metadata.path().display(),
-                        if regular.is_dir() { "/" } else { "" }
-                    ));
-
-                    let metadata = Metadata::new(
-                        regular.creation_time,
-                        regular.last_modification_time,
+                    scanned.push(ScannedEntry {
                         name,
-                        regular.file_size,
-                        path,
-                        regular.cluster(),
-                        self.metadata.path().clone(),
-                        regular.attributes,
-                    );
-
-                    info!("Metadata: {:?}", metadata);
-
-                    let new_fn = if regular.is_dir() {
-                        VfatEntry::new_directory
-                    } else {
-                        VfatEntry::new_file
-                    };
-
-                    contents.push(new_fn(metadata, self.vfat_filesystem.clone()));
-                }
-                // The for loop stops on EndOfEntries
-                VfatDirectoryEntry::EndOfEntries(_) => {
-                    panic!("This cannot happen! Found EndOfEntries")
+                        regular,
+                        index,
+                        first_index,
+                    });
                 }
+                // contents_direntry stops on EndOfEntries
+                VfatDirectoryEntry::EndOfEntries(_) => break,
             }
         }
+        if let Some(run) = lfn_run {
+            report(run.first_index, LfnAnomaly::NonContiguous);
+        }
+        scanned
+    }
+
+    pub fn contents(&self) -> error::Result<Vec<VfatEntry>> {
+        let _guard = self.vfat_filesystem.read_lock();
+        self.contents_inner()
+            .log_failure("list", self.metadata.path().display())
+    }
+
+    pub(crate) fn contents_inner(&self) -> error::Result<Vec<VfatEntry>> {
+        info!("Directory contents, cluster: {:?}", self.metadata.cluster);
+
+        let contents = self
+            .scan()?
+            .into_iter()
+            .map(|ScannedEntry { name, regular, .. }| {
+                let path = self.metadata.path().join(&name);
+
+                let metadata = Metadata::new(
+                    regular.creation_time,
+                    regular.last_modification_time,
+                    name,
+                    regular.short_name(),
+                    regular.file_size,
+                    path,
+                    regular.cluster(),
+                    self.metadata.path().clone(),
+                    self.metadata.cluster,
+                    regular.attributes,
+                );
+
+                info!("Metadata: {:?}", metadata);
+
+                let new_fn = if regular.is_dir() {
+                    VfatEntry::new_directory
+                } else {
+                    VfatEntry::new_file
+                };
+
+                new_fn(metadata, self.vfat_filesystem.clone())
+            })
+            .collect();
         Ok(contents)
     }
 
@@ -337,7 +878,8 @@ impl Directory {
         let target_name = metadata.name().to_string();
         info!("Running update entry on target name: {}", target_name);
         let regular: RegularDirectoryEntry = metadata.into();
-        self.update_entry_inner(target_name, regular.into())
+        let scanned = self.find_scanned_entry(target_name)?;
+        self.update_entry_by_index(regular.into(), scanned.index)
     }
 
     fn cluster_chain_reader(&self) -> ClusterChainReader {
@@ -345,60 +887,17 @@ impl Directory {
             .cluster_chain_reader(self.metadata.cluster)
     }
 
-    // create a string from a vec
-    fn string_from_lfn(mut lfn_vec: Vec<(u8, String)>) -> String {
-        // lfn are not assumed to be created in order, hence we need to
-        // sort using the sequence number
-        lfn_vec.sort();
-        // Build the string.
-        lfn_vec
-            .into_iter()
-            .map(|(_, name)| name)
-            .collect::<Vec<String>>()
-            .join("")
-    }
-
     // TODO: Currently this doesn't support renaming file, just updating/replacing metadatas...
-    fn update_entry_inner(
-        &mut self,
-        target_name: String,
-        new_entry: UnknownDirectoryEntry,
-    ) -> error::Result<()> {
-        info!("Running update entry routine...");
-        let mut lfn_buff: Vec<(u8, String)> = Vec::new();
-
-        let entries = self.contents_direntry()?;
-        //info!("target_name: {}, entries: {:?}", target_name, entries);
-        for (index, dir_entry) in entries.iter().enumerate() {
-            match dir_entry {
-                VfatDirectoryEntry::LongFileName(lfn) => {
-                    lfn_buff.push((lfn.sequence_number.get_position(), lfn.collect_name()))
-                }
-                VfatDirectoryEntry::Deleted(_) => lfn_buff.clear(),
-                VfatDirectoryEntry::Regular(regular) => {
-                    let name = if !lfn_buff.is_empty() {
-                        let file_name = Self::string_from_lfn(lfn_buff);
-                        // prepare the buffer for the next file.
-                        lfn_buff = Vec::new();
-                        file_name
-                    } else {
-                        regular.full_name()
-                    };
-                    if name == target_name {
-                        self.update_entry_by_index(new_entry, index)?;
-                        return Ok(());
-                    }
-                }
-                // The for loop stops on EndOfEntries
-                VfatDirectoryEntry::EndOfEntries(_) => {
-                    panic!("This cannot happen! Found EndOfEntries")
+    fn find_scanned_entry(&self, target_name: String) -> error::Result<ScannedEntry> {
+        self.scan()?
+            .into_iter()
+            .find(|scanned| eq_ignore_case(&scanned.name, &target_name))
+            .ok_or_else(|| {
+                error!("Directory update entry {}: file not found!!", target_name);
+                error::VfatRsError::FileNotFound {
+                    target: target_name,
                 }
-            };
-        }
-        error!("Directory update entry {}: file not found!!", target_name);
-        Err(error::VfatRsError::FileNotFound {
-            target: target_name,
-        })
+            })
     }
 
     // Replace entry with index `index` with input `entry`.
@@ -418,12 +917,25 @@ impl Directory {
         Ok(())
     }
 
+    /// Marks the entries in `indexes` as deleted. Only their ID (first byte) is changed.
+    pub(crate) fn mark_deleted(&self, indexes: RangeInclusive<usize>) -> error::Result<()> {
+        for index in indexes {
+            // Seek is relative to the writer's current cluster: use a new writer for each entry.
+            let mut ccw = self
+                .vfat_filesystem
+                .cluster_chain_writer(self.metadata.cluster);
+            ccw.seek(mem::size_of::<UnknownDirectoryEntry>() * index)?;
+            ccw.write(&[EntryId::Deleted.into()])?;
+        }
+        Ok(())
+    }
+
     fn delete_entry(&mut self, entry: VfatEntry) -> error::Result<()> {
         info!("Running delete entry");
         const SPECIAL_CURRENT_UPPER_DIRECTORY: usize = 2;
         let entry = if entry.is_dir() {
             let directory = entry.into_directory_unchecked();
-            if directory.contents()?.len() > SPECIAL_CURRENT_UPPER_DIRECTORY {
+            if directory.contents_inner()?.len() > SPECIAL_CURRENT_UPPER_DIRECTORY {
                 return Err(error::VfatRsError::NonEmptyDirectory {
                     target: directory.metadata.name().to_string(),
                 });
@@ -434,26 +946,37 @@ impl Directory {
             entry
         };
 
-        info!(
-            "Deleting entry's associated clusters starting at {:?}",
-            entry.metadata.cluster
+        let open_file = self.vfat_filesystem.open_files.get(&entry.metadata);
+        ensure!(
+            open_file.is_none() || self.vfat_filesystem.delete_policy == DeletePolicy::Defer,
+            error::FileInUseSnafu {
+                target: entry.metadata.name(),
+            }
         );
-        self.vfat_filesystem
-            .delete_fat_cluster_chain(entry.metadata.cluster)?;
-        let target_name = entry.metadata().name().to_string();
-        // Directory Entry change to DeleteEntry
-        // 2. Set VfatDirectoryEntry to Deleted.
-        let dir_entry: RegularDirectoryEntry = entry.metadata.into();
-        let mut dir_entry: UnknownDirectoryEntry = dir_entry.into();
-
-        dir_entry.set_id(EntryId::Deleted);
-        self.update_entry_inner(target_name, dir_entry)
+        // Empty files have no cluster allocated: cluster 0 is a reserved FAT entry, not a chain.
+        if open_file.is_none() && entry.metadata.cluster != ClusterId::new(0) {
+            info!(
+                "Deleting entry's associated clusters starting at {:?}",
+                entry.metadata.cluster
+            );
+            self.vfat_filesystem
+                .delete_fat_cluster_chain(entry.metadata.cluster)?;
+        }
+        // Mark the regular entry and its LFN entries as deleted, so no orphaned LFN is left behind.
+        let scanned = self.find_scanned_entry(entry.metadata().name().to_string())?;
+        self.mark_deleted(scanned.first_index..=scanned.index)?;
+        if let Some(node) = open_file {
+            // The clusters are freed when the last handle is dropped.
+            info!("Deleted open file: {:?}", entry.metadata.path());
+            self.vfat_filesystem.open_files.unlink(&node);
+        }
+        Ok(())
     }
 
     fn attributes_from_entry(entry: &EntryType) -> Attributes {
         match entry {
             EntryType::Directory => Attributes::new_directory(),
-            EntryType::File => Attributes(0),
+            EntryType::File => Attributes::ARCHIVE,
         }
     }
 }
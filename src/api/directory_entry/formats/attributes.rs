@@ -1,6 +1,7 @@
 use crate::const_assert_size;
 use core::fmt;
 use core::fmt::Debug;
+use core::ops::BitOr;
 
 pub mod attribute {
     pub const READ_ONLY: u8 = 0x01;
@@ -12,13 +13,34 @@ pub mod attribute {
     pub const LFN: u8 = READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID;
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct Attributes(pub u8);
 impl Attributes {
+    pub const READ_ONLY: Self = Self(attribute::READ_ONLY);
+    pub const HIDDEN: Self = Self(attribute::HIDDEN);
+    pub const SYSTEM: Self = Self(attribute::SYSTEM);
+    pub const ARCHIVE: Self = Self(attribute::ARCHIVE);
+    /// The attributes which can be changed on an entry. The others describe what the entry is.
+    pub(crate) const USER_SETTABLE: Self =
+        Self(attribute::READ_ONLY | attribute::HIDDEN | attribute::SYSTEM | attribute::ARCHIVE);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
     pub fn new_directory() -> Self {
         Self(attribute::DIRECTORY)
     }
+    /// Returns true if all the attributes in `other` are set.
+    pub fn contains(&self, other: Attributes) -> bool {
+        self.matches(other.0)
+    }
+    pub(crate) fn insert(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+    pub(crate) fn remove(&mut self, other: Attributes) {
+        self.0 &= !other.0;
+    }
     fn matches(&self, attribute: u8) -> bool {
         self.0 & attribute == attribute
     }
@@ -44,6 +66,14 @@ impl Attributes {
         self.matches(attribute::ARCHIVE)
     }
 }
+impl BitOr for Attributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
 impl Debug for Attributes {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Attributes(")?;
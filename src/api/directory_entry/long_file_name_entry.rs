@@ -2,6 +2,7 @@ use crate::api::directory_entry::Attributes;
 use crate::const_assert_size;
 use crate::defbit;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
 use core::fmt::{Debug, Formatter};
 
@@ -28,6 +29,11 @@ impl SequenceNumber {
     pub fn set_is_last_bit(&mut self) {
         self.set_bit(SequenceNumber::LastLogical);
     }
+
+    /// True for the first physical entry of a run, holding the last part of the name.
+    pub fn is_last_logical(&self) -> bool {
+        self.get_masked(SequenceNumber::LastLogical) != 0
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -96,30 +102,28 @@ impl LongFileNameEntry {
         self.attributes.is_lfn()
     }
 
-    // Returns a subset of the input, early stopping according to LFN name rules.
-    fn early_terminate_pos(name_array: &[u16]) -> &[u16] {
-        let get_pos = |string: &[u16]| {
-            for (pos, ch) in string.iter().enumerate() {
-                if *ch == 0x00 || *ch == 0xFFFF {
-                    return pos;
-                }
-            }
-            string.len()
-        };
-        &name_array[..get_pos(name_array)]
-    }
-
-    pub fn collect_name(&self) -> String {
+    /// The UCS-2 units of the name held by this entry, up to the terminator. A surrogate pair
+    /// might straddle two entries, so the name is decoded once its whole run is collected,
+    /// see `decode_name`.
+    pub fn collect_name(&self) -> Vec<u16> {
         let name_characters = { self.name_characters };
         let second_set_name = { self.second_set_name };
         let third_set_name = { self.third_set_name };
-        alloc::format!(
-            "{}{}{}",
-            String::from_utf16_lossy(Self::early_terminate_pos(&name_characters)),
-            String::from_utf16_lossy(Self::early_terminate_pos(&second_set_name)),
-            String::from_utf16_lossy(Self::early_terminate_pos(&third_set_name)),
-        )
+        name_characters
+            .into_iter()
+            .chain(second_set_name)
+            .chain(third_set_name)
+            .take_while(|ch| *ch != 0x00 && *ch != 0xFFFF)
+            .collect()
     }
+
+    /// Decodes the name held by a run of LFN entries, given the `collect_name` of each entry
+    /// in physical order (i.e. the last part of the name first).
+    pub fn decode_name(parts: Vec<Vec<u16>>) -> String {
+        let units: Vec<u16> = parts.into_iter().rev().flatten().collect();
+        String::from_utf16_lossy(&units)
+    }
+
     /// If the sequence number is 0x00, the previous entry was the last entry.
     pub fn was_last_entry_last(&self) -> bool {
         self.sequence_number.0 == 0x00
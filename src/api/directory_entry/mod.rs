@@ -1,4 +1,5 @@
-use alloc::string::String;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cmp::min;
@@ -10,7 +11,12 @@ use log::{debug, info};
 use regex::Regex;
 
 pub use crate::api::directory_entry::formats::{attribute, Attributes, EntryId};
-use crate::api::directory_entry::long_file_name_entry::{LongFileNameEntry, SequenceNumber};
+pub(crate) use crate::api::directory_entry::long_file_name_entry::{
+    LongFileNameEntry, SequenceNumber,
+};
+pub(crate) use crate::api::directory_entry::name::{
+    eq_ignore_case, fat_upcase, validate_name, ShortName,
+};
 pub use crate::api::directory_entry::regular_entry::RegularDirectoryEntry;
 pub use crate::api::directory_entry::unknown_entry::*;
 use crate::api::timestamp::VfatTimestamp;
@@ -18,6 +24,7 @@ use crate::ClusterId;
 
 mod formats;
 mod long_file_name_entry;
+mod name;
 mod regular_entry;
 mod unknown_entry;
 
@@ -66,9 +73,12 @@ impl From<&UnknownDirectoryEntry> for VfatDirectoryEntry {
 /// Because Linux uses 0x20 for Regular filename, I will follow that convention.
 const PADDING_CHARACTER: u8 = b' ';
 const DOT_CHARACTER: u8 = b'.';
+/// How many UTF-16 units fit in a single LFN entry.
+const SINGLE_LFN_SIZE: usize = 5 + 6 + 2;
 
 impl VfatDirectoryEntry {
     // pseudo dir entries are entries . and ..
+    // `parent_dir` is ClusterId(0) when the parent is the root directory.
     pub(crate) fn create_pseudo_dir_entries(
         current_dir: ClusterId,
         parent_dir: ClusterId,
@@ -94,16 +104,7 @@ impl VfatDirectoryEntry {
 
         let parent_name = [DOT_CHARACTER, DOT_CHARACTER, 0, 0, 0, 0, 0, 0];
 
-        // According to experiments against Linux fat32 driver, when I create a directory under root
-        // it uses ClusterId(0) instead of ClusterId(2).
-        // I'm not sure the reason behind it, but I need this otherwise the directory is not interpreted
-        // correctly.
-        let (parent_high, parent_low) = if parent_dir == ClusterId::new(2) {
-            ClusterId::new(0)
-        } else {
-            parent_dir
-        }
-        .into_high_low();
+        let (parent_high, parent_low) = parent_dir.into_high_low();
 
         let parent_entry = new_regular_dir_entry(parent_name, parent_high, parent_low);
         [
@@ -139,37 +140,47 @@ impl VfatDirectoryEntry {
 
     /// The short name is derived from the long name as follows:
     /// The extension is the extension of the long name, truncated to
-    /// length at most three. The first six bytes of the short name equal
-    /// the first six nonspace bytes of the long name, but bytes +,;=[],
+    /// length at most three. The first bytes of the short name equal
+    /// the first nonspace, nonperiod bytes of the long name, but bytes +,;=[],
     /// that are not allowed under DOS, are replaced by underscore.
     /// Lower case is converted to upper case. The final two (or more, up
     /// to seven, if necessary) bytes become ~1, or, if that exists already,
-    /// ~2, etc., up to ~999999.
-    // TODO: add some check for presence of another file called in the same way (I'm using always ~1).
-    pub fn regular_filename_from(name: &str) -> [u8; 8] {
+    /// ~2, etc., up to ~999999. Picking a free `tail` is up to the caller.
+    pub fn regular_filename_from(name: &str, tail: u32) -> [u8; 8] {
         // FIXME: return a result, and reject these filenames?
-        let replace_invalid_dos_char = |ch| {
+        let replace_invalid_dos_char = |ch: char| {
             const INVALID_CHARS: [char; 6] = ['+', ',', ';', '=', '[', ']'];
-            if INVALID_CHARS.contains(&ch) {
+            if INVALID_CHARS.contains(&ch) || !ch.is_ascii() {
                 '_'
             } else {
                 ch
             }
         };
+        let tail = format!("~{}", tail);
+        // Up to three chars for the basis, less if the tail doesn't leave enough space.
+        let basis_len = min(3, 8 - tail.len());
+        let basis = match name.rfind('.') {
+            Some(pos) if Self::get_regular_filename_ext(name) != [PADDING_CHARACTER; 3] => {
+                &name[..pos]
+            }
+            _ => name,
+        };
 
-        let regular_filename_substr: String = name
+        let mut regular_filename_substr: String = basis
             .chars()
+            .filter(|ch| *ch != '.' && *ch != ' ')
             .map(replace_invalid_dos_char)
-            .flat_map(char::to_uppercase)
-            .take(3)
+            .map(|ch| ch.to_ascii_uppercase())
+            .take(basis_len)
             .collect();
-        let regular_filename_bytes = regular_filename_substr.as_bytes();
+        if regular_filename_substr.is_empty() {
+            regular_filename_substr.push('_');
+        }
+        regular_filename_substr.push_str(&tail);
+
         let mut regular_filename: [u8; 8] = [PADDING_CHARACTER; 8];
-        regular_filename[0] = regular_filename_bytes[0];
-        regular_filename[1] = regular_filename_bytes[1];
-        regular_filename[2] = regular_filename_bytes[2];
-        regular_filename[3] = b'~';
-        regular_filename[4] = b'1';
+        regular_filename[..regular_filename_substr.len()]
+            .copy_from_slice(regular_filename_substr.as_bytes());
         regular_filename
     }
 
@@ -200,7 +211,7 @@ impl VfatDirectoryEntry {
     ///         sum = (((sum&1)<<7)|((sum&0xfe)>>1)) + name[i]
     ///  }
     /// ```
-    fn checksum(name: &[u8], ext: &[u8]) -> u8 {
+    pub(crate) fn checksum(name: &[u8], ext: &[u8]) -> u8 {
         let mut sum = 0u8;
         for ch in name.iter().chain(ext) {
             sum = ((sum & 1) << 7)
@@ -209,10 +220,10 @@ impl VfatDirectoryEntry {
         }
         sum
     }
-    pub(crate) fn convert<const T: usize>(buf: &[u8]) -> [u16; T] {
+    pub(crate) fn convert<const T: usize, U: Into<u16> + Copy>(buf: &[U]) -> [u16; T] {
         let padding = || iter::repeat(0x0000u16);
         buf.iter()
-            .map(|v| *v as u16)
+            .map(|v| (*v).into())
             .chain(padding())
             .take(T)
             .collect::<Vec<u16>>()
@@ -220,63 +231,53 @@ impl VfatDirectoryEntry {
             .try_into()
             .unwrap()
     }
+    /// How many entries are needed to store `name`: the regular entry, plus the LFN entries
+    /// unless the short name is an exact representation of the name.
+    /// `name` is expected to be valid (see `validate_name`), so at most 20 LFNs are needed.
+    pub(crate) fn required_entries(name: &str, short_name: ShortName) -> usize {
+        if short_name.to_string() == name {
+            return 1;
+        }
+        let name_len = name.encode_utf16().count();
+        1 + name_len.div_ceil(SINGLE_LFN_SIZE)
+    }
+
     // The implementation of this function is inspired by tests I've run on my
     // Linux machine.
-    // Even if there is a perfect fit for a RegularFileEntry, Linux creates a new
-    // LongFileName nevertheless. I assume the reason for this is because of different
-    // char encoding. LFN uses a subset of utf-16 while RegularFileEntry uses ASCII.
+    // If the short name is an exact representation of the name (NT case flags
+    // included), no LFN entry is needed and a single regular entry is returned.
     pub(crate) fn new_vfat_entry(
         name: &str,
-        cluster_id: ClusterId,
-        attributes: Attributes,
+        regular: RegularDirectoryEntry,
     ) -> Vec<UnknownDirectoryEntry> {
-        // this can be used to populate a regular directory entry
-
-        let regular_filename = Self::regular_filename_from(name);
-        let regular_filename_ext = Self::get_regular_filename_ext(name);
-        let checksum = Self::checksum(&regular_filename, &regular_filename_ext);
-
-        info!(
-            "regular_filename: {}",
-            String::from_utf8_lossy(&regular_filename)
-        );
-        let (high_cluster_id, low_cluster_id) = cluster_id.into_high_low();
-
-        let regular = RegularDirectoryEntry {
-            file_name: regular_filename,
-            file_ext: regular_filename_ext,
-            attributes,
-            _reseverd_win_nt: 0,
-            creation_millis: Default::default(),
-            creation_time: VfatTimestamp::new(1385663476),
-            last_access_date: 0,
-            high_16bits: high_cluster_id,
-            last_modification_time: VfatTimestamp::new(1385663476),
-            low_16bits: low_cluster_id,
-            file_size: 0,
-        };
+        let short_name = regular.short_name();
+        let checksum = Self::checksum(&short_name.name, &short_name.ext);
+        info!("regular_filename: {}", short_name);
         let mut ret = vec![];
-        let mut buff_b = name;
-        // Calculate how many lfns we will need.
-        const SINGLE_LFN_SIZE: f64 = 5.0 + 6.0 + 2.0;
-        // TODO: this cast to u8 might overflow. this is because lfn have a limit in length. in that case we should error.
-        let required_lfns = (name.len() as f64 / SINGLE_LFN_SIZE).ceil() as u8;
+        let required_lfns = Self::required_entries(name, short_name) - 1;
         debug!("Required LFNS: {}", required_lfns);
         // Other then for stopping the loop below, it's also useful for the SequenceNumber attribute.
+        let required_lfns = required_lfns as u8;
+        // The name is terminated by 0x0000, unless it perfectly fits the entries.
+        let mut name_units = name.encode_utf16().chain(iter::once(0x0000));
 
         while (ret.len() + 1) as u8 <= required_lfns {
-            let buff = buff_b;
-            let (first_set_str, buff) = buff.split_at(min(5, buff.len()));
-            let (second_set_str, buff) = buff.split_at(min(6, buff.len()));
-            let (third_set_str, buff) = buff.split_at(min(2, buff.len()));
-            buff_b = buff;
+            let lfn_units = name_units
+                .by_ref()
+                .take(SINGLE_LFN_SIZE)
+                .collect::<Vec<u16>>();
+            let (first_set_units, rest) = lfn_units.split_at(min(5, lfn_units.len()));
+            let (second_set_units, third_set_units) = rest.split_at(min(6, rest.len()));
             info!(
                 "LongFileName: full name:'{:?}', first_set: '{:?}' second_set: '{:?}', third_set: '{:?}'",
-                name, first_set_str, second_set_str, third_set_str
+                name,
+                String::from_utf16_lossy(first_set_units),
+                String::from_utf16_lossy(second_set_units),
+                String::from_utf16_lossy(third_set_units),
             );
-            let first_set = Self::convert(first_set_str.as_bytes());
-            let second_set = Self::convert(second_set_str.as_bytes());
-            let third_set = Self::convert(third_set_str.as_bytes());
+            let first_set = Self::convert(first_set_units);
+            let second_set = Self::convert(second_set_units);
+            let third_set = Self::convert(third_set_units);
             info!(
                 "final sets: {:?}, {:?}, {:?}",
                 first_set, second_set, third_set
@@ -318,32 +319,15 @@ impl VfatDirectoryEntry {
         ret
     }
 }
-trait ToUtf16<const T: usize> {
-    fn into_utf16(self) -> [u16; T];
-}
-impl<const T: usize> ToUtf16<T> for &str {
-    fn into_utf16(self) -> [u16; T] {
-        let padding = || iter::repeat(0x00u16);
-        self.as_bytes()
-            .iter()
-            .map(|v| *v as u16)
-            .chain(padding())
-            .take(T)
-            .collect::<Vec<u16>>()
-            .as_slice()
-            .try_into()
-            .unwrap()
-    }
-}
-
 #[cfg(test)]
 mod test {
     extern crate std;
 
     use crate::api::directory_entry::formats::Attributes;
     use crate::api::directory_entry::{
-        LongFileNameEntry, RegularDirectoryEntry, VfatDirectoryEntry,
+        LongFileNameEntry, RegularDirectoryEntry, ShortName, VfatDirectoryEntry,
     };
+    use crate::api::timestamp::VfatTimestamp;
     use crate::ClusterId;
 
     fn init() {
@@ -351,6 +335,23 @@ mod test {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    fn new_regular(short_name: ShortName) -> RegularDirectoryEntry {
+        let (high_16bits, low_16bits) = ClusterId::new(0).into_high_low();
+        RegularDirectoryEntry {
+            file_name: short_name.name,
+            file_ext: short_name.ext,
+            attributes: Attributes::new_directory(),
+            _reseverd_win_nt: 0,
+            creation_millis: Default::default(),
+            creation_time: VfatTimestamp::new(1385663476),
+            last_access_date: 0,
+            high_16bits,
+            last_modification_time: VfatTimestamp::new(1385663476),
+            low_16bits,
+            file_size: 0,
+        }
+    }
+
     #[test]
     fn test_checksum() {
         assert_eq!(VfatDirectoryEntry::checksum(b"4CS~1   ", b"E  "), 75);
@@ -362,8 +363,12 @@ mod test {
     #[test]
     fn test_short_filename() {
         init();
-        let res = VfatDirectoryEntry::regular_filename_from("4cs....e");
+        let res = VfatDirectoryEntry::regular_filename_from("4cs....e", 1);
         assert_eq!(res, *b"4CS~1   ");
+        let res = VfatDirectoryEntry::regular_filename_from("4cs....e", 12345);
+        assert_eq!(res, *b"4C~12345");
+        let res = VfatDirectoryEntry::regular_filename_from(".bashrc", 2);
+        assert_eq!(res, *b"BAS~2   ");
         assert_eq!(
             VfatDirectoryEntry::get_regular_filename_ext("4cs....e"),
             *b"E  "
@@ -380,14 +385,13 @@ mod test {
 
         let given = VfatDirectoryEntry::new_vfat_entry(
             "4chars.ext",
-            ClusterId::new(0),
-            Attributes::new_directory(),
+            new_regular(ShortName::from_long_name("4chars.ext", 1)),
         );
         let expected_regular_name = b"4CH~1   ";
         let expecte_ext = b"EXT";
-        assert!(given.len() > 0);
+        assert!(!given.is_empty());
 
-        let lfn: LongFileNameEntry = VfatDirectoryEntry::from(given.get(0).unwrap())
+        let lfn: LongFileNameEntry = VfatDirectoryEntry::from(given.first().unwrap())
             .into_long_file_name()
             .unwrap();
         let first_set: [u16; 5] = VfatDirectoryEntry::convert(b"4char");
@@ -405,6 +409,34 @@ mod test {
         assert_eq!(&get_regular.file_ext, expecte_ext);
     }
 
+    #[test]
+    fn test_surrogate_pair_name() {
+        // The emoji is a surrogate pair, straddling the first two sets of an entry in the
+        // first name, and two entries in the second one.
+        for name in ["abcd\u{1F600}.txt", "abcdefghijkl\u{1F600}.txt"] {
+            let mut given = VfatDirectoryEntry::new_vfat_entry(
+                name,
+                new_regular(ShortName::from_long_name(name, 1)),
+            );
+            given
+                .pop()
+                .map(VfatDirectoryEntry::from)
+                .unwrap()
+                .into_regular()
+                .unwrap();
+            let parts = given
+                .into_iter()
+                .map(|entry| {
+                    VfatDirectoryEntry::from(entry)
+                        .into_long_file_name()
+                        .unwrap()
+                        .collect_name()
+                })
+                .collect();
+            assert_eq!(LongFileNameEntry::decode_name(parts), name);
+        }
+    }
+
     #[test]
     fn test_long_entry() {
         init();
@@ -412,8 +444,7 @@ mod test {
         println!("Name: {}", name);
         let mut given = VfatDirectoryEntry::new_vfat_entry(
             name,
-            ClusterId::new(0),
-            Attributes::new_directory(),
+            new_regular(ShortName::from_long_name(name, 1)),
         );
         given
             .clone()
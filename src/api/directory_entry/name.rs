@@ -0,0 +1,300 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
+
+use snafu::ensure;
+
+use crate::error::{InvalidNameSnafu, NameTooLongSnafu, Result};
+
+/// Maximum length of a long file name, in UTF-16 units.
+pub(crate) const MAX_NAME_LENGTH: usize = 255;
+
+/// Validates `name` against the VFAT rules, before using it for a new entry.
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    const FORBIDDEN_CHARS: &str = "/\\:*?\"<>|";
+    const RESERVED_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+    const RESERVED_NUMBERED_NAMES: [&str; 2] = ["COM", "LPT"];
+    let invalid = |reason| {
+        InvalidNameSnafu {
+            target: name.to_string(),
+            reason,
+        }
+        .fail()
+    };
+
+    if name.is_empty() {
+        return invalid("empty name");
+    }
+    if name == "." || name == ".." {
+        return invalid("reserved for pseudo directories");
+    }
+    if name
+        .chars()
+        .any(|ch| ch.is_ascii_control() || FORBIDDEN_CHARS.contains(ch))
+    {
+        return invalid("forbidden character");
+    }
+    if name.ends_with('.') || name.ends_with(' ') {
+        return invalid("trailing dot or space");
+    }
+    // Device names are reserved regardless of case and extension (e.g. "con.txt").
+    let base = name.split('.').next().unwrap_or_default().trim_end();
+    let is_reserved = RESERVED_NAMES
+        .iter()
+        .any(|reserved| base.eq_ignore_ascii_case(reserved))
+        || RESERVED_NUMBERED_NAMES.iter().any(|reserved| {
+            base.len() == 4
+                && base.is_char_boundary(3)
+                && base[..3].eq_ignore_ascii_case(reserved)
+                && matches!(base.as_bytes()[3], b'1'..=b'9')
+        });
+    if is_reserved {
+        return invalid("reserved device name");
+    }
+    let length = name.encode_utf16().count();
+    ensure!(
+        length <= MAX_NAME_LENGTH,
+        NameTooLongSnafu {
+            target: name,
+            length,
+            max: MAX_NAME_LENGTH,
+        }
+    );
+    Ok(())
+}
+
+/// Up-case a single character following FAT rules: characters whose upper case form
+/// would expand to more than one character (e.g. 'ß') are left untouched, as the
+/// up-case table used by FAT maps each UCS-2 unit to exactly one unit.
+pub(crate) fn fat_upcase(ch: char) -> char {
+    let mut upper = ch.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(up), None) => up,
+        _ => ch,
+    }
+}
+
+/// Compares two names the way FAT does: case-insensitively, while the stored case is kept.
+pub(crate) fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars().map(fat_upcase).eq(b.chars().map(fat_upcase))
+}
+
+/// Windows NT flag: the base name is displayed in lower case.
+pub(crate) const LOWERCASE_BASE: u8 = 0x08;
+/// Windows NT flag: the extension is displayed in lower case.
+pub(crate) const LOWERCASE_EXT: u8 = 0x10;
+
+/// The 8.3 name (a.k.a. short name or alias) stored in a regular directory entry.
+/// Both parts are padded with spaces.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ShortName {
+    pub(crate) name: [u8; 8],
+    pub(crate) ext: [u8; 3],
+    /// The NT case flags (`LOWERCASE_BASE`, `LOWERCASE_EXT`). They only affect how the name is
+    /// displayed: the name itself is always stored in upper case.
+    pub(crate) case_flags: u8,
+}
+
+impl ShortName {
+    pub(crate) fn new(name: [u8; 8], ext: [u8; 3]) -> Self {
+        Self {
+            name,
+            ext,
+            case_flags: 0,
+        }
+    }
+
+    pub(crate) fn with_case_flags(mut self, case_flags: u8) -> Self {
+        self.case_flags = case_flags & (LOWERCASE_BASE | LOWERCASE_EXT);
+        self
+    }
+
+    /// Returns the short name which is an exact representation of `name`, if any. That is the case
+    /// when `name` fits in 8.3, only uses valid characters, and each part is either all lower
+    /// case or all upper case. Lower case parts are stored using the NT case flags.
+    pub(crate) fn from_plain_name(name: &str) -> Option<Self> {
+        const VALID_SPECIAL_CHARS: &str = "!#$%&'()-@^_`{}~";
+        // Returns the case flag needed by `part`, if it can be represented at all.
+        let part_case = |part: &str, max_len: usize, flag: u8| -> Option<u8> {
+            let valid = part.len() <= max_len
+                && part
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || VALID_SPECIAL_CHARS.contains(ch));
+            let has_lower = part.chars().any(|ch| ch.is_ascii_lowercase());
+            let has_upper = part.chars().any(|ch| ch.is_ascii_uppercase());
+            match (valid, has_lower, has_upper) {
+                (false, _, _) | (true, true, true) => None,
+                (true, true, false) => Some(flag),
+                (true, false, _) => Some(0),
+            }
+        };
+        let (base, ext) = match name.split_once('.') {
+            Some((_, "")) => return None,
+            Some((base, ext)) => (base, ext),
+            None => (name, ""),
+        };
+        if base.is_empty() {
+            return None;
+        }
+        let case_flags = part_case(base, 8, LOWERCASE_BASE)? | part_case(ext, 3, LOWERCASE_EXT)?;
+        let mut short_name = Self::empty();
+        short_name.name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+        short_name.ext[..ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+        Some(short_name.with_case_flags(case_flags))
+    }
+
+    /// The root directory has no entry, hence no short name.
+    pub(crate) fn empty() -> Self {
+        Self::new([b' '; 8], [b' '; 3])
+    }
+
+    /// Builds the alias of `long_name`, using `~tail` as numeric tail.
+    pub(crate) fn from_long_name(long_name: &str, tail: u32) -> Self {
+        use crate::api::directory_entry::VfatDirectoryEntry;
+        Self::new(
+            VfatDirectoryEntry::regular_filename_from(long_name, tail),
+            VfatDirectoryEntry::get_regular_filename_ext(long_name),
+        )
+    }
+
+    fn early_terminate_pos(v: &[u8]) -> usize {
+        v.iter()
+            .position(|ch| *ch == 0x00 || *ch == 0x20)
+            .unwrap_or(v.len())
+    }
+
+    pub fn file_name(&self) -> &[u8] {
+        &self.name[..Self::early_terminate_pos(&self.name)]
+    }
+
+    pub fn extension(&self) -> Option<&[u8]> {
+        let pos = Self::early_terminate_pos(&self.ext);
+        (pos > 0).then(|| &self.ext[..pos])
+    }
+}
+
+impl fmt::Display for ShortName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let apply_case = |part: &[u8], flag: u8| {
+            let part = String::from_utf8_lossy(part);
+            if self.case_flags & flag != 0 {
+                part.to_ascii_lowercase()
+            } else {
+                part.into_owned()
+            }
+        };
+        let name = apply_case(self.file_name(), LOWERCASE_BASE);
+        let ext = self
+            .extension()
+            .map(|ext| format!(".{}", apply_case(ext, LOWERCASE_EXT)))
+            .unwrap_or_default();
+        write!(f, "{}{}", name, ext)
+    }
+}
+
+impl fmt::Debug for ShortName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ShortName({})", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{eq_ignore_case, validate_name, ShortName, LOWERCASE_BASE, LOWERCASE_EXT};
+    use crate::VfatRsError;
+
+    #[test]
+    fn test_eq_ignore_case() {
+        assert!(eq_ignore_case("MyFoLdEr", "myfolder"));
+        assert!(eq_ignore_case("readme.txt", "README.TXT"));
+        assert!(eq_ignore_case("àbc", "ÀBC"));
+        assert!(!eq_ignore_case("readme.txt", "readme.tx"));
+        // 'ß' upper cases to "SS": FAT leaves it alone.
+        assert!(!eq_ignore_case("ß", "SS"));
+    }
+
+    #[test]
+    fn test_from_plain_name() {
+        let short_name = ShortName::from_plain_name("readme.txt").unwrap();
+        assert_eq!(&short_name.name, b"README  ");
+        assert_eq!(&short_name.ext, b"TXT");
+        assert_eq!(short_name.case_flags, LOWERCASE_BASE | LOWERCASE_EXT);
+        assert_eq!(short_name.to_string(), "readme.txt");
+
+        let short_name = ShortName::from_plain_name("README.md").unwrap();
+        assert_eq!(short_name.case_flags, LOWERCASE_EXT);
+        assert_eq!(short_name.to_string(), "README.md");
+        assert_eq!(
+            ShortName::from_plain_name("makefile").unwrap().to_string(),
+            "makefile"
+        );
+        assert_eq!(ShortName::from_plain_name("A1").unwrap().case_flags, 0);
+
+        // Mixed case, too long, or invalid names need an LFN.
+        for name in [
+            "ReadMe.txt",
+            "longer-name.txt",
+            "file.html",
+            "a.b.c",
+            ".bashrc",
+            "trailing.",
+            "with space",
+            "plus+sign",
+            "àccent",
+        ] {
+            assert!(ShortName::from_plain_name(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_validate_name() {
+        for name in [
+            "hello.txt",
+            "a-very-long-file-name-entry.txt",
+            ".bashrc",
+            "with space",
+            "café.txt",
+            "console",
+            "com0",
+            "COM10",
+        ] {
+            assert!(validate_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            "a/b",
+            "a\\b",
+            "c:",
+            "*",
+            "what?",
+            "\"quoted\"",
+            "<>",
+            "pipe|",
+            "tab\t",
+            "trailing.",
+            "trailing ",
+            "CON",
+            "nul.txt",
+            "Com1",
+            "lpt9.tar.gz",
+        ] {
+            assert!(
+                matches!(validate_name(name), Err(VfatRsError::InvalidName { .. })),
+                "{}",
+                name
+            );
+        }
+        assert!(validate_name(&"a".repeat(255)).is_ok());
+        assert!(matches!(
+            validate_name(&"a".repeat(256)),
+            Err(VfatRsError::NameTooLong { length: 256, .. })
+        ));
+        // Lengths are counted in UTF-16 units.
+        assert!(matches!(
+            validate_name(&"😀".repeat(128)),
+            Err(VfatRsError::NameTooLong { length: 256, .. })
+        ));
+    }
+}
@@ -1,9 +1,8 @@
-use alloc::format;
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use core::fmt;
 use core::fmt::{Debug, Formatter};
 
-use crate::api::directory_entry::{Attributes, VfatDirectoryEntry};
+use crate::api::directory_entry::{Attributes, ShortName};
 use crate::api::timestamp::{Milliseconds, VfatTimestamp};
 use crate::api::Metadata;
 use crate::{const_assert_size, ClusterId};
@@ -20,7 +19,8 @@ pub struct RegularDirectoryEntry {
     pub file_ext: [u8; 3],
     /// Attributes of this file.
     pub(crate) attributes: Attributes,
-    /// Reserved for future use
+    /// Reserved by Windows NT. Bits 3 and 4 tell whether the base name and the extension
+    /// should be displayed in lower case.
     pub(crate) _reseverd_win_nt: u8,
     /// Creation time's milliseconds
     pub(crate) creation_millis: Milliseconds,
@@ -59,8 +59,12 @@ impl Debug for RegularDirectoryEntry {
 
 impl From<Metadata> for RegularDirectoryEntry {
     fn from(metadata: Metadata) -> Self {
-        let file_name = VfatDirectoryEntry::regular_filename_from(metadata.name());
-        let file_ext = VfatDirectoryEntry::get_regular_filename_ext(metadata.name());
+        // The short name is kept as found on disk: LFN entries are bound to it by their checksum.
+        let ShortName {
+            name: file_name,
+            ext: file_ext,
+            case_flags,
+        } = metadata.short_name;
         let (high_16bits, low_16bits) = metadata.cluster.into_high_low();
         RegularDirectoryEntry {
             file_name,
@@ -71,7 +75,7 @@ impl From<Metadata> for RegularDirectoryEntry {
             creation_time: metadata.creation().unwrap(),
             last_modification_time: metadata.last_update().unwrap(),
             file_size: metadata.size,
-            _reseverd_win_nt: 0,
+            _reseverd_win_nt: case_flags,
             creation_millis: Default::default(),
             last_access_date: 0,
         }
@@ -91,29 +95,11 @@ impl RegularDirectoryEntry {
     pub fn is_lfn(&self) -> bool {
         self.attributes.is_lfn()
     }
-    /// Handles everything needed for returning a correct name.
+    /// Handles everything needed for returning a correct name, NT lower case flags included.
     pub fn full_name(&self) -> String {
-        let name = String::from_utf8_lossy(self.file_name());
-        let ext = self
-            .extension()
-            .map(|ext| format!(".{}", String::from_utf8_lossy(ext)))
-            .unwrap_or_else(String::new);
-        format!("{}{}", name, ext)
+        self.short_name().to_string()
     }
-    fn early_terminate_pos(v: &[u8]) -> usize {
-        for (pos, ch) in v.iter().enumerate() {
-            if *ch == 0x00 || *ch == 0x20 {
-                return pos;
-            }
-        }
-        v.len()
-    }
-    pub fn file_name(&self) -> &[u8] {
-        let pos = Self::early_terminate_pos(&self.file_name);
-        &self.file_name[..pos]
-    }
-    pub fn extension(&self) -> Option<&[u8]> {
-        let pos = Self::early_terminate_pos(&self.file_ext);
-        (pos > 0).then(|| &self.file_ext[..pos])
+    pub(crate) fn short_name(&self) -> ShortName {
+        ShortName::new(self.file_name, self.file_ext).with_case_flags(self._reseverd_win_nt)
     }
 }
@@ -1,9 +1,12 @@
+use crate::api::directory_entry::Attributes;
 use crate::api::timestamp::VfatTimestamp;
 use crate::api::{Directory, File, Metadata};
+use crate::error::{IsADirectorySnafu, NotADirectorySnafu};
 use crate::{Result, VfatFS};
+use snafu::ensure;
 
 /// This is a library's user interface. Each directory can contain either a File or a Directory.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum EntryKind {
     File,
     Directory,
@@ -34,7 +37,7 @@ impl VfatMetadataTrait for VfatEntry {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VfatEntry {
     kind: EntryKind,
     pub metadata: Metadata,
@@ -62,6 +65,20 @@ impl VfatEntry {
         &self.metadata
     }
 
+    /// Sets `attributes` (READ_ONLY, HIDDEN, SYSTEM, ARCHIVE) on this entry.
+    pub fn set_attributes(&mut self, attributes: Attributes) -> Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.vfat_filesystem
+            .change_attributes(&mut self.metadata, attributes, Attributes::empty())
+    }
+
+    /// Clears `attributes` (READ_ONLY, HIDDEN, SYSTEM, ARCHIVE) on this entry.
+    pub fn clear_attributes(&mut self, attributes: Attributes) -> Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.vfat_filesystem
+            .change_attributes(&mut self.metadata, Attributes::empty(), attributes)
+    }
+
     pub(crate) fn is_dir(&self) -> bool {
         matches!(&self.kind, EntryKind::Directory)
     }
@@ -82,6 +99,16 @@ impl VfatEntry {
             })
         }
     }
+    /// Like `into_directory`, but fails with `NotADirectory` if this entry is a file.
+    pub fn try_into_directory(self) -> Result<Directory> {
+        ensure!(
+            self.is_dir(),
+            NotADirectorySnafu {
+                target: self.metadata.path().display(),
+            }
+        );
+        Ok(self.into_directory_unchecked())
+    }
     fn is_file(&self) -> bool {
         !self.is_dir()
     }
@@ -89,10 +116,21 @@ impl VfatEntry {
         self.is_file()
             .then(|| File::new(self.vfat_filesystem, self.metadata))
     }
-    pub fn into_file_unchecked(self) -> File {
-        self.is_file()
-            .then(|| File::new(self.vfat_filesystem, self.metadata))
-            .unwrap()
+    /// Like `into_file`, but fails with `IsADirectory` if this entry is a directory.
+    pub fn try_into_file(self) -> Result<File> {
+        ensure!(
+            self.is_file(),
+            IsADirectorySnafu {
+                target: self.metadata.path().display(),
+            }
+        );
+        Ok(File::new(self.vfat_filesystem, self.metadata))
+    }
+}
+
+impl From<File> for VfatEntry {
+    fn from(file: File) -> Self {
+        VfatEntry::new_file(file.metadata().clone(), file.vfat_filesystem.clone())
     }
 }
 
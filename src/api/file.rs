@@ -1,20 +1,36 @@
 use crate::io::{SeekFrom, Write};
+use alloc::sync::Arc;
 use core::fmt::Formatter;
 use core::{cmp, fmt};
 
-use log::{debug, info};
+use log::{debug, error, info};
+use snafu::ensure;
 
+use crate::api::directory_entry::Attributes;
+use crate::api::open_file::FileNode;
 use crate::api::Metadata;
+use crate::error::LogFailure;
 use crate::{error, ClusterId, Result, VfatFS};
 
 /// A File representation in a VfatFilesystem.
-//#[derive(Clone)]
+/// Handles to the same file share its size and clusters: what is written through one handle
+/// can be read through the others.
 pub struct File {
     pub(crate) vfat_filesystem: VfatFS,
+    /// A copy of the shared metadata, refreshed by every operation on this handle.
     pub(crate) metadata: Metadata,
+    node: Arc<FileNode>,
     // Current Seek position
     pub offset: usize,
 }
+/// The state of a `File` handle, see `File::checkpoint`.
+#[cfg(feature = "async")]
+pub(crate) struct FileCheckpoint {
+    metadata: Metadata,
+    shared: Metadata,
+    offset: usize,
+}
+
 impl fmt::Debug for File {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
@@ -27,17 +43,57 @@ impl fmt::Debug for File {
 
 impl File {
     pub fn new(vfat_filesystem: VfatFS, metadata: Metadata) -> Self {
+        let node = vfat_filesystem.open_files.open(&metadata);
         File {
+            metadata: node.metadata(),
             vfat_filesystem,
-            metadata,
+            node,
             offset: 0,
         }
     }
+
+    /// The metadata as of the last operation on this handle. Other handles to the same file
+    /// might have changed it since, see `size`.
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
 
+    /// The current size of this file, including writes made through other handles.
+    pub fn size(&self) -> usize {
+        self.node.metadata().size()
+    }
+
+    /// Picks up the changes made through other handles.
+    fn refresh(&mut self) {
+        self.metadata = self.node.metadata();
+    }
+
+    /// Saves the state of this handle, including the metadata shared with the other handles.
+    #[cfg(feature = "async")]
+    pub(crate) fn checkpoint(&self) -> FileCheckpoint {
+        FileCheckpoint {
+            metadata: self.metadata.clone(),
+            shared: self.node.metadata(),
+            offset: self.offset,
+        }
+    }
+
+    /// Restores the state saved by `checkpoint`, undoing an operation whose writes were
+    /// discarded.
+    #[cfg(feature = "async")]
+    pub(crate) fn rollback(&mut self, checkpoint: FileCheckpoint) {
+        self.node.set_metadata(&checkpoint.shared);
+        self.metadata = checkpoint.metadata;
+        self.offset = checkpoint.offset;
+    }
+
     pub fn update_file_size(&mut self, amount_written: usize) -> Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.refresh();
+        self.update_file_size_inner(amount_written)
+    }
+
+    fn update_file_size_inner(&mut self, amount_written: usize) -> Result<()> {
         if self.offset + amount_written <= self.metadata.size as usize {
             return Ok(());
         }
@@ -51,23 +107,109 @@ impl File {
             "I'm going to update file size on the fs... Parent path: {:?}",
             self.metadata.parent()
         );
-        self.update_metadata()
+        self.update_metadata_inner()
     }
 
     pub fn update_metadata(&mut self) -> Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.update_metadata_inner()
+    }
+
+    /// Shares the metadata with the other handles, and writes it to disk unless the file
+    /// was deleted.
+    fn update_metadata_inner(&mut self) -> Result<()> {
+        self.node.set_metadata(&self.metadata);
+        if self.node.is_unlinked() {
+            return Ok(());
+        }
         debug!("Going to update metadata on disk...");
+        self.vfat_filesystem.update_entry(&self.metadata)
+    }
+
+    /// Sets `attributes` (READ_ONLY, HIDDEN, SYSTEM, ARCHIVE) on this file.
+    pub fn set_attributes(&mut self, attributes: Attributes) -> Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
+        self.vfat_filesystem
+            .change_attributes(&mut self.metadata, attributes, Attributes::empty())
+    }
+
+    /// Clears `attributes` (READ_ONLY, HIDDEN, SYSTEM, ARCHIVE) on this file.
+    pub fn clear_attributes(&mut self, attributes: Attributes) -> Result<()> {
+        let _guard = self.vfat_filesystem.write_lock()?;
         self.vfat_filesystem
-            .get_path(self.metadata.parent().clone())?
-            .into_directory()
-            .unwrap()
-            .update_entry(self.metadata.clone())
+            .change_attributes(&mut self.metadata, Attributes::empty(), attributes)
+    }
+
+    /// Fails if this file is read-only. Otherwise, marks it as modified (ARCHIVE),
+    /// updating the entry on disk if needed.
+    fn prepare_modification(&mut self) -> Result<()> {
+        ensure!(
+            !self.metadata.attributes.is_read_only(),
+            error::ReadOnlyEntrySnafu {
+                target: self.metadata.name(),
+            }
+        );
+        if !self.metadata.attributes.is_archive() {
+            self.metadata.attributes.insert(Attributes::ARCHIVE);
+            self.update_metadata_inner()?;
+        }
+        Ok(())
+    }
+
+    /// Truncates or extends this file to `size` bytes. Extended space is filled with zeros.
+    /// The offset is left untouched.
+    pub fn truncate(&mut self, size: usize) -> Result<()> {
+        let _guard = self
+            .vfat_filesystem
+            .write_lock()
+            .log_failure("truncate", self.metadata.path().display())?;
+        self.truncate_inner(size)
+            .log_failure("truncate", self.metadata.path().display())
+    }
+
+    pub(crate) fn truncate_inner(&mut self, size: usize) -> Result<()> {
+        self.refresh();
+        self.prepare_modification()?;
+        let current_size = self.metadata.size();
+        if size > current_size {
+            let offset = self.offset;
+            self.offset = current_size;
+            let zeros = alloc::vec![0u8; size - current_size];
+            let written = self.write_inner(&zeros);
+            self.offset = offset;
+            return written.map(|_| ());
+        }
+        if self.metadata.cluster != ClusterId::new(0) {
+            if size == 0 {
+                self.vfat_filesystem
+                    .delete_fat_cluster_chain(self.metadata.cluster)?;
+                self.metadata.cluster = ClusterId::new(0);
+            } else {
+                let keep = size.div_ceil(self.vfat_filesystem.cluster_size());
+                self.vfat_filesystem
+                    .truncate_cluster_chain(self.metadata.cluster, keep)?;
+            }
+        }
+        self.metadata.size = size as u32;
+        self.update_metadata_inner()
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
+        let _guard = self
+            .vfat_filesystem
+            .write_lock()
+            .log_failure("write", self.metadata.path().display())?;
+        self.write_inner(buf)
+            .log_failure("write", self.metadata.path().display())
+    }
+
+    fn write_inner(&mut self, buf: &[u8]) -> Result<usize> {
         debug!("Requested write on file.");
+        self.refresh();
+        self.prepare_modification()?;
         if self.metadata.cluster == ClusterId::new(0) {
             debug!("File's cluster is none.");
             self.metadata.cluster = self.vfat_filesystem.allocate_cluster_new_entry()?;
@@ -75,12 +217,13 @@ impl File {
                 "Allocated cluster to file: {}, updating metadata...",
                 self.metadata.cluster
             );
-            self.update_metadata()?;
+            self.update_metadata_inner()?;
             debug!("Updated metadata");
         }
         let mut ccw = self
             .vfat_filesystem
-            .cluster_chain_writer(self.metadata.cluster);
+            .cluster_chain_writer(self.metadata.cluster)
+            .relocating_head();
 
         ccw.seek(self.offset)?;
 
@@ -90,9 +233,14 @@ impl File {
             buf.len(),
             self.offset,
         );
-        let amount_written = ccw.write(buf)?;
+        let written = ccw.write(buf);
+        if ccw.head() != self.metadata.cluster {
+            self.metadata.cluster = ccw.head();
+            self.update_metadata_inner()?;
+        }
+        let amount_written = written?;
         info!("File: Write: Amount written: {}", amount_written);
-        self.update_file_size(amount_written)?;
+        self.update_file_size_inner(amount_written)?;
         self.offset += amount_written;
 
         Ok(amount_written)
@@ -104,6 +252,12 @@ impl File {
     }
 
     pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.seek_inner(pos)
+            .log_failure("seek", self.metadata.path().display())
+    }
+
+    fn seek_inner(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.refresh();
         match pos {
             SeekFrom::Start(val) => {
                 self.offset = val as usize;
@@ -135,7 +289,14 @@ impl File {
         }
         Ok(self.offset as u64)
     }
-    pub fn read(&mut self, mut buf: &mut [u8]) -> Result<usize> {
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let _guard = self.vfat_filesystem.read_lock();
+        self.read_inner(buf)
+            .log_failure("read", self.metadata.path().display())
+    }
+
+    fn read_inner(&mut self, mut buf: &mut [u8]) -> Result<usize> {
+        self.refresh();
         // it should read at most the buf size or the missing file data.
         let amount_to_read = cmp::min(buf.len(), self.metadata.size().saturating_sub(self.offset));
         if amount_to_read == 0
@@ -163,18 +324,40 @@ impl File {
         self.offset += amount_read;
         Ok(amount_read)
     }
+}
 
-    fn _sync(&mut self) -> error::Result<()> {
-        unimplemented!()
+impl Drop for File {
+    /// The clusters of a file deleted while open are freed along with its last handle.
+    fn drop(&mut self) {
+        if !self.vfat_filesystem.open_files.close(&self.node) {
+            return;
+        }
+        let cluster = self.node.metadata().cluster;
+        if cluster == ClusterId::new(0) {
+            return;
+        }
+        let Ok(_guard) = self.vfat_filesystem.write_lock() else {
+            return;
+        };
+        if let Err(err) = self.vfat_filesystem.delete_fat_cluster_chain(cluster) {
+            error!("Failed to free the clusters of a deleted file: {}", err);
+        }
     }
 }
 
+// The variant is lost in the conversion, so the message keeps the operation and path.
 impl Write for File {
     fn write(&mut self, buf: &[u8]) -> crate::io::Result<usize> {
-        Ok(self.write(buf)?)
+        self.write(buf).map_err(|err| {
+            err.with_context("write", self.metadata.path().display())
+                .into()
+        })
     }
 
     fn flush(&mut self) -> crate::io::Result<()> {
-        Ok(self.flush()?)
+        self.flush().map_err(|err| {
+            err.with_context("flush", self.metadata.path().display())
+                .into()
+        })
     }
 }
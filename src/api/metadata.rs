@@ -1,8 +1,8 @@
-use crate::api::directory_entry::Attributes;
+use crate::api::directory_entry::{eq_ignore_case, Attributes, ShortName};
 use crate::api::timestamp::VfatTimestamp;
 use crate::ClusterId;
 use crate::Path;
-use alloc::string::String;
+use alloc::string::{String, ToString};
 
 /// Metadatas are common to every entry type.
 #[derive(Debug, Clone)]
@@ -11,6 +11,8 @@ pub struct Metadata {
     last_update: VfatTimestamp,
     //last_access: VfatTimestamp,
     name: String,
+    /// The 8.3 alias of this entry. It's the same as `name` if the entry has no long name.
+    pub(crate) short_name: ShortName,
     /// Size of this file in bytes. For directories, it should be the sum of the sizes
     /// occupied by the metadatas of the contained files.
     pub(crate) size: u32,
@@ -20,6 +22,9 @@ pub struct Metadata {
     pub(crate) cluster: ClusterId,
     /// The path to this file - it doesn't include the file name.
     parent: Path,
+    /// First cluster of the directory holding this entry. Used to update the entry without
+    /// resolving `parent` from the root.
+    pub(crate) parent_cluster: ClusterId,
     pub(crate) attributes: Attributes,
 }
 
@@ -29,10 +34,12 @@ impl Metadata {
         last_update: VfatTimestamp,
         //last_access: VfatTimestamp,
         name: S,
+        short_name: ShortName,
         size: u32,
         path: Path,
         cluster: ClusterId,
         parent: Path,
+        parent_cluster: ClusterId,
         attributes: Attributes,
     ) -> Self {
         Self {
@@ -40,13 +47,24 @@ impl Metadata {
             last_update,
             //last_access,
             name: String::from(name.as_ref()),
+            short_name,
             size,
             path,
             cluster,
             parent,
+            parent_cluster,
             attributes,
         }
     }
+
+    /// Moves this entry to the directory described by `parent`, under a new name.
+    pub(crate) fn relocate(&mut self, name: &str, short_name: ShortName, parent: &Metadata) {
+        self.name = String::from(name);
+        self.short_name = short_name;
+        self.path = parent.path().join(name);
+        self.parent = parent.path().clone();
+        self.parent_cluster = parent.cluster;
+    }
 }
 impl Metadata {
     pub fn size(&self) -> usize {
@@ -77,4 +95,16 @@ impl Metadata {
     pub fn name(&self) -> &str {
         &self.name
     }
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+    /// The 8.3 alias of this entry, e.g. `PROGRA~1`.
+    pub fn short_name(&self) -> String {
+        self.short_name.to_string()
+    }
+    /// Returns true if `name` refers to this entry. Like on any FAT implementation,
+    /// comparison is case-insensitive and both the long name and the 8.3 alias are matched.
+    pub fn matches_name(&self, name: &str) -> bool {
+        eq_ignore_case(&self.name, name) || eq_ignore_case(&self.short_name(), name)
+    }
 }
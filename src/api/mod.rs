@@ -3,9 +3,13 @@ pub mod directory_entry;
 mod entry;
 mod file;
 mod metadata;
+pub(crate) mod open_file;
 pub mod timestamp;
+pub(crate) mod walk;
 
 pub use directory::*;
 pub use entry::*;
 pub use file::*;
 pub use metadata::*;
+pub use open_file::DeletePolicy;
+pub use walk::*;
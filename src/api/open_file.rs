@@ -0,0 +1,132 @@
+//! The open-file table. Every `File` handle to the same on-disk entry shares a single node,
+//! holding the entry's metadata: size and cluster changes made through one handle are seen by
+//! the others, and deleting a file which is still open can be deferred.
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use spin::mutex::SpinMutex;
+
+use crate::api::Metadata;
+use crate::ClusterId;
+
+/// What happens when deleting a file which has open handles, see `VfatFS::set_delete_policy`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DeletePolicy {
+    /// The entry is removed from its directory right away, but its clusters are only freed
+    /// once the last handle is dropped: open handles keep working, like on Unix.
+    #[default]
+    Defer,
+    /// Deleting the file fails with `FileInUse`.
+    Deny,
+}
+
+/// Identifies an on-disk entry. The 8.3 alias is unique within a directory, and unlike the
+/// position of the entry, it doesn't change when the directory is shrunk.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+struct EntryKey {
+    parent_cluster: ClusterId,
+    name: [u8; 8],
+    ext: [u8; 3],
+}
+
+impl From<&Metadata> for EntryKey {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            parent_cluster: metadata.parent_cluster,
+            name: metadata.short_name.name,
+            ext: metadata.short_name.ext,
+        }
+    }
+}
+
+struct NodeState {
+    metadata: Metadata,
+    /// How many `File` handles refer to this node.
+    handles: usize,
+    /// Set once the entry is deleted while still open.
+    unlinked: bool,
+}
+
+/// The in-memory state of an open file, shared by all its handles.
+pub(crate) struct FileNode {
+    state: SpinMutex<NodeState>,
+}
+
+impl FileNode {
+    pub(crate) fn metadata(&self) -> Metadata {
+        self.state.lock().metadata.clone()
+    }
+
+    pub(crate) fn set_metadata(&self, metadata: &Metadata) {
+        self.state.lock().metadata = metadata.clone();
+    }
+
+    /// True if the entry was deleted: there is no entry on disk to keep up to date anymore.
+    pub(crate) fn is_unlinked(&self) -> bool {
+        self.state.lock().unlinked
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct OpenFiles {
+    nodes: SpinMutex<BTreeMap<EntryKey, Arc<FileNode>>>,
+}
+
+impl OpenFiles {
+    /// Registers a new handle to the entry described by `metadata`. If the entry is open
+    /// already, its node is returned, otherwise a node is created from `metadata`.
+    pub(crate) fn open(&self, metadata: &Metadata) -> Arc<FileNode> {
+        let mut nodes = self.nodes.lock();
+        let node = nodes.entry(EntryKey::from(metadata)).or_insert_with(|| {
+            Arc::new(FileNode {
+                state: SpinMutex::new(NodeState {
+                    metadata: metadata.clone(),
+                    handles: 0,
+                    unlinked: false,
+                }),
+            })
+        });
+        node.state.lock().handles += 1;
+        node.clone()
+    }
+
+    /// Unregisters a handle to `node`. Returns true if it was the last handle to a deleted
+    /// entry: its clusters can be freed.
+    pub(crate) fn close(&self, node: &Arc<FileNode>) -> bool {
+        let mut nodes = self.nodes.lock();
+        let mut state = node.state.lock();
+        state.handles -= 1;
+        if state.handles > 0 {
+            return false;
+        }
+        if state.unlinked {
+            return true;
+        }
+        nodes.remove(&EntryKey::from(&state.metadata));
+        false
+    }
+
+    /// The node of the entry described by `metadata`, if it's open.
+    pub(crate) fn get(&self, metadata: &Metadata) -> Option<Arc<FileNode>> {
+        self.nodes.lock().get(&EntryKey::from(metadata)).cloned()
+    }
+
+    /// Updates the node of an entry which was renamed or moved, if it's open. `old` and `new`
+    /// describe the entry before and after the move.
+    pub(crate) fn relocate(&self, old: &Metadata, new: &Metadata) {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.remove(&EntryKey::from(old)) {
+            node.set_metadata(new);
+            nodes.insert(EntryKey::from(new), node);
+        }
+    }
+
+    /// Detaches `node` from its entry, which is being deleted. Its name can then be reused by
+    /// a new entry, while the node lives on until its last handle is dropped.
+    pub(crate) fn unlink(&self, node: &Arc<FileNode>) {
+        let mut nodes = self.nodes.lock();
+        let mut state = node.state.lock();
+        nodes.remove(&EntryKey::from(&state.metadata));
+        state.unlinked = true;
+    }
+}
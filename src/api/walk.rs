@@ -0,0 +1,381 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::{IntoIter, Vec};
+
+use crate::api::directory_entry::{fat_upcase, Attributes};
+use crate::api::{Directory, VfatEntry};
+use crate::diagnostics::Diagnostic;
+use crate::{error, ClusterId, VfatFS};
+
+/// In which order `Walk` yields directories, compared to their contents.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum WalkOrder {
+    /// Directories are yielded before their contents.
+    #[default]
+    PreOrder,
+    /// Directories are yielded after their contents, e.g. for deleting a tree.
+    PostOrder,
+}
+
+/// Options used by `VfatFS::walk_with`.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// How deep to go: entries directly contained in the root have depth 1. No limit if unset.
+    pub max_depth: Option<usize>,
+    pub order: WalkOrder,
+    /// Skips entries with the HIDDEN attribute, and the contents of hidden directories.
+    pub skip_hidden: bool,
+    /// Skips entries with the SYSTEM attribute, and the contents of system directories.
+    pub skip_system: bool,
+}
+
+impl WalkOptions {
+    fn skips(&self, entry: &VfatEntry) -> bool {
+        let name = entry.metadata.name();
+        let attributes = entry.metadata.attributes();
+        // The volume label is stored as an entry of the root, but it's not a file.
+        name == "."
+            || name == ".."
+            || attributes.is_volume_id()
+            || (self.skip_hidden && attributes.contains(Attributes::HIDDEN))
+            || (self.skip_system && attributes.contains(Attributes::SYSTEM))
+    }
+}
+
+/// An entry found by `Walk`.
+#[derive(Debug)]
+pub struct WalkEntry {
+    pub entry: VfatEntry,
+    /// Entries directly contained in the root have depth 1.
+    pub depth: usize,
+    /// The path of this entry relative to the root, without a leading `/`.
+    pub relative_path: String,
+}
+
+struct Frame {
+    /// The directory being visited, to be yielded once done when walking in post-order.
+    directory: Option<WalkEntry>,
+    children: IntoIter<VfatEntry>,
+    /// Depth of the children.
+    depth: usize,
+    relative_path: String,
+}
+
+/// A recursive iterator over the contents of a directory, see `VfatFS::walk`.
+/// The pseudo directories `.` and `..` and the volume label are skipped, and each directory is
+/// visited at most once: a directory sharing its cluster with an already visited one (e.g.
+/// because of a corrupted volume) is yielded, but not descended into, and it's reported through
+/// the diagnostics hook.
+///
+/// On error (e.g. while reading a directory) the error is yielded, and the walk goes on
+/// with the next entry.
+pub struct Walk {
+    vfat_filesystem: VfatFS,
+    options: WalkOptions,
+    stack: Vec<Frame>,
+    visited: BTreeSet<ClusterId>,
+}
+
+impl Walk {
+    pub(crate) fn new(root: Directory, options: WalkOptions) -> error::Result<Self> {
+        let vfat_filesystem = root.vfat_filesystem.clone();
+        let mut walk = Self {
+            vfat_filesystem,
+            options,
+            stack: Vec::new(),
+            visited: BTreeSet::new(),
+        };
+        if walk.options.max_depth != Some(0) {
+            walk.visited
+                .insert(walk.directory_cluster(&root.metadata.cluster));
+            walk.stack.push(Frame {
+                directory: None,
+                children: root.contents()?.into_iter(),
+                depth: 1,
+                relative_path: String::new(),
+            });
+        }
+        Ok(walk)
+    }
+
+    /// A `..` entry pointing to the root has cluster 0.
+    fn directory_cluster(&self, cluster: &ClusterId) -> ClusterId {
+        if *cluster == ClusterId::new(0) {
+            self.vfat_filesystem.root_cluster
+        } else {
+            *cluster
+        }
+    }
+
+    /// Pushes a frame for visiting `directory`, unless it was visited already.
+    fn descend(&mut self, directory: &WalkEntry) -> error::Result<()> {
+        let cluster = self.directory_cluster(&directory.entry.metadata.cluster);
+        if !self.visited.insert(cluster) {
+            self.vfat_filesystem.report(Diagnostic::DirectoryLoop {
+                path: directory.entry.metadata.path().display().to_string(),
+                cluster: cluster.into(),
+            });
+            return Ok(());
+        }
+        let contents = directory
+            .entry
+            .clone()
+            .into_directory_unchecked()
+            .contents()?;
+        self.stack.push(Frame {
+            directory: None,
+            children: contents.into_iter(),
+            depth: directory.depth + 1,
+            relative_path: directory.relative_path.clone(),
+        });
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = error::Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            let Some(entry) = frame.children.next() else {
+                let frame = self.stack.pop()?;
+                match frame.directory {
+                    Some(directory) => return Some(Ok(directory)),
+                    None => continue,
+                }
+            };
+            if self.options.skips(&entry) {
+                continue;
+            }
+            let relative_path = match frame.relative_path.as_str() {
+                "" => entry.metadata.name().to_string(),
+                parent => format!("{}/{}", parent, entry.metadata.name()),
+            };
+            let walk_entry = WalkEntry {
+                depth: frame.depth,
+                relative_path,
+                entry,
+            };
+            let descend = walk_entry.entry.is_dir()
+                && self
+                    .options
+                    .max_depth
+                    .is_none_or(|max_depth| walk_entry.depth < max_depth);
+            if !descend {
+                return Some(Ok(walk_entry));
+            }
+            if let Err(err) = self.descend(&walk_entry) {
+                return Some(Err(err));
+            }
+            match self.options.order {
+                WalkOrder::PreOrder => return Some(Ok(walk_entry)),
+                WalkOrder::PostOrder => match self.stack.last_mut() {
+                    // Yielded once its contents are done.
+                    Some(frame) if frame.depth == walk_entry.depth + 1 => {
+                        frame.directory = Some(walk_entry)
+                    }
+                    // Not descended into (loop).
+                    _ => return Some(Ok(walk_entry)),
+                },
+            }
+        }
+    }
+}
+
+/// Matches a single path component against a pattern supporting `*` and `?`.
+/// Like names lookups, matching is case-insensitive.
+fn matches_component(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The last `*` seen, and the position in `name` it's currently matched up to.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if fat_upcase(*c) == fat_upcase(name[n]) => {
+                p += 1;
+                n += 1;
+            }
+            // On a mismatch, the last `*` takes one more character.
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches path components against pattern components, where `**` matches any amount of
+/// components (including none).
+fn matches_path(pattern: &[Vec<char>], path: &[Vec<char>]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(p), _) if p.iter().eq(['*', '*'].iter()) => {
+            matches_path(&pattern[1..], path)
+                || (!path.is_empty() && matches_path(pattern, &path[1..]))
+        }
+        (Some(p), Some(n)) => matches_component(p, n) && matches_path(&pattern[1..], &path[1..]),
+        _ => false,
+    }
+}
+
+fn is_wildcard(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+/// Splits an absolute glob pattern in the path of the directory where the walk starts (the
+/// components before the first wildcard), and the pattern to match relative paths against.
+/// The pattern always keeps the last component, so that `/dir/file` can be matched as well.
+pub(crate) fn split_glob(pattern: &str) -> (String, Vec<Vec<char>>) {
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let literal_prefix = components
+        .iter()
+        .position(|c| is_wildcard(c))
+        .unwrap_or(components.len())
+        .min(components.len().saturating_sub(1));
+    let mut root = String::from("/");
+    for component in &components[..literal_prefix] {
+        root.push_str(component);
+        root.push('/');
+    }
+    let pattern = components[literal_prefix..]
+        .iter()
+        .map(|c| c.chars().collect())
+        .collect();
+    (root, pattern)
+}
+
+/// The entries matching a glob pattern, see `VfatFS::glob`.
+pub struct Glob {
+    walk: Walk,
+    pattern: Vec<Vec<char>>,
+}
+
+impl Glob {
+    pub(crate) fn new(root: Directory, pattern: Vec<Vec<char>>) -> error::Result<Self> {
+        let max_depth =
+            (!pattern.iter().any(|c| c.iter().eq(['*', '*'].iter()))).then_some(pattern.len());
+        let options = WalkOptions {
+            max_depth,
+            ..Default::default()
+        };
+        Ok(Self {
+            walk: Walk::new(root, options)?,
+            pattern,
+        })
+    }
+}
+
+impl Iterator for Glob {
+    type Item = error::Result<VfatEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for walk_entry in self.walk.by_ref() {
+            let walk_entry = match walk_entry {
+                Ok(walk_entry) => walk_entry,
+                Err(err) => return Some(Err(err)),
+            };
+            let path: Vec<Vec<char>> = walk_entry
+                .relative_path
+                .split('/')
+                .map(|c| c.chars().collect())
+                .collect();
+            if matches_path(&self.pattern, &path) {
+                return Some(Ok(walk_entry.entry));
+            }
+        }
+        None
+    }
+}
+
+/// Space used by a tree, see `VfatFS::du`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct DiskUsage {
+    pub files: usize,
+    /// Includes the directory `du` was called on.
+    pub directories: usize,
+    /// Sum of the file sizes.
+    pub logical_size: u64,
+    /// Space taken by the clusters allocated to files and directories.
+    pub allocated_size: u64,
+}
+
+impl DiskUsage {
+    pub(crate) fn add(&mut self, entry: &VfatEntry, vfat_filesystem: &VfatFS) -> error::Result<()> {
+        let _guard = vfat_filesystem.read_lock();
+        if entry.is_dir() {
+            self.directories += 1;
+        } else {
+            self.files += 1;
+            self.logical_size += entry.metadata.size() as u64;
+        }
+        // Empty files have no clusters.
+        if entry.metadata.cluster != ClusterId::new(0) {
+            let clusters = vfat_filesystem.cluster_chain_len(entry.metadata.cluster)?;
+            self.allocated_size += (clusters * vfat_filesystem.cluster_size()) as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches_component, matches_path, split_glob};
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    fn components(path: &str) -> Vec<Vec<char>> {
+        path.split('/').map(|c| c.chars().collect()).collect()
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        let (root, pattern) = split_glob("/logs/**/*.txt");
+        assert_eq!(root, "/logs/");
+        assert!(matches_path(&pattern, &components("a.txt")));
+        assert!(matches_path(&pattern, &components("2024/01/A.TXT")));
+        assert!(!matches_path(&pattern, &components("2024/a.txt.gz")));
+
+        let (root, pattern) = split_glob("/a/b?/c*");
+        assert_eq!(root, "/a/");
+        assert!(matches_path(&pattern, &components("b1/c")));
+        assert!(matches_path(&pattern, &components("b1/cde")));
+        assert!(!matches_path(&pattern, &components("b/c")));
+        assert!(!matches_path(&pattern, &components("b1/x/c")));
+
+        let (root, pattern) = split_glob("/exact/path");
+        assert_eq!(root, "/exact/");
+        assert!(matches_path(&pattern, &components("PATH")));
+    }
+
+    #[test]
+    fn test_component_matching() {
+        let matches = |pattern: &str, name: &str| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            matches_component(&pattern, &name)
+        };
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("*.TXT", "notes.txt"));
+        assert!(matches("?*?", "ab"));
+        assert!(!matches("?*?", "a"));
+        assert!(!matches("a*b", "aXbY"));
+        // Would take exponential time when backtracking over every `*`.
+        let pattern = format!("{}b", "*a".repeat(30));
+        assert!(!matches(&pattern, &"a".repeat(200)));
+    }
+}
@@ -0,0 +1,114 @@
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::ops::{Deref, DerefMut};
+use core::task::{Poll, Waker};
+
+use spin::mutex::SpinMutex;
+
+use crate::{error, SectorId};
+
+/// A block device accessed asynchronously, e.g. through the queues of a virtio or NVMe
+/// driver. Unlike `BlockDevice`, it only transfers whole sectors.
+// The futures are not required to be `Send`: no_std executors are usually single threaded.
+#[allow(async_fn_in_trait)]
+pub trait AsyncBlockDevice {
+    /// Sector size in bytes.
+    fn sector_size(&self) -> usize {
+        512
+    }
+
+    /// Reads `count` consecutive sectors, starting from `start`, in the first
+    /// `count * sector_size()` bytes of `buf`. Returns the amount of bytes read.
+    async fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize>;
+
+    /// Writes `count` consecutive sectors, starting from `start`, from the first
+    /// `count * sector_size()` bytes of `buf`. Returns the amount of bytes written.
+    async fn write_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &[u8],
+    ) -> error::Result<usize>;
+}
+
+/// Gives exclusive access to the device across await points. Unlike a spin lock, it doesn't
+/// block: tasks waiting for the device are woken up when it's released.
+pub(crate) struct DeviceLock<D> {
+    slot: SpinMutex<Slot<D>>,
+}
+
+struct Slot<D> {
+    /// Taken by the guard in use, if any.
+    device: Option<D>,
+    waiters: Vec<Waker>,
+}
+
+impl<D> DeviceLock<D> {
+    pub(crate) fn new(device: D) -> Self {
+        Self {
+            slot: SpinMutex::new(Slot {
+                device: Some(device),
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    pub(crate) async fn lock(&self) -> DeviceGuard<'_, D> {
+        poll_fn(|cx| {
+            let mut slot = self.slot.lock();
+            match slot.device.take() {
+                Some(device) => Poll::Ready(DeviceGuard {
+                    lock: self,
+                    device: Some(device),
+                }),
+                None => {
+                    if !slot.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                        slot.waiters.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// Puts the device back when dropped, waking up the tasks waiting for it.
+pub(crate) struct DeviceGuard<'a, D> {
+    lock: &'a DeviceLock<D>,
+    /// Always set, until the guard is dropped.
+    device: Option<D>,
+}
+
+impl<D> Deref for DeviceGuard<'_, D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        self.device
+            .as_ref()
+            .expect("the device is taken on drop only")
+    }
+}
+
+impl<D> DerefMut for DeviceGuard<'_, D> {
+    fn deref_mut(&mut self) -> &mut D {
+        self.device
+            .as_mut()
+            .expect("the device is taken on drop only")
+    }
+}
+
+impl<D> Drop for DeviceGuard<'_, D> {
+    fn drop(&mut self) {
+        let mut slot = self.lock.slot.lock();
+        slot.device = self.device.take();
+        for waker in slot.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::asynchronous::{AsyncBlockDevice, File, VfatFS};
+use crate::{Metadata, OpenOptions, Path, Result, VfatMetadataTrait};
+
+/// A directory on an async volume, see `crate::Directory`.
+pub struct Directory<D> {
+    vfat: VfatFS<D>,
+    metadata: Metadata,
+}
+
+impl<D> fmt::Debug for Directory<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AsyncVfatDirectory: metadata: {:?}.", self.metadata)
+    }
+}
+
+impl<D> VfatMetadataTrait for Directory<D> {
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+impl<D: AsyncBlockDevice> Directory<D> {
+    pub(crate) fn new(vfat: VfatFS<D>, metadata: Metadata) -> Self {
+        Self { vfat, metadata }
+    }
+
+    /// Runs `operation` on a sync handle to this directory. The metadata it leaves (e.g. the
+    /// size, once the directory grows) is kept.
+    async fn run<T>(
+        &mut self,
+        mut operation: impl FnMut(&mut crate::Directory) -> Result<T>,
+    ) -> Result<T> {
+        let metadata = &self.metadata;
+        let (result, metadata) = self
+            .vfat
+            .run(|fs| {
+                let mut directory = crate::Directory::new(fs.clone(), metadata.clone());
+                let result = operation(&mut directory)?;
+                Ok((result, directory.metadata))
+            })
+            .await?;
+        self.metadata = metadata;
+        Ok(result)
+    }
+
+    /// The metadata of the entries in this directory.
+    pub async fn contents(&mut self) -> Result<Vec<Metadata>> {
+        self.run(|directory| {
+            Ok(directory
+                .contents()?
+                .into_iter()
+                .map(|entry| entry.metadata)
+                .collect())
+        })
+        .await
+    }
+
+    /// Opens the file at `path`, relative to this directory.
+    pub async fn open(&mut self, path: Path) -> Result<File<D>> {
+        let file = self
+            .run(|directory| {
+                directory
+                    .open_at(path.clone(), OpenOptions::default())?
+                    .try_into_file()
+            })
+            .await?;
+        Ok(File::new(self.vfat.clone(), file))
+    }
+
+    /// Opens the directory at `path`, relative to this directory.
+    pub async fn open_directory(&mut self, path: Path) -> Result<Directory<D>> {
+        let directory = self
+            .run(|directory| {
+                directory
+                    .open_at(path.clone(), OpenOptions::default())?
+                    .try_into_directory()
+            })
+            .await?;
+        Ok(Directory::new(self.vfat.clone(), directory.metadata))
+    }
+
+    pub async fn create_file(&mut self, name: String) -> Result<File<D>> {
+        let file = self
+            .run(|directory| directory.create_file(name.clone()))
+            .await?;
+        Ok(File::new(self.vfat.clone(), file))
+    }
+
+    pub async fn create_directory(&mut self, name: String) -> Result<Directory<D>> {
+        let directory = self
+            .run(|directory| directory.create_directory(name.clone()))
+            .await?;
+        Ok(Directory::new(self.vfat.clone(), directory.metadata))
+    }
+
+    /// Deletes the entry named `name`, see `crate::Directory::delete`. Open files can't be
+    /// deleted.
+    pub async fn delete(&mut self, name: String) -> Result<()> {
+        self.run(|directory| directory.delete(name.clone())).await
+    }
+}
@@ -0,0 +1,100 @@
+use core::{cmp, fmt};
+
+use crate::asynchronous::{AsyncBlockDevice, VfatFS};
+use crate::io::SeekFrom;
+use crate::{Metadata, Result, VfatMetadataTrait};
+
+/// Reads and writes are split in operations of at most this many bytes: an operation is run
+/// again after each missing sector is fetched, and its sectors are kept in memory until it ends.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file on an async volume, see `crate::File`.
+pub struct File<D> {
+    vfat: VfatFS<D>,
+    file: crate::File,
+}
+
+impl<D> fmt::Debug for File<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AsyncVfatFile: metadata: {:?}, offset: {:?}.",
+            self.file.metadata, self.file.offset
+        )
+    }
+}
+
+impl<D> VfatMetadataTrait for File<D> {
+    fn metadata(&self) -> &Metadata {
+        &self.file.metadata
+    }
+}
+
+impl<D: AsyncBlockDevice> File<D> {
+    pub(crate) fn new(vfat: VfatFS<D>, file: crate::File) -> Self {
+        Self { vfat, file }
+    }
+
+    /// The current size of this file, including writes made through other handles.
+    pub fn size(&self) -> usize {
+        self.file.size()
+    }
+
+    /// The current seek position.
+    pub fn offset(&self) -> usize {
+        self.file.offset
+    }
+
+    /// Moves the seek position. It doesn't access the device.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.file.seek(pos)
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut amount_read = 0;
+        for chunk in buf.chunks_mut(CHUNK_SIZE) {
+            let amount = self
+                .vfat
+                .volume
+                .run(&mut self.file, |file| file.read(chunk))
+                .await?;
+            amount_read += amount;
+            if amount < chunk.len() {
+                break;
+            }
+        }
+        Ok(amount_read)
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut amount_written = 0;
+        for chunk in buf.chunks(CHUNK_SIZE) {
+            amount_written += self
+                .vfat
+                .volume
+                .run(&mut self.file, |file| file.write(chunk))
+                .await?;
+        }
+        Ok(amount_written)
+    }
+
+    /// Truncates or extends this file to `size` bytes, see `crate::File::truncate`.
+    pub async fn truncate(&mut self, size: usize) -> Result<()> {
+        // The zeros extending the file are written in chunks too.
+        loop {
+            let step = cmp::min(size, self.size() + CHUNK_SIZE);
+            self.vfat
+                .volume
+                .run(&mut self.file, |file| file.truncate(step))
+                .await?;
+            if step == size {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes the sectors which are not on the device yet, see `VfatFS::flush`.
+    pub async fn flush(&mut self) -> Result<()> {
+        self.vfat.flush().await
+    }
+}
@@ -0,0 +1,21 @@
+//! An async API, on top of an `AsyncBlockDevice`. It's enabled by the `async` feature, and
+//! doesn't depend on a specific executor.
+//!
+//! The on-disk logic is the one of the sync API: each operation runs on a `BlockDevice` which
+//! serves the sectors cached from the async device, and stages the sectors written. If sectors
+//! are missing, the operation is rolled back (its writes are discarded), the sectors are
+//! fetched from the device, and the operation is run again. Once it completes, the sectors it
+//! wrote are written to the device.
+//!
+//! Files deleted while open can't be freed when their last handle is dropped, as that can't
+//! wait for the device: deleting an open file fails (`DeletePolicy::Deny`).
+mod device;
+mod directory;
+mod file;
+mod stage;
+mod vfat;
+
+pub use device::AsyncBlockDevice;
+pub use directory::Directory;
+pub use file::File;
+pub use vfat::VfatFS;
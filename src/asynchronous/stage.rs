@@ -0,0 +1,365 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{cmp, mem};
+
+use spin::mutex::SpinMutex;
+
+use crate::device::run_len;
+use crate::io::{Error as IoError, ErrorKind};
+use crate::{error, BlockDevice, SectorId};
+
+/// `count` consecutive sectors, starting from `start`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Run {
+    pub(crate) start: SectorId,
+    pub(crate) count: usize,
+}
+
+impl Run {
+    pub(crate) fn contains(&self, sector: SectorId) -> bool {
+        (self.start.0..self.start.0 + self.count as u32).contains(&sector.0)
+    }
+
+    /// Merges `sectors`, in ascending order, in runs of consecutive sectors.
+    fn merge(sectors: impl IntoIterator<Item = u32>) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+        for sector in sectors {
+            match runs.last_mut() {
+                Some(run) if run.start.0 + run.count as u32 == sector => run.count += 1,
+                _ => runs.push(Run {
+                    start: SectorId(sector),
+                    count: 1,
+                }),
+            }
+        }
+        runs
+    }
+}
+
+/// The sectors cached from the device. Operations run as attempts: the sectors written by an
+/// attempt are staged, then committed when it ends, unless it needed sectors which were not
+/// cached. In that case they're discarded, and the attempt must be run again once the missing
+/// sectors are fetched. Meanwhile, missing sectors read as zeros: the attempt runs on, finding
+/// the other sectors it needs, so they're all fetched before it's run again.
+pub(crate) struct Stage {
+    pub(crate) sector_size: usize,
+    /// Clean sectors are evicted between operations when more than this many are cached.
+    pub(crate) capacity: usize,
+    /// Includes the committed sectors, until they're written to the device.
+    cached: BTreeMap<u32, Box<[u8]>>,
+    /// Committed sectors, not written to the device yet.
+    dirty: BTreeSet<u32>,
+    /// Written by the ongoing attempt.
+    staged: BTreeMap<u32, Box<[u8]>>,
+    /// Sectors needed by the ongoing attempt which were not cached.
+    missing: BTreeSet<u32>,
+    /// Sectors the device failed to read: reading them fails, instead of fetching them again.
+    failed: BTreeSet<u32>,
+}
+
+impl Stage {
+    pub(crate) fn new(sector_size: usize, capacity: usize) -> Self {
+        Self {
+            sector_size,
+            capacity,
+            cached: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            staged: BTreeMap::new(),
+            missing: BTreeSet::new(),
+            failed: BTreeSet::new(),
+        }
+    }
+
+    /// Evicts the clean sectors, if too many are cached.
+    pub(crate) fn evict(&mut self) {
+        if self.cached.len() > self.capacity {
+            let dirty = &self.dirty;
+            self.cached.retain(|sector, _| dirty.contains(sector));
+        }
+    }
+
+    pub(crate) fn begin(&mut self) {
+        self.staged.clear();
+        self.missing.clear();
+    }
+
+    /// Ends the attempt, committing its writes. If it needed sectors which were not cached,
+    /// its writes are discarded instead, and the missing sectors are returned.
+    pub(crate) fn finish(&mut self) -> Vec<Run> {
+        if !self.missing.is_empty() {
+            self.staged.clear();
+            return Run::merge(mem::take(&mut self.missing));
+        }
+        for (sector, data) in mem::take(&mut self.staged) {
+            self.cached.insert(sector, data);
+            self.dirty.insert(sector);
+        }
+        Vec::new()
+    }
+
+    /// True if every sector of `run` is cached, e.g. read ahead along with other sectors.
+    pub(crate) fn is_cached(&self, run: Run) -> bool {
+        (0..run.count as u32).all(|index| self.cached.contains_key(&(run.start.0 + index)))
+    }
+
+    /// Caches the sectors fetched from the device, starting from `start`. The ones cached
+    /// already are more recent, if dirty.
+    pub(crate) fn insert(&mut self, start: SectorId, buf: &[u8]) {
+        for (index, data) in buf.chunks_exact(self.sector_size).enumerate() {
+            self.cached
+                .entry(start.0 + index as u32)
+                .or_insert_with(|| data.into());
+        }
+    }
+
+    /// Records that the device failed to read `run`, until `clear_failed`.
+    pub(crate) fn fail(&mut self, run: Run) {
+        self.failed
+            .extend((0..run.count as u32).map(|index| run.start.0 + index));
+    }
+
+    pub(crate) fn clear_failed(&mut self) {
+        self.failed.clear();
+    }
+
+    pub(crate) fn has_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Takes the dirty sectors, merged in runs of consecutive sectors.
+    pub(crate) fn take_dirty(&mut self) -> Vec<(Run, Vec<u8>)> {
+        let mut runs: Vec<(Run, Vec<u8>)> = Vec::new();
+        for sector in mem::take(&mut self.dirty) {
+            let Some(data) = self.cached.get(&sector) else {
+                continue;
+            };
+            match runs.last_mut() {
+                Some((run, buf)) if run.start.0 + run.count as u32 == sector => {
+                    run.count += 1;
+                    buf.extend_from_slice(data);
+                }
+                _ => runs.push((
+                    Run {
+                        start: SectorId(sector),
+                        count: 1,
+                    },
+                    data.to_vec(),
+                )),
+            }
+        }
+        runs
+    }
+
+    /// Marks the sectors of `runs` as dirty again, after failing to write them.
+    pub(crate) fn redirty(&mut self, runs: impl Iterator<Item = Run>) {
+        for run in runs {
+            self.dirty
+                .extend((0..run.count as u32).map(|index| run.start.0 + index));
+        }
+    }
+
+    fn sector(&self, sector: SectorId) -> Option<&[u8]> {
+        self.staged
+            .get(&sector.0)
+            .or_else(|| self.cached.get(&sector.0))
+            .map(|data| &data[..])
+    }
+
+    /// Called when the sync code needs `sector`, which is not cached: the attempt is marked as
+    /// needing it, and the sector reads as zeros. If the device failed to read it already, the
+    /// error is returned instead.
+    // `Error::other` is not available without std.
+    #[allow(clippy::io_other_error)]
+    fn miss(&mut self, sector: SectorId) -> error::Result<()> {
+        if self.failed.contains(&sector.0) {
+            return Err(
+                IoError::new(ErrorKind::Other, "The device failed to read the sector").into(),
+            );
+        }
+        self.missing.insert(sector.0);
+        Ok(())
+    }
+}
+
+/// The `BlockDevice` the sync code runs on: it reads the cached sectors, and stages writes.
+pub(crate) struct StagedDevice {
+    pub(crate) stage: Arc<SpinMutex<Stage>>,
+}
+
+impl BlockDevice for StagedDevice {
+    fn sector_size(&self) -> usize {
+        self.stage.lock().sector_size
+    }
+
+    fn read_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let mut stage = self.stage.lock();
+        let Some(data) = stage.sector(sector) else {
+            stage.miss(sector)?;
+            let amount = cmp::min(buf.len(), stage.sector_size.saturating_sub(offset));
+            buf[..amount].fill(0);
+            return Ok(amount);
+        };
+        let data = &data[cmp::min(offset, data.len())..];
+        let amount = cmp::min(buf.len(), data.len());
+        buf[..amount].copy_from_slice(&data[..amount]);
+        Ok(amount)
+    }
+
+    fn write_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &[u8],
+    ) -> error::Result<usize> {
+        let mut stage = self.stage.lock();
+        let sector_size = stage.sector_size;
+        let amount = cmp::min(buf.len(), sector_size.saturating_sub(offset));
+        if amount == 0 {
+            return Ok(0);
+        }
+        // Unless the whole sector is overwritten, the rest of it is needed.
+        let mut data: Box<[u8]> = if amount == sector_size {
+            vec![0; sector_size].into()
+        } else {
+            match stage.sector(sector) {
+                Some(data) => data.into(),
+                None => {
+                    stage.miss(sector)?;
+                    vec![0; sector_size].into()
+                }
+            }
+        };
+        data[offset..offset + amount].copy_from_slice(&buf[..amount]);
+        stage.staged.insert(sector.0, data);
+        Ok(amount)
+    }
+
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let mut stage = self.stage.lock();
+        let len = run_len(buf.len(), count, stage.sector_size)?;
+        for (index, sector_buf) in buf[..len].chunks_mut(stage.sector_size).enumerate() {
+            let sector = SectorId(start.0 + index as u32);
+            match stage.sector(sector) {
+                Some(data) => sector_buf.copy_from_slice(data),
+                None => {
+                    stage.miss(sector)?;
+                    sector_buf.fill(0);
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_sectors(&mut self, start: SectorId, count: usize, buf: &[u8]) -> error::Result<usize> {
+        let mut stage = self.stage.lock();
+        let len = run_len(buf.len(), count, stage.sector_size)?;
+        for (index, data) in buf[..len].chunks(stage.sector_size).enumerate() {
+            stage.staged.insert(start.0 + index as u32, data.into());
+        }
+        Ok(len)
+    }
+
+    fn get_canonical_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "StagedDevice"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use alloc::vec;
+
+    use spin::mutex::SpinMutex;
+
+    use super::{Run, Stage, StagedDevice};
+    use crate::{BlockDevice, SectorId};
+
+    fn staged_device() -> (StagedDevice, Arc<SpinMutex<Stage>>) {
+        let stage = Arc::new(SpinMutex::new(Stage::new(4, 16)));
+        let device = StagedDevice {
+            stage: stage.clone(),
+        };
+        (device, stage)
+    }
+
+    #[test]
+    fn test_attempts() {
+        let (mut device, stage) = staged_device();
+        let mut buf = [0; 4];
+
+        // The first attempt misses sectors 2, 3 and 5: they read as zeros, and its writes are
+        // discarded.
+        stage.lock().begin();
+        device.write_sector(SectorId(1), &[1; 4]).unwrap();
+        let mut run = [7; 8];
+        device.read_sectors(SectorId(2), 2, &mut run).unwrap();
+        assert_eq!(run, [0; 8]);
+        assert!(device.read_sector(SectorId(1), &mut buf).is_ok());
+        device.read_sector(SectorId(5), &mut buf).unwrap();
+        let missing = stage.lock().finish();
+        let expected = vec![
+            Run {
+                start: SectorId(2),
+                count: 2,
+            },
+            Run {
+                start: SectorId(5),
+                count: 1,
+            },
+        ];
+        assert_eq!(missing, expected);
+        assert!(!stage.lock().has_dirty());
+
+        // Once the missing sectors are fetched, the attempt is committed.
+        stage.lock().insert(SectorId(2), &[2, 2, 2, 2, 3, 3, 3, 3]);
+        stage.lock().begin();
+        device.write_sector(SectorId(1), &[1; 4]).unwrap();
+        let mut run = [0; 8];
+        device.read_sectors(SectorId(2), 2, &mut run).unwrap();
+        assert_eq!(run, [2, 2, 2, 2, 3, 3, 3, 3]);
+        // Partial writes keep the rest of the sector.
+        device.write_sector_offset(SectorId(2), 1, &[9, 9]).unwrap();
+        assert!(stage.lock().finish().is_empty());
+        device.read_sector(SectorId(2), &mut buf).unwrap();
+        assert_eq!(buf, [2, 9, 9, 2]);
+
+        // Dirty sectors are merged in runs. Fetching a sector again doesn't overwrite them.
+        stage.lock().insert(SectorId(1), &[0; 4]);
+        let runs = stage.lock().take_dirty();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(
+            runs[0].0,
+            Run {
+                start: SectorId(1),
+                count: 2
+            }
+        );
+        assert_eq!(runs[0].1, vec![1, 1, 1, 1, 2, 9, 9, 2]);
+        assert!(!stage.lock().has_dirty());
+
+        // Sectors the device failed to read fail without being fetched again.
+        stage.lock().fail(Run {
+            start: SectorId(7),
+            count: 1,
+        });
+        stage.lock().begin();
+        assert!(device.read_sector(SectorId(7), &mut buf).is_err());
+        assert!(stage.lock().finish().is_empty());
+    }
+}
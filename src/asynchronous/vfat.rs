@@ -0,0 +1,433 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use core::{cmp, fmt};
+
+use log::warn;
+use spin::mutex::SpinMutex;
+use spin::Once;
+
+use crate::asynchronous::device::DeviceLock;
+use crate::asynchronous::stage::{Run, Stage, StagedDevice};
+use crate::asynchronous::{AsyncBlockDevice, Directory, File};
+use crate::cache::FreeCount;
+use crate::{
+    CachedPartition, DeletePolicy, MountStatus, Path, Result, TimeManagerTrait, VfatFS as SyncFS,
+};
+
+/// Clean sectors are evicted when more than this many are cached, see `VfatFS::set_cache_capacity`.
+/// With 512 bytes sectors, it's 4 MiB: the whole FAT of a volume of up to a million clusters.
+const DEFAULT_CACHE_CAPACITY: usize = 8192;
+/// Missing sectors outside of the FAT are fetched along with the following ones, up to this
+/// many: directory sectors are mostly read in sequence.
+const READ_AHEAD: usize = 8;
+
+/// State an attempt (see `Stage`) might change before it's discarded: it's restored then.
+pub(crate) trait Attempt {
+    type Checkpoint;
+
+    fn checkpoint(&self) -> Self::Checkpoint;
+    fn rollback(&mut self, checkpoint: Self::Checkpoint);
+}
+
+impl Attempt for () {
+    type Checkpoint = ();
+
+    fn checkpoint(&self) {}
+    fn rollback(&mut self, _checkpoint: ()) {}
+}
+
+impl Attempt for SyncFS {
+    /// Whether the volume was marked dirty (the write doing it might be discarded), and the
+    /// free clusters count.
+    type Checkpoint = (bool, FreeCount);
+
+    fn checkpoint(&self) -> Self::Checkpoint {
+        (self.device.is_marked_dirty(), self.device.free_count())
+    }
+
+    fn rollback(&mut self, (marked_dirty, free_count): Self::Checkpoint) {
+        self.device.set_marked_dirty(marked_dirty);
+        self.device.set_free_count(free_count);
+    }
+}
+
+impl Attempt for crate::File {
+    type Checkpoint = (crate::api::FileCheckpoint, <SyncFS as Attempt>::Checkpoint);
+
+    fn checkpoint(&self) -> Self::Checkpoint {
+        (self.checkpoint(), self.vfat_filesystem.checkpoint())
+    }
+
+    fn rollback(&mut self, (file, volume): Self::Checkpoint) {
+        self.rollback(file);
+        self.vfat_filesystem.rollback(volume);
+    }
+}
+
+/// Shared by the handles to a volume: the device, and the sectors cached from it.
+pub(crate) struct Volume<D> {
+    device: DeviceLock<D>,
+    stage: Arc<SpinMutex<Stage>>,
+    /// Held while an attempt runs: attempts use the stage one at a time.
+    attempts: SpinMutex<()>,
+    /// The FAT, known once the volume is mounted. It's mostly read as a whole (e.g. looking for
+    /// free clusters), so a missing FAT sector is fetched along with the rest of it.
+    fat: Once<Run>,
+    sector_size: usize,
+}
+
+impl<D: AsyncBlockDevice> Volume<D> {
+    fn new(device: D) -> Self {
+        let sector_size = device.sector_size();
+        Self {
+            device: DeviceLock::new(device),
+            stage: Arc::new(SpinMutex::new(Stage::new(
+                sector_size,
+                DEFAULT_CACHE_CAPACITY,
+            ))),
+            attempts: SpinMutex::new(()),
+            fat: Once::new(),
+            sector_size,
+        }
+    }
+
+    fn staged_device(&self) -> StagedDevice {
+        StagedDevice {
+            stage: self.stage.clone(),
+        }
+    }
+
+    /// Runs `operation` on the cached sectors, fetching the missing ones until it can
+    /// complete. Each attempt finds all the sectors it misses, so that they're fetched at once.
+    /// Then, the sectors it wrote are written to the device.
+    pub(crate) async fn run<S: Attempt, T>(
+        &self,
+        state: &mut S,
+        mut operation: impl FnMut(&mut S) -> Result<T>,
+    ) -> Result<T> {
+        self.stage.lock().evict();
+        let result = loop {
+            let (result, missing) = {
+                let _attempt = self.attempts.lock();
+                let checkpoint = state.checkpoint();
+                self.stage.lock().begin();
+                let result = operation(state);
+                let missing = self.stage.lock().finish();
+                if !missing.is_empty() {
+                    state.rollback(checkpoint);
+                }
+                (result, missing)
+            };
+            if missing.is_empty() {
+                break result;
+            }
+            drop(result);
+            for run in missing {
+                if let Err(err) = self.fetch(run).await {
+                    // The operation is run again, failing to read these sectors.
+                    warn!("Failed to read {:?}: {}", run, err);
+                    self.stage.lock().fail(run);
+                }
+            }
+        };
+        self.stage.lock().clear_failed();
+        self.flush().await?;
+        result
+    }
+
+    async fn fetch(&self, missing: Run) -> Result<()> {
+        if self.stage.lock().is_cached(missing) {
+            return Ok(());
+        }
+        let read_ahead = self.read_ahead(missing);
+        // Past the end of the device, only the missing sectors are read.
+        if read_ahead != missing && self.read(read_ahead).await.is_ok() {
+            return Ok(());
+        }
+        self.read(missing).await
+    }
+
+    /// The sectors to fetch along with `missing`: the rest of the FAT, up to the cache
+    /// capacity, or the next few sectors.
+    fn read_ahead(&self, missing: Run) -> Run {
+        let count = match self.fat.get() {
+            Some(fat) if fat.contains(missing.start) => {
+                let fat_left = (fat.start.0 + fat.count as u32 - missing.start.0) as usize;
+                cmp::min(fat_left, self.stage.lock().capacity)
+            }
+            _ => READ_AHEAD,
+        };
+        Run {
+            start: missing.start,
+            count: cmp::max(missing.count, count),
+        }
+    }
+
+    async fn read(&self, run: Run) -> Result<()> {
+        let mut buf = vec![0; run.count * self.sector_size];
+        let amount = self
+            .device
+            .lock()
+            .await
+            .read_sectors(run.start, run.count, &mut buf)
+            .await?;
+        CachedPartition::check_run(amount, buf.len())?;
+        self.stage.lock().insert(run.start, &buf);
+        Ok(())
+    }
+
+    /// Writes the dirty sectors to the device.
+    pub(crate) async fn flush(&self) -> Result<()> {
+        if !self.stage.lock().has_dirty() {
+            return Ok(());
+        }
+        // Taken first, so the sectors are written in the order they were committed.
+        let mut device = self.device.lock().await;
+        let runs = self.stage.lock().take_dirty();
+        for (index, (run, buf)) in runs.iter().enumerate() {
+            let written = device
+                .write_sectors(run.start, run.count, buf)
+                .await
+                .and_then(|amount| CachedPartition::check_run(amount, buf.len()));
+            if let Err(err) = written {
+                self.stage
+                    .lock()
+                    .redirty(runs[index..].iter().map(|(run, _)| *run));
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A mounted vfat volume, on an `AsyncBlockDevice`. Like `crate::VfatFS`, it's cheap to clone,
+/// and clones share the same device.
+pub struct VfatFS<D> {
+    pub(crate) fs: SyncFS,
+    pub(crate) volume: Arc<Volume<D>>,
+}
+
+impl<D> Clone for VfatFS<D> {
+    fn clone(&self) -> Self {
+        Self {
+            fs: self.fs.clone(),
+            volume: self.volume.clone(),
+        }
+    }
+}
+
+impl<D> fmt::Debug for VfatFS<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AsyncVfatFilesystem")
+    }
+}
+
+impl<D: AsyncBlockDevice> VfatFS<D> {
+    /// Mounts the volume starting at `partition_start_sector`, see `crate::VfatFS::new`.
+    pub async fn new(device: D, partition_start_sector: u32) -> Result<Self> {
+        Self::mount(device, |device| SyncFS::new(device, partition_start_sector)).await
+    }
+
+    /// Mounts the volume read-only, see `crate::VfatFS::new_read_only`.
+    pub async fn new_read_only(device: D, partition_start_sector: u32) -> Result<Self> {
+        Self::mount(device, |device| {
+            SyncFS::new_read_only(device, partition_start_sector)
+        })
+        .await
+    }
+
+    pub async fn new_tm(
+        device: D,
+        partition_start_sector: u32,
+        time_manager: impl TimeManagerTrait + Clone + 'static,
+    ) -> Result<Self> {
+        Self::mount(device, |device| {
+            SyncFS::new_tm(device, partition_start_sector, time_manager.clone())
+        })
+        .await
+    }
+
+    async fn mount(device: D, mount: impl Fn(StagedDevice) -> Result<SyncFS>) -> Result<Self> {
+        let volume = Arc::new(Volume::new(device));
+        let mut fs = volume
+            .run(&mut (), |_| mount(volume.staged_device()))
+            .await?;
+        volume.fat.call_once(|| Run {
+            start: fs.fat_start_sector,
+            count: fs.sectors_per_fat as usize,
+        });
+        // The clusters of a file deleted while open would be freed when its last handle is
+        // dropped, which can't wait for the device.
+        fs.set_delete_policy(DeletePolicy::Deny);
+        Ok(Self { fs, volume })
+    }
+
+    /// Runs `operation` on the sync volume, see `Volume::run`.
+    pub(crate) async fn run<T>(
+        &self,
+        operation: impl FnMut(&mut SyncFS) -> Result<T>,
+    ) -> Result<T> {
+        self.volume.run(&mut self.fs.clone(), operation).await
+    }
+
+    pub async fn get_root(&self) -> Result<Directory<D>> {
+        let root = self.run(|fs| fs.get_root()).await?;
+        Ok(Directory::new(self.clone(), root.metadata))
+    }
+
+    /// Opens the file at `path`.
+    pub async fn open(&self, path: Path) -> Result<File<D>> {
+        let file = self
+            .run(|fs| fs.get_path(path.clone())?.try_into_file())
+            .await?;
+        Ok(File::new(self.clone(), file))
+    }
+
+    /// Opens the directory at `path`.
+    pub async fn open_directory(&self, path: Path) -> Result<Directory<D>> {
+        let directory = self
+            .run(|fs| fs.get_path(path.clone())?.try_into_directory())
+            .await?;
+        Ok(Directory::new(self.clone(), directory.metadata))
+    }
+
+    pub async fn path_exists(&self, path: Path) -> Result<bool> {
+        self.run(|fs| fs.path_exists(path.clone())).await
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.fs.is_read_only()
+    }
+
+    pub fn mount_status(&self) -> MountStatus {
+        self.fs.mount_status()
+    }
+
+    /// Clean sectors are evicted between operations when more than `sectors` are cached.
+    /// Sectors written to the volume are not cached until they reach the device.
+    pub fn set_cache_capacity(&self, sectors: usize) {
+        self.volume.stage.lock().capacity = sectors;
+    }
+
+    /// Writes the sectors which are not on the device yet. Operations do it before returning,
+    /// but if the device fails, the sectors are kept and written by the next operation.
+    pub async fn flush(&self) -> Result<()> {
+        self.volume.flush().await
+    }
+
+    /// Marks the volume as cleanly unmounted, see `crate::VfatFS::unmount`.
+    pub async fn unmount(self) -> Result<()> {
+        self.run(|fs| fs.clone().unmount()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::{Context, Poll, Waker};
+
+    use super::{Run, Volume};
+    use crate::asynchronous::AsyncBlockDevice;
+    use crate::{BlockDevice, MemoryBlockDevice, Result, SectorId};
+
+    /// Counts the requests reaching the device.
+    struct CountingDevice {
+        device: MemoryBlockDevice,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl AsyncBlockDevice for CountingDevice {
+        async fn read_sectors(
+            &mut self,
+            start: SectorId,
+            count: usize,
+            buf: &mut [u8],
+        ) -> Result<usize> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            self.device.read_sectors(start, count, buf)
+        }
+
+        async fn write_sectors(
+            &mut self,
+            start: SectorId,
+            count: usize,
+            buf: &[u8],
+        ) -> Result<usize> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            self.device.write_sectors(start, count, buf)
+        }
+    }
+
+    /// Polls `future` until it completes: the device never makes it wait.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_cold_scan() {
+        const FAT: Run = Run {
+            start: SectorId(32),
+            count: 256,
+        };
+        let requests = Arc::new(AtomicUsize::new(0));
+        let volume = Volume::new(CountingDevice {
+            device: MemoryBlockDevice::new(vec![0; 1024 * 512]),
+            requests: requests.clone(),
+        });
+        volume.fat.call_once(|| FAT);
+        // Reads `run` one sector at a time, like a FAT or directory scan does.
+        let scan = |run: Run| {
+            let mut attempts = 0;
+            block_on(volume.run(&mut (), |_| {
+                attempts += 1;
+                let mut device = volume.staged_device();
+                let mut buf = [0; 512];
+                for index in 0..run.count as u32 {
+                    device.read_sector(SectorId(run.start.0 + index), &mut buf)?;
+                }
+                Ok(())
+            }))
+            .unwrap();
+            attempts
+        };
+
+        // The sectors missed by the first attempt are fetched at once.
+        assert_eq!(scan(FAT), 2);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        assert_eq!(scan(FAT), 1);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // The first sector of the FAT brings the rest of it.
+        requests.store(0, Ordering::Relaxed);
+        let mut stage = volume.stage.lock();
+        stage.capacity = 0;
+        stage.evict();
+        stage.capacity = super::DEFAULT_CACHE_CAPACITY;
+        drop(stage);
+        let first = Run {
+            start: FAT.start,
+            count: 1,
+        };
+        assert_eq!(scan(first), 2);
+        assert_eq!(scan(FAT), 1);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Elsewhere, a directory cluster of 64 sectors.
+        let cluster = Run {
+            start: SectorId(512),
+            count: 64,
+        };
+        assert_eq!(scan(cluster), 2);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,53 +1,190 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use log::info;
-use spin::mutex::SpinMutex;
+use spin::mutex::{SpinMutex, SpinMutexGuard};
 
-use crate::device::BlockDevice;
-use crate::error::Result;
+use crate::device::{run_len, BlockDevice};
+use snafu::ensure;
+
+use crate::error::{ReadOnlyFilesystemSnafu, Result};
+use crate::fat_table::{CLEAN_SHUTDOWN_BIT, FAT_ENTRY_SIZE, NO_HARD_ERRORS_BIT};
 use crate::formats::cluster_id::ClusterId;
-use crate::SectorId;
+use crate::io::{Error as IoError, ErrorKind};
+use crate::{MountStatus, SectorId};
 
 /// An interface to the underlaying Block Device.
 /// It will cache entries, and help with reading and writing sectors.
 pub(crate) struct CachedPartition {
-    device: SpinMutex<Box<dyn BlockDevice>>,
+    device: SpinMutex<Box<dyn BlockDevice + Send>>,
     pub(crate) sector_size: usize,
     pub(crate) fat_start_sector: SectorId,
+    /// How many sectors each FAT uses.
+    pub(crate) sectors_per_fat: u32,
+    /// How many copies of the FAT follow each other from `fat_start_sector`.
+    pub(crate) fat_amount: u8,
     /// How many sectors are mapped to a single cluster
     pub(crate) sectors_per_cluster: u32,
     /// First sector containing actual data - after all FAT tables.
     pub(crate) data_start_sector: SectorId,
+    /// Number of data clusters, hence the highest valid cluster id is `total_clusters + 1`.
+    pub(crate) total_clusters: u32,
+    /// Set while FAT[1] marks the volume as dirty. The first write after mount sets it.
+    marked_dirty: AtomicBool,
+    /// Set if the volume was dirty at mount, until it's checked: it's left dirty on unmount.
+    needs_check: AtomicBool,
+    /// Set once a check found the volume clean (or repaired it): on unmount, the hard errors
+    /// flag is cleared as well.
+    checked: AtomicBool,
+    /// If set, nothing is written to the device. It's only set at mount.
+    read_only: bool,
+    /// The free clusters count, kept in memory: FSInfo is only written on unmount.
+    free_count: SpinMutex<FreeCount>,
+}
+
+/// The free clusters count as loaded from FSInfo, and kept up to date while mounted.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FreeCount {
+    /// As stored in FSInfo, hence possibly implausible. `None` if unknown.
+    pub(crate) count: Option<u32>,
+    /// Set if it changed since it was loaded or written.
+    pub(crate) dirty: bool,
 }
 impl CachedPartition {
     pub fn new<T>(
         device: T,
         sector_size: usize,
         fat_start_sector: SectorId,
+        sectors_per_fat: u32,
+        fat_amount: u8,
         sectors_per_cluster: u32,
         data_start_sector: SectorId,
+        total_clusters: u32,
     ) -> Self
     where
-        T: BlockDevice + 'static,
+        T: BlockDevice + Send + 'static,
     {
         info!("Creating cached partition");
         Self {
             device: SpinMutex::new(Box::new(device)),
             sector_size,
             fat_start_sector,
+            sectors_per_fat,
+            fat_amount,
             sectors_per_cluster,
             data_start_sector,
+            total_clusters,
+            marked_dirty: AtomicBool::new(false),
+            needs_check: AtomicBool::new(false),
+            checked: AtomicBool::new(false),
+            read_only: false,
+            free_count: SpinMutex::new(FreeCount::default()),
+        }
+    }
+
+    /// Records the state of the volume found at mount.
+    pub(crate) fn set_mount_status(&self, status: MountStatus) {
+        self.marked_dirty.store(status.was_dirty, Ordering::Relaxed);
+        self.needs_check
+            .store(status.needs_check(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_read_only(&mut self, enabled: bool) {
+        self.read_only = enabled;
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub(crate) fn free_count(&self) -> FreeCount {
+        *self.free_count.lock()
+    }
+
+    pub(crate) fn set_free_count(&self, free_count: FreeCount) {
+        *self.free_count.lock() = free_count;
+    }
+
+    /// Adds `delta` to the free clusters count, if it's known.
+    pub(crate) fn update_free_count(&self, delta: i64) {
+        let mut free_count = self.free_count.lock();
+        if let (Some(count), true) = (free_count.count, delta != 0) {
+            let count = (count as i64 + delta).clamp(0, self.total_clusters as i64);
+            free_count.count = Some(count as u32);
+            free_count.dirty = true;
+        }
+    }
+
+    /// True once FAT[1] marks the volume as dirty.
+    #[cfg(feature = "async")]
+    pub(crate) fn is_marked_dirty(&self) -> bool {
+        self.marked_dirty.load(Ordering::Relaxed)
+    }
+
+    /// Restores the flag when the write marking the volume as dirty is discarded, so the
+    /// next write marks it again. See `crate::asynchronous`.
+    #[cfg(feature = "async")]
+    pub(crate) fn set_marked_dirty(&self, marked: bool) {
+        self.marked_dirty.store(marked, Ordering::Relaxed);
+    }
+
+    /// The volume was checked (and repaired, if needed): it can be marked clean on unmount.
+    pub(crate) fn set_checked(&self) {
+        self.needs_check.store(false, Ordering::Relaxed);
+        self.checked.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks the volume as cleanly unmounted in FAT[1], unless it needs a check. Once checked,
+    /// the hard errors flag is cleared too. A later write marks it as dirty again.
+    pub(crate) fn mark_clean(&self) -> Result<()> {
+        let mut dev_lock = self.device.lock();
+        let checked = self.checked.load(Ordering::Relaxed);
+        if self.is_read_only()
+            || self.needs_check.load(Ordering::Relaxed)
+            || !(checked || self.marked_dirty.load(Ordering::Relaxed))
+        {
+            return Ok(());
+        }
+        let bits = if checked {
+            CLEAN_SHUTDOWN_BIT | NO_HARD_ERRORS_BIT
+        } else {
+            CLEAN_SHUTDOWN_BIT
+        };
+        Self::set_state_bits(&mut **dev_lock, self.fat_start_sector, bits, true)?;
+        self.marked_dirty.store(false, Ordering::Relaxed);
+        self.checked.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sets (or clears) `bits` of FAT[1], the volume state flags.
+    fn set_state_bits(
+        device: &mut (dyn BlockDevice + Send),
+        fat_start_sector: SectorId,
+        bits: u32,
+        set: bool,
+    ) -> Result<()> {
+        let mut raw = [0; FAT_ENTRY_SIZE];
+        device.read_sector_offset(fat_start_sector, FAT_ENTRY_SIZE, &mut raw)?;
+        let mut entry = u32::from_le_bytes(raw);
+        if set {
+            entry |= bits;
+        } else {
+            entry &= !bits;
         }
+        device.write_sector_offset(fat_start_sector, FAT_ENTRY_SIZE, &entry.to_le_bytes())?;
+        Ok(())
     }
 
+    /// Nothing is cached yet: every write already reached the device.
     pub fn flush(&self) -> Result<()> {
-        todo!()
+        Ok(())
     }
 
     pub(crate) fn read_sector(&self, sector: SectorId, buf: &mut [u8]) -> Result<usize> {
         let mut dev_lock = self.device.lock();
-        dev_lock.read_sector(sector, buf)
+        let amount = dev_lock.read_sector(sector, buf)?;
+        Self::check_transferred(amount, buf.len())
     }
 
     pub(crate) fn read_sector_offset(
@@ -57,7 +194,8 @@ impl CachedPartition {
         buf: &mut [u8],
     ) -> Result<usize> {
         let mut dev_lock = self.device.lock();
-        dev_lock.read_sector_offset(sector, offset, buf)
+        let amount = dev_lock.read_sector_offset(sector, offset, buf)?;
+        Self::check_transferred(amount, buf.len())
     }
     #[allow(unused)]
     fn write_sector(self: Arc<Self>, sector: SectorId, buf: &[u8]) -> Result<usize> {
@@ -71,17 +209,81 @@ impl CachedPartition {
         offset: usize,
         buf: &[u8],
     ) -> Result<usize> {
+        let mut dev_lock = self.lock_for_write()?;
+        let amount = dev_lock.write_sector_offset(sector, offset, buf)?;
+        Self::check_transferred(amount, buf.len())
+    }
+
+    /// Reads `count` consecutive sectors in one device request, see `BlockDevice::read_sectors`.
+    pub(crate) fn read_sectors(
+        &self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let len = run_len(buf.len(), count, self.sector_size)?;
         let mut dev_lock = self.device.lock();
-        dev_lock.write_sector_offset(sector, offset, buf)
+        let amount = dev_lock.read_sectors(start, count, &mut buf[..len])?;
+        Self::check_run(amount, len)
+    }
+
+    /// Writes `count` consecutive sectors in one device request, see
+    /// `BlockDevice::write_sectors`.
+    pub(crate) fn write_sectors(&self, start: SectorId, count: usize, buf: &[u8]) -> Result<usize> {
+        let len = run_len(buf.len(), count, self.sector_size)?;
+        let mut dev_lock = self.lock_for_write()?;
+        let amount = dev_lock.write_sectors(start, count, &buf[..len])?;
+        Self::check_run(amount, len)
+    }
+
+    /// Locks the device for a write. Anything written after mount marks the volume as dirty,
+    /// until it's unmounted.
+    fn lock_for_write(&self) -> Result<SpinMutexGuard<'_, Box<dyn BlockDevice + Send>>> {
+        ensure!(!self.is_read_only(), ReadOnlyFilesystemSnafu);
+        let mut dev_lock = self.device.lock();
+        if !self.marked_dirty.load(Ordering::Relaxed) {
+            Self::set_state_bits(
+                &mut **dev_lock,
+                self.fat_start_sector,
+                CLEAN_SHUTDOWN_BIT,
+                false,
+            )?;
+            self.marked_dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(dev_lock)
+    }
+
+    /// Unlike single sectors, runs are transferred as a whole.
+    pub(crate) fn check_run(amount: usize, requested: usize) -> Result<usize> {
+        if amount != requested {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "The device transferred only part of the sectors",
+            )
+            .into());
+        }
+        Ok(amount)
+    }
+
+    /// The readers and writers loop until buffers are transferred, and slice them using the
+    /// amounts returned by the device: those must make progress and stay within the buffer.
+    fn check_transferred(amount: usize, requested: usize) -> Result<usize> {
+        if amount > requested || (amount == 0 && requested > 0) {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "The device transferred an invalid amount of bytes",
+            )
+            .into());
+        }
+        Ok(amount)
     }
 
     /// Converts a cluster (a FAT concept) to a sector (a BlockDevice concept).
     ///
     /// To do so, it uses some useful info from the BPB section.
     pub(crate) fn cluster_to_sector(&self, cluster: ClusterId) -> SectorId {
-        let selected_sector =
-            u32::from(cluster).saturating_sub(2) * self.sectors_per_cluster as u32;
-        let sect = self.data_start_sector.0 as u32 + selected_sector as u32;
+        let selected_sector = u32::from(cluster).saturating_sub(2) * self.sectors_per_cluster;
+        let sect = self.data_start_sector.0 + selected_sector;
         SectorId(sect)
     }
 
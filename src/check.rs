@@ -0,0 +1,725 @@
+//! A consistency checker for FAT32 volumes, akin to `fsck.fat`. See `VfatFS::check`.
+//!
+//! The first FAT is loaded in memory, then every chain reachable from the root directory is
+//! followed and bound to the entry using it. Clusters allocated but not bound to any entry are
+//! lost, clusters bound twice are cross-linked. Directories are read straight from their
+//! (validated) chains, so that a corrupted FAT can't make the checker loop.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::RangeInclusive;
+
+use log::info;
+
+use crate::api::directory_entry::VfatDirectoryEntry;
+use crate::api::ScannedEntry;
+use crate::fat_table::{FatEntry, CLEAN_SHUTDOWN_BIT, FAT_ENTRY_SIZE, NO_HARD_ERRORS_BIT};
+use crate::{
+    ClusterId, CreateOptions, Directory, EntryType, Path, RegularDirectoryEntry, Result, SectorId,
+    UnknownDirectoryEntry, VfatFS,
+};
+
+/// Options for `VfatFS::check`.
+#[derive(Debug, Clone, Default)]
+pub struct CheckOptions {
+    /// Fix the problems found, instead of only reporting them.
+    pub repair: bool,
+}
+
+/// An inconsistency found by `VfatFS::check`, and how it's repaired. Paths are absolute.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// Allocated clusters not used by any entry. They are saved as a `FSCKnnnn.REC` file in the
+    /// root directory.
+    LostChain { first_cluster: u32, clusters: usize },
+    /// The chain of `path` runs into `cluster`, which is used by `other` too. The chain of
+    /// `path` is ended right before it.
+    CrossLinked {
+        path: String,
+        other: String,
+        cluster: u32,
+    },
+    /// The chain of `path` loops back to `cluster`. The chain is ended right before it.
+    ChainCycle { path: String, cluster: u32 },
+    /// The chain of `path` reaches a cluster which is free, reserved, marked bad or out of the
+    /// volume. The chain is ended right before it. If it's the first cluster, files are
+    /// truncated to 0 bytes and directories are removed.
+    BadCluster { path: String, cluster: u32 },
+    /// The size of the file at `path` doesn't match the length of its chain. The chain is
+    /// truncated if it's too long, otherwise the size is shrunk to fit the chain.
+    SizeMismatch {
+        path: String,
+        size: u32,
+        clusters: usize,
+    },
+    /// The "." or ".." entry of the directory at `path` is missing (`cluster` is `None`) or
+    /// points to the wrong cluster. Wrong entries are fixed, missing ones are only reported.
+    BadDotEntry {
+        path: String,
+        name: &'static str,
+        cluster: Option<u32>,
+    },
+    /// A run of LFN entries not bound to any regular entry. The entries are deleted.
+    OrphanLfn {
+        directory: String,
+        entry_index: usize,
+        entries: usize,
+    },
+    /// The 8.3 name of the entry at `path` contains forbidden characters. It's replaced with
+    /// a generated alias.
+    InvalidShortName { path: String },
+    /// FAT copy number `copy` (0 being the first FAT) differs from the first FAT in `sectors`
+    /// sectors. The first FAT is copied over it.
+    FatCopyMismatch { copy: u8, sectors: usize },
+    /// The free clusters count stored in FSInfo is wrong. It's overwritten.
+    FreeCountMismatch { stored: u32, actual: u32 },
+}
+
+/// The outcome of `VfatFS::check`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+    /// True if the problems were fixed.
+    pub repaired: bool,
+    /// How many files were checked.
+    pub files: usize,
+    /// How many directories were checked, the root directory included.
+    pub directories: usize,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Marks clusters not bound to any chain.
+const NO_OWNER: usize = usize::MAX;
+/// Recovered files are named `FSCK0000.REC` up to `FSCK9999.REC`.
+const MAX_RECOVERED_FILES: u32 = 10_000;
+const ENTRY_SIZE: usize = mem::size_of::<UnknownDirectoryEntry>();
+
+/// A directory waiting to be checked.
+struct PendingDirectory {
+    path: Path,
+    /// Its chain, already validated.
+    clusters: Vec<ClusterId>,
+    /// The cluster expected in its ".." entry.
+    parent_link: ClusterId,
+}
+
+pub(crate) struct Checker<'a> {
+    vfat: &'a VfatFS,
+    repair: bool,
+    /// The first FAT, kept in sync with the repairs.
+    fat: Vec<FatEntry>,
+    /// For each cluster, the index in `owners` of the chain using it.
+    owner: Vec<usize>,
+    /// Paths of the entries whose chain was followed.
+    owners: Vec<String>,
+    report: CheckReport,
+}
+
+impl<'a> Checker<'a> {
+    /// Loads the first FAT. The caller holds the filesystem lock, exclusively if repairing.
+    pub(crate) fn new(vfat: &'a VfatFS, options: CheckOptions) -> Result<Self> {
+        let fat = vfat.read_fat()?;
+        Ok(Self {
+            vfat,
+            repair: options.repair,
+            owner: vec![NO_OWNER; fat.len()],
+            fat,
+            owners: Vec::new(),
+            report: CheckReport::default(),
+        })
+    }
+
+    pub(crate) fn run(mut self) -> Result<CheckReport> {
+        for (copy, sectors) in self.compare_fat_copies(false)? {
+            self.report
+                .problems
+                .push(Problem::FatCopyMismatch { copy, sectors });
+        }
+        self.check_free_count()?;
+
+        let root_clusters = self.claim_chain(self.vfat.root_cluster, "/")?;
+        let mut pending = Vec::new();
+        if !root_clusters.is_empty() {
+            pending.push(PendingDirectory {
+                path: Path::from("/"),
+                clusters: root_clusters,
+                parent_link: ClusterId::new(0),
+            });
+        }
+        while let Some(directory) = pending.pop() {
+            self.check_directory(directory, &mut pending)?;
+        }
+        self.check_lost_chains()?;
+
+        if self.repair {
+            self.compare_fat_copies(true)?;
+            if self.vfat.read_fs_info()?.is_some() {
+                let free_clusters = self.vfat.count_free_clusters()?;
+                self.vfat.write_fs_info_free_count(free_clusters)?;
+            }
+        }
+        self.report.repaired = self.repair;
+        info!("Check completed: {:?}", self.report);
+        Ok(self.report)
+    }
+
+    /// Compares every FAT copy with the first FAT, and returns the copies which differ along
+    /// with how many sectors differ. If `fix`, the first FAT is copied over the differing sectors.
+    fn compare_fat_copies(&self, fix: bool) -> Result<Vec<(u8, usize)>> {
+        let fat_amount = self.vfat.read_boot_sector()?.bpb.fat_amount;
+        let sector_size = self.vfat.device.sector_size;
+        let mut primary = vec![0; sector_size];
+        let mut copy_buf = vec![0; sector_size];
+        let mut mismatches = Vec::new();
+        for copy in 1..fat_amount {
+            let copy_start =
+                self.vfat.fat_start_sector + SectorId(copy as u32 * self.vfat.sectors_per_fat);
+            let mut differing = 0;
+            for sector in 0..self.vfat.sectors_per_fat {
+                self.vfat
+                    .device
+                    .read_sector(self.vfat.fat_start_sector + SectorId(sector), &mut primary)?;
+                self.vfat
+                    .device
+                    .read_sector(copy_start + SectorId(sector), &mut copy_buf)?;
+                if primary == copy_buf
+                    || (sector == 0 && differ_in_volume_state_only(&primary, &copy_buf))
+                {
+                    continue;
+                }
+                differing += 1;
+                if fix {
+                    self.vfat.device.clone().write_sector_offset(
+                        copy_start + SectorId(sector),
+                        0,
+                        &primary,
+                    )?;
+                }
+            }
+            if differing > 0 {
+                mismatches.push((copy, differing));
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Compares the free clusters count kept for FSInfo (see `VfatFS::unmount`) with the FAT,
+    /// as loaded before any repair. The count is rewritten at the end of a repair anyway.
+    fn check_free_count(&mut self) -> Result<()> {
+        if self.vfat.read_fs_info()?.is_none() {
+            return Ok(());
+        }
+        let Some(stored) = self.vfat.device.free_count().count else {
+            return Ok(());
+        };
+        let actual = self.fat[2..]
+            .iter()
+            .filter(|entry| **entry == FatEntry::Unused)
+            .count() as u32;
+        if stored != actual {
+            self.report
+                .problems
+                .push(Problem::FreeCountMismatch { stored, actual });
+        }
+        Ok(())
+    }
+
+    /// Follows the chain starting from `head`, used by the entry at `path`, and returns its
+    /// clusters. The chain stops at the first cluster which is out of the volume, free,
+    /// reserved, already part of this chain (a cycle) or of another one (a cross-link): the
+    /// problem is reported, and when repairing the chain is ended right before that cluster.
+    fn claim_chain(&mut self, head: ClusterId, path: &str) -> Result<Vec<ClusterId>> {
+        let owner = self.owners.len();
+        self.owners.push(path.to_string());
+        let mut clusters = Vec::new();
+        let mut current = u32::from(head);
+        loop {
+            let index = current as usize;
+            let allocated = current >= 2
+                && matches!(
+                    self.fat.get(index),
+                    Some(FatEntry::DataCluster(_) | FatEntry::LastCluster(_))
+                );
+            let problem = if !allocated {
+                Some(Problem::BadCluster {
+                    path: path.to_string(),
+                    cluster: current,
+                })
+            } else if self.owner[index] == owner {
+                Some(Problem::ChainCycle {
+                    path: path.to_string(),
+                    cluster: current,
+                })
+            } else if self.owner[index] != NO_OWNER {
+                Some(Problem::CrossLinked {
+                    path: path.to_string(),
+                    other: self.owners[self.owner[index]].clone(),
+                    cluster: current,
+                })
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                self.report.problems.push(problem);
+                if let Some(last) = clusters.last() {
+                    self.end_chain(*last)?;
+                }
+                return Ok(clusters);
+            }
+            self.owner[index] = owner;
+            clusters.push(ClusterId::new(current));
+            match self.fat[index] {
+                FatEntry::DataCluster(next) => current = next,
+                _ => return Ok(clusters),
+            }
+        }
+    }
+
+    fn set_fat_entry(&mut self, cluster: ClusterId, entry: FatEntry) -> Result<()> {
+        self.fat[u32::from(cluster) as usize] = entry;
+        self.vfat.write_entry_in_vfat_table(cluster, entry)
+    }
+
+    /// Makes `last` the last cluster of its chain, when repairing.
+    fn end_chain(&mut self, last: ClusterId) -> Result<()> {
+        if !self.repair {
+            return Ok(());
+        }
+        let end_of_chain = self.vfat.new_last_cluster_fat_entry();
+        self.set_fat_entry(last, end_of_chain)
+    }
+
+    fn free_clusters(&mut self, clusters: &[ClusterId]) -> Result<()> {
+        for cluster in clusters {
+            self.owner[u32::from(*cluster) as usize] = NO_OWNER;
+            self.set_fat_entry(*cluster, FatEntry::Unused)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the raw entries of a directory, up to the first EndOfEntries marker.
+    fn read_entries(&self, clusters: &[ClusterId]) -> Result<Vec<VfatDirectoryEntry>> {
+        let device = &self.vfat.device;
+        let sectors_per_cluster = device.sectors_per_cluster as usize;
+        let mut buf = vec![0; sectors_per_cluster * device.sector_size];
+        let mut entries = Vec::new();
+        for cluster in clusters {
+            let first_sector = device.cluster_to_sector(*cluster);
+            device.read_sectors(first_sector, sectors_per_cluster, &mut buf)?;
+            for raw in buf.chunks_exact(ENTRY_SIZE) {
+                let mut raw_entry = [0; ENTRY_SIZE];
+                raw_entry.copy_from_slice(raw);
+                let entry = VfatDirectoryEntry::from(UnknownDirectoryEntry::from(raw_entry));
+                if let VfatDirectoryEntry::EndOfEntries(_) = entry {
+                    return Ok(entries);
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn check_directory(
+        &mut self,
+        directory: PendingDirectory,
+        pending: &mut Vec<PendingDirectory>,
+    ) -> Result<()> {
+        self.report.directories += 1;
+        let cluster = directory.clusters[0];
+        let is_root = cluster == self.vfat.root_cluster;
+        let handle = Directory::at(self.vfat.clone(), cluster, directory.path.clone());
+        let entries = self.read_entries(&directory.clusters)?;
+        let scanned = handle.scan_entries(entries.clone());
+
+        self.check_orphan_lfns(&handle, &entries, &scanned)?;
+        if !is_root {
+            self.check_dot_entries(&handle, &directory, &scanned)?;
+        }
+        // The cluster stored in the ".." entry of the subdirectories.
+        let parent_link = if is_root { ClusterId::new(0) } else { cluster };
+        for entry in scanned {
+            if entry.regular.is_volume_id() || pseudo_name(&entry.regular).is_some() {
+                continue;
+            }
+            let path = directory.path.join(&entry.name);
+            let mut regular = entry.regular;
+            let mut changed = false;
+            if !is_valid_short_name(&regular) {
+                self.report.problems.push(Problem::InvalidShortName {
+                    path: path.display().to_string(),
+                });
+                if self.repair {
+                    self.replace_short_name(&handle, &entries, &entry, &mut regular)?;
+                    changed = true;
+                }
+            }
+            if regular.is_dir() {
+                let clusters = match regular.cluster() {
+                    head if head == ClusterId::new(0) => {
+                        self.report.problems.push(Problem::BadCluster {
+                            path: path.display().to_string(),
+                            cluster: 0,
+                        });
+                        Vec::new()
+                    }
+                    head => self.claim_chain(head, path.display())?,
+                };
+                if clusters.is_empty() {
+                    if self.repair {
+                        info!("Removing directory without clusters: {}", path);
+                        handle.mark_deleted(entry.first_index..=entry.index)?;
+                    }
+                    continue;
+                }
+                pending.push(PendingDirectory {
+                    path,
+                    clusters,
+                    parent_link,
+                });
+            } else {
+                changed |= self.check_file(&path, &mut regular)?;
+            }
+            if changed {
+                handle.update_entry_by_index(regular.into(), entry.index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the chain of a file against its size. Returns true if `regular` was repaired.
+    fn check_file(&mut self, path: &Path, regular: &mut RegularDirectoryEntry) -> Result<bool> {
+        self.report.files += 1;
+        let head = regular.cluster();
+        let clusters = if head == ClusterId::new(0) {
+            Vec::new()
+        } else {
+            let clusters = self.claim_chain(head, path.display())?;
+            if clusters.is_empty() {
+                // The first cluster is not valid, already reported.
+                if self.repair {
+                    (regular.high_16bits, regular.low_16bits) = ClusterId::new(0).into_high_low();
+                    regular.file_size = 0;
+                }
+                return Ok(self.repair);
+            }
+            clusters
+        };
+        let cluster_size = self.vfat.cluster_size();
+        let expected = (regular.file_size as usize).div_ceil(cluster_size);
+        if clusters.len() == expected {
+            return Ok(false);
+        }
+        self.report.problems.push(Problem::SizeMismatch {
+            path: path.display().to_string(),
+            size: regular.file_size,
+            clusters: clusters.len(),
+        });
+        if !self.repair {
+            return Ok(false);
+        }
+        if clusters.len() < expected {
+            regular.file_size = (clusters.len() * cluster_size) as u32;
+        } else if expected == 0 {
+            self.free_clusters(&clusters)?;
+            (regular.high_16bits, regular.low_16bits) = ClusterId::new(0).into_high_low();
+        } else {
+            self.end_chain(clusters[expected - 1])?;
+            self.free_clusters(&clusters[expected..])?;
+        }
+        Ok(true)
+    }
+
+    /// Reports the LFN entries which are not part of any scanned entry, grouped in runs.
+    /// When repairing, they are deleted.
+    fn check_orphan_lfns(
+        &mut self,
+        handle: &Directory,
+        entries: &[VfatDirectoryEntry],
+        scanned: &[ScannedEntry],
+    ) -> Result<()> {
+        let mut bound = vec![false; entries.len()];
+        for entry in scanned {
+            bound[entry.first_index..=entry.index].fill(true);
+        }
+        let mut runs: Vec<RangeInclusive<usize>> = Vec::new();
+        let orphans = entries.iter().enumerate().filter(|(index, entry)| {
+            matches!(entry, VfatDirectoryEntry::LongFileName(_)) && !bound[*index]
+        });
+        for (index, _) in orphans {
+            match runs.last_mut() {
+                Some(run) if *run.end() + 1 == index => *run = *run.start()..=index,
+                _ => runs.push(index..=index),
+            }
+        }
+        for run in runs {
+            self.report.problems.push(Problem::OrphanLfn {
+                directory: handle.metadata.path().display().to_string(),
+                entry_index: *run.start(),
+                entries: run.clone().count(),
+            });
+            if self.repair {
+                handle.mark_deleted(run)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// "." must point to the directory itself, ".." to its parent. Links to the root directory
+    /// are stored as cluster 0, but some implementations use the root cluster.
+    fn check_dot_entries(
+        &mut self,
+        handle: &Directory,
+        directory: &PendingDirectory,
+        scanned: &[ScannedEntry],
+    ) -> Result<()> {
+        let expected = [(".", directory.clusters[0]), ("..", directory.parent_link)];
+        for (name, cluster) in expected {
+            let found = scanned
+                .iter()
+                .find(|entry| pseudo_name(&entry.regular) == Some(name));
+            let Some(entry) = found else {
+                self.report.problems.push(Problem::BadDotEntry {
+                    path: directory.path.display().to_string(),
+                    name,
+                    cluster: None,
+                });
+                continue;
+            };
+            let actual = entry.regular.cluster();
+            let links_root = cluster == ClusterId::new(0) && actual == self.vfat.root_cluster;
+            if actual == cluster || links_root {
+                continue;
+            }
+            self.report.problems.push(Problem::BadDotEntry {
+                path: directory.path.display().to_string(),
+                name,
+                cluster: Some(actual.into()),
+            });
+            if self.repair {
+                let mut regular = entry.regular;
+                (regular.high_16bits, regular.low_16bits) = cluster.into_high_low();
+                handle.update_entry_by_index(regular.into(), entry.index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives `entry` a new alias derived from its name, and updates the checksum of its LFN
+    /// entries. `regular` is written back by the caller.
+    fn replace_short_name(
+        &self,
+        handle: &Directory,
+        entries: &[VfatDirectoryEntry],
+        entry: &ScannedEntry,
+        regular: &mut RegularDirectoryEntry,
+    ) -> Result<()> {
+        const FORBIDDEN_CHARS: &str = "\"*/:<>?\\|";
+        let base: String = entry
+            .name
+            .chars()
+            .map(|ch| {
+                if ch.is_ascii_control() || FORBIDDEN_CHARS.contains(ch) {
+                    '_'
+                } else {
+                    ch
+                }
+            })
+            .collect();
+        let alias = Directory::unique_short_name(&base, &handle.contents_inner()?)?;
+        info!("Replacing short name of '{}' with {}", entry.name, alias);
+        regular.file_name = alias.name;
+        regular.file_ext = alias.ext;
+        regular._reseverd_win_nt = alias.case_flags;
+        let checksum = VfatDirectoryEntry::checksum(&alias.name, &alias.ext);
+        let lfns = entries[entry.first_index..entry.index].iter().enumerate();
+        for (offset, dir_entry) in lfns {
+            if let VfatDirectoryEntry::LongFileName(lfn) = dir_entry {
+                let mut lfn = *lfn;
+                lfn.checksum_dos_filename = checksum;
+                handle.update_entry_by_index(lfn.into(), entry.first_index + offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports the allocated clusters not used by any entry, grouped in chains. When
+    /// repairing, every chain is saved as a `FSCKnnnn.REC` file in the root directory.
+    fn check_lost_chains(&mut self) -> Result<()> {
+        // A lost cluster pointed by another lost cluster is not the head of a chain.
+        let mut has_predecessor = vec![false; self.fat.len()];
+        for (cluster, entry) in self.fat.iter().enumerate() {
+            if let FatEntry::DataCluster(next) = entry {
+                if self.is_lost(cluster) && self.is_lost(*next as usize) {
+                    has_predecessor[*next as usize] = true;
+                }
+            }
+        }
+        let heads: Vec<usize> = (2..self.fat.len())
+            .filter(|cluster| self.is_lost(*cluster) && !has_predecessor[*cluster])
+            .collect();
+        let mut chains: Vec<_> = heads
+            .into_iter()
+            .map(|head| self.claim_lost_chain(head))
+            .collect();
+        // What's left are cycles, with no head: they're broken at their lowest cluster.
+        for cluster in 2..self.fat.len() {
+            if self.is_lost(cluster) {
+                chains.push(self.claim_lost_chain(cluster));
+            }
+        }
+        for chain in chains {
+            self.report.problems.push(Problem::LostChain {
+                first_cluster: chain[0].into(),
+                clusters: chain.len(),
+            });
+            if self.repair {
+                self.recover_chain(&chain)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_lost(&self, cluster: usize) -> bool {
+        cluster >= 2
+            && cluster < self.fat.len()
+            && self.owner[cluster] == NO_OWNER
+            && matches!(
+                self.fat[cluster],
+                FatEntry::DataCluster(_) | FatEntry::LastCluster(_)
+            )
+    }
+
+    fn claim_lost_chain(&mut self, head: usize) -> Vec<ClusterId> {
+        let owner = self.owners.len();
+        self.owners.push(format!("<lost chain at {}>", head));
+        let mut chain = Vec::new();
+        let mut current = head;
+        loop {
+            self.owner[current] = owner;
+            chain.push(ClusterId::new(current as u32));
+            match self.fat[current] {
+                FatEntry::DataCluster(next) if self.is_lost(next as usize) => {
+                    current = next as usize
+                }
+                _ => return chain,
+            }
+        }
+    }
+
+    /// Saves a lost chain as a file in the root directory, named after the first free
+    /// `FSCKnnnn.REC` name.
+    fn recover_chain(&mut self, chain: &[ClusterId]) -> Result<()> {
+        let last = chain[chain.len() - 1];
+        if !matches!(self.fat[u32::from(last) as usize], FatEntry::LastCluster(_)) {
+            self.end_chain(last)?;
+        }
+        let mut root = self.vfat.root_directory()?;
+        let contents = root.contents_inner()?;
+        let name = (0..MAX_RECOVERED_FILES)
+            .map(|number| format!("FSCK{:04}.REC", number))
+            .find(|name| {
+                !contents
+                    .iter()
+                    .any(|entry| entry.metadata.matches_name(name))
+            });
+        let Some(name) = name else {
+            info!("No name left to recover the chain at {}", chain[0]);
+            return Ok(());
+        };
+        info!("Recovering the chain at {} as {}", chain[0], name);
+        let mut metadata = root
+            .create(name, EntryType::File, CreateOptions::default())?
+            .metadata;
+        metadata.cluster = chain[0];
+        metadata.size = chain
+            .len()
+            .saturating_mul(self.vfat.cluster_size())
+            .try_into()
+            .unwrap_or(u32::MAX);
+        self.vfat.update_entry(&metadata)
+    }
+}
+
+/// True if the first sectors of two FATs only differ in the volume state bits of FAT[1]: they
+/// are only kept up to date in the first FAT.
+fn differ_in_volume_state_only(first: &[u8], copy: &[u8]) -> bool {
+    const STATE_BITS: u32 = CLEAN_SHUTDOWN_BIT | NO_HARD_ERRORS_BIT;
+    let state_entry = FAT_ENTRY_SIZE..2 * FAT_ENTRY_SIZE;
+    let without_state = |buf: &[u8]| {
+        let mut raw = [0; FAT_ENTRY_SIZE];
+        raw.copy_from_slice(&buf[state_entry.clone()]);
+        u32::from_le_bytes(raw) | STATE_BITS
+    };
+    first[..state_entry.start] == copy[..state_entry.start]
+        && first[state_entry.end..] == copy[state_entry.end..]
+        && without_state(first) == without_state(copy)
+}
+
+/// "." or ".." if `regular` is one of the pseudo entries. Their names can be padded with
+/// spaces or zeros.
+fn pseudo_name(regular: &RegularDirectoryEntry) -> Option<&'static str> {
+    let is_padding = |bytes: &[u8]| bytes.iter().all(|ch| *ch == b' ' || *ch == 0);
+    if !is_padding(&regular.file_ext) {
+        return None;
+    }
+    match regular.file_name {
+        [b'.', b'.', ref rest @ ..] if is_padding(rest) => Some(".."),
+        [b'.', ref rest @ ..] if is_padding(rest) => Some("."),
+        _ => None,
+    }
+}
+
+/// Checks the 8.3 name against the characters allowed by DOS. 0x05 is allowed as first
+/// character: it stands for 0xE5, which marks deleted entries.
+fn is_valid_short_name(regular: &RegularDirectoryEntry) -> bool {
+    const FORBIDDEN_CHARS: &[u8] = b"\"*+,./:;<=>?[\\]|";
+    regular.file_name[0] != b' '
+        && regular
+            .file_name
+            .iter()
+            .chain(&regular.file_ext)
+            .enumerate()
+            .all(|(index, ch)| match *ch {
+                0x05 => index == 0,
+                ch => ch >= 0x20 && ch != 0x7F && !FORBIDDEN_CHARS.contains(&ch),
+            })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_valid_short_name, pseudo_name};
+    use crate::api::directory_entry::VfatDirectoryEntry;
+    use crate::{ClusterId, RegularDirectoryEntry};
+
+    fn regular(name: &[u8; 8], ext: &[u8; 3]) -> RegularDirectoryEntry {
+        let [current, _] =
+            VfatDirectoryEntry::create_pseudo_dir_entries(ClusterId::new(3), ClusterId::new(0));
+        let mut regular: RegularDirectoryEntry = current.into();
+        regular.file_name = *name;
+        regular.file_ext = *ext;
+        regular
+    }
+
+    #[test]
+    fn test_short_name_validation() {
+        assert!(is_valid_short_name(&regular(b"HELLO   ", b"TXT")));
+        assert!(is_valid_short_name(&regular(b"\x05BC     ", b"   ")));
+        assert!(!is_valid_short_name(&regular(b" HELLO  ", b"TXT")));
+        assert!(!is_valid_short_name(&regular(b"HE*LO   ", b"TXT")));
+        assert!(!is_valid_short_name(&regular(b"HELLO   ", b"T\x01T")));
+        assert!(!is_valid_short_name(&regular(b"A\x05      ", b"   ")));
+
+        assert_eq!(pseudo_name(&regular(b".\0\0\0\0\0\0\0", b"   ")), Some("."));
+        assert_eq!(pseudo_name(&regular(b"..      ", b"   ")), Some(".."));
+        assert_eq!(pseudo_name(&regular(b"...     ", b"   ")), None);
+        assert_eq!(pseudo_name(&regular(b".A      ", b"   ")), None);
+    }
+}
@@ -1,10 +1,12 @@
 use crate::cache::CachedPartition;
+use crate::fat_table::ChainCursor;
 use crate::{fat_table, ArcMutex, ClusterId, Result, SectorId};
 
 /// this implements and encapsulates the logic needed to traverse
 /// cluster chains, by reading the FAT table.
 pub(crate) struct ClusterChainReader {
     device: ArcMutex<CachedPartition>,
+    chain: ChainCursor,
     current_cluster: Option<ClusterId>,
     current_sector: SectorId,
     /// Offset in current_sector. In case buf.len()%sector_size != 0, this sector is not full read.
@@ -16,17 +18,18 @@ impl ClusterChainReader {
         let current_sector = device.cluster_to_sector(start_cluster);
 
         Self {
+            chain: ChainCursor::new(start_cluster),
             current_cluster: Some(start_cluster),
             offset_byte_in_current_sector: 0,
             current_sector,
             device,
         }
     }
-    fn next_cluster(&self) -> Result<Option<ClusterId>> {
+    fn next_cluster(&mut self) -> Result<Option<ClusterId>> {
         if self.current_cluster.is_none() {
             return Ok(None);
         }
-        fat_table::next_cluster(self.current_cluster.unwrap(), self.device.clone())
+        self.chain.advance(&self.device)
     }
 
     /// Assumptions: offset less then this object's size.
@@ -46,11 +49,18 @@ impl ClusterChainReader {
             "Offset: {}, cluster_offset: {}, sector offset: {}, offset in sector: {}",
             offset, cluster_offset, sector_offset, offset_in_sector
         );*/
+        if let Some(current_cluster) = self.current_cluster {
+            fat_table::check_cluster(&self.device, current_cluster)?;
+        }
         for _ in 0..cluster_offset {
             self.current_cluster = self.next_cluster()?;
         }
-        let start_sector = self.device.cluster_to_sector(self.current_cluster.unwrap())
-            + SectorId(sector_offset as u32);
+        // The chain is shorter than `offset`: there's nothing left to read.
+        let Some(current_cluster) = self.current_cluster else {
+            return Ok(());
+        };
+        let start_sector =
+            self.device.cluster_to_sector(current_cluster) + SectorId(sector_offset as u32);
         self.current_sector = start_sector;
         self.offset_byte_in_current_sector = offset_in_sector;
 
@@ -58,22 +68,30 @@ impl ClusterChainReader {
     }
 
     pub(crate) fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if self.current_cluster.is_none() || buf.is_empty() {
+        let Some(current_cluster) = self.current_cluster else {
+            return Ok(0);
+        };
+        if buf.is_empty() {
             return Ok(0);
         }
+        // The first cluster comes from a directory entry, the next ones are checked by the cursor.
+        fat_table::check_cluster(&self.device, current_cluster)?;
 
         let mut amount = 0;
         while amount < buf.len() && self.current_cluster.is_some() {
-            let current_amount_read = self.read_cluster(&mut buf[amount..])?;
-            amount += current_amount_read;
-            if current_amount_read == 0 {
+            if self.cluster_is_over() {
                 self.current_cluster = self.next_cluster()?;
-                if self.current_cluster.is_some() {
-                    self.current_sector =
-                        self.device.cluster_to_sector(self.current_cluster.unwrap());
-                    self.offset_byte_in_current_sector = 0;
-                }
+                self.move_to_current_cluster();
+                continue;
             }
+            let remaining = &mut buf[amount..];
+            amount += if self.offset_byte_in_current_sector == 0
+                && remaining.len() >= self.device.sector_size
+            {
+                self.read_run(remaining)?
+            } else {
+                self.read_sector(remaining)?
+            };
         }
         /*debug!(
             "CRR completed, red<buf: {}, is some: {}",
@@ -84,40 +102,73 @@ impl ClusterChainReader {
         Ok(amount)
     }
 
-    /// A reader for the content of a single FAT cluster
-    /// It reads from the beginning of the cluster.
-    /// It's not thread-safe and should not be shared.
-    /// Given a buffer buf, it will try to read as much sectors as it can in order to fill the buffer.
-    fn read_cluster(&mut self, buf: &mut [u8]) -> Result<usize> {
-        if self.cluster_is_over() || buf.is_empty() {
-            return Ok(0);
+    /// Moves to the beginning of the current cluster, if any.
+    fn move_to_current_cluster(&mut self) {
+        if let Some(current_cluster) = self.current_cluster {
+            self.current_sector = self.device.cluster_to_sector(current_cluster);
+            self.offset_byte_in_current_sector = 0;
         }
+    }
 
-        let mut total = 0;
-        let buf_len = buf.len();
-        // Until buffer is full or I have read the whole cluster:
-        while total < buf_len && !self.cluster_is_over() {
-            let space_left_in_current_sector =
-                self.device.sector_size - self.offset_byte_in_current_sector;
-            let amount = self.device.clone().read_sector_offset(
-                self.current_sector,
-                self.offset_byte_in_current_sector,
-                &mut buf[total..core::cmp::min(buf_len, space_left_in_current_sector)],
-            )?;
-            total += amount;
-            self.offset_byte_in_current_sector += amount;
-            assert!(self.offset_byte_in_current_sector <= self.device.sector_size);
-
-            if self.offset_byte_in_current_sector == self.device.sector_size {
-                self.current_sector = SectorId(self.current_sector + 1);
-                self.offset_byte_in_current_sector = 0;
+    /// Reads as many whole sectors as `buf` can hold with a single device request, starting
+    /// from the beginning of the current sector. The run continues past the current cluster as
+    /// long as the next clusters of the chain are contiguous on disk.
+    fn read_run(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let sectors_per_cluster = self.device.sectors_per_cluster;
+        let wanted = buf.len() / self.device.sector_size;
+        let Some(mut last_cluster) = self.current_cluster else {
+            return Ok(0);
+        };
+        let cluster_end = self.device.cluster_to_sector(last_cluster) + sectors_per_cluster;
+        let mut count = (cluster_end - self.current_sector.0) as usize;
+        // Set if the run stops before a cluster which is not contiguous: the whole run is read.
+        let mut after_run = None;
+        while count < wanted {
+            match self.chain.advance(&self.device)? {
+                Some(next) if u32::from(next) == u32::from(last_cluster) + 1 => {
+                    last_cluster = next;
+                    count += sectors_per_cluster as usize;
+                }
+                next => {
+                    after_run = Some(next);
+                    break;
+                }
             }
         }
+        let count = core::cmp::min(count, wanted);
+        let amount = self.device.read_sectors(self.current_sector, count, buf)?;
+        self.current_cluster = Some(last_cluster);
+        self.current_sector = SectorId(self.current_sector + count as u32);
+        if let Some(next) = after_run {
+            self.current_cluster = next;
+            self.move_to_current_cluster();
+        }
+        Ok(amount)
+    }
 
-        Ok(total)
+    /// Reads from the current sector, up to its end.
+    fn read_sector(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let space_left_in_current_sector =
+            self.device.sector_size - self.offset_byte_in_current_sector;
+        let buf_len = core::cmp::min(buf.len(), space_left_in_current_sector);
+        let amount = self.device.clone().read_sector_offset(
+            self.current_sector,
+            self.offset_byte_in_current_sector,
+            &mut buf[..buf_len],
+        )?;
+        self.offset_byte_in_current_sector += amount;
+        if self.offset_byte_in_current_sector == self.device.sector_size {
+            self.current_sector = SectorId(self.current_sector + 1);
+            self.offset_byte_in_current_sector = 0;
+        }
+        Ok(amount)
     }
+
     fn cluster_is_over(&self) -> bool {
-        let cluster_start = self.device.cluster_to_sector(self.current_cluster.unwrap());
+        let Some(current_cluster) = self.current_cluster else {
+            return true;
+        };
+        let cluster_start = self.device.cluster_to_sector(current_cluster);
         let final_sector = SectorId(self.device.sectors_per_cluster) + cluster_start;
         self.current_sector >= final_sector
     }
@@ -1,14 +1,26 @@
-use crate::{error::Result, fat_table, ClusterId, SectorId, VfatFS};
-use log::debug;
+use crate::fat_table::{ChainCursor, FatEntry};
+use crate::{error::Result, fat_table, ClusterId, SectorId, VfatFS, VfatRsError};
+use alloc::vec;
+use log::{debug, warn};
 
 #[derive(Debug)]
 pub(crate) struct ClusterChainWriter {
     vfat_fs: VfatFS,
+    /// First cluster of the chain. It changes if the first cluster is relocated.
+    head: ClusterId,
+    /// The cluster linking to `current_cluster`, if it's not the head.
+    previous_cluster: Option<ClusterId>,
     pub(crate) current_cluster: ClusterId,
+    /// Follows the chain up to `current_cluster`, failing on corrupted links and cycles.
+    chain: ChainCursor,
     pub(crate) current_sector: SectorId,
     /// Offset in current_sector. In case buf.len()%sector_size != 0, this sector is not full read.
     /// The next read call will start from this offset.
     pub(crate) offset_byte_in_current_sector: usize,
+    /// If set, clusters allocated to the chain are zeroed (used by directories).
+    zero_new_clusters: bool,
+    /// If set, the head can be relocated too. Its owner must then update its entry, see `head`.
+    relocate_head: bool,
 }
 
 impl ClusterChainWriter {
@@ -21,22 +33,62 @@ impl ClusterChainWriter {
         let current_sector =
             vfat_fs.device.cluster_to_sector(start_cluster) + offset_sector_in_cluster;
         Self {
+            head: start_cluster,
+            previous_cluster: None,
             current_cluster: start_cluster,
+            chain: ChainCursor::new(start_cluster),
             offset_byte_in_current_sector: offset_in_sector,
             current_sector,
             vfat_fs,
+            zero_new_clusters: false,
+            relocate_head: false,
         }
     }
 
+    /// Clusters allocated by this writer will be zeroed.
+    pub(crate) fn zeroing_new_clusters(mut self) -> Self {
+        self.zero_new_clusters = true;
+        self
+    }
+
+    /// The first cluster of the chain can be relocated by this writer, see `head`.
+    pub(crate) fn relocating_head(mut self) -> Self {
+        self.relocate_head = true;
+        self
+    }
+
+    /// The first cluster of the chain: it's different from the one this writer was created
+    /// with if it was relocated.
+    pub(crate) fn head(&self) -> ClusterId {
+        self.head
+    }
+
+    /// Moves to the next cluster of the chain, allocating it if needed.
+    fn move_to_next_cluster(&mut self) -> Result<()> {
+        let next = self.next_cluster_alloc()?;
+        self.previous_cluster = Some(self.current_cluster);
+        self.current_cluster = next;
+        Ok(())
+    }
+
     fn next_cluster_alloc(&mut self) -> Result<ClusterId> {
-        let ret = fat_table::next_cluster(self.current_cluster, self.vfat_fs.device.clone())?;
+        let mut chain = self.chain.clone();
+        let next = self.cluster_after_alloc(&mut chain)?;
+        self.chain = chain;
+        Ok(next)
+    }
 
-        Ok(match ret {
-            None => self
-                .vfat_fs
-                .allocate_cluster_to_chain(self.current_cluster)?,
-            Some(r) => r,
-        })
+    /// Moves `chain` to the next cluster and returns it, allocating it if needed.
+    fn cluster_after_alloc(&self, chain: &mut ChainCursor) -> Result<ClusterId> {
+        if let Some(next) = chain.advance(&self.vfat_fs.device)? {
+            return Ok(next);
+        }
+        let allocated = self.vfat_fs.allocate_cluster_to_chain(chain.current())?;
+        if self.zero_new_clusters {
+            self.vfat_fs.zero_cluster(allocated)?;
+        }
+        chain.append(allocated);
+        Ok(allocated)
     }
 
     // If the offset is outside file, it will allocate clusters to accommodate requested seek size.
@@ -60,7 +112,7 @@ impl ClusterChainWriter {
         let offset_in_sector = offset % self.vfat_fs.device.sector_size;
 
         for _ in 0..cluster_offset {
-            self.current_cluster = self.next_cluster_alloc()?;
+            self.move_to_next_cluster()?;
         }
         debug!(
             "offset in sector: {}, current cluster:{}",
@@ -78,18 +130,15 @@ impl ClusterChainWriter {
             return Ok(0);
         }
 
-        assert_ne!(
-            self.current_cluster,
-            ClusterId::new(0),
-            "current cluster is ClusterId(0)."
-        );
+        // The first cluster comes from a directory entry, which might be corrupted.
+        fat_table::check_cluster(&self.vfat_fs.device, self.current_cluster)?;
 
         let mut amount = 0;
         while amount < buf.len() {
             let current_amount_written = self.write_cluster(&buf[amount..])?;
             amount += current_amount_written;
             if current_amount_written == 0 {
-                self.current_cluster = self.next_cluster_alloc()?;
+                self.move_to_next_cluster()?;
                 self.current_sector = self.vfat_fs.device.cluster_to_sector(self.current_cluster);
                 self.offset_byte_in_current_sector = 0;
             }
@@ -102,15 +151,40 @@ impl ClusterChainWriter {
         if self.cluster_is_over() || buf.is_empty() {
             return Ok(0);
         }
+        if self.offset_byte_in_current_sector == 0 && buf.len() >= self.vfat_fs.device.sector_size {
+            match self.write_run(buf) {
+                // The sectors are written one by one instead, relocating the failed cluster.
+                Err(VfatRsError::IoError { source }) => {
+                    warn!("Failed to write a run of sectors: {}", source)
+                }
+                written => return written,
+            }
+        }
         let mut total = 0;
+        let mut relocated = false;
         while total < buf.len() && !self.cluster_is_over() {
             let space_left_in_current_sector =
                 self.vfat_fs.device.sector_size - self.offset_byte_in_current_sector;
-            let amount = self.vfat_fs.device.clone().write_sector_offset(
+            let written = self.vfat_fs.device.clone().write_sector_offset(
                 self.current_sector,
                 self.offset_byte_in_current_sector,
                 &buf[total..core::cmp::min(total + space_left_in_current_sector, buf.len())],
-            )?;
+            );
+            let amount = match written {
+                // Only once: if the device fails every write, there's no point in going on.
+                Err(VfatRsError::IoError { source })
+                    if !relocated && (self.previous_cluster.is_some() || self.relocate_head) =>
+                {
+                    warn!(
+                        "Failed to write cluster {}: {}. Relocating it.",
+                        self.current_cluster, source
+                    );
+                    self.relocate()?;
+                    relocated = true;
+                    continue;
+                }
+                written => written?,
+            };
             total += amount;
             self.offset_byte_in_current_sector += amount;
             assert!(self.offset_byte_in_current_sector <= self.vfat_fs.device.sector_size);
@@ -124,6 +198,87 @@ impl ClusterChainWriter {
         Ok(total)
     }
 
+    /// Writes as many whole sectors of `buf` as possible with a single device request, starting
+    /// from the beginning of the current sector. The run continues past the current cluster as
+    /// long as the next clusters of the chain (allocated if needed) are contiguous on disk.
+    /// If the run fails, the position is left untouched.
+    fn write_run(&mut self, buf: &[u8]) -> Result<usize> {
+        let device = self.vfat_fs.device.clone();
+        let sectors_per_cluster = device.sectors_per_cluster;
+        let wanted = buf.len() / device.sector_size;
+        let cluster_end = device.cluster_to_sector(self.current_cluster) + sectors_per_cluster;
+        let mut count = (cluster_end - self.current_sector.0) as usize;
+        let (mut previous_cluster, mut last_cluster) =
+            (self.previous_cluster, self.current_cluster);
+        let mut chain = self.chain.clone();
+        // Set if the run stops before a cluster which is not contiguous: the whole run is written.
+        let mut after_run = None;
+        while count < wanted {
+            let next = self.cluster_after_alloc(&mut chain)?;
+            if u32::from(next) != u32::from(last_cluster) + 1 {
+                after_run = Some(next);
+                break;
+            }
+            (previous_cluster, last_cluster) = (Some(last_cluster), next);
+            count += sectors_per_cluster as usize;
+        }
+        let count = core::cmp::min(count, wanted);
+        let amount = device.write_sectors(self.current_sector, count, buf)?;
+        self.previous_cluster = previous_cluster;
+        self.current_cluster = last_cluster;
+        self.chain = chain;
+        self.current_sector = SectorId(self.current_sector + count as u32);
+        if let Some(next) = after_run {
+            self.previous_cluster = Some(last_cluster);
+            self.current_cluster = next;
+            self.current_sector = device.cluster_to_sector(next);
+        }
+        Ok(amount)
+    }
+
+    /// Replaces the current cluster, which can't be written, with a new one: its content is
+    /// copied, the chain is rewritten to use the new cluster, and the old one is marked bad.
+    /// If the content can't be read, nothing is changed and the read error is returned: the
+    /// data would be lost otherwise.
+    fn relocate(&mut self) -> Result<()> {
+        let device = self.vfat_fs.device.clone();
+        let failed = self.current_cluster;
+        let next = fat_table::read_fat_entry(failed, device.clone())?;
+        let failed_start = device.cluster_to_sector(failed);
+        let mut content = vec![0; self.vfat_fs.cluster_size()];
+        for (sector, buf) in (0..).zip(content.chunks_mut(device.sector_size)) {
+            device.read_sector(failed_start + SectorId(sector), buf)?;
+        }
+        let relocated = self.vfat_fs.allocate_cluster_new_entry()?;
+        let relocated_start = device.cluster_to_sector(relocated);
+        let copied = (0..)
+            .zip(content.chunks(device.sector_size))
+            .try_for_each(|(sector, buf)| {
+                device
+                    .clone()
+                    .write_sector_offset(relocated_start + SectorId(sector), 0, buf)
+                    .map(|_| ())
+            });
+        if let Err(err) = copied {
+            self.vfat_fs
+                .write_entry_in_vfat_table(relocated, FatEntry::Bad)?;
+            return Err(err);
+        }
+        self.vfat_fs.write_entry_in_vfat_table(relocated, next)?;
+        match self.previous_cluster {
+            Some(previous) => self
+                .vfat_fs
+                .write_entry_in_vfat_table(previous, FatEntry::from_chain(relocated))?,
+            None => self.head = relocated,
+        }
+        self.vfat_fs
+            .write_entry_in_vfat_table(failed, FatEntry::Bad)?;
+        self.current_sector = relocated_start + SectorId(self.current_sector.0 - failed_start.0);
+        self.current_cluster = relocated;
+        self.chain.replace_current(relocated);
+        Ok(())
+    }
+
     fn cluster_is_over(&self) -> bool {
         let cluster_start = self.vfat_fs.device.cluster_to_sector(self.current_cluster);
         let final_sector = SectorId(self.vfat_fs.device.sectors_per_cluster) + cluster_start;
@@ -1,6 +1,11 @@
+use alloc::vec::Vec;
+use core::cmp;
+use core::ops::Range;
+
+use crate::io::{Error as IoError, ErrorKind};
 #[cfg(feature = "std")]
 use crate::io::{Read, Seek};
-use crate::{error, SectorId};
+use crate::{error, SectorId, VfatRsError};
 #[cfg(feature = "std")]
 use log::debug;
 
@@ -44,6 +49,58 @@ pub trait BlockDevice {
         buf: &[u8],
     ) -> error::Result<usize>;
 
+    /// Reads `count` consecutive sectors, starting from `start`, in the first
+    /// `count * sector_size()` bytes of `buf`. Returns the amount of bytes read.
+    ///
+    /// The default implementation reads one sector at a time: devices able to transfer
+    /// several sectors per request should override it.
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let sector_size = self.sector_size();
+        let len = run_len(buf.len(), count, sector_size)?;
+        let buf = &mut buf[..len];
+        for (sector, sector_buf) in buf.chunks_mut(sector_size).enumerate() {
+            let mut total = 0;
+            while total < sector_size {
+                let amount = self.read_sector_offset(
+                    start + SectorId(sector as u32),
+                    total,
+                    &mut sector_buf[total..],
+                )?;
+                ensure_progress(amount)?;
+                total += amount;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Writes `count` consecutive sectors, starting from `start`, from the first
+    /// `count * sector_size()` bytes of `buf`. Returns the amount of bytes written.
+    ///
+    /// The default implementation writes one sector at a time: devices able to transfer
+    /// several sectors per request should override it.
+    fn write_sectors(&mut self, start: SectorId, count: usize, buf: &[u8]) -> error::Result<usize> {
+        let sector_size = self.sector_size();
+        let buf = &buf[..run_len(buf.len(), count, sector_size)?];
+        for (sector, sector_buf) in buf.chunks(sector_size).enumerate() {
+            let mut total = 0;
+            while total < sector_size {
+                let amount = self.write_sector_offset(
+                    start + SectorId(sector as u32),
+                    total,
+                    &sector_buf[total..],
+                )?;
+                ensure_progress(amount)?;
+                total += amount;
+            }
+        }
+        Ok(buf.len())
+    }
+
     /// A human readable name for this device
     fn get_canonical_name() -> &'static str
     where
@@ -53,6 +110,230 @@ pub trait BlockDevice {
     }
 }
 
+/// How many bytes a run of `count` sectors transfers. Fails if `buf_len` is too small for it.
+pub(crate) fn run_len(buf_len: usize, count: usize, sector_size: usize) -> error::Result<usize> {
+    match count.checked_mul(sector_size) {
+        Some(len) if len <= buf_len => Ok(len),
+        _ => Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "The buffer is too small for the requested sectors",
+        )
+        .into()),
+    }
+}
+
+/// Devices must make progress, or the sector would be read (or written) forever.
+fn ensure_progress(amount: usize) -> error::Result<()> {
+    if amount == 0 {
+        return Err(IoError::new(ErrorKind::WriteZero, "The device transferred no bytes").into());
+    }
+    Ok(())
+}
+
+/// The bytes of `image` holding `len` bytes of `sector`, starting from `offset`. At most a
+/// sector is transferred, so `len` is capped to its end.
+fn sector_range(
+    image: &[u8],
+    sector_size: usize,
+    sector: SectorId,
+    offset: usize,
+    len: usize,
+) -> error::Result<Range<usize>> {
+    let start = (sector.0 as usize)
+        .checked_mul(sector_size)
+        .and_then(|start| start.checked_add(offset));
+    image_range(
+        image,
+        start,
+        cmp::min(len, sector_size.saturating_sub(offset)),
+    )
+}
+
+/// The bytes of `image` holding the run of `count` sectors starting from `start`.
+fn run_range(
+    image: &[u8],
+    sector_size: usize,
+    start: SectorId,
+    count: usize,
+    buf_len: usize,
+) -> error::Result<Range<usize>> {
+    let len = run_len(buf_len, count, sector_size)?;
+    image_range(image, (start.0 as usize).checked_mul(sector_size), len)
+}
+
+/// Fails if the range is past the end of the image, or its start overflowed.
+fn image_range(image: &[u8], start: Option<usize>, len: usize) -> error::Result<Range<usize>> {
+    match start {
+        Some(start) if start.checked_add(len).is_some_and(|end| end <= image.len()) => {
+            Ok(start..start + len)
+        }
+        _ => Err(IoError::new(
+            ErrorKind::UnexpectedEof,
+            "The sector is past the end of the device",
+        )
+        .into()),
+    }
+}
+
+/// A `BlockDevice` backed by an in-memory image, e.g. to create a volume or run tests without
+/// touching the disk. Its size is the size of the image: it doesn't grow.
+#[derive(Debug, Clone)]
+pub struct MemoryBlockDevice {
+    image: Vec<u8>,
+    sector_size: usize,
+}
+
+impl MemoryBlockDevice {
+    /// A device holding `image`, with 512 bytes sectors.
+    pub fn new(image: Vec<u8>) -> Self {
+        Self::with_sector_size(image, 512)
+    }
+
+    pub fn with_sector_size(image: Vec<u8>, sector_size: usize) -> Self {
+        Self { image, sector_size }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Returns the image, with everything written to this device.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.image
+    }
+}
+
+impl BlockDevice for MemoryBlockDevice {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let range = sector_range(&self.image, self.sector_size, sector, offset, buf.len())?;
+        buf[..range.len()].copy_from_slice(&self.image[range.clone()]);
+        Ok(range.len())
+    }
+
+    fn write_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &[u8],
+    ) -> error::Result<usize> {
+        let range = sector_range(&self.image, self.sector_size, sector, offset, buf.len())?;
+        let len = range.len();
+        self.image[range].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let range = run_range(&self.image, self.sector_size, start, count, buf.len())?;
+        buf[..range.len()].copy_from_slice(&self.image[range.clone()]);
+        Ok(range.len())
+    }
+
+    fn write_sectors(&mut self, start: SectorId, count: usize, buf: &[u8]) -> error::Result<usize> {
+        let range = run_range(&self.image, self.sector_size, start, count, buf.len())?;
+        let len = range.len();
+        self.image[range].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn get_canonical_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "MemoryBlockDevice"
+    }
+}
+
+/// A read-only `BlockDevice` over an image in memory, which is not copied: e.g. one embedded
+/// with `include_bytes!`. Writes fail with `ReadOnlyFilesystem`, so the volume should be
+/// mounted read-only (see `VfatFS::new_read_only`). `VfatFS` needs a `'static` device.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceBlockDevice<'a> {
+    image: &'a [u8],
+    sector_size: usize,
+}
+
+impl<'a> SliceBlockDevice<'a> {
+    /// A device over `image`, with 512 bytes sectors.
+    pub fn new(image: &'a [u8]) -> Self {
+        Self::with_sector_size(image, 512)
+    }
+
+    pub fn with_sector_size(image: &'a [u8], sector_size: usize) -> Self {
+        Self { image, sector_size }
+    }
+
+    pub fn image(&self) -> &'a [u8] {
+        self.image
+    }
+}
+
+impl BlockDevice for SliceBlockDevice<'_> {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let range = sector_range(self.image, self.sector_size, sector, offset, buf.len())?;
+        buf[..range.len()].copy_from_slice(&self.image[range.clone()]);
+        Ok(range.len())
+    }
+
+    fn write_sector_offset(
+        &mut self,
+        _sector: SectorId,
+        _offset: usize,
+        _buf: &[u8],
+    ) -> error::Result<usize> {
+        Err(VfatRsError::ReadOnlyFilesystem)
+    }
+
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> error::Result<usize> {
+        let range = run_range(self.image, self.sector_size, start, count, buf.len())?;
+        buf[..range.len()].copy_from_slice(&self.image[range.clone()]);
+        Ok(range.len())
+    }
+
+    fn write_sectors(
+        &mut self,
+        _start: SectorId,
+        _count: usize,
+        _buf: &[u8],
+    ) -> error::Result<usize> {
+        Err(VfatRsError::ReadOnlyFilesystem)
+    }
+
+    fn get_canonical_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "SliceBlockDevice"
+    }
+}
+
 /// FilebackedBlockDevice is an implementation of BlockDevice backed by
 /// std::fs::File. It's a simple way to explore a vfat fs on a file.
 #[cfg(feature = "std")]
@@ -70,11 +351,10 @@ impl BlockDevice for FilebackedBlockDevice {
         &mut self,
         sector: SectorId,
         offset: usize,
-        mut buf: &mut [u8],
+        buf: &mut [u8],
     ) -> crate::Result<usize> {
         use core::cmp::min;
         let max_read = min(buf.len(), self.sector_size());
-        let mut temp_buf = vec![0; max_read];
         let final_destination = sector.0 as u64 * self.sector_size() as u64 + offset as u64;
         debug!(
             "Sector: {}, offset: {}, finaldest: {}",
@@ -83,15 +363,10 @@ impl BlockDevice for FilebackedBlockDevice {
             final_destination
         );
         self.image
-            .seek(std::io::SeekFrom::Start(final_destination))
-            .expect("Impossible to seek to the sector");
-
-        self.image
-            .read_exact(temp_buf.as_mut_slice())
-            .expect("Impossible to read from image");
+            .seek(std::io::SeekFrom::Start(final_destination))?;
+        self.image.read_exact(&mut buf[..max_read])?;
         debug!("done reading read_sector_offset...");
-        use crate::io::Write;
-        buf.write(temp_buf.as_mut_slice()).map_err(Into::into)
+        Ok(max_read)
     }
 
     fn write_sector_offset(
@@ -110,15 +385,39 @@ impl BlockDevice for FilebackedBlockDevice {
             final_destination
         );
         self.image
-            .seek(std::io::SeekFrom::Start(final_destination))
-            .expect("Error seek");
+            .seek(std::io::SeekFrom::Start(final_destination))?;
         debug!("Writing the buffer to the image..");
-        self.image.write_all(buf).expect("Write sector");
+        self.image.write_all(buf)?;
         debug!("Written: {}", buf.len());
-        self.image.flush().unwrap();
+        self.image.flush()?;
         Ok(buf.len())
     }
 
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> crate::Result<usize> {
+        let len = run_len(buf.len(), count, self.sector_size())?;
+        self.image.seek(std::io::SeekFrom::Start(
+            start.0 as u64 * self.sector_size() as u64,
+        ))?;
+        self.image.read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_sectors(&mut self, start: SectorId, count: usize, buf: &[u8]) -> crate::Result<usize> {
+        use std::io::Write;
+        let len = run_len(buf.len(), count, self.sector_size())?;
+        self.image.seek(std::io::SeekFrom::Start(
+            start.0 as u64 * self.sector_size() as u64,
+        ))?;
+        self.image.write_all(&buf[..len])?;
+        self.image.flush()?;
+        Ok(len)
+    }
+
     fn get_canonical_name() -> &'static str
     where
         Self: Sized,
@@ -126,3 +425,60 @@ impl BlockDevice for FilebackedBlockDevice {
         "FileBasedBlockDevice"
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{BlockDevice, MemoryBlockDevice, SliceBlockDevice};
+    use crate::{ErrorKind, SectorId, VfatRsError};
+    use alloc::vec;
+
+    #[test]
+    fn test_memory_block_device() {
+        let mut device = MemoryBlockDevice::with_sector_size(vec![0; 16], 4);
+        device.write_sector(SectorId(1), &[1; 4]).unwrap();
+        // Writes stop at the end of the sector.
+        assert_eq!(
+            device.write_sector_offset(SectorId(2), 2, &[2; 4]).unwrap(),
+            2
+        );
+        device.write_sectors(SectorId(3), 1, &[3; 6]).unwrap();
+        assert_eq!(
+            device.image(),
+            &[0, 0, 0, 0, 1, 1, 1, 1, 0, 0, 2, 2, 3, 3, 3, 3]
+        );
+
+        let mut buf = [0; 8];
+        assert_eq!(
+            device.read_sector_offset(SectorId(1), 3, &mut buf).unwrap(),
+            1
+        );
+        assert_eq!(device.read_sectors(SectorId(2), 2, &mut buf).unwrap(), 8);
+        assert_eq!(buf, [0, 0, 2, 2, 3, 3, 3, 3]);
+
+        // The device doesn't grow.
+        let past_end = device.read_sectors(SectorId(3), 2, &mut buf).unwrap_err();
+        assert_eq!(past_end.kind(), ErrorKind::Corrupted);
+        assert!(device.write_sector(SectorId(4), &[4; 4]).is_err());
+        assert!(device.read_sector(SectorId(u32::MAX), &mut buf).is_err());
+        assert_eq!(device.into_inner().len(), 16);
+    }
+
+    #[test]
+    fn test_slice_block_device() {
+        let image = [5; 8];
+        let mut device = SliceBlockDevice::with_sector_size(&image, 4);
+        let mut buf = [0; 4];
+        assert_eq!(device.read_sector(SectorId(1), &mut buf).unwrap(), 4);
+        assert_eq!(buf, [5; 4]);
+        assert!(device.read_sector(SectorId(2), &mut buf).is_err());
+        assert!(matches!(
+            device.write_sector(SectorId(0), &[0; 4]),
+            Err(VfatRsError::ReadOnlyFilesystem)
+        ));
+        assert!(matches!(
+            device.write_sectors(SectorId(0), 1, &[0; 4]),
+            Err(VfatRsError::ReadOnlyFilesystem)
+        ));
+        assert_eq!(device.image(), &[5; 8]);
+    }
+}
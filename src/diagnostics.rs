@@ -0,0 +1,34 @@
+//! Anomalies found while reading on-disk structures.
+//! They don't prevent the operation from completing (the damaged structure is ignored),
+//! but they are reported through the optional `DiagnosticsTrait` hook.
+use alloc::string::String;
+
+/// Why a run of long file name entries was discarded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LfnAnomaly {
+    /// The run doesn't start with an entry having the `LastLogical` bit set.
+    MissingLastLogical,
+    /// Sequence numbers are not contiguous (or out of range), or the run was interrupted.
+    NonContiguous,
+    /// The checksum stored in the run doesn't match the following 8.3 entry.
+    ChecksumMismatch,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Diagnostic {
+    /// A run of LFN entries was ignored, the 8.3 name of the entry (if any) was used instead.
+    DiscardedLfn {
+        /// Path of the directory containing the run.
+        directory: String,
+        /// Index of the first discarded entry in the directory.
+        entry_index: usize,
+        anomaly: LfnAnomaly,
+    },
+    /// A directory uses the same cluster as another one (e.g. a cross-linked directory, or a
+    /// corrupted `..` entry). It was not visited again.
+    DirectoryLoop {
+        /// Path of the directory.
+        path: String,
+        cluster: u32,
+    },
+}
@@ -1,4 +1,6 @@
+use alloc::boxed::Box;
 use alloc::string::String;
+use log::debug;
 use snafu::prelude::*;
 
 /// VfatRS result type
@@ -20,6 +22,33 @@ pub enum VfatRsError {
     IoError { source: IoError },
     #[snafu(display("Unsupported vfat partition found, signature: {}", target))]
     InvalidVfat { target: u8 },
+    #[snafu(display("Invalid boot sector signature: {:#06x}", signature))]
+    InvalidBootSignature { signature: u16 },
+    #[snafu(display(
+        "Invalid bytes per sector: {}, the device's sector size is {}",
+        bytes_per_sector,
+        device_sector_size
+    ))]
+    InvalidBytesPerSector {
+        bytes_per_sector: u16,
+        device_sector_size: usize,
+    },
+    #[snafu(display(
+        "Invalid sectors per cluster: {}, it must be a power of two",
+        sectors_per_cluster
+    ))]
+    InvalidSectorsPerCluster { sectors_per_cluster: u8 },
+    #[snafu(display("Invalid number of FATs: {}", fat_amount))]
+    InvalidFatAmount { fat_amount: u8 },
+    #[snafu(display("Invalid volume layout: {}", reason))]
+    InvalidVolumeLayout { reason: &'static str },
+    #[snafu(display(
+        "The volume has {} clusters, too few for FAT32: it's probably FAT12 or FAT16",
+        clusters
+    ))]
+    NotFat32 { clusters: u32 },
+    #[snafu(display("Invalid root cluster: {}, valid clusters are 2..={}", cluster, max))]
+    InvalidRootCluster { cluster: u32, max: u32 },
     #[snafu(display("Impossible delete non empty directory: {}", target))]
     NonEmptyDirectory { target: String },
     #[snafu(display("File not found: '{}'", target))]
@@ -28,6 +57,262 @@ pub enum VfatRsError {
     EntryNotFound { target: String },
     #[snafu(display("Cannot delete pseudo directory: '{}'", target))]
     CannotDeletePseudoDir { target: String },
+    #[snafu(display("Invalid name '{}': {}", target, reason))]
+    InvalidName {
+        target: String,
+        reason: &'static str,
+    },
+    #[snafu(display(
+        "Name too long ({} UTF-16 units, max is {}): '{}'",
+        length,
+        max,
+        target
+    ))]
+    NameTooLong {
+        target: String,
+        length: usize,
+        max: usize,
+    },
+    #[snafu(display(
+        "Directory '{}' is full, it can't hold more than {} entries",
+        target,
+        max
+    ))]
+    DirectoryFull { target: String, max: usize },
+    #[snafu(display("Invalid attributes for '{}': {}", target, reason))]
+    InvalidAttributes {
+        target: String,
+        reason: &'static str,
+    },
+    #[snafu(display("Entry '{}' is read-only", target))]
+    ReadOnlyEntry { target: String },
+    #[snafu(display("The filesystem is mounted read-only"))]
+    ReadOnlyFilesystem,
+    #[snafu(display(
+        "Can't find the parent of '{}': the '..' entries are corrupted",
+        target
+    ))]
+    BrokenParentLink { target: String },
+    #[snafu(display("'{}' is a directory", target))]
+    IsADirectory { target: String },
+    #[snafu(display("'{}' is not a directory", target))]
+    NotADirectory { target: String },
+    #[snafu(display("Can't move '{}': {}", target, reason))]
+    InvalidMove {
+        target: String,
+        reason: &'static str,
+    },
+    #[snafu(display("Corrupted cluster chain at cluster {}: {}", cluster, reason))]
+    CorruptedChain { cluster: u32, reason: &'static str },
+    #[snafu(display("Invalid cluster: {}, valid clusters are 2..={}", cluster, max))]
+    InvalidCluster { cluster: u32, max: u32 },
+    #[snafu(display("Cluster {} is in use", cluster))]
+    ClusterInUse { cluster: u32 },
+    #[snafu(display("File '{}' is open, it can't be deleted", target))]
+    FileInUse { target: String },
+    #[snafu(display("Invalid volume label '{}': {}", target, reason))]
+    InvalidLabel {
+        target: String,
+        reason: &'static str,
+    },
+    /// An error with the operation and path it happened on, see `VfatRsError::with_context`.
+    /// The public operations return the bare variants; the context is added where the
+    /// variant can't be matched anyway, e.g. when converting to an `io::Error`.
+    #[snafu(display("Failed to {} '{}': {}", operation, path, source))]
+    Context {
+        operation: &'static str,
+        path: String,
+        source: Box<VfatRsError>,
+    },
+}
+
+impl VfatRsError {
+    /// Records the operation and path the error happened on, unless it already has a
+    /// context: the innermost operation is the one that failed.
+    pub fn with_context(self, operation: &'static str, path: &str) -> Self {
+        match self {
+            VfatRsError::Context { .. } => self,
+            err => VfatRsError::Context {
+                operation,
+                path: path.into(),
+                source: Box::new(err),
+            },
+        }
+    }
+
+    /// The error without its context, see `Context`.
+    pub fn cause(&self) -> &VfatRsError {
+        match self {
+            VfatRsError::Context { source, .. } => source.cause(),
+            err => err,
+        }
+    }
+
+    /// Like `cause`, but takes ownership of the error.
+    pub fn into_cause(self) -> VfatRsError {
+        match self {
+            VfatRsError::Context { source, .. } => source.into_cause(),
+            err => err,
+        }
+    }
+
+    /// The failed operation (e.g. "open", "rename"), if known.
+    pub fn operation(&self) -> Option<&'static str> {
+        match self {
+            VfatRsError::Context { operation, .. } => Some(operation),
+            _ => None,
+        }
+    }
+
+    /// The path the failed operation was working on, if known.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            VfatRsError::Context { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// A stable classification of this error. Unlike the variants, kinds are not going to
+    /// change, so they are what callers should match on to handle errors.
+    pub fn kind(&self) -> ErrorKind {
+        use VfatRsError::*;
+        match self {
+            NameAlreadyInUse { .. } => ErrorKind::AlreadyExists,
+            FileNotFound { .. } | EntryNotFound { .. } => ErrorKind::NotFound,
+            NonEmptyDirectory { .. } => ErrorKind::DirectoryNotEmpty,
+            FreeClusterNotFound | DirectoryFull { .. } => ErrorKind::NoSpace,
+            ReadOnlyFilesystem => ErrorKind::ReadOnly,
+            ReadOnlyEntry { .. } => ErrorKind::PermissionDenied,
+            NameTooLong { .. } => ErrorKind::NameTooLong,
+            IsADirectory { .. } => ErrorKind::IsADirectory,
+            NotADirectory { .. } => ErrorKind::NotADirectory,
+            ClusterInUse { .. } | FileInUse { .. } => ErrorKind::Busy,
+            CannotDeletePseudoDir { .. }
+            | InvalidName { .. }
+            | InvalidAttributes { .. }
+            | InvalidMove { .. }
+            | InvalidCluster { .. }
+            | InvalidLabel { .. } => ErrorKind::InvalidInput,
+            Mbr { .. }
+            | InvalidVfat { .. }
+            | InvalidBootSignature { .. }
+            | InvalidBytesPerSector { .. }
+            | InvalidSectorsPerCluster { .. }
+            | InvalidFatAmount { .. }
+            | InvalidVolumeLayout { .. }
+            | NotFat32 { .. }
+            | InvalidRootCluster { .. } => ErrorKind::InvalidFilesystem,
+            CheckedMulFailed | BrokenParentLink { .. } | CorruptedChain { .. } => {
+                ErrorKind::Corrupted
+            }
+            IoError { source } => match source.kind() {
+                crate::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                crate::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                crate::io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+                crate::io::ErrorKind::InvalidData | crate::io::ErrorKind::UnexpectedEof => {
+                    ErrorKind::Corrupted
+                }
+                _ => ErrorKind::Io,
+            },
+            Context { source, .. } => source.kind(),
+        }
+    }
+
+    /// The POSIX errno value (as a positive number) matching this error, see `ErrorKind::errno`.
+    pub fn errno(&self) -> i32 {
+        self.kind().errno()
+    }
+
+    /// The closest `io::ErrorKind`, used when this error is turned into an I/O error.
+    pub(crate) fn io_kind(&self) -> crate::io::ErrorKind {
+        use crate::io::ErrorKind as Io;
+        if let VfatRsError::IoError { source } = self.cause() {
+            return source.kind();
+        }
+        match self.kind() {
+            ErrorKind::NotFound => Io::NotFound,
+            ErrorKind::AlreadyExists => Io::AlreadyExists,
+            ErrorKind::ReadOnly | ErrorKind::PermissionDenied => Io::PermissionDenied,
+            ErrorKind::InvalidInput | ErrorKind::NameTooLong => Io::InvalidInput,
+            ErrorKind::Corrupted | ErrorKind::InvalidFilesystem => Io::InvalidData,
+            _ => Io::Other,
+        }
+    }
+}
+
+/// A coarse classification of `VfatRsError`, see `VfatRsError::kind`.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    NotFound,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    /// No free cluster is left, or a directory can't hold more entries.
+    NoSpace,
+    /// The filesystem is mounted read-only.
+    ReadOnly,
+    /// The entry is read-only.
+    PermissionDenied,
+    NameTooLong,
+    IsADirectory,
+    NotADirectory,
+    InvalidInput,
+    /// The entry or cluster is in use.
+    Busy,
+    /// The volume is not a valid FAT32 volume.
+    InvalidFilesystem,
+    /// The on-disk structures are inconsistent.
+    Corrupted,
+    /// The device failed.
+    Io,
+}
+
+impl ErrorKind {
+    /// The POSIX errno value (as a positive number) matching this kind, as used by Linux.
+    pub fn errno(self) -> i32 {
+        match self {
+            ErrorKind::NotFound => errno::ENOENT,
+            ErrorKind::AlreadyExists => errno::EEXIST,
+            ErrorKind::DirectoryNotEmpty => errno::ENOTEMPTY,
+            ErrorKind::NoSpace => errno::ENOSPC,
+            ErrorKind::ReadOnly => errno::EROFS,
+            ErrorKind::PermissionDenied => errno::EACCES,
+            ErrorKind::NameTooLong => errno::ENAMETOOLONG,
+            ErrorKind::IsADirectory => errno::EISDIR,
+            ErrorKind::NotADirectory => errno::ENOTDIR,
+            ErrorKind::InvalidInput | ErrorKind::InvalidFilesystem => errno::EINVAL,
+            ErrorKind::Busy => errno::EBUSY,
+            ErrorKind::Corrupted | ErrorKind::Io => errno::EIO,
+        }
+    }
+}
+
+/// The errno values returned by `ErrorKind::errno`, as defined on Linux.
+pub mod errno {
+    pub const ENOENT: i32 = 2;
+    pub const EIO: i32 = 5;
+    pub const EACCES: i32 = 13;
+    pub const EBUSY: i32 = 16;
+    pub const EEXIST: i32 = 17;
+    pub const ENOTDIR: i32 = 20;
+    pub const EISDIR: i32 = 21;
+    pub const EINVAL: i32 = 22;
+    pub const ENOSPC: i32 = 28;
+    pub const EROFS: i32 = 30;
+    pub const ENAMETOOLONG: i32 = 36;
+    pub const ENOTEMPTY: i32 = 39;
+}
+
+/// Logs the operation and path of the failed public operations.
+pub(crate) trait LogFailure<T> {
+    /// Logs the error, which is returned unchanged so callers can still match on its variant.
+    fn log_failure(self, operation: &'static str, path: &str) -> Result<T>;
+}
+
+impl<T> LogFailure<T> for Result<T> {
+    fn log_failure(self, operation: &'static str, path: &str) -> Result<T> {
+        self.inspect_err(|err| debug!("Failed to {} '{}': {}", operation, path, err))
+    }
 }
 
 impl From<IoError> for VfatRsError {
@@ -46,33 +331,109 @@ impl From<crate::io::ErrorKind> for VfatRsError {
 pub enum MbrError {
     #[snafu(display("Not a fat32 partition: {index}"))]
     InvalidPartition { index: usize },
+    #[snafu(display("Invalid MBR signature: {signature:02x?}"))]
+    InvalidSignature { signature: [u8; 2] },
 }
 
 // Used for Impl Write/Read
+#[cfg(feature = "std")]
 impl From<VfatRsError> for binrw::io::Error {
-    fn from(_err: VfatRsError) -> Self {
-        // TODO: provide useful output
-        binrw::io::ErrorKind::Other.into()
+    fn from(err: VfatRsError) -> Self {
+        match err {
+            VfatRsError::IoError { source } => source,
+            err => binrw::io::Error::new(err.io_kind(), err),
+        }
+    }
+}
+
+// Without std, binrw's I/O errors only hold a kind.
+#[cfg(not(feature = "std"))]
+impl From<VfatRsError> for binrw::io::Error {
+    fn from(err: VfatRsError) -> Self {
+        use crate::io::ErrorKind as Io;
+        use binrw::io::ErrorKind as Binrw;
+        let kind = match err.io_kind() {
+            Io::NotFound => Binrw::NotFound,
+            Io::PermissionDenied => Binrw::PermissionDenied,
+            Io::AlreadyExists => Binrw::AlreadyExists,
+            Io::InvalidInput => Binrw::InvalidInput,
+            Io::InvalidData => Binrw::InvalidData,
+            Io::UnexpectedEof => Binrw::UnexpectedEof,
+            Io::WriteZero => Binrw::WriteZero,
+            Io::Interrupted => Binrw::Interrupted,
+            _ => Binrw::Other,
+        };
+        kind.into()
     }
 }
 
 impl From<binrw::Error> for VfatRsError {
     fn from(err: binrw::Error) -> Self {
-        // todo
-        let kind = crate::io::ErrorKind::Other;
         match err {
-            binrw::Error::Io(_err) => Self::from(IoError::new(kind, "IoError")),
-            _ => {
-                panic!("todo.")
-            }
+            binrw::Error::Io(err) => Self::from(err),
+            // Magic numbers and asserts failing to parse an on-disk structure.
+            _ => Self::from(IoError::new(
+                crate::io::ErrorKind::InvalidData,
+                "Malformed on-disk structure",
+            )),
         }
     }
 }
 #[cfg(not(feature = "std"))]
 impl From<binrw::io::Error> for VfatRsError {
-    fn from(_err: binrw::io::Error) -> Self {
-        // todo
-        let kind = crate::io::ErrorKind::Other;
-        Self::from(IoError::new(kind, "IoError"))
+    fn from(err: binrw::io::Error) -> Self {
+        use crate::io::ErrorKind as Io;
+        use binrw::io::ErrorKind as Binrw;
+        let kind = match err.kind() {
+            Binrw::NotFound => Io::NotFound,
+            Binrw::PermissionDenied => Io::PermissionDenied,
+            Binrw::AlreadyExists => Io::AlreadyExists,
+            Binrw::InvalidInput => Io::InvalidInput,
+            Binrw::InvalidData => Io::InvalidData,
+            Binrw::UnexpectedEof => Io::UnexpectedEof,
+            Binrw::WriteZero => Io::WriteZero,
+            Binrw::Interrupted => Io::Interrupted,
+            _ => Io::Other,
+        };
+        Self::from(IoError::new(kind, "binrw I/O error"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{errno, ErrorKind, VfatRsError};
+    use crate::io::{Error as IoError, ErrorKind as IoErrorKind};
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_error_context() {
+        let err = VfatRsError::NonEmptyDirectory {
+            target: "folder".into(),
+        }
+        .with_context("remove", "/folder")
+        .with_context("rename", "/other");
+        assert_eq!(err.operation(), Some("remove"));
+        assert_eq!(err.path(), Some("/folder"));
+        assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+        assert_eq!(err.errno(), errno::ENOTEMPTY);
+        assert!(err.to_string().starts_with("Failed to remove '/folder'"));
+        assert!(matches!(
+            err.into_cause(),
+            VfatRsError::NonEmptyDirectory { .. }
+        ));
+    }
+
+    #[test]
+    // `Error::other` is not available without std.
+    #[allow(clippy::io_other_error)]
+    fn test_io_errors() {
+        let err = VfatRsError::from(IoError::new(IoErrorKind::Other, "sector 42 is damaged"));
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert_eq!(err.errno(), errno::EIO);
+        assert!(err.to_string().contains("sector 42 is damaged"));
+
+        let io_err = IoError::from(VfatRsError::ReadOnlyFilesystem);
+        assert_eq!(io_err.kind(), IoErrorKind::PermissionDenied);
+        assert!(io_err.to_string().contains("read-only"));
     }
 }
@@ -3,30 +3,37 @@ use core::mem;
 use crate::ClusterId;
 
 pub(crate) const FAT_ENTRY_SIZE: usize = mem::size_of::<u32>();
+/// Written in the FAT entry of the last cluster of a chain.
+pub(crate) const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+/// Written in the FAT entry of a cluster with a defect, so that it's never allocated.
+pub(crate) const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+/// FAT32 keeps the state of the volume in FAT[1]. This bit is cleared while the volume is
+/// mounted and being changed: if it's still cleared at mount, the volume was not unmounted
+/// cleanly.
+pub(crate) const CLEAN_SHUTDOWN_BIT: u32 = 1 << 27;
+/// Cleared by drivers hitting disk I/O errors on the volume.
+pub(crate) const NO_HARD_ERRORS_BIT: u32 = 1 << 26;
 
 /// A fat32 row entry. Each entry represents a cluster. This is the "high level" view
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
 #[repr(C)]
 pub(crate) enum FatEntry {
     /// Entry 0, formatted as 0xFFFFFFFN
     #[allow(dead_code)]
     Id(u32),
     /// A free, unused cluster. 0x00
+    #[default]
     Unused,
     /// 0x01: reserved
     Reserved(u32),
+    /// A cluster with a defect, 0x0FFFFFF7. It's never allocated.
+    Bad,
     /// A data cluster; value points to next cluster in chain.
     DataCluster(u32),
     /// Last cluster in chain. Should be, but may not be, the EndOfChainMarker (e.g. entry 1).
     LastCluster(u32),
 }
 
-impl Default for FatEntry {
-    fn default() -> Self {
-        FatEntry::Unused
-    }
-}
-
 impl FatEntry {
     pub(crate) fn from_chain(next: ClusterId) -> Self {
         Self::DataCluster(next.into())
@@ -53,7 +60,8 @@ impl From<[u8; FAT_ENTRY_SIZE]> for FatEntry {
             0x0 => Unused,
             //0x1 => Reserved(val),
             0x0000002..=0xFFFFFEF => DataCluster(val),
-            //0xFFFFFF0..=0xFFFFFF7 => Reserved(val),
+            BAD_CLUSTER => Bad,
+            //0xFFFFFF0..=0xFFFFFF6 => Reserved(val),
             0xFFFFFF8..=0xFFFFFFF => LastCluster(val),
             val => Reserved(val),
         }
@@ -66,6 +74,7 @@ impl From<FatEntry> for u32 {
         match fat_entry {
             Unused => 0x0,
             Reserved(i) => i,
+            Bad => BAD_CLUSTER,
             DataCluster(i) => i,
             LastCluster(i) => i,
             Id(i) => i,
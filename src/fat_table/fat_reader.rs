@@ -1,24 +1,97 @@
-use crate::error::Result;
+use snafu::ensure;
+
+use crate::error::{CorruptedChainSnafu, Result};
 use crate::fat_table::fat_entry::FAT_ENTRY_SIZE;
-use crate::fat_table::{get_params, FatEntry};
+use crate::fat_table::{check_cluster, get_params, FatEntry};
 use crate::ArcMutex;
 use crate::{CachedPartition, ClusterId};
 
 /// Returns the next clusterid in the chain after the provided cluster_id, if any.
 /// To do that, query the fat table for this cluster id, and see if it is a Data Cluster (e.g. a
 /// node in the chain) return the next element, otherwise it's a dead end and return null.
+/// Links to clusters outside the data region fail with `CorruptedChain`.
 pub(crate) fn next_cluster(
     cluster_id: ClusterId,
     device: ArcMutex<CachedPartition>,
 ) -> Result<Option<ClusterId>> {
-    let fat_entry = read_fat_entry(cluster_id, device)?;
+    let fat_entry = read_fat_entry(cluster_id, device.clone())?;
     // info!("Fat entry: {:?}", fat_entry);
     Ok(match fat_entry {
-        FatEntry::DataCluster(id) => Some(ClusterId::new(id)),
+        FatEntry::DataCluster(id) => {
+            let next = ClusterId::new(id);
+            ensure!(
+                check_cluster(&device, next).is_ok(),
+                CorruptedChainSnafu {
+                    cluster: cluster_id,
+                    reason: "it links to a cluster outside the data region",
+                }
+            );
+            Some(next)
+        }
         _ => None,
     })
 }
 
+/// Walks a cluster chain one link at a time, guarding against corrupted FATs: every link is
+/// checked by `next_cluster`, and since a chain can't hold more clusters than the volume, a
+/// longer one must have a cycle.
+#[derive(Debug, Clone)]
+pub(crate) struct ChainCursor {
+    head: ClusterId,
+    current: ClusterId,
+    /// How many links were followed from `head`.
+    links: u32,
+}
+
+impl ChainCursor {
+    pub(crate) fn new(head: ClusterId) -> Self {
+        Self {
+            head,
+            current: head,
+            links: 0,
+        }
+    }
+
+    pub(crate) fn current(&self) -> ClusterId {
+        self.current
+    }
+
+    /// Moves to the next cluster of the chain and returns it, or returns None at the end of
+    /// the chain.
+    pub(crate) fn advance(
+        &mut self,
+        device: &ArcMutex<CachedPartition>,
+    ) -> Result<Option<ClusterId>> {
+        let Some(next) = next_cluster(self.current, device.clone())? else {
+            return Ok(None);
+        };
+        self.links += 1;
+        ensure!(
+            self.links < device.total_clusters,
+            CorruptedChainSnafu {
+                cluster: self.head,
+                reason: "it has a cycle",
+            }
+        );
+        self.current = next;
+        Ok(Some(next))
+    }
+
+    /// Moves to `cluster`, which was just linked after the current one (e.g. allocated).
+    pub(crate) fn append(&mut self, cluster: ClusterId) {
+        self.links += 1;
+        self.current = cluster;
+    }
+
+    /// Continues from `cluster`, which took the place of the current cluster in the chain.
+    pub(crate) fn replace_current(&mut self, cluster: ClusterId) {
+        if self.links == 0 {
+            self.head = cluster;
+        }
+        self.current = cluster;
+    }
+}
+
 pub(crate) fn read_fat_entry(
     cluster_id: ClusterId,
     device: ArcMutex<CachedPartition>,
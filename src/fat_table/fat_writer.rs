@@ -1,28 +1,62 @@
 use alloc::sync::Arc;
 
 use crate::error::Result;
-use crate::fat_table::{get_params, FatEntry};
-use crate::{fat_table, ArcMutex, CachedPartition, ClusterId};
+use crate::fat_table::{get_params, ChainCursor, FatEntry};
+use crate::{ArcMutex, CachedPartition, ClusterId, SectorId};
 
-/// Delete a cluster chain starting from `current`.
+/// Delete a cluster chain starting from `current`. Returns how many clusters were freed.
 /// TODO: Start from the end of the chain to make the operation safer.
 /// TODO: Check if "current" is of "Used" type.
 /// TODO: Test with array backed dev.
 pub(crate) fn delete_cluster_chain(
-    mut current: ClusterId,
+    head: ClusterId,
     device: ArcMutex<CachedPartition>,
-) -> Result<()> {
+) -> Result<usize> {
     const DELETED_ENTRY: FatEntry = FatEntry::Unused;
-    while let Some(next) = fat_table::next_cluster(current, device.clone())? {
+    let mut chain = ChainCursor::new(head);
+    let mut current = head;
+    let mut freed = 1;
+    while let Some(next) = chain.advance(&device)? {
         set_fat_entry(device.clone(), current, DELETED_ENTRY)?;
         current = next;
+        freed += 1;
     }
 
     set_fat_entry(device, current, DELETED_ENTRY)?;
 
-    Ok(())
+    Ok(freed)
 }
 
+/// Keeps the first `keep` (at least one) clusters of the chain starting from `head`, and frees
+/// the others. Returns how many clusters were freed.
+pub(crate) fn truncate_cluster_chain(
+    head: ClusterId,
+    keep: usize,
+    end_of_chain: FatEntry,
+    device: ArcMutex<CachedPartition>,
+) -> Result<usize> {
+    let mut chain = ChainCursor::new(head);
+    for _ in 1..keep.max(1) {
+        if chain.advance(&device)?.is_none() {
+            return Ok(0);
+        }
+    }
+    let last_kept = chain.current();
+    let Some(mut current) = chain.advance(&device)? else {
+        return Ok(0);
+    };
+    set_fat_entry(device.clone(), last_kept, end_of_chain)?;
+    let mut freed = 1;
+    while let Some(next) = chain.advance(&device)? {
+        set_fat_entry(device.clone(), current, FatEntry::Unused)?;
+        current = next;
+        freed += 1;
+    }
+    set_fat_entry(device, current, FatEntry::Unused)?;
+    Ok(freed)
+}
+
+/// Writes the entry of `cluster_id` in every copy of the FAT.
 pub(crate) fn set_fat_entry(
     device: Arc<CachedPartition>,
     cluster_id: ClusterId,
@@ -34,6 +68,11 @@ pub(crate) fn set_fat_entry(
         "Requested cid: {}, containing sector: {}, offset in sector: {}",
         cluster_id, sector, offset
     );*/
-    device.write_sector_offset(sector, offset, &entry.as_buff())?;
+    for copy in 0..device.fat_amount as u32 {
+        let copy_sector = sector + SectorId(copy * device.sectors_per_fat);
+        device
+            .clone()
+            .write_sector_offset(copy_sector, offset, &entry.as_buff())?;
+    }
     Ok(())
 }
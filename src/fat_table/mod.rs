@@ -2,7 +2,10 @@ pub(crate) use fat_entry::*;
 pub(crate) use fat_reader::*;
 pub(crate) use fat_writer::*;
 
+use snafu::ensure;
+
 use crate::cache::CachedPartition;
+use crate::error::CorruptedChainSnafu;
 use crate::formats::cluster_id::ClusterId;
 use crate::VfatRsError::CheckedMulFailed;
 use crate::{error, SectorId};
@@ -11,9 +14,24 @@ mod fat_entry;
 mod fat_reader;
 mod fat_writer;
 
+/// Fails with `CorruptedChain` if `cluster_id` is not a data cluster of the volume: ids 0 and 1
+/// are reserved, and ids past the last cluster would map outside the partition.
+pub(crate) fn check_cluster(device: &CachedPartition, cluster_id: ClusterId) -> error::Result<()> {
+    let cluster = u32::from(cluster_id);
+    ensure!(
+        (2..=device.total_clusters.saturating_add(1)).contains(&cluster),
+        CorruptedChainSnafu {
+            cluster,
+            reason: "not a data cluster of this volume",
+        }
+    );
+    Ok(())
+}
+
 /// Given a cluster_id, returns the sector id to read in order to get the FAT table entry for
 /// this cluster id.
 fn get_params(device: &CachedPartition, cluster_id: ClusterId) -> error::Result<(SectorId, usize)> {
+    check_cluster(device, cluster_id)?;
     // this should be 512 / 32 = 18
     let fat_entries_per_sector = device.sector_size / FAT_ENTRY_SIZE;
     // In which sector is this cid contained. Cid: 222 / 18 = 12.3333
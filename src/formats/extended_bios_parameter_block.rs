@@ -1,5 +1,13 @@
 use crate::const_assert_size;
+use crate::error::{
+    InvalidBootSignatureSnafu, InvalidBytesPerSectorSnafu, InvalidFatAmountSnafu,
+    InvalidRootClusterSnafu, InvalidSectorsPerClusterSnafu, InvalidVfatSnafu,
+    InvalidVolumeLayoutSnafu, NotFat32Snafu, Result,
+};
+use crate::fat_table::FAT_ENTRY_SIZE;
+use crate::{EBPF_VFAT_MAGIC, EBPF_VFAT_MAGIC_ALT};
 use binrw::BinRead;
+use snafu::ensure;
 
 // TODO: Impl debug.
 /// https://wiki.osdev.org/FAT#BPB_.28BIOS_Parameter_Block.29
@@ -13,7 +21,7 @@ pub struct BiosParameterBlock {
     /// however it does recommend the value "MSWIN4.1" as some 3rd party drivers supposedly check it and expect it to have that value.
     /// Older versions of dos also report MSDOS5.1, linux-formatted floppy will likely to carry "mkdosfs" here, and FreeDOS formatted disks have been observed to have "FRDOS5.1" here.
     /// If the string is less than 8 bytes, it is padded with spaces.
-    oem_identifier: u64,
+    pub oem_identifier: [u8; 8],
     /// Number of bytes per sector, in little-endian format
     pub bytes_per_sector: u16,
     /// Numbr of sectors per cluster:
@@ -23,8 +31,8 @@ pub struct BiosParameterBlock {
     /// Number of File Allocation Tables (FAT's) on the storage media. Often 2
     pub fat_amount: u8,
     max_num_directory_entries: u16,
-    // Total logical sectors (if zero, use total_logical_sectors_gt_u16 field instead)
-    total_logical_sectors: u16,
+    /// Total logical sectors (if zero, use total_logical_sectors_gt_u16 field instead)
+    pub total_logical_sectors: u16,
     fat_id: u8,
     /// Number of sectors per FAT. 0 for FAT32; use 32-bit value in extended bpb instead
     pub sectors_per_fat: u16,
@@ -43,14 +51,16 @@ pub struct ExtendedBiosParameterBlock {
     _fat_version: u16,
     /// Cluster pointing to the root (`/`) directory
     pub root_cluster: u32,
-    _fsinfo_sector: u16,
-    _backup_boot_sector: u16,
+    /// Sector of the FSInfo structure, relative to the partition's start. 0 or 0xFFFF if none.
+    pub fsinfo_sector: u16,
+    /// Sector of the backup boot sector, relative to the partition's start. 0 or 0xFFFF if none.
+    pub backup_boot_sector: u16,
     _reserved: [u8; 12],
     _drive_number: u8,
     _reserved2: u8,
     /// 0x28 or 0x29 for VFat / fat 32.
     pub signature: u8,
-    _volumeid_serial_number: u32,
+    pub volumeid_serial_number: u32,
     /// Padded with spaces.
     pub volume_label_string: [u8; 11],
     /// System identifier string. This field is a string representation of the FAT file system type.
@@ -59,11 +69,24 @@ pub struct ExtendedBiosParameterBlock {
     _system_identifier_string: [u8; 8],
     _boot_code: [u8; 420],
     /// https://stackoverflow.com/questions/1125025/what-is-the-role-of-magic-number-in-boot-loading-in-linux
-    _bootable_partition_signature: u16,
+    pub bootable_partition_signature: u16,
 }
 
 const_assert_size!(ExtendedBiosParameterBlock, 476);
 
+/// The last two bytes of a valid boot sector: 0x55, 0xAA.
+pub const BOOT_SIGNATURE: u16 = 0xAA55;
+/// Where the backup boot sector usually is, relative to the partition's start. It's looked
+/// for here when the primary boot sector is invalid, as its location can't be trusted then.
+pub(crate) const DEFAULT_BACKUP_BOOT_SECTOR: u32 = 6;
+/// FAT12 and FAT16 volumes have fewer clusters than this.
+pub(crate) const MIN_FAT32_CLUSTERS: u32 = 65525;
+
+/// Offset of `volumeid_serial_number` in the boot sector.
+pub(crate) const SERIAL_NUMBER_OFFSET: usize = 67;
+/// Offset of `volume_label_string` in the boot sector.
+pub(crate) const VOLUME_LABEL_OFFSET: usize = 71;
+
 #[derive(Debug, Clone, BinRead)]
 pub struct FullExtendedBIOSParameterBlock {
     pub bpb: BiosParameterBlock,
@@ -74,7 +97,104 @@ impl FullExtendedBIOSParameterBlock {
         self.extended.sectors_per_fat * self.bpb.bytes_per_sector as u32
     }
     pub fn sectors_occupied_by_all_fats(&self) -> u32 {
-        self.bpb.fat_amount as u32 * self.bpb.sectors_per_fat as u32
+        self.bpb.fat_amount as u32 * self.extended.sectors_per_fat
+    }
+    pub fn total_sectors(&self) -> u32 {
+        match self.bpb.total_logical_sectors {
+            0 => self.bpb.total_logical_sectors_gt_u16,
+            sectors => sectors as u32,
+        }
+    }
+
+    /// Sectors before the data region, relative to the partition's start: the reserved
+    /// sectors and the FATs. None if they don't fit in a sector number.
+    pub(crate) fn sectors_before_data(&self) -> Option<u32> {
+        (self.extended.sectors_per_fat as u64 * self.bpb.fat_amount as u64)
+            .checked_add(self.bpb.reserved_sectors as u64)?
+            .try_into()
+            .ok()
+    }
+
+    /// Number of data clusters. It's only meaningful for a valid BPB, see `validate`.
+    pub fn total_clusters(&self) -> u32 {
+        let data_sectors = self
+            .sectors_before_data()
+            .map_or(0, |sectors| self.total_sectors().saturating_sub(sectors));
+        data_sectors
+            .checked_div(self.bpb.sectors_per_cluster as u32)
+            .unwrap_or(0)
+    }
+
+    /// Checks the invariants the rest of the crate relies on, so that a random or corrupted
+    /// boot sector is rejected instead of being used to compute the volume layout.
+    pub fn validate(&self, device_sector_size: usize) -> Result<()> {
+        let signature = self.extended.bootable_partition_signature;
+        ensure!(
+            signature == BOOT_SIGNATURE,
+            InvalidBootSignatureSnafu { signature }
+        );
+        let bytes_per_sector = self.bpb.bytes_per_sector;
+        ensure!(
+            bytes_per_sector as usize == device_sector_size
+                && bytes_per_sector.is_power_of_two()
+                && (512..=4096).contains(&bytes_per_sector),
+            InvalidBytesPerSectorSnafu {
+                bytes_per_sector,
+                device_sector_size,
+            }
+        );
+        let sectors_per_cluster = self.bpb.sectors_per_cluster;
+        ensure!(
+            sectors_per_cluster.is_power_of_two(),
+            InvalidSectorsPerClusterSnafu {
+                sectors_per_cluster
+            }
+        );
+        let fat_amount = self.bpb.fat_amount;
+        ensure!(fat_amount > 0, InvalidFatAmountSnafu { fat_amount });
+        let target = self.extended.signature;
+        ensure!(
+            target == EBPF_VFAT_MAGIC || target == EBPF_VFAT_MAGIC_ALT,
+            InvalidVfatSnafu { target }
+        );
+
+        let layout = |reason| InvalidVolumeLayoutSnafu { reason };
+        ensure!(
+            self.bpb.reserved_sectors > 0,
+            layout("no reserved sectors, the FAT overlaps the boot sector")
+        );
+        ensure!(
+            self.extended.sectors_per_fat > 0,
+            layout("the FAT has no sectors")
+        );
+        // FAT32 only uses the 32-bit field of the extended BPB.
+        ensure!(
+            self.bpb.sectors_per_fat == 0,
+            layout("the 16-bit sectors per FAT is set on a FAT32 volume")
+        );
+        ensure!(
+            self.sectors_before_data()
+                .is_some_and(|sectors| sectors < self.total_sectors()),
+            layout("the FATs don't fit in the volume")
+        );
+        let clusters = self.total_clusters();
+        ensure!(clusters >= MIN_FAT32_CLUSTERS, NotFat32Snafu { clusters });
+        // Two reserved entries, followed by one per cluster.
+        let fat_entries =
+            self.extended.sectors_per_fat as u64 * bytes_per_sector as u64 / FAT_ENTRY_SIZE as u64;
+        ensure!(
+            fat_entries >= clusters as u64 + 2,
+            layout("the FAT is too small for the number of clusters")
+        );
+        let cluster = self.extended.root_cluster;
+        ensure!(
+            (2..=clusters + 1).contains(&cluster),
+            InvalidRootClusterSnafu {
+                cluster,
+                max: clusters + 1,
+            }
+        );
+        Ok(())
     }
 }
 
@@ -0,0 +1,59 @@
+//! The FSInfo sector: a FAT32 structure holding hints about the free clusters.
+//! https://wiki.osdev.org/FAT#FSInfo_Structure_.28FAT32_only.29
+//! Its content is just a hint: it can be missing, outdated or invalid, so it's never trusted
+//! blindly.
+
+const LEAD_SIGNATURE: u32 = 0x4161_5252;
+const STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+
+const LEAD_SIGNATURE_OFFSET: usize = 0;
+const STRUCT_SIGNATURE_OFFSET: usize = 484;
+/// Offset of the free clusters count in the FSInfo sector.
+pub(crate) const FREE_COUNT_OFFSET: usize = 488;
+const TRAIL_SIGNATURE_OFFSET: usize = 508;
+/// The free count is unknown, and must be computed.
+pub(crate) const UNKNOWN_FREE_COUNT: u32 = 0xFFFF_FFFF;
+/// The size of the FSInfo structure.
+pub(crate) const FS_INFO_SIZE: usize = 512;
+
+fn read_u32(buf: &[u8; FS_INFO_SIZE], offset: usize) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(raw)
+}
+
+/// Returns true if all the signatures are in place.
+pub(crate) fn is_valid(buf: &[u8; FS_INFO_SIZE]) -> bool {
+    read_u32(buf, LEAD_SIGNATURE_OFFSET) == LEAD_SIGNATURE
+        && read_u32(buf, STRUCT_SIGNATURE_OFFSET) == STRUCT_SIGNATURE
+        && read_u32(buf, TRAIL_SIGNATURE_OFFSET) == TRAIL_SIGNATURE
+}
+
+/// The free clusters count stored in a valid FSInfo sector, if known.
+pub(crate) fn free_count(buf: &[u8; FS_INFO_SIZE]) -> Option<u32> {
+    match read_u32(buf, FREE_COUNT_OFFSET) {
+        UNKNOWN_FREE_COUNT => None,
+        count => Some(count),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fs_info_free_count() {
+        let mut buf = [0u8; FS_INFO_SIZE];
+        assert!(!is_valid(&buf));
+        buf[LEAD_SIGNATURE_OFFSET..][..4].copy_from_slice(&LEAD_SIGNATURE.to_le_bytes());
+        buf[STRUCT_SIGNATURE_OFFSET..][..4].copy_from_slice(&STRUCT_SIGNATURE.to_le_bytes());
+        buf[TRAIL_SIGNATURE_OFFSET..][..4].copy_from_slice(&TRAIL_SIGNATURE.to_le_bytes());
+        assert!(is_valid(&buf));
+
+        buf[FREE_COUNT_OFFSET..][..4].copy_from_slice(&1234u32.to_le_bytes());
+        assert_eq!(free_count(&buf), Some(1234));
+        buf[FREE_COUNT_OFFSET..][..4].copy_from_slice(&UNKNOWN_FREE_COUNT.to_le_bytes());
+        assert_eq!(free_count(&buf), None);
+    }
+}
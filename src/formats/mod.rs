@@ -1,4 +1,5 @@
 pub mod cluster_id;
 pub mod extended_bios_parameter_block;
+pub mod fs_info;
 pub mod path;
 pub mod sector_id;
@@ -1,23 +1,136 @@
 use alloc::string::String;
-use core::iter;
+use alloc::vec::Vec;
 
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone)]
+/// A path on a vfat volume. Components are separated by `/`, and absolute paths start with `/`.
+/// The same type is used with and without the `std` feature.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Default)]
 pub struct Path(pub String);
 
+/// A component of a `Path`, see `Path::components`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Component<'a> {
+    /// The leading `/` of an absolute path.
+    RootDir,
+    /// `.`
+    CurDir,
+    /// `..`
+    ParentDir,
+    Normal(&'a str),
+}
+
+impl<'a> Component<'a> {
+    pub fn as_str(&self) -> &'a str {
+        match self {
+            Component::RootDir => "/",
+            Component::CurDir => ".",
+            Component::ParentDir => "..",
+            Component::Normal(name) => name,
+        }
+    }
+}
+
 impl Path {
     pub fn new<S: AsRef<str>>(path: S) -> Self {
         Self(String::from(path.as_ref()))
     }
-    pub fn iter(&self) -> impl Iterator<Item = &str> {
-        iter::once("/").chain(self.0[1..].split_terminator('/'))
-    }
     pub fn to_str(&self) -> &str {
         self.0.as_str()
     }
     pub fn display(&self) -> &str {
         self.to_str()
     }
+    pub fn is_absolute(&self) -> bool {
+        self.0.starts_with('/')
+    }
+
+    /// Splits the path in its components. Repeated and trailing slashes are ignored.
+    pub fn components(&self) -> impl Iterator<Item = Component<'_>> {
+        let root = self.is_absolute().then_some(Component::RootDir);
+        root.into_iter()
+            .chain(self.0.split('/').filter_map(|component| match component {
+                "" => None,
+                "." => Some(Component::CurDir),
+                ".." => Some(Component::ParentDir),
+                name => Some(Component::Normal(name)),
+            }))
+    }
+
+    /// Like `components`, but as strings (the root is `/`).
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.components().map(|component| component.as_str())
+    }
+
+    fn from_components(components: &[Component]) -> Self {
+        let mut path = String::new();
+        for component in components {
+            if !path.is_empty() && !path.ends_with('/') {
+                path.push('/');
+            }
+            path.push_str(component.as_str());
+        }
+        Self(path)
+    }
+
+    /// Appends `path` to this one. If `path` is absolute, it replaces this path.
+    pub fn join<P: AsRef<str>>(&self, path: P) -> Self {
+        let path = path.as_ref();
+        if path.starts_with('/') || self.0.is_empty() {
+            Self::new(path)
+        } else if self.0.ends_with('/') {
+            Self::new([self.0.as_str(), path].concat())
+        } else {
+            Self::new([self.0.as_str(), "/", path].concat())
+        }
+    }
+
+    /// This path without its last component, if any. The root has no parent.
+    pub fn parent(&self) -> Option<Self> {
+        let mut components: Vec<Component> = self.components().collect();
+        match components.pop() {
+            None | Some(Component::RootDir) => None,
+            Some(_) => Some(Self::from_components(&components)),
+        }
+    }
+
+    /// The last component, unless it's the root, `.` or `..`.
+    pub fn file_name(&self) -> Option<&str> {
+        match self.components().last() {
+            Some(Component::Normal(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The extension of the file name, if any. Names starting with a dot (e.g. `.bashrc`) have
+    /// no extension.
+    pub fn extension(&self) -> Option<&str> {
+        match self.file_name()?.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => Some(extension),
+            _ => None,
+        }
+    }
+
+    /// Lexically removes `.` components and repeated slashes, and resolves `..` against the
+    /// preceding component. `..` components at the beginning of a relative path are kept,
+    /// while the parent of the root is the root itself.
+    pub fn normalize(&self) -> Self {
+        let mut normalized: Vec<Component> = Vec::new();
+        for component in self.components() {
+            match (component, normalized.last()) {
+                (Component::CurDir, _) => {}
+                (Component::ParentDir, Some(Component::Normal(_))) => {
+                    normalized.pop();
+                }
+                (Component::ParentDir, Some(Component::RootDir)) => {}
+                (component, _) => normalized.push(component),
+            }
+        }
+        if normalized.is_empty() {
+            return Self::new(".");
+        }
+        Self::from_components(&normalized)
+    }
 }
+
 impl core::fmt::Display for Path {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", self.0)
@@ -33,6 +146,16 @@ impl PartialEq<&str> for &Path {
         *other == self.0.as_str()
     }
 }
+impl PartialEq<&str> for Path {
+    fn eq(&self, other: &&str) -> bool {
+        *other == self.0.as_str()
+    }
+}
+impl AsRef<str> for Path {
+    fn as_ref(&self) -> &str {
+        self.to_str()
+    }
+}
 
 impl From<&str> for Path {
     fn from(s: &str) -> Self {
@@ -41,6 +164,82 @@ impl From<&str> for Path {
 }
 impl From<String> for Path {
     fn from(s: String) -> Self {
-        Self::new(s)
+        Self(s)
+    }
+}
+#[cfg(feature = "std")]
+impl From<&std::path::Path> for Path {
+    fn from(path: &std::path::Path) -> Self {
+        Self::new(path.to_string_lossy())
+    }
+}
+#[cfg(feature = "std")]
+impl From<std::path::PathBuf> for Path {
+    fn from(path: std::path::PathBuf) -> Self {
+        path.as_path().into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Component, Path};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_components() {
+        let path = Path::from("//folder/./something/../file.txt/");
+        assert_eq!(
+            path.components().collect::<Vec<_>>(),
+            [
+                Component::RootDir,
+                Component::Normal("folder"),
+                Component::CurDir,
+                Component::Normal("something"),
+                Component::ParentDir,
+                Component::Normal("file.txt"),
+            ]
+        );
+        assert_eq!(
+            Path::from("relative/path").iter().collect::<Vec<_>>(),
+            ["relative", "path"]
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(Path::from("//a/./b/../c/").normalize(), "/a/c");
+        assert_eq!(Path::from("/../a").normalize(), "/a");
+        assert_eq!(Path::from("/a/..").normalize(), "/");
+        assert_eq!(Path::from("../a/../../b").normalize(), "../../b");
+        assert_eq!(Path::from("a/..").normalize(), ".");
+    }
+
+    #[test]
+    fn test_join_and_parent() {
+        assert_eq!(Path::from("/").join("a"), "/a");
+        assert_eq!(Path::from("/a").join("b.txt"), "/a/b.txt");
+        assert_eq!(Path::from("/a/").join("b.txt"), "/a/b.txt");
+        assert_eq!(Path::from("/a").join("/b"), "/b");
+        assert_eq!(Path::from("").join("b"), "b");
+        // References compare too, e.g. while iterating over paths.
+        assert_eq!(&Path::from("/a").join("b"), "/a/b");
+
+        assert_eq!(Path::from("/a/b").parent(), Some(Path::from("/a")));
+        assert_eq!(Path::from("/a/b/").parent(), Some(Path::from("/a")));
+        assert_eq!(Path::from("/a").parent(), Some(Path::from("/")));
+        assert_eq!(Path::from("a").parent(), Some(Path::from("")));
+        assert_eq!(Path::from("/").parent(), None);
+    }
+
+    #[test]
+    fn test_file_name_and_extension() {
+        let path = Path::from("/dir/archive.tar.gz");
+        assert_eq!(path.file_name(), Some("archive.tar.gz"));
+        assert_eq!(path.extension(), Some("gz"));
+        assert_eq!(Path::from("/dir/.bashrc").extension(), None);
+        assert_eq!(Path::from("/dir/Makefile").extension(), None);
+        assert_eq!(Path::from("/dir/").file_name(), Some("dir"));
+        assert_eq!(Path::from("/dir/..").file_name(), None);
+        assert_eq!(Path::from("/").file_name(), None);
     }
 }
@@ -20,8 +20,10 @@ mod std_io {
 }
 
 #[cfg(not(feature = "std"))]
+#[allow(clippy::module_inception)]
 mod io {
     use crate::VfatRsError;
+    use alloc::string::{String, ToString};
     use core::cmp;
     use core::fmt;
     use core::mem;
@@ -124,7 +126,7 @@ mod io {
             } else {
                 Err(Error::new(
                     ErrorKind::WriteZero,
-                    &"failed to write whole buffer",
+                    "failed to write whole buffer",
                 ))
             }
         }
@@ -170,7 +172,7 @@ mod io {
     }
 
     #[derive(Snafu)]
-    #[snafu(visibility(pub(crate)))]
+    #[snafu(visibility(pub(crate)), display("{}", repr))]
     /// The error type for I/O operations of the [`Read`], [`Write`], [`Seek`], and
     /// associated traits.
     ///
@@ -189,6 +191,19 @@ mod io {
     #[derive(Debug)]
     pub enum Repr {
         Simple(ErrorKind),
+        /// Like in std, the payload is kept: here, as a message.
+        Custom {
+            kind: ErrorKind,
+            message: String,
+        },
+    }
+    impl fmt::Display for Repr {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Repr::Simple(kind) => write!(f, "{:?}", kind),
+                Repr::Custom { message, .. } => f.write_str(message),
+            }
+        }
     }
 
     /// A list specifying general categories of I/O error.
@@ -242,9 +257,12 @@ mod io {
         /// Creates a new I/O error from a known kind of error as well as an
         /// arbitrary error payload.
         #[must_use]
-        pub fn new<A>(kind: ErrorKind, _: A) -> Self {
+        pub fn new<A: fmt::Display>(kind: ErrorKind, payload: A) -> Self {
             Self {
-                repr: Repr::Simple(kind),
+                repr: Repr::Custom {
+                    kind,
+                    message: payload.to_string(),
+                },
             }
         }
 
@@ -252,7 +270,7 @@ mod io {
         #[must_use]
         pub fn kind(&self) -> ErrorKind {
             match self.repr {
-                Repr::Simple(kind) => kind,
+                Repr::Simple(kind) | Repr::Custom { kind, .. } => kind,
             }
         }
     }
@@ -265,7 +283,7 @@ mod io {
     }
     impl From<VfatRsError> for Error {
         fn from(err: VfatRsError) -> Self {
-            Self::new(ErrorKind::Other, err)
+            Self::new(err.io_kind(), err)
         }
     }
 }
@@ -12,37 +12,50 @@ extern crate core;
 
 use alloc::sync::Arc;
 
+pub use api::directory_entry::Attributes;
 use api::directory_entry::{
-    Attributes, RegularDirectoryEntry, UnknownDirectoryEntry, VfatDirectoryEntry,
+    RegularDirectoryEntry, ShortName, UnknownDirectoryEntry, VfatDirectoryEntry,
 };
+pub use api::timestamp::VfatTimestamp;
 pub use api::EntryType;
-pub use api::{Directory, Metadata, VfatEntry, VfatMetadataTrait};
+pub use api::{
+    CreateOptions, DeletePolicy, Directory, DiskUsage, File, Glob, Metadata, OpenOptions,
+    VfatEntry, VfatMetadataTrait, Walk, WalkEntry, WalkOptions, WalkOrder,
+};
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncBlockDevice;
 pub(crate) use cache::CachedPartition;
-pub use device::BlockDevice;
+pub use check::{CheckOptions, CheckReport, Problem};
 #[cfg(feature = "std")]
 pub use device::FilebackedBlockDevice;
-pub use error::{Result, VfatRsError};
+pub use device::{BlockDevice, MemoryBlockDevice, SliceBlockDevice};
+pub use error::{errno, ErrorKind, MbrError, Result, VfatRsError};
 pub(crate) use formats::cluster_id::ClusterId;
-#[cfg(not(feature = "std"))]
-pub use formats::path::Path;
-#[cfg(feature = "std")]
-pub use std::path::PathBuf as Path;
+pub use formats::path::{Component, Path};
+pub use lock::SpinFsLock;
 
 pub use formats::sector_id::SectorId;
+pub use stats::{BootSectorCopy, FatType, MountStatus, VolumeStats};
 pub use vfat::VfatFS;
 
 mod api;
+#[cfg(feature = "async")]
+pub mod asynchronous;
 mod cache;
+mod check;
 mod cluster;
 mod device;
+pub mod diagnostics;
 /// NtfsRs error definitions
 mod error;
 mod fat_table;
 mod formats;
 pub mod io;
+mod lock;
 mod macros;
 /// A simple Master Booot Record implementation
 pub mod mbr;
+mod stats;
 mod vfat;
 
 const EBPF_VFAT_MAGIC: u8 = 0x28;
@@ -52,14 +65,15 @@ const EBPF_VFAT_MAGIC_ALT: u8 = 0x29;
 /// Vfat needs to be cloned, and potentially we could send references across threads.
 type ArcMutex<CachedPartition> = Arc<CachedPartition>;
 
-pub use traits::{TimeManagerNoop, TimeManagerTrait};
+pub use traits::{DiagnosticsTrait, FsLockTrait, TimeManagerNoop, TimeManagerTrait};
 pub mod traits {
     use crate::api::timestamp::VfatTimestamp;
+    use crate::diagnostics::Diagnostic;
     use alloc::sync::Arc;
     use core::fmt::Debug;
 
     // An interface to the OS-owned timer. Needed for timestamping file creations and update.
-    pub trait TimeManagerTrait: Debug {
+    pub trait TimeManagerTrait: Debug + Send + Sync {
         /// Get the current Unix timestamp in milliseconds.
         /// The number of seconds since January 1, 1970, 00:00:00 UTC
         fn get_current_timestamp(&self) -> u64;
@@ -130,6 +144,24 @@ pub mod traits {
         }
     }
 
+    /// An optional hook, notified about anomalies found on disk (e.g. orphaned LFN entries).
+    /// Useful for logging, or for deciding whether a consistency check is needed.
+    pub trait DiagnosticsTrait: Debug + Send + Sync {
+        fn report(&self, diagnostic: Diagnostic);
+    }
+
+    /// A reader-writer lock guarding the whole filesystem, see `VfatFS::set_lock`.
+    /// Unlike a lock in `std`, it doesn't hold any data: lock and unlock calls are always
+    /// paired by the caller. `SpinFsLock` is used by default.
+    pub trait FsLockTrait: Send + Sync {
+        /// Blocks until no exclusive lock is held, then takes a shared one.
+        fn lock_shared(&self);
+        fn unlock_shared(&self);
+        /// Blocks until no lock is held, then takes an exclusive one.
+        fn lock_exclusive(&self);
+        fn unlock_exclusive(&self);
+    }
+
     #[derive(Clone, Debug, Default)]
     pub struct TimeManagerNoop {}
     impl TimeManagerNoop {
@@ -0,0 +1,124 @@
+//! The filesystem lock, shared by every handle (files, directories, clones of `VfatFS`) of a
+//! mounted volume. Operations reading the volume (lookups, listings, file reads) hold it
+//! shared, so they run in parallel. Operations changing it (cluster allocations, directory
+//! updates, file writes) hold it exclusively, so that each of them is atomic.
+//!
+//! Every public operation takes the lock exactly once: internal helpers expect it to be held.
+use alloc::sync::Arc;
+use core::fmt;
+use core::hint;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::FsLockTrait;
+
+/// Set while a writer holds the lock, the other bits count the readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// The default `FsLockTrait` implementation: a reader-writer spin lock, usable without `std`.
+/// It's not fair: a steady flow of readers can starve writers.
+#[derive(Default)]
+pub struct SpinFsLock {
+    state: AtomicUsize,
+}
+
+impl SpinFsLock {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl fmt::Debug for SpinFsLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SpinFsLock")
+    }
+}
+
+impl FsLockTrait for SpinFsLock {
+    fn lock_shared(&self) {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0
+                && self
+                    .state
+                    .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    fn unlock_shared(&self) {
+        self.state.fetch_sub(1, Ordering::Release);
+    }
+
+    fn lock_exclusive(&self) {
+        while self
+            .state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+    }
+
+    fn unlock_exclusive(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+}
+
+/// Holds the filesystem lock until dropped.
+pub(crate) struct FsLockGuard {
+    lock: Arc<dyn FsLockTrait>,
+    exclusive: bool,
+}
+
+impl FsLockGuard {
+    pub(crate) fn shared(lock: Arc<dyn FsLockTrait>) -> Self {
+        lock.lock_shared();
+        Self {
+            lock,
+            exclusive: false,
+        }
+    }
+
+    pub(crate) fn exclusive(lock: Arc<dyn FsLockTrait>) -> Self {
+        lock.lock_exclusive();
+        Self {
+            lock,
+            exclusive: true,
+        }
+    }
+}
+
+impl Drop for FsLockGuard {
+    fn drop(&mut self) {
+        if self.exclusive {
+            self.lock.unlock_exclusive();
+        } else {
+            self.lock.unlock_shared();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SpinFsLock, WRITER};
+    use crate::FsLockTrait;
+    use core::sync::atomic::Ordering;
+
+    #[test]
+    fn test_spin_fs_lock() {
+        let lock = SpinFsLock::new();
+        lock.lock_shared();
+        lock.lock_shared();
+        assert_eq!(lock.state.load(Ordering::Relaxed), 2);
+        lock.unlock_shared();
+        lock.unlock_shared();
+        lock.lock_exclusive();
+        assert_eq!(lock.state.load(Ordering::Relaxed), WRITER);
+        lock.unlock_exclusive();
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+    }
+}
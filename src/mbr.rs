@@ -1,8 +1,7 @@
 use crate::{const_assert_size, error, BlockDevice, SectorId};
-use binrw::io::Cursor;
 use binrw::BinRead;
-use binrw::BinReaderExt;
 use log::error;
+use snafu::ensure;
 
 /// Magic indicating a valid bootsector
 pub const VALID_BOOTSECTOR_SIGN: [u8; 2] = [0x55, 0xAA];
@@ -29,30 +28,68 @@ pub struct MasterBootRecord {
 }
 const_assert_size!(MasterBootRecord, 512);
 
+/// Copies `N` bytes of `input` starting from `offset`.
+fn bytes<const N: usize>(input: &[u8], offset: usize) -> [u8; N] {
+    core::array::from_fn(|i| input[offset + i])
+}
+
+/// The sector is parsed as is: `try_load` checks the signature too.
 impl From<[u8; 512]> for MasterBootRecord {
     fn from(input: [u8; 512]) -> Self {
-        Cursor::new(&input).read_ne().unwrap()
+        const PARTITIONS_OFFSET: usize = 446;
+        let partition = |index: usize| {
+            PartitionEntry::from(bytes::<16>(&input, PARTITIONS_OFFSET + index * 16))
+        };
+        Self {
+            _mbr_bootstrap: bytes(&input, 0),
+            disk_id: bytes(&input, 436),
+            partitions: core::array::from_fn(partition),
+            valid_bootsector_sign: bytes(&input, 510),
+        }
     }
 }
 
 impl MasterBootRecord {
     /// Load a MBR from a device T.
+    ///
+    /// # Panics
+    /// If the device fails to read sector 0.
+    #[deprecated(note = "use `try_load`, which doesn't panic")]
     pub fn load<T: BlockDevice>(mut device: T) -> MasterBootRecord {
+        Self::try_load(&mut device).expect("Failed to load the MBR")
+    }
+
+    /// Loads the MBR from sector 0 of `device`. Fails if it can't be read, or if it lacks
+    /// the "valid bootsector" signature.
+    pub fn try_load<T: BlockDevice>(device: &mut T) -> error::Result<MasterBootRecord> {
         let mut buff = [0; 512];
-        device.read_sector(SectorId(0), &mut buff).unwrap();
-        MasterBootRecord::from(buff)
+        device.read_sector(SectorId(0), &mut buff)?;
+        let mbr = MasterBootRecord::from(buff);
+        ensure!(
+            mbr.valid_bootsector_sign == VALID_BOOTSECTOR_SIGN,
+            error::MbrSnafu {
+                error: error::MbrError::InvalidSignature {
+                    signature: mbr.valid_bootsector_sign,
+                },
+            }
+        );
+        Ok(mbr)
     }
+
     /// Returns OK if index is a vfat partition.
     pub fn get_vfat_partition(&self, index: usize) -> error::Result<&PartitionEntry> {
-        let partition = &self.partitions[index];
+        let invalid = error::VfatRsError::Mbr {
+            error: error::MbrError::InvalidPartition { index },
+        };
+        let Some(partition) = self.partitions.get(index) else {
+            return Err(invalid);
+        };
         if !FAT32_PARTITION_ID.contains(&partition.partition_type) {
             error!(
                 "Requested partition index: {}, but partition's type is :{}",
                 index, partition.partition_type
             );
-            return Err(error::VfatRsError::Mbr {
-                error: error::MbrError::InvalidPartition { index },
-            });
+            return Err(invalid);
         }
         Ok(partition)
     }
@@ -77,3 +114,20 @@ pub struct PartitionEntry {
     pub start_sector: u32,
     _total_sectors: u32,
 }
+
+impl From<[u8; 16]> for PartitionEntry {
+    fn from(input: [u8; 16]) -> Self {
+        Self {
+            bootable_indicator_flag: input[0],
+            _starting_header: input[1],
+            _starting_sector: input[2],
+            _starting_cylinder: input[3],
+            partition_type: input[4],
+            _ending_header: input[5],
+            _ending_sector: input[6],
+            _ending_cylinder: input[7],
+            start_sector: u32::from_le_bytes(bytes(&input, 8)),
+            _total_sectors: u32::from_le_bytes(bytes(&input, 12)),
+        }
+    }
+}
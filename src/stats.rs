@@ -0,0 +1,70 @@
+use alloc::string::String;
+
+/// The FAT variant of a volume.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FatType {
+    Fat32,
+}
+
+/// The state of a volume when it was mounted, as recorded in FAT[1]. See `VfatFS::mount_status`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MountStatus {
+    /// The volume was not unmounted cleanly (e.g. after a crash or a power loss).
+    pub was_dirty: bool,
+    /// A driver hit disk I/O errors on this volume.
+    pub hard_errors: bool,
+}
+
+impl MountStatus {
+    /// True if the volume should be checked (see `VfatFS::check`) before use.
+    pub fn needs_check(&self) -> bool {
+        self.was_dirty || self.hard_errors
+    }
+}
+
+/// Which copy of a boot structure (the boot sector, or FSInfo) was found valid at mount.
+/// See `VfatFS::boot_sector_copy`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BootSectorCopy {
+    #[default]
+    Primary,
+    /// The primary copy is invalid, its backup is used instead.
+    Backup,
+}
+
+/// Information about a mounted volume, akin to the output of `statfs`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VolumeStats {
+    pub fat_type: FatType,
+    /// OEM name from the boot sector, trailing spaces removed.
+    pub oem_name: String,
+    pub label: String,
+    pub serial: u32,
+    /// Bytes per sector.
+    pub sector_size: usize,
+    /// Bytes per cluster.
+    pub cluster_size: usize,
+    /// Number of file allocation tables.
+    pub fat_amount: u8,
+    /// Sectors before the first FAT, including the boot sector.
+    pub reserved_sectors: u16,
+    /// Number of data clusters.
+    pub total_clusters: u32,
+    pub free_clusters: u32,
+}
+
+impl VolumeStats {
+    pub fn used_clusters(&self) -> u32 {
+        self.total_clusters - self.free_clusters
+    }
+    pub fn total_bytes(&self) -> u64 {
+        self.total_clusters as u64 * self.cluster_size as u64
+    }
+    pub fn free_bytes(&self) -> u64 {
+        self.free_clusters as u64 * self.cluster_size as u64
+    }
+    pub fn used_bytes(&self) -> u64 {
+        self.used_clusters() as u64 * self.cluster_size as u64
+    }
+}
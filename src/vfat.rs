@@ -1,26 +1,49 @@
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::{fmt, mem};
 
 use binrw::io::Cursor;
 use binrw::BinReaderExt;
-use log::{debug, info};
+use log::{debug, info, warn};
+use snafu::ensure;
 
+use crate::api::open_file::OpenFiles;
+use crate::api::walk;
+use crate::cache::FreeCount;
+use crate::check::Checker;
 use crate::cluster::{cluster_reader, cluster_writer};
-use crate::fat_table::FatEntry;
+use crate::diagnostics::Diagnostic;
+use crate::error::{
+    ClusterInUseSnafu, ErrorKind, InvalidClusterSnafu, InvalidLabelSnafu, LogFailure,
+    ReadOnlyFilesystemSnafu,
+};
 use crate::fat_table::FAT_ENTRY_SIZE;
-use crate::formats::extended_bios_parameter_block::FullExtendedBIOSParameterBlock;
+use crate::fat_table::{ChainCursor, FatEntry};
+use crate::formats::extended_bios_parameter_block::{
+    FullExtendedBIOSParameterBlock, DEFAULT_BACKUP_BOOT_SECTOR, SERIAL_NUMBER_OFFSET,
+    VOLUME_LABEL_OFFSET,
+};
+use crate::formats::fs_info;
+use crate::lock::FsLockGuard;
 use crate::Result;
 use crate::{
     fat_table, ArcMutex, Attributes, BlockDevice, CachedPartition, ClusterId, Directory, Metadata,
-    RegularDirectoryEntry, SectorId, UnknownDirectoryEntry, VfatDirectoryEntry, VfatEntry,
-    VfatRsError, EBPF_VFAT_MAGIC, EBPF_VFAT_MAGIC_ALT,
+    RegularDirectoryEntry, SectorId, ShortName, UnknownDirectoryEntry, VfatDirectoryEntry,
+    VfatEntry, VfatRsError,
+};
+use crate::{
+    BootSectorCopy, CheckOptions, CheckReport, DeletePolicy, DiskUsage, FatType, Glob, MountStatus,
+    VolumeStats, Walk, WalkOptions,
 };
-use crate::{Path, TimeManagerTrait};
+use crate::{Component, DiagnosticsTrait, FsLockTrait, Path, SpinFsLock, TimeManagerTrait};
 
+/// A mounted vfat volume. It's cheap to clone, and clones (as well as the files and directories
+/// obtained from them) can be sent to other threads: they share the same device and lock.
 #[derive(Clone)]
 pub struct VfatFS {
-    // we need arc around device, because _maybe_ something might need to `Send` this device or Vfat
-    // to a different thread.
     pub(crate) device: ArcMutex<CachedPartition>,
     /// Sector of the file allocation table
     pub(crate) fat_start_sector: SectorId,
@@ -28,10 +51,30 @@ pub struct VfatFS {
     pub(crate) sectors_per_fat: u32,
     /// Id for the root_cluster
     pub(crate) root_cluster: ClusterId,
-    /// End of chain marker
-    pub(crate) eoc_marker: FatEntry,
+    /// The state of the volume found at mount.
+    pub(crate) mount_status: MountStatus,
+    /// First sector of the partition, holding the boot sector.
+    pub(crate) partition_start_sector: SectorId,
+    /// Backup of the boot sector, if any.
+    pub(crate) backup_boot_sector: Option<SectorId>,
+    /// The boot sector in use: the primary one, or its backup if the primary is invalid.
+    pub(crate) boot_sector_copy: BootSectorCopy,
+    /// The FSInfo sector in use, if any.
+    pub(crate) fsinfo_sector: Option<SectorId>,
+    pub(crate) fs_info_copy: BootSectorCopy,
+    /// Number of data clusters, hence the highest valid cluster id is `total_clusters + 1`.
+    pub(crate) total_clusters: u32,
     // heap allocated to mostly to ease api
     pub(crate) time_manager: Arc<dyn TimeManagerTrait>,
+    /// Notified about anomalies found on disk, if set.
+    pub(crate) diagnostics: Option<Arc<dyn DiagnosticsTrait>>,
+    /// If set, directories are shrunk after deleting an entry from them.
+    pub(crate) auto_shrink: bool,
+    /// Shared by all the handles to this volume, see `crate::lock`.
+    pub(crate) lock: Arc<dyn FsLockTrait>,
+    /// Nodes shared by the handles to the same file.
+    pub(crate) open_files: Arc<OpenFiles>,
+    pub(crate) delete_policy: DeletePolicy,
 }
 
 impl fmt::Debug for VfatFS {
@@ -42,7 +85,7 @@ impl fmt::Debug for VfatFS {
 
 impl VfatFS {
     #[cfg(not(feature = "std"))]
-    pub fn new<B: BlockDevice + 'static>(
+    pub fn new<B: BlockDevice + Send + 'static>(
         device: B,
         // time_manager: T,
         partition_start_sector: u32,
@@ -53,19 +96,73 @@ impl VfatFS {
 
     #[cfg(feature = "std")]
     // chronos will be used as a time manager.
-    pub fn new<B: BlockDevice + 'static>(device: B, partition_start_sector: u32) -> Result<Self> {
+    pub fn new<B: BlockDevice + Send + 'static>(
+        device: B,
+        partition_start_sector: u32,
+    ) -> Result<Self> {
         let chronos_tm = crate::traits::TimeManagerChronos::new();
         Self::new_tm(device, partition_start_sector, chronos_tm)
     }
 
-    pub fn new_tm<B: BlockDevice + 'static>(
-        mut device: B,
+    pub fn new_tm<B: BlockDevice + Send + 'static>(
+        device: B,
         partition_start_sector: u32,
         time_manager: impl TimeManagerTrait + 'static,
     ) -> Result<Self> {
-        let time_manager = Arc::new(time_manager);
-        let full_ebpb = Self::read_fullebpb(&mut device, partition_start_sector)?;
-        Self::new_with_ebpb(device, partition_start_sector, full_ebpb, time_manager)
+        Self::mount(
+            device,
+            partition_start_sector,
+            Arc::new(time_manager),
+            false,
+        )
+    }
+
+    /// Mounts the volume read-only: every operation changing it fails with `ReadOnlyFilesystem`,
+    /// and nothing is written to the device, e.g. a `FilebackedBlockDevice` can use a file
+    /// opened without write access. Every handle to this volume is read-only.
+    pub fn new_read_only<B: BlockDevice + Send + 'static>(
+        device: B,
+        partition_start_sector: u32,
+    ) -> Result<Self> {
+        let no_op = crate::traits::TimeManagerNoop::new_arc();
+        Self::mount(device, partition_start_sector, no_op, true)
+    }
+
+    fn mount<B: BlockDevice + Send + 'static>(
+        mut device: B,
+        partition_start_sector: u32,
+        time_manager: Arc<dyn TimeManagerTrait>,
+        read_only: bool,
+    ) -> Result<Self> {
+        let (full_ebpb, boot_sector_copy) =
+            match Self::read_valid_ebpb(&mut device, partition_start_sector) {
+                Ok(full_ebpb) => (full_ebpb, BootSectorCopy::Primary),
+                Err(err) => {
+                    warn!("Invalid boot sector: {}. Trying its backup.", err);
+                    let backup_sector = partition_start_sector + DEFAULT_BACKUP_BOOT_SECTOR;
+                    match Self::read_valid_ebpb(&mut device, backup_sector) {
+                        Ok(full_ebpb) => (full_ebpb, BootSectorCopy::Backup),
+                        Err(_) => return Err(err),
+                    }
+                }
+            };
+        Self::new_with_ebpb(
+            device,
+            partition_start_sector,
+            full_ebpb,
+            boot_sector_copy,
+            time_manager,
+            read_only,
+        )
+    }
+
+    fn read_valid_ebpb<B: BlockDevice + 'static>(
+        device: &mut B,
+        sector: u32,
+    ) -> Result<FullExtendedBIOSParameterBlock> {
+        let full_ebpb = Self::read_fullebpb(device, sector)?;
+        full_ebpb.validate(device.sector_size())?;
+        Ok(full_ebpb)
     }
 
     pub fn read_fullebpb<B: BlockDevice + 'static>(
@@ -78,64 +175,176 @@ impl VfatFS {
     }
 
     /// start_sector: Partition's start sector, or "Entry Offset Sector".
-    fn new_with_ebpb<B: BlockDevice + 'static>(
+    fn new_with_ebpb<B: BlockDevice + Send + 'static>(
         mut device: B,
         partition_start_sector: u32,
         full_ebpb: FullExtendedBIOSParameterBlock,
+        boot_sector_copy: BootSectorCopy,
         time_manager: Arc<dyn TimeManagerTrait>,
+        read_only: bool,
     ) -> Result<Self> {
         let fat_start_sector =
             (partition_start_sector + full_ebpb.bpb.reserved_sectors as u32).into();
-        let fats_total_size = full_ebpb.extended.sectors_per_fat * full_ebpb.bpb.fat_amount as u32;
-        let data_start_sector =
-            fat_start_sector + fats_total_size as u32 + full_ebpb.sectors_occupied_by_all_fats();
-        let data_start_sector = SectorId(data_start_sector);
+        // Checked by `validate`.
+        let sectors_before_data =
+            full_ebpb
+                .sectors_before_data()
+                .ok_or(VfatRsError::InvalidVolumeLayout {
+                    reason: "the FATs don't fit in the volume",
+                })?;
+        let data_start_sector = SectorId(partition_start_sector + sectors_before_data);
 
         let sectors_per_cluster = full_ebpb.bpb.sectors_per_cluster as u32;
         let root_cluster = ClusterId::new(full_ebpb.extended.root_cluster);
-        let eoc_marker = Self::read_end_of_chain_marker(&mut device, fat_start_sector)?;
+        let mount_status = Self::read_mount_status(&mut device, fat_start_sector)?;
+        // Both are in the reserved sectors, if present.
+        let relative_sector = |sector| match sector {
+            0 | 0xFFFF => None,
+            sector if sector >= full_ebpb.bpb.reserved_sectors => None,
+            sector => Some(partition_start_sector + sector as u32),
+        };
+        let backup_boot_sector = relative_sector(full_ebpb.extended.backup_boot_sector);
+        let (fsinfo_sector, fs_info_copy) = match relative_sector(full_ebpb.extended.fsinfo_sector)
+        {
+            Some(sector) => {
+                let backup = backup_boot_sector
+                    .map(|backup| backup - partition_start_sector + sector)
+                    .filter(|backup| *backup < fat_start_sector.0);
+                let (sector, copy) = Self::find_fs_info(&mut device, sector, backup);
+                (Some(SectorId(sector)), copy)
+            }
+            None => (None, BootSectorCopy::Primary),
+        };
         let sector_size = device.sector_size();
-        let cached_partition = CachedPartition::new(
+        let total_clusters = full_ebpb.total_clusters();
+        let sectors_per_fat = full_ebpb.extended.sectors_per_fat;
+        let mut cached_partition = CachedPartition::new(
             device,
             sector_size,
             fat_start_sector,
+            sectors_per_fat,
+            full_ebpb.bpb.fat_amount,
             sectors_per_cluster,
             data_start_sector,
+            total_clusters,
         );
-        let sectors_per_fat = full_ebpb.extended.sectors_per_fat;
-        if full_ebpb.extended.signature != EBPF_VFAT_MAGIC
-            && full_ebpb.extended.signature != EBPF_VFAT_MAGIC_ALT
-        {
-            return Err(VfatRsError::InvalidVfat {
-                target: full_ebpb.extended.signature,
-            });
-        }
-        Ok(VfatFS {
-            device: Arc::new(cached_partition),
+        cached_partition.set_mount_status(mount_status);
+        cached_partition.set_read_only(read_only);
+        let device = Arc::new(cached_partition);
+        let vfat = VfatFS {
+            device,
             fat_start_sector,
             root_cluster,
-            eoc_marker,
+            mount_status,
+            partition_start_sector: SectorId(partition_start_sector),
+            backup_boot_sector: backup_boot_sector.map(SectorId),
+            boot_sector_copy,
+            fsinfo_sector,
+            fs_info_copy,
+            total_clusters,
             sectors_per_fat,
             time_manager,
-        })
+            diagnostics: None,
+            auto_shrink: false,
+            lock: Arc::new(SpinFsLock::new()),
+            open_files: Arc::new(OpenFiles::default()),
+            delete_policy: DeletePolicy::default(),
+        };
+        vfat.device.set_free_count(FreeCount {
+            count: vfat
+                .read_fs_info()?
+                .and_then(|buf| fs_info::free_count(&buf)),
+            dirty: false,
+        });
+        Ok(vfat)
+    }
+
+    /// Sets a hook to be notified about anomalies found on disk. Handles cloned from this
+    /// filesystem afterwards (e.g. files and directories) share the same hook.
+    pub fn set_diagnostics(&mut self, diagnostics: impl DiagnosticsTrait + 'static) {
+        self.diagnostics = Some(Arc::new(diagnostics));
+    }
+
+    /// When enabled, deleting an entry from a directory also shrinks the directory, if doing so
+    /// frees at least one cluster. See `Directory::shrink`. Disabled by default.
+    pub fn set_auto_shrink(&mut self, enabled: bool) {
+        self.auto_shrink = enabled;
+    }
+
+    /// Sets what happens when deleting a file which is still open. See `DeletePolicy`.
+    pub fn set_delete_policy(&mut self, policy: DeletePolicy) {
+        self.delete_policy = policy;
+    }
+
+    /// True if the volume was mounted with `new_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    /// Replaces the filesystem lock (a `SpinFsLock` by default), e.g. with one that puts the
+    /// thread to sleep. Handles are only synchronized if they share the same lock: call this
+    /// right after mounting, before cloning the filesystem or opening any entry.
+    pub fn set_lock(&mut self, lock: impl FsLockTrait + 'static) {
+        self.lock = Arc::new(lock);
     }
 
-    fn read_end_of_chain_marker<B>(device: &mut B, fat_start_sector: SectorId) -> Result<FatEntry>
+    /// Takes the filesystem lock for an operation only reading the volume.
+    pub(crate) fn read_lock(&self) -> FsLockGuard {
+        FsLockGuard::shared(self.lock.clone())
+    }
+
+    /// Takes the filesystem lock for an operation changing the volume. Fails if the volume
+    /// is mounted read-only.
+    pub(crate) fn write_lock(&self) -> Result<FsLockGuard> {
+        ensure!(!self.is_read_only(), ReadOnlyFilesystemSnafu);
+        Ok(FsLockGuard::exclusive(self.lock.clone()))
+    }
+
+    pub(crate) fn report(&self, diagnostic: Diagnostic) {
+        warn!("Found anomaly: {:?}", diagnostic);
+        if let Some(diagnostics) = &self.diagnostics {
+            diagnostics.report(diagnostic);
+        }
+    }
+
+    /// Picks the FSInfo sector to use: its copy following the backup boot sector, if only the
+    /// copy is valid.
+    fn find_fs_info<B: BlockDevice>(
+        device: &mut B,
+        primary: u32,
+        backup: Option<u32>,
+    ) -> (u32, BootSectorCopy) {
+        let mut is_valid = |sector: u32| {
+            let mut buf = [0; fs_info::FS_INFO_SIZE];
+            device.read_sector(sector.into(), &mut buf).is_ok() && fs_info::is_valid(&buf)
+        };
+        match backup {
+            Some(backup) if !is_valid(primary) && is_valid(backup) => {
+                warn!("Invalid FSInfo sector, using its backup.");
+                (backup, BootSectorCopy::Backup)
+            }
+            _ => (primary, BootSectorCopy::Primary),
+        }
+    }
+
+    /// Reads the state of the volume from FAT[1].
+    fn read_mount_status<B>(device: &mut B, fat_start_sector: SectorId) -> Result<MountStatus>
     where
         B: BlockDevice,
     {
-        const ENTRIES_BUF_SIZE: usize = 1;
-        const BUF_SIZE: usize = FAT_ENTRY_SIZE * ENTRIES_BUF_SIZE;
-        let mut buf = [0; BUF_SIZE];
-        device.read_sector(fat_start_sector, &mut buf).unwrap();
-        let raw_entry = FatEntry::from(buf);
-        info!("End of chain marker: {:?}", raw_entry);
-        Ok(raw_entry)
+        let mut buf = [0; FAT_ENTRY_SIZE];
+        device.read_sector_offset(fat_start_sector, FAT_ENTRY_SIZE, &mut buf)?;
+        let flags = u32::from_le_bytes(buf);
+        let mount_status = MountStatus {
+            was_dirty: flags & fat_table::CLEAN_SHUTDOWN_BIT == 0,
+            hard_errors: flags & fat_table::NO_HARD_ERRORS_BIT == 0,
+        };
+        info!("Mount status: {:?}", mount_status);
+        Ok(mount_status)
     }
 
-    fn new_last_cluster_fat_entry(&self) -> FatEntry {
-        // Last cluster is initialized with the eoc_marker
-        FatEntry::LastCluster(self.eoc_marker.into())
+    pub(crate) fn new_last_cluster_fat_entry(&self) -> FatEntry {
+        FatEntry::LastCluster(fat_table::END_OF_CHAIN)
     }
 
     /// Find next free cluster
@@ -149,8 +358,7 @@ impl VfatFS {
             let mut buf = [0; BUF_SIZE];
             info!("reading sector: {}/{}", i, self.sectors_per_fat);
             self.device
-                .read_sector(SectorId(self.fat_start_sector + i), &mut buf)
-                .unwrap();
+                .read_sector(SectorId(self.fat_start_sector + i), &mut buf)?;
             let mut fat_entries = [FatEntry::default(); ENTRIES_BUF_SIZE];
 
             for (i, bytes) in buf.chunks(4).enumerate() {
@@ -159,6 +367,14 @@ impl VfatFS {
 
             for (id, fat_entry) in fat_entries.into_iter().enumerate() {
                 let cid = (ENTRIES_BUF_SIZE as u32 * i) + id as u32;
+                // The first two entries are reserved, they don't map to data clusters.
+                if cid < 2 {
+                    continue;
+                }
+                // The FAT can be longer than needed: the last entries don't map to clusters.
+                if cid > self.total_clusters + 1 {
+                    return Ok(None);
+                }
                 debug!("(cid: {:?}) Fat entry: {:?}", fat_entry, cid);
                 if let FatEntry::Unused = fat_entry {
                     debug!("Found an unused cluster with id: {}", cid);
@@ -170,7 +386,8 @@ impl VfatFS {
     }
 
     /// Allocate a cluster for a new file.
-    /// First find an empty cluster. Then set this cluster id as LastCluster
+    /// First find an empty cluster. Then set this cluster id as LastCluster.
+    /// The caller holds the write lock, so no one else can pick the same cluster.
     pub(crate) fn allocate_cluster_new_entry(&self) -> Result<ClusterId> {
         let free_cluster_id = self
             .find_free_cluster()?
@@ -178,12 +395,14 @@ impl VfatFS {
         let entry = self.new_last_cluster_fat_entry();
         info!("Found free cluster: {}", free_cluster_id);
         self.write_entry_in_vfat_table(free_cluster_id, entry)?;
+        self.device.update_free_count(-1);
         Ok(free_cluster_id)
     }
 
     /// Finds a free clusters and updates the chain:
     ///  * previous cluster in the chain to point to the newly allocated one,
-    /// * new clusterId added as final entry
+    ///  * new clusterId added as final entry
+    ///
     /// TODO: invert writes, first update head, and then allocate the cluster.
     pub(crate) fn allocate_cluster_to_chain(&self, head: ClusterId) -> Result<ClusterId> {
         info!("Allocating cluster to chain: {}", head);
@@ -198,20 +417,58 @@ impl VfatFS {
         info!("Updated the entry");
         Ok(free_cluster_id)
     }
-    fn write_entry_in_vfat_table(&self, cluster_id: ClusterId, entry: FatEntry) -> Result<()> {
+    pub(crate) fn write_entry_in_vfat_table(
+        &self,
+        cluster_id: ClusterId,
+        entry: FatEntry,
+    ) -> Result<()> {
         fat_table::set_fat_entry(self.device.clone(), cluster_id, entry)
     }
 
-    fn get_last_cluster_in_chain(&self, starting: ClusterId) -> Result<ClusterId> {
-        info!("Getting last cluster in the chain..");
-        let mut last = starting;
-        loop {
-            match fat_table::next_cluster(last, self.device.clone())? {
-                Some(cluster_id) => last = cluster_id,
-                None => return Ok(last),
+    /// Loads the first FAT, up to the entry of the last data cluster.
+    pub(crate) fn read_fat(&self) -> Result<Vec<FatEntry>> {
+        let sector_size = self.device.sector_size;
+        // Entries 0 and 1 are reserved, data clusters start from 2.
+        let entries = self.total_clusters as usize + 2;
+        let sectors = (entries * FAT_ENTRY_SIZE).div_ceil(sector_size);
+        let mut raw = vec![0; sectors * sector_size];
+        for (sector, buf) in raw.chunks_mut(sector_size).enumerate() {
+            self.device
+                .read_sector(self.fat_start_sector + SectorId(sector as u32), buf)?;
+        }
+        Ok(raw
+            .chunks(FAT_ENTRY_SIZE)
+            .take(entries)
+            .map(FatEntry::new_ref)
+            .collect())
+    }
+
+    /// Marks a free cluster as bad, so that it's never allocated. Clusters already marked bad
+    /// are left untouched.
+    pub(crate) fn mark_bad_inner(&self, cluster: ClusterId) -> Result<()> {
+        let max = self.total_clusters + 1;
+        ensure!(
+            fat_table::check_cluster(&self.device, cluster).is_ok(),
+            InvalidClusterSnafu { cluster, max }
+        );
+        match fat_table::read_fat_entry(cluster, self.device.clone())? {
+            FatEntry::Bad => Ok(()),
+            FatEntry::Unused => {
+                warn!("Marking cluster {} as bad.", cluster);
+                self.write_entry_in_vfat_table(cluster, FatEntry::Bad)?;
+                self.device.update_free_count(-1);
+                Ok(())
             }
+            _ => ClusterInUseSnafu { cluster }.fail(),
         }
     }
+
+    fn get_last_cluster_in_chain(&self, starting: ClusterId) -> Result<ClusterId> {
+        info!("Getting last cluster in the chain..");
+        let mut chain = ChainCursor::new(starting);
+        while chain.advance(&self.device)?.is_some() {}
+        Ok(chain.current())
+    }
     pub(crate) fn cluster_chain_writer(
         &self,
         cluster_id: ClusterId,
@@ -228,54 +485,320 @@ impl VfatFS {
 
     /// This will delete all the cluster chain starting from cluster_id.
     pub(crate) fn delete_fat_cluster_chain(&self, cluster_id: ClusterId) -> Result<()> {
-        fat_table::delete_cluster_chain(cluster_id, self.device.clone())
+        let freed = fat_table::delete_cluster_chain(cluster_id, self.device.clone())?;
+        self.device.update_free_count(freed as i64);
+        Ok(())
     }
 
-    /// p should start with `/`.
-    /// Test with a path to a file, test with a path to root.
-    pub fn get_path(&mut self, path: Path) -> Result<VfatEntry> {
-        info!("FS: requested path: {:?}", path);
-        if path == Path::from("/") {
-            return self.get_root().map(From::from);
+    /// Keeps the first `keep` clusters of the chain starting from `head`, and frees the rest.
+    /// Returns how many clusters were freed.
+    pub(crate) fn truncate_cluster_chain(&self, head: ClusterId, keep: usize) -> Result<usize> {
+        let freed = fat_table::truncate_cluster_chain(
+            head,
+            keep,
+            self.new_last_cluster_fat_entry(),
+            self.device.clone(),
+        )?;
+        self.device.update_free_count(freed as i64);
+        Ok(freed)
+    }
+
+    /// Reads the FSInfo sector, if present and valid.
+    pub(crate) fn read_fs_info(&self) -> Result<Option<[u8; fs_info::FS_INFO_SIZE]>> {
+        let Some(sector) = self.fsinfo_sector else {
+            return Ok(None);
+        };
+        let mut buf = [0; fs_info::FS_INFO_SIZE];
+        self.device.read_sector(sector, &mut buf)?;
+        Ok(fs_info::is_valid(&buf).then_some(buf))
+    }
+
+    /// The free clusters count loaded from FSInfo and kept up to date since, if it's known
+    /// and plausible.
+    fn known_free_count(&self) -> Option<u32> {
+        self.device
+            .free_count()
+            .count
+            .filter(|count| *count <= self.total_clusters)
+    }
+
+    pub(crate) fn write_fs_info_free_count(&self, count: u32) -> Result<()> {
+        if let Some(sector) = self.fsinfo_sector {
+            self.device.clone().write_sector_offset(
+                sector,
+                fs_info::FREE_COUNT_OFFSET,
+                &count.to_le_bytes(),
+            )?;
         }
-        let mut path_iter = path.iter();
-        let mut current_entry = VfatEntry::from(self.get_root()?);
-        path_iter.next();
-        for sub_path in path_iter {
-            info!("Visiting path: {:?}", sub_path);
-            let directory = current_entry.into_directory_or_not_found()?;
-            let directory_iter = directory.iter()?;
-            let matches: Option<VfatEntry> = directory_iter
-                .filter(|entry| {
-                    info!(
-                        "Entry name: {:?}, looking for sub_path: {:?}",
-                        entry.metadata().name(),
-                        sub_path
-                    );
-                    entry.metadata().name() == sub_path
-                })
-                .last();
-            current_entry = matches.ok_or_else(|| {
-                info!("Matches for {:?} is empty: path not found!", sub_path);
-                VfatRsError::EntryNotFound {
-                    #[cfg(feature = "std")]
-                    target: sub_path.to_str().unwrap().into(),
-                    #[cfg(not(feature = "std"))]
-                    target: sub_path.into(),
+        self.device.set_free_count(FreeCount {
+            count: Some(count),
+            dirty: false,
+        });
+        Ok(())
+    }
+
+    /// Counts the free clusters by scanning the whole FAT.
+    pub(crate) fn count_free_clusters(&self) -> Result<u32> {
+        let sector_size = self.device.sector_size;
+        let entries_per_sector = (sector_size / FAT_ENTRY_SIZE) as u32;
+        // Entries 0 and 1 are reserved, data clusters start from 2.
+        let entries = self.total_clusters + 2;
+        let mut buf = vec![0; sector_size];
+        let mut free = 0;
+        for sector in 0..entries.div_ceil(entries_per_sector) {
+            self.device
+                .read_sector(self.fat_start_sector + SectorId(sector), &mut buf)?;
+            let first_cid = sector * entries_per_sector;
+            free += buf
+                .chunks(FAT_ENTRY_SIZE)
+                .zip(first_cid..entries)
+                .filter(|(raw, cid)| *cid >= 2 && FatEntry::new_ref(raw) == FatEntry::Unused)
+                .count() as u32;
+        }
+        Ok(free)
+    }
+
+    pub(crate) fn cluster_chain_len(&self, head: ClusterId) -> Result<usize> {
+        let mut len = 1;
+        let mut chain = ChainCursor::new(head);
+        while chain.advance(&self.device)?.is_some() {
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    pub(crate) fn cluster_size(&self) -> usize {
+        self.device.sectors_per_cluster as usize * self.device.sector_size
+    }
+
+    /// Fills a cluster with zeros. Needed for directories: a zeroed cluster is full of
+    /// EndOfEntries markers, while a reused one might contain stale entries.
+    pub(crate) fn zero_cluster(&self, cluster_id: ClusterId) -> Result<()> {
+        let zeros = vec![0; self.cluster_size()];
+        let first_sector = self.device.cluster_to_sector(cluster_id);
+        self.device.write_sectors(
+            first_sector,
+            self.device.sectors_per_cluster as usize,
+            &zeros,
+        )?;
+        Ok(())
+    }
+
+    /// Returns the entry at `path`. Relative paths are resolved from the root directory.
+    /// `.` and repeated slashes are ignored, while `..` is resolved by following the on-disk
+    /// `..` entries.
+    pub fn get_path(&self, path: Path) -> Result<VfatEntry> {
+        info!("FS: requested path: {:?}", path);
+        let _guard = self.read_lock();
+        let root = self.root_directory()?;
+        self.resolve(root, &path)
+            .log_failure("look up", path.display())
+    }
+
+    /// Resolves `path` starting from `start`.
+    pub(crate) fn resolve(&self, start: Directory, path: &Path) -> Result<VfatEntry> {
+        let mut current_entry = VfatEntry::from(start);
+        for component in path.components() {
+            info!("Visiting path: {:?}", component);
+            current_entry = match component {
+                Component::RootDir => self.root_directory()?.into(),
+                Component::CurDir => current_entry.try_into_directory()?.into(),
+                Component::ParentDir => {
+                    let directory = current_entry.try_into_directory()?;
+                    self.parent_directory(&directory)?.into()
+                }
+                Component::Normal(sub_path) => {
+                    let directory = current_entry.try_into_directory()?;
+                    let matches: Option<VfatEntry> = directory
+                        .contents_inner()?
+                        .into_iter()
+                        .filter(|entry| {
+                            info!(
+                                "Entry name: {:?}, looking for sub_path: {:?}",
+                                entry.metadata().name(),
+                                sub_path
+                            );
+                            entry.metadata().matches_name(sub_path)
+                        })
+                        .last();
+                    matches.ok_or_else(|| {
+                        info!("Matches for {:?} is empty: path not found!", sub_path);
+                        VfatRsError::EntryNotFound {
+                            target: sub_path.into(),
+                        }
+                    })?
                 }
-            })?;
+            };
         }
         Ok(current_entry)
     }
 
-    pub fn path_exists(&mut self, path: Path) -> Result<bool> {
-        let entry = self.get_path(path).map(|_| true);
-        match entry {
-            Err(error) if matches!(error, VfatRsError::EntryNotFound { .. }) => Ok(false),
-            x => x,
+    /// Returns the parent of `directory`. The parent's cluster is read from the `..` entry, up
+    /// to the root. The chain is then followed downward from the root to build the parent's
+    /// entry, so that its name and path don't depend on how `directory` was reached.
+    /// The parent of the root is the root itself.
+    fn parent_directory(&self, directory: &Directory) -> Result<Directory> {
+        let broken_link = || VfatRsError::BrokenParentLink {
+            target: directory.metadata.path().display().to_string(),
+        };
+        // Ancestors' clusters, from the parent up to the root (excluded).
+        let mut ancestors = Vec::new();
+        let mut current = directory.metadata.cluster;
+        while current != self.root_cluster {
+            current = self.parent_cluster(current)?;
+            if ancestors.contains(&current) || current == directory.metadata.cluster {
+                return Err(broken_link());
+            }
+            ancestors.push(current);
+        }
+        ancestors.pop();
+
+        let mut parent = self.root_directory()?;
+        for cluster in ancestors.into_iter().rev() {
+            parent = parent
+                .contents_inner()?
+                .into_iter()
+                .find(|entry| {
+                    entry.is_dir()
+                        && entry.metadata.cluster == cluster
+                        && entry.metadata.name() != "."
+                        && entry.metadata.name() != ".."
+                })
+                .ok_or_else(broken_link)?
+                .into_directory_unchecked();
         }
+        Ok(parent)
+    }
+
+    /// Reads the cluster of the parent of the directory starting at `cluster`, from its
+    /// `..` entry. It should be the second entry, but some implementations put LFN entries
+    /// before the pseudo directories: the whole first sector is looked at.
+    pub(crate) fn parent_cluster(&self, cluster: ClusterId) -> Result<ClusterId> {
+        const ENTRY_SIZE: usize = mem::size_of::<UnknownDirectoryEntry>();
+        let mut buf = vec![0; self.device.sector_size];
+        self.cluster_chain_reader(cluster).read(&mut buf)?;
+        let parent = buf
+            .chunks_exact(ENTRY_SIZE)
+            .map(|raw| {
+                let mut entry = [0; ENTRY_SIZE];
+                entry.copy_from_slice(raw);
+                VfatDirectoryEntry::from(UnknownDirectoryEntry::from(entry))
+            })
+            .take_while(|entry| !matches!(entry, VfatDirectoryEntry::EndOfEntries(_)))
+            .filter_map(VfatDirectoryEntry::into_regular)
+            .find(|regular| regular.is_dir() && regular.short_name().to_string() == "..")
+            .ok_or_else(|| VfatRsError::BrokenParentLink {
+                target: format!("cluster {}", cluster),
+            })?
+            .cluster();
+        // The root is referred to as cluster 0.
+        Ok(if parent == ClusterId::new(0) {
+            self.root_cluster
+        } else {
+            parent
+        })
+    }
+
+    /// Writes `metadata` back to the entry it describes, in its parent directory.
+    pub(crate) fn update_entry(&self, metadata: &Metadata) -> Result<()> {
+        Directory::at(
+            self.clone(),
+            metadata.parent_cluster,
+            metadata.parent().clone(),
+        )
+        .update_entry(metadata.clone())
     }
-    pub fn get_root(&mut self) -> Result<Directory> {
+
+    /// Sets the attributes in `set` and clears the ones in `clear` on the entry described by
+    /// `metadata`, then writes the entry back to disk.
+    pub(crate) fn change_attributes(
+        &self,
+        metadata: &mut Metadata,
+        set: Attributes,
+        clear: Attributes,
+    ) -> Result<()> {
+        let invalid = |reason| {
+            Err(VfatRsError::InvalidAttributes {
+                target: metadata.name().to_string(),
+                reason,
+            })
+        };
+        if (set | clear).0 & !Attributes::USER_SETTABLE.0 != 0 {
+            return invalid("only READ_ONLY, HIDDEN, SYSTEM and ARCHIVE can be changed");
+        }
+        // The root directory and the pseudo directories have no entry of their own.
+        if metadata.cluster == self.root_cluster
+            || metadata.name() == "."
+            || metadata.name() == ".."
+        {
+            return invalid("the root directory and pseudo directories have no attributes");
+        }
+        // If the file is open, its handles might have changed it (e.g. its size) since
+        // `metadata` was read.
+        let node = self.open_files.get(metadata);
+        if let Some(node) = &node {
+            *metadata = node.metadata();
+        }
+        metadata.attributes.insert(set);
+        metadata.attributes.remove(clear);
+        self.update_entry(metadata)?;
+        if let Some(node) = node {
+            node.set_metadata(metadata);
+        }
+        Ok(())
+    }
+
+    pub fn path_exists(&self, path: Path) -> Result<bool> {
+        match self.get_path(path) {
+            Ok(_) => Ok(true),
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Recursively iterates over the contents of the directory at `root`, in pre-order.
+    pub fn walk(&self, root: Path) -> Result<Walk> {
+        self.walk_with(root, WalkOptions::default())
+    }
+
+    /// Like `walk`, with depth limit, order and filtering set by `options`.
+    pub fn walk_with(&self, root: Path, options: WalkOptions) -> Result<Walk> {
+        self.get_path(root.clone())?
+            .try_into_directory()
+            .and_then(|directory| Walk::new(directory, options))
+            .log_failure("walk", root.display())
+    }
+
+    /// Returns the entries matching an absolute glob `pattern`, e.g. `/logs/**/*.txt`.
+    /// `*` and `?` match within a path component, `**` matches any amount of components.
+    /// Like lookups, matching is case-insensitive.
+    pub fn glob(&self, pattern: &str) -> Result<Glob> {
+        let (root, pattern) = walk::split_glob(pattern);
+        let root = self.get_path(root.into())?.try_into_directory()?;
+        Glob::new(root, pattern)
+    }
+
+    /// Totals the logical and allocated size of the tree at `path`, which can also be a file.
+    pub fn du(&self, path: Path) -> Result<DiskUsage> {
+        self.du_inner(&path).log_failure("measure", path.display())
+    }
+
+    fn du_inner(&self, path: &Path) -> Result<DiskUsage> {
+        let entry = self.get_path(path.clone())?;
+        let mut usage = DiskUsage::default();
+        usage.add(&entry, self)?;
+        if entry.is_dir() {
+            for walk_entry in self.walk(path.clone())? {
+                usage.add(&walk_entry?.entry, self)?;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// The volume entry is expected to be the first entry of the root directory.
+    fn volume_id_entry(&self) -> Result<RegularDirectoryEntry> {
         const UNKNOWN_ENTRIES: usize = 1;
         const BUF_SIZE: usize = UNKNOWN_ENTRIES * mem::size_of::<UnknownDirectoryEntry>();
         let mut buf = [0; BUF_SIZE];
@@ -283,21 +806,302 @@ impl VfatFS {
         let _ = cluster_reader.read(&mut buf)?;
         let unknown_entries: UnknownDirectoryEntry = buf.into();
         debug!("Unknown entries: {:?}", unknown_entries);
-        let volume_id = VfatDirectoryEntry::from(unknown_entries)
+        Ok(VfatDirectoryEntry::from(unknown_entries)
             .into_regular()
             .filter(|regular| regular.is_volume_id())
             .ok_or_else(|| {
                 crate::io::Error::new(crate::io::ErrorKind::NotFound, "Volume id not found?!")
-            })?;
+            })?)
+    }
+
+    pub(crate) fn read_boot_sector(&self) -> Result<FullExtendedBIOSParameterBlock> {
+        let mut buff = [0u8; 512];
+        self.device.read_sector(self.boot_sector(), &mut buff)?;
+        Ok(Cursor::new(&buff).read_le()?)
+    }
+
+    /// The boot sector in use, see `boot_sector_copy`.
+    fn boot_sector(&self) -> SectorId {
+        match (self.boot_sector_copy, self.backup_boot_sector) {
+            (BootSectorCopy::Backup, Some(backup)) => backup,
+            _ => self.partition_start_sector,
+        }
+    }
+
+    /// Which copy of the boot sector is in use: the backup one is only used if the primary
+    /// one is invalid (e.g. corrupted). See `restore_boot_sectors`.
+    pub fn boot_sector_copy(&self) -> BootSectorCopy {
+        self.boot_sector_copy
+    }
+
+    /// Which copy of the FSInfo sector is in use, like `boot_sector_copy`.
+    pub fn fs_info_copy(&self) -> BootSectorCopy {
+        self.fs_info_copy
+    }
+
+    /// Overwrites the primary boot sector and FSInfo sector with their backups, if the backups
+    /// were used at mount: the primary copies are used afterwards. Like `set_lock`, call this
+    /// right after mounting, before cloning the filesystem or opening any entry.
+    pub fn restore_boot_sectors(&mut self) -> Result<()> {
+        let _guard = self.write_lock()?;
+        let mut buf = vec![0; self.device.sector_size];
+        if self.boot_sector_copy == BootSectorCopy::Backup {
+            info!("Restoring the boot sector from its backup.");
+            self.device.read_sector(self.boot_sector(), &mut buf)?;
+            self.device
+                .clone()
+                .write_sector_offset(self.partition_start_sector, 0, &buf)?;
+            self.boot_sector_copy = BootSectorCopy::Primary;
+        }
+        if let (BootSectorCopy::Backup, Some(sector), Some(backup_boot_sector)) = (
+            self.fs_info_copy,
+            self.fsinfo_sector,
+            self.backup_boot_sector,
+        ) {
+            info!("Restoring the FSInfo sector from its backup.");
+            let primary = SectorId(sector.0 - backup_boot_sector.0 + self.partition_start_sector.0);
+            self.device.read_sector(sector, &mut buf)?;
+            self.device.clone().write_sector_offset(primary, 0, &buf)?;
+            self.fsinfo_sector = Some(primary);
+            self.fs_info_copy = BootSectorCopy::Primary;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at `offset` in the boot sector, and in its backup if any. While the backup
+    /// is in use, the invalid primary boot sector is left alone: `restore_boot_sectors` brings
+    /// it back along with the changes.
+    fn write_boot_sectors(&self, offset: usize, buf: &[u8]) -> Result<()> {
+        let primary = (self.boot_sector_copy == BootSectorCopy::Primary)
+            .then_some(self.partition_start_sector);
+        for sector in primary.into_iter().chain(self.backup_boot_sector) {
+            self.device
+                .clone()
+                .write_sector_offset(sector, offset, buf)?;
+        }
+        Ok(())
+    }
+
+    /// The volume label, as stored in the root directory. Trailing spaces are removed.
+    pub fn label(&self) -> Result<String> {
+        let _guard = self.read_lock();
+        self.label_inner()
+    }
+
+    fn label_inner(&self) -> Result<String> {
+        let volume_id = self.volume_id_entry()?;
+        let label = [
+            volume_id.file_name.as_slice(),
+            volume_id.file_ext.as_slice(),
+        ]
+        .concat();
+        Ok(String::from_utf8_lossy(&label).trim_end().to_string())
+    }
+
+    /// Changes the volume label, both in the root directory and in the boot sectors.
+    /// Labels are up to 11 ASCII characters long, and they are stored in upper case.
+    pub fn set_label(&self, label: &str) -> Result<()> {
+        let encoded = Self::encode_label(label)?;
+        let _guard = self.write_lock()?;
+        // Makes sure the volume entry exists before changing anything.
+        self.volume_id_entry()?;
+        self.cluster_chain_writer(self.root_cluster)
+            .write(&encoded)?;
+        self.write_boot_sectors(VOLUME_LABEL_OFFSET, &encoded)
+    }
+
+    /// Information about the volume. The free clusters count comes from the FSInfo sector if
+    /// available, otherwise the FAT is scanned. See `stats_exact`.
+    pub fn stats(&self) -> Result<VolumeStats> {
+        let _guard = self.read_lock();
+        let free_clusters = match self.known_free_count() {
+            Some(count) => count,
+            None => self.count_free_clusters()?,
+        };
+        self.build_stats(free_clusters)
+    }
+
+    /// Like `stats`, but the free clusters are always counted by scanning the whole FAT.
+    /// FSInfo is just a hint (e.g. it's not updated by every driver): if it's wrong,
+    /// it's fixed using the counted value on unmount, unless the volume is read-only.
+    pub fn stats_exact(&self) -> Result<VolumeStats> {
+        let _guard = if self.is_read_only() {
+            self.read_lock()
+        } else {
+            self.write_lock()?
+        };
+        let free_clusters = self.count_free_clusters()?;
+        if !self.is_read_only()
+            && self.read_fs_info()?.is_some()
+            && self.known_free_count() != Some(free_clusters)
+        {
+            info!("Fixing FSInfo free clusters count: {}", free_clusters);
+            self.device.set_free_count(FreeCount {
+                count: Some(free_clusters),
+                dirty: true,
+            });
+        }
+        self.build_stats(free_clusters)
+    }
+
+    /// Checks the consistency of the volume, like `fsck.fat` does: every directory and cluster
+    /// chain is visited, see `Problem` for what's reported. With `CheckOptions::repair`, the
+    /// problems are fixed too. Repairs don't update open handles: close them first.
+    /// A volume found dirty at mount (see `mount_status`) is marked clean again on `unmount`
+    /// only once a check finds it clean, or repairs it.
+    pub fn check(&self, options: CheckOptions) -> Result<CheckReport> {
+        let _guard = if options.repair {
+            self.write_lock()?
+        } else {
+            self.read_lock()
+        };
+        let report = Checker::new(self, options)?.run()?;
+        if report.is_clean() || report.repaired {
+            self.device.set_checked();
+        }
+        Ok(report)
+    }
+
+    /// Marks `cluster` as bad: it won't be allocated anymore. Only free clusters can be marked,
+    /// as marking one in use would break the chain using it.
+    pub fn mark_bad(&self, cluster: u32) -> Result<()> {
+        let _guard = self.write_lock()?;
+        self.mark_bad_inner(ClusterId::new(cluster))
+    }
+
+    /// Reads every free cluster, and marks the ones which can't be read as bad (see `mark_bad`).
+    /// Returns the clusters marked bad. It reads the whole free space: it's slow on big volumes.
+    pub fn scan_surface(&self) -> Result<Vec<u32>> {
+        let _guard = self.write_lock()?;
+        let mut buf = vec![0; self.cluster_size()];
+        let mut marked = Vec::new();
+        for (cluster, entry) in self.read_fat()?.into_iter().enumerate().skip(2) {
+            if entry != FatEntry::Unused {
+                continue;
+            }
+            let cluster = ClusterId::new(cluster as u32);
+            let first_sector = self.device.cluster_to_sector(cluster);
+            let readable = self
+                .device
+                .read_sectors(
+                    first_sector,
+                    self.device.sectors_per_cluster as usize,
+                    &mut buf,
+                )
+                .is_ok();
+            if !readable {
+                self.mark_bad_inner(cluster)?;
+                marked.push(u32::from(cluster));
+            }
+        }
+        info!(
+            "Surface scan completed, {} bad clusters found.",
+            marked.len()
+        );
+        Ok(marked)
+    }
+
+    /// The state of the volume when it was mounted. If `MountStatus::needs_check`, the volume
+    /// was not unmounted cleanly or had I/O errors: it might be inconsistent, see `check`.
+    pub fn mount_status(&self) -> MountStatus {
+        self.mount_status
+    }
+
+    /// Marks the volume as cleanly unmounted. The first write after mount marks it as dirty,
+    /// so a volume which is not unmounted (e.g. after a crash) is reported by `mount_status`
+    /// at the next mount. Clones of this `VfatFS` can still be used: writing through them
+    /// marks the volume as dirty again. The free clusters count, kept in memory while mounted,
+    /// is written to FSInfo as well. Read-only volumes are left untouched.
+    pub fn unmount(self) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let _guard = self.write_lock()?;
+        let free_count = self.device.free_count();
+        if let (Some(count), true) = (free_count.count, free_count.dirty) {
+            self.write_fs_info_free_count(count)?;
+        }
+        self.device.mark_clean()
+    }
+
+    fn build_stats(&self, free_clusters: u32) -> Result<VolumeStats> {
+        let boot_sector = self.read_boot_sector()?;
+        Ok(VolumeStats {
+            fat_type: FatType::Fat32,
+            oem_name: String::from_utf8_lossy(&boot_sector.bpb.oem_identifier)
+                .trim_end()
+                .to_string(),
+            label: self.label_inner()?,
+            serial: boot_sector.extended.volumeid_serial_number,
+            sector_size: self.device.sector_size,
+            cluster_size: self.cluster_size(),
+            fat_amount: boot_sector.bpb.fat_amount,
+            reserved_sectors: boot_sector.bpb.reserved_sectors,
+            total_clusters: self.total_clusters,
+            free_clusters,
+        })
+    }
+
+    /// The volume serial number, as stored in the boot sector.
+    pub fn serial(&self) -> Result<u32> {
+        let _guard = self.read_lock();
+        Ok(self.read_boot_sector()?.extended.volumeid_serial_number)
+    }
+
+    /// Changes the volume serial number, in the boot sectors.
+    pub fn set_serial(&self, serial: u32) -> Result<()> {
+        let _guard = self.write_lock()?;
+        self.write_boot_sectors(SERIAL_NUMBER_OFFSET, &serial.to_le_bytes())
+    }
+
+    fn encode_label(label: &str) -> Result<[u8; 11]> {
+        const FORBIDDEN_CHARS: &str = "\"*+,./:;<=>?[\\]|";
+        let invalid = |reason| {
+            InvalidLabelSnafu {
+                target: label,
+                reason,
+            }
+            .fail()
+        };
+        if label.is_empty() || label.starts_with(' ') {
+            return invalid("empty, or starting with a space");
+        }
+        if label
+            .chars()
+            .any(|ch| !ch.is_ascii() || ch.is_ascii_control() || FORBIDDEN_CHARS.contains(ch))
+        {
+            return invalid("forbidden character");
+        }
+        if label.len() > 11 {
+            return invalid("longer than 11 characters");
+        }
+        if label.eq_ignore_ascii_case("NO NAME") {
+            return invalid("reserved for volumes without a label");
+        }
+        let mut encoded = [b' '; 11];
+        encoded[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+        Ok(encoded)
+    }
+
+    pub fn get_root(&self) -> Result<Directory> {
+        let _guard = self.read_lock();
+        self.root_directory()
+    }
+
+    pub(crate) fn root_directory(&self) -> Result<Directory> {
+        let volume_id = self.volume_id_entry()?;
 
         let metadata = Metadata::new(
             volume_id.creation_time,
             volume_id.last_modification_time,
             "/",
+            ShortName::empty(),
             mem::size_of::<RegularDirectoryEntry>() as u32,
             Path::from("/"),
             self.root_cluster,
             Path::from(""),
+            // The root has no entry, hence no parent.
+            self.root_cluster,
             Attributes::new_directory(),
         );
         Ok(Directory::new(self.clone(), metadata))
@@ -309,65 +1113,27 @@ mod test {
     use std::sync::Arc;
 
     use crate::fat_table::FAT_ENTRY_SIZE;
-    use crate::io::Write;
     use crate::{
-        BlockDevice, CachedPartition, ClusterId, Result, SectorId, TimeManagerNoop, VfatFS,
+        BootSectorCopy, CachedPartition, ClusterId, Directory, File, MemoryBlockDevice, SectorId,
+        SpinFsLock, TimeManagerNoop, VfatFS,
     };
 
-    pub struct ArrayBackedBlockDevice {
-        pub arr: Vec<u8>,
-        pub read_iteration: usize,
-    }
-
-    impl BlockDevice for ArrayBackedBlockDevice {
-        fn read_sector(&mut self, sector: SectorId, buf: &mut [u8]) -> Result<usize> {
-            self.read_sector_offset(sector, 0, buf)
-        }
-
-        fn read_sector_offset(
-            &mut self,
-            _sector: SectorId,
-            _offset: usize,
-            mut buf: &mut [u8],
-        ) -> Result<usize> {
-            let ret = buf.write(&self.arr[self.read_iteration..512]);
-            self.read_iteration += 1;
-            ret.map_err(Into::into)
-        }
-
-        fn write_sector_offset(
-            &mut self,
-            _sector: SectorId,
-            _offset: usize,
-            _buf: &[u8],
-        ) -> Result<usize> {
-            unreachable!()
-        }
-
-        fn get_canonical_name() -> &'static str
-        where
-            Self: Sized,
-        {
-            "ArrayBackedBlockDevice"
-        }
-    }
-
     #[test]
     fn test_find_next_free() {
         let mut ret = Vec::new();
-        // Reserved entry:
+        // Reserved entries, cluster 1 is "free" but doesn't map to a data cluster:
+        ret.extend_from_slice(&[0x01; FAT_ENTRY_SIZE]);
+        ret.extend_from_slice(&[0x00; FAT_ENTRY_SIZE]);
+        // Used entry:
         ret.extend_from_slice(&[0x01; FAT_ENTRY_SIZE]);
         // Free entry:
         ret.extend_from_slice(&[0x00; FAT_ENTRY_SIZE]);
 
         // Complete the sector:
-        ret.extend_from_slice(&[0x01; 512 - (FAT_ENTRY_SIZE * 2)]);
+        ret.extend_from_slice(&[0x01; 512 - (FAT_ENTRY_SIZE * 4)]);
 
-        let dev = ArrayBackedBlockDevice {
-            arr: ret,
-            read_iteration: 0,
-        };
-        let sector_size = 1;
+        let dev = MemoryBlockDevice::new(ret);
+        let sector_size = 512;
         let fat_start_sector = SectorId(0);
         let sectors_per_cluster = 1;
         let data_start_sector = SectorId(2);
@@ -376,18 +1142,40 @@ mod test {
                 dev,
                 sector_size,
                 fat_start_sector,
+                1,
+                1,
                 sectors_per_cluster,
                 data_start_sector,
+                2,
             )),
             fat_start_sector,
             sectors_per_fat: 1,
             root_cluster: ClusterId::new(0),
-            eoc_marker: Default::default(),
+            mount_status: Default::default(),
+            partition_start_sector: SectorId(0),
+            backup_boot_sector: None,
+            boot_sector_copy: BootSectorCopy::Primary,
+            fsinfo_sector: None,
+            fs_info_copy: BootSectorCopy::Primary,
+            total_clusters: 2,
             time_manager: TimeManagerNoop::new_arc(),
+            diagnostics: None,
+            auto_shrink: false,
+            lock: Arc::new(SpinFsLock::new()),
+            open_files: Default::default(),
+            delete_policy: Default::default(),
         };
         assert_eq!(
             vfat.find_free_cluster().unwrap().unwrap(),
-            ClusterId::new(1)
+            ClusterId::new(3)
         );
     }
+
+    #[test]
+    fn test_handles_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<VfatFS>();
+        assert_send_sync::<File>();
+        assert_send_sync::<Directory>();
+    }
 }
@@ -0,0 +1,229 @@
+#![cfg(feature = "async")]
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use rand::Rng;
+
+use common::SharedBlockDevice;
+use vfat_rs::asynchronous::{AsyncBlockDevice, VfatFS};
+use vfat_rs::io::SeekFrom;
+use vfat_rs::mbr::MasterBootRecord;
+use vfat_rs::{BlockDevice, MemoryBlockDevice, SectorId, VfatMetadataTrait, VfatRsError};
+
+#[allow(dead_code)]
+mod common;
+
+/// Wakes up the thread blocked on a future.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// A minimal executor, polling `future` on the current thread until it completes.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Runs both futures concurrently, on the same task.
+async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    let (mut out_a, mut out_b) = (None, None);
+    poll_fn(|cx| {
+        if out_a.is_none() {
+            if let Poll::Ready(out) = a.as_mut().poll(cx) {
+                out_a = Some(out);
+            }
+        }
+        if out_b.is_none() {
+            if let Poll::Ready(out) = b.as_mut().poll(cx) {
+                out_b = Some(out);
+            }
+        }
+        if out_a.is_some() && out_b.is_some() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    })
+    .await;
+    (out_a.unwrap(), out_b.unwrap())
+}
+
+/// Yields once before completing, like a request queued to a driver.
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// An `AsyncBlockDevice` over a sync one, yielding before each request.
+struct AsyncAdapter<B>(B);
+
+impl<B: BlockDevice> AsyncBlockDevice for AsyncAdapter<B> {
+    async fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> vfat_rs::Result<usize> {
+        yield_now().await;
+        self.0.read_sectors(start, count, buf)
+    }
+
+    async fn write_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &[u8],
+    ) -> vfat_rs::Result<usize> {
+        yield_now().await;
+        self.0.write_sectors(start, count, buf)
+    }
+}
+
+type SharedMemoryDevice = SharedBlockDevice<MemoryBlockDevice>;
+
+/// An in-memory image, returning the device and the start of its partition. The device can be
+/// cloned, to mount the image again.
+fn init() -> (AsyncAdapter<SharedMemoryDevice>, u32) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let mut device = SharedBlockDevice::new(MemoryBlockDevice::new(common::memory_image()));
+    let mut buf = [0; 512];
+    device.read_sector(SectorId(0), &mut buf).unwrap();
+    let start = MasterBootRecord::from(buf).partitions[0].start_sector;
+    (AsyncAdapter(device), start)
+}
+
+fn random_content(len: usize) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+#[test]
+fn test_async_file_operations() -> vfat_rs::Result<()> {
+    let (device, start) = init();
+    let shared = device.0.clone();
+    block_on(async {
+        let vfat = VfatFS::new(device, start).await?;
+        // Evicting the cached sectors often, they're fetched again.
+        vfat.set_cache_capacity(16);
+
+        let mut hello = vfat.open("/hello.txt".into()).await?;
+        let mut buf = [0; 64];
+        let amount = hello.read(&mut buf).await?;
+        assert_eq!(&buf[..amount], b"Hello, Iris OS!\n");
+
+        let mut root = vfat.get_root().await?;
+        let mut directory = root.create_directory("async".into()).await?;
+        let mut file = directory.create_file("data.bin".into()).await?;
+        // Larger than a chunk, and ending in the middle of a sector.
+        let content = random_content(150 * 1024 + 7);
+        assert_eq!(file.write(&content).await?, content.len());
+        assert_eq!(file.size(), content.len());
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut read = vec![0; content.len() + 10];
+        assert_eq!(file.read(&mut read).await?, content.len());
+        assert_eq!(&read[..content.len()], &content[..]);
+
+        // Overwriting an unaligned range.
+        file.seek(SeekFrom::Start(1000))?;
+        file.write(&[7; 100]).await?;
+        let mut reopened = vfat.open("/async/data.bin".into()).await?;
+        reopened.seek(SeekFrom::Start(990))?;
+        let mut range = [0; 120];
+        reopened.read(&mut range).await?;
+        assert_eq!(&range[..10], &content[990..1000]);
+        assert_eq!(&range[10..110], &[7; 100]);
+        assert_eq!(&range[110..], &content[1100..1110]);
+
+        file.truncate(100).await?;
+        assert_eq!(reopened.size(), 100);
+        file.truncate(70 * 1024).await?;
+        assert_eq!(file.size(), 70 * 1024);
+
+        let names: Vec<String> = directory
+            .contents()
+            .await?
+            .iter()
+            .map(|metadata| metadata.name().to_string())
+            .collect();
+        assert!(names.contains(&"data.bin".to_string()));
+
+        // Open files can't be deleted.
+        assert!(matches!(
+            directory.delete("data.bin".into()).await,
+            Err(VfatRsError::FileInUse { .. })
+        ));
+        drop(file);
+        drop(reopened);
+        let mut empty = directory.create_file("empty.txt".into()).await?;
+        empty.write(b"async").await?;
+        drop(empty);
+        directory.delete("data.bin".into()).await?;
+        assert!(!vfat.path_exists("/async/data.bin".into()).await?);
+        assert_eq!(directory.metadata().name(), "async");
+        vfat.unmount().await
+    })?;
+
+    // The same volume, mounted through the sync API.
+    let vfat = vfat_rs::VfatFS::new(shared, start)?;
+    assert!(!vfat.mount_status().was_dirty);
+    let mut file = vfat
+        .get_path("/async/empty.txt".into())?
+        .into_file()
+        .unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(file.read(&mut buf)?, 5);
+    assert_eq!(&buf[..5], b"async");
+    Ok(())
+}
+
+#[test]
+fn test_async_concurrent_operations() -> vfat_rs::Result<()> {
+    let (device, start) = init();
+    block_on(async {
+        let vfat = VfatFS::new(device, start).await?;
+        let mut root = vfat.get_root().await?;
+        let mut first = root.create_file("first.bin".into()).await?;
+        let mut second = root.create_file("second.bin".into()).await?;
+        let (first_content, second_content) = (random_content(20000), random_content(30000));
+
+        // The writes are interleaved, sharing the clusters allocation and the device.
+        let (written_first, written_second) =
+            join(first.write(&first_content), second.write(&second_content)).await;
+        assert_eq!(written_first?, first_content.len());
+        assert_eq!(written_second?, second_content.len());
+
+        for (path, content) in [
+            ("/first.bin", first_content),
+            ("/second.bin", second_content),
+        ] {
+            let mut file = vfat.open(path.into()).await?;
+            let mut buf = vec![0; content.len()];
+            assert_eq!(file.read(&mut buf).await?, content.len());
+            assert_eq!(buf, content);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+
+use vfat_rs::BlockDevice;
+use vfat_rs::SectorId;
+
+/// The requests received by a `CountingBlockDevice`.
+#[derive(Debug, Default)]
+pub struct Requests {
+    /// Single sector reads and writes.
+    pub sectors: usize,
+    /// Multi-sector reads and writes, with the amount of sectors of each.
+    pub runs: Vec<usize>,
+}
+
+/// Wraps a block device, counting the requests it receives.
+pub struct CountingBlockDevice<B> {
+    pub inner: B,
+    pub requests: Arc<Mutex<Requests>>,
+}
+
+impl<B> CountingBlockDevice<B> {
+    pub fn new(inner: B) -> (Self, Arc<Mutex<Requests>>) {
+        let requests = Arc::new(Mutex::new(Requests::default()));
+        let device = Self {
+            inner,
+            requests: requests.clone(),
+        };
+        (device, requests)
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for CountingBlockDevice<B> {
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn read_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfat_rs::Result<usize> {
+        self.requests.lock().unwrap().sectors += 1;
+        self.inner.read_sector_offset(sector, offset, buf)
+    }
+
+    fn write_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfat_rs::Result<usize> {
+        self.requests.lock().unwrap().sectors += 1;
+        self.inner.write_sector_offset(sector, offset, buf)
+    }
+
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> vfat_rs::Result<usize> {
+        self.requests.lock().unwrap().runs.push(count);
+        self.inner.read_sectors(start, count, buf)
+    }
+
+    fn write_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &[u8],
+    ) -> vfat_rs::Result<usize> {
+        self.requests.lock().unwrap().runs.push(count);
+        self.inner.write_sectors(start, count, buf)
+    }
+
+    fn get_canonical_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "CountingBlockDevice"
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use vfat_rs::io::{Error, ErrorKind};
+use vfat_rs::BlockDevice;
+use vfat_rs::SectorId;
+
+/// The sectors a `FaultyBlockDevice` fails to read or write.
+#[derive(Debug, Default)]
+pub struct Faults {
+    pub unreadable: HashSet<u32>,
+    pub unwritable: HashSet<u32>,
+    /// If set, every access fails once this many accesses (counted in `accesses`) succeeded.
+    pub fail_after: Option<usize>,
+    pub accesses: usize,
+}
+
+impl Faults {
+    /// Makes every access fail after the next `accesses` ones.
+    pub fn fail_after(&mut self, accesses: usize) {
+        self.fail_after = Some(accesses);
+        self.accesses = 0;
+    }
+
+    fn fails(&mut self, sector: SectorId, faulty_sectors: fn(&Self) -> &HashSet<u32>) -> bool {
+        if self.fail_after.is_some_and(|limit| self.accesses >= limit) {
+            return true;
+        }
+        self.accesses += 1;
+        faulty_sectors(self).contains(&sector.0)
+    }
+}
+
+/// Wraps a block device, failing the reads and writes of some sectors like a damaged medium.
+/// Faults are shared, so they can be changed while the device is in use.
+pub struct FaultyBlockDevice<B> {
+    pub inner: B,
+    pub faults: Arc<Mutex<Faults>>,
+}
+
+impl<B> FaultyBlockDevice<B> {
+    pub fn new(inner: B) -> (Self, Arc<Mutex<Faults>>) {
+        let faults = Arc::new(Mutex::new(Faults::default()));
+        let device = Self {
+            inner,
+            faults: faults.clone(),
+        };
+        (device, faults)
+    }
+}
+
+// `Error::other` is not available without std.
+#[allow(clippy::io_other_error)]
+fn injected(what: &str, sector: SectorId) -> vfat_rs::VfatRsError {
+    let message = format!("injected {} fault on sector {}", what, sector.0);
+    Error::new(ErrorKind::Other, message).into()
+}
+
+impl<B: BlockDevice> BlockDevice for FaultyBlockDevice<B> {
+    fn sector_size(&self) -> usize {
+        self.inner.sector_size()
+    }
+
+    fn read_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfat_rs::Result<usize> {
+        if self.faults.lock().unwrap().fails(sector, |f| &f.unreadable) {
+            return Err(injected("read", sector));
+        }
+        self.inner.read_sector_offset(sector, offset, buf)
+    }
+
+    fn write_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfat_rs::Result<usize> {
+        if self.faults.lock().unwrap().fails(sector, |f| &f.unwritable) {
+            return Err(injected("write", sector));
+        }
+        self.inner.write_sector_offset(sector, offset, buf)
+    }
+
+    fn get_canonical_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "FaultyBlockDevice"
+    }
+}
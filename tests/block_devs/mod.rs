@@ -1,4 +1,7 @@
-mod array_blockdev;
+mod counting_blockdev;
+mod faulty_blockdev;
 mod file_blockdev;
 
+pub use counting_blockdev::CountingBlockDevice;
+pub use faulty_blockdev::{Faults, FaultyBlockDevice};
 pub use file_blockdev::FilebackedBlockDevice;
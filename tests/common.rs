@@ -1,9 +1,11 @@
-use rand::{random, Rng};
-use std::path::{Path, PathBuf};
+use rand::Rng;
+use std::fs;
+use std::path::PathBuf;
 use std::process::Command;
-use std::{fs, io};
+use std::sync::{Arc, Mutex};
 
-const FS_PATH: &str = "/tmp/irisos_fat32/fat32.fs";
+use vfat_rs::io::Write;
+use vfat_rs::{BlockDevice, MemoryBlockDevice, SectorId, VfatFS};
 
 pub fn create_random_dir() -> PathBuf {
     let random_dir_name: String = rand::thread_rng()
@@ -61,3 +63,215 @@ pub fn setup() -> VfatFsRandomPath {
         fs_path: random_dir_path,
     }
 }
+
+const SECTOR_SIZE: usize = 512;
+/// Start of the partition in the image built by `memory_image`, 1 MiB like `setup.sh`.
+pub const MEMORY_PARTITION_START: u32 = 2048;
+/// Sectors of the volume built by `memory_image`: 36 MiB are enough for FAT32 (at least 65525
+/// clusters) with clusters of one sector.
+const MEMORY_VOLUME_SECTORS: u32 = 36 * 2048;
+const RESERVED_SECTORS: u32 = 32;
+const FAT_AMOUNT: u32 = 2;
+const ROOT_CLUSTER: u32 = 2;
+
+const A_BIG_FILE: &str = "From fairest creatures we desire increase,
+That thereby beauty's rose might never die,
+But as the riper should by time decrease,
+His tender heir mught bear his memeory:
+But thou, contracted to thine own bright eyes,
+Feed'st thy light'st flame with self-substantial fuel,
+Making a famine where abundance lies,
+Thyself thy foe, to thy sweet self too cruel.
+Thou that art now the world's fresh ornament
+And only herald to the gaudy spring,
+Within thine own bud buriest thy content
+And, tender churl, makest waste in niggarding.
+Pity the world, or else this glutton be,
+To eat the world's due, by the grave and thee.
+";
+
+/// Builds in memory an image like the one `setup.sh` creates: an MBR with a FAT32 partition
+/// labeled IRISVOL, holding the same files. Unlike `setup`, it needs neither mkfs.fat nor root
+/// to mount the image.
+pub fn memory_image() -> Vec<u8> {
+    let partition_start = MEMORY_PARTITION_START as usize * SECTOR_SIZE;
+    let mut image = vec![0; partition_start + MEMORY_VOLUME_SECTORS as usize * SECTOR_SIZE];
+    write_mbr(&mut image);
+    format_fat32(&mut image[partition_start..], *b"IRISVOL    ");
+
+    let device = SharedBlockDevice::new(MemoryBlockDevice::new(image));
+    let vfat = VfatFS::new(device.clone(), MEMORY_PARTITION_START).unwrap();
+    let mut root = vfat.get_root().unwrap();
+    let mut directory = root.create_directory("folder".into()).unwrap();
+    for name in ["some", "deep", "nested", "folder"] {
+        directory = directory.create_directory(name.into()).unwrap();
+    }
+    directory.create_file("file".into()).unwrap();
+    root.create_directory("MyFoLdEr".into()).unwrap();
+    let files = [
+        ("a-big-file.txt", A_BIG_FILE),
+        ("a-very-long-file-name-entry.txt", ""),
+        ("hello.txt", "Hello, Iris OS!\n"),
+    ];
+    for (name, content) in files {
+        let mut file = root.create_file(name.into()).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+    vfat.unmount().unwrap();
+    let image = device.inner.lock().unwrap().image().to_vec();
+    image
+}
+
+/// A single bootable partition of type 0x0C (FAT32, LBA), filling the image.
+fn write_mbr(image: &mut [u8]) {
+    let sectors = (image.len() / SECTOR_SIZE) as u32 - MEMORY_PARTITION_START;
+    let entry = &mut image[446..462];
+    entry[0] = 0x80;
+    entry[4] = 0x0C;
+    entry[8..12].copy_from_slice(&MEMORY_PARTITION_START.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xAA]);
+}
+
+/// Formats `volume` like `mkfs.fat -F32` would: a sector per cluster, the boot sector and
+/// FSInfo with their backups, two FATs and an empty root directory holding the label.
+fn format_fat32(volume: &mut [u8], label: [u8; 11]) {
+    let total_sectors = (volume.len() / SECTOR_SIZE) as u32;
+    // The FATs hold an entry per cluster, and take space from the data region.
+    let mut sectors_per_fat = 1;
+    loop {
+        let clusters = total_sectors - RESERVED_SECTORS - FAT_AMOUNT * sectors_per_fat;
+        let needed = ((clusters + 2) * 4).div_ceil(SECTOR_SIZE as u32);
+        if needed <= sectors_per_fat {
+            break;
+        }
+        sectors_per_fat = needed;
+    }
+    let data_start = RESERVED_SECTORS + FAT_AMOUNT * sectors_per_fat;
+    let clusters = total_sectors - data_start;
+
+    let mut boot_sector = [0u8; SECTOR_SIZE];
+    boot_sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot_sector[3..11].copy_from_slice(b"mkfs.fat");
+    boot_sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot_sector[13] = 1;
+    boot_sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot_sector[16] = FAT_AMOUNT as u8;
+    boot_sector[21] = 0xF8;
+    boot_sector[24..26].copy_from_slice(&32u16.to_le_bytes());
+    boot_sector[26..28].copy_from_slice(&8u16.to_le_bytes());
+    boot_sector[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+    boot_sector[36..40].copy_from_slice(&sectors_per_fat.to_le_bytes());
+    boot_sector[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+    // FSInfo, and the backup of the boot sector.
+    boot_sector[48..50].copy_from_slice(&1u16.to_le_bytes());
+    boot_sector[50..52].copy_from_slice(&6u16.to_le_bytes());
+    boot_sector[64] = 0x80;
+    boot_sector[66] = 0x29;
+    boot_sector[67..71].copy_from_slice(&rand::thread_rng().gen::<u32>().to_le_bytes());
+    boot_sector[71..82].copy_from_slice(&label);
+    boot_sector[82..90].copy_from_slice(b"FAT32   ");
+    boot_sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let mut fs_info = [0u8; SECTOR_SIZE];
+    fs_info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    // The root directory takes the first cluster.
+    fs_info[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+    fs_info[492..496].copy_from_slice(&(ROOT_CLUSTER + 1).to_le_bytes());
+    fs_info[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    let sector = |index: u32| index as usize * SECTOR_SIZE..(index as usize + 1) * SECTOR_SIZE;
+    for first in [0, 6] {
+        volume[sector(first)].copy_from_slice(&boot_sector);
+        volume[sector(first + 1)].copy_from_slice(&fs_info);
+    }
+    // The media descriptor, the volume state flags (clean, no errors) and the root's chain.
+    let reserved_entries = [0x0FFF_FFF8u32, 0x0FFF_FFFF, 0x0FFF_FFFF];
+    for fat in 0..FAT_AMOUNT {
+        let start = (RESERVED_SECTORS + fat * sectors_per_fat) as usize * SECTOR_SIZE;
+        for (index, entry) in reserved_entries.iter().enumerate() {
+            volume[start + index * 4..][..4].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+    let label_entry = &mut volume[sector(data_start)][..32];
+    label_entry[0..11].copy_from_slice(&label);
+    label_entry[11] = 0x08;
+}
+
+/// Shares a block device between its clones, e.g. to look at an in-memory image while a volume
+/// is mounted on it, or to mount it again.
+pub struct SharedBlockDevice<B> {
+    pub inner: Arc<Mutex<B>>,
+}
+
+impl<B> SharedBlockDevice<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+}
+
+impl<B> Clone for SharedBlockDevice<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: BlockDevice> BlockDevice for SharedBlockDevice<B> {
+    fn sector_size(&self) -> usize {
+        self.inner.lock().unwrap().sector_size()
+    }
+
+    fn read_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> vfat_rs::Result<usize> {
+        self.inner
+            .lock()
+            .unwrap()
+            .read_sector_offset(sector, offset, buf)
+    }
+
+    fn write_sector_offset(
+        &mut self,
+        sector: SectorId,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfat_rs::Result<usize> {
+        self.inner
+            .lock()
+            .unwrap()
+            .write_sector_offset(sector, offset, buf)
+    }
+
+    fn read_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &mut [u8],
+    ) -> vfat_rs::Result<usize> {
+        self.inner.lock().unwrap().read_sectors(start, count, buf)
+    }
+
+    fn write_sectors(
+        &mut self,
+        start: SectorId,
+        count: usize,
+        buf: &[u8],
+    ) -> vfat_rs::Result<usize> {
+        self.inner.lock().unwrap().write_sectors(start, count, buf)
+    }
+
+    fn get_canonical_name() -> &'static str
+    where
+        Self: Sized,
+    {
+        "SharedBlockDevice"
+    }
+}
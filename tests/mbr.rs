@@ -1,7 +1,12 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
-use vfat_rs::mbr;
+use vfat_rs::{mbr, MbrError, VfatRsError};
 
+use block_devs::{FaultyBlockDevice, FilebackedBlockDevice};
+
+#[allow(dead_code, unused_imports)]
+mod block_devs;
+#[allow(dead_code)]
 mod common;
 
 #[test]
@@ -23,3 +28,35 @@ fn test_mbr_reader() {
     );
     drop(vfatfs_randompath);
 }
+
+#[test]
+fn test_mbr_try_load() {
+    let vfatfs_randompath = common::setup();
+    let open = |path: &std::path::Path| FilebackedBlockDevice {
+        image: OpenOptions::new().read(true).open(path).unwrap(),
+    };
+    let mbr = mbr::MasterBootRecord::try_load(&mut open(&vfatfs_randompath.fs_path)).unwrap();
+    assert_eq!(mbr.partitions[0].partition_type, 0xC);
+    assert!(mbr.get_vfat_partition(0).is_ok());
+    assert!(mbr.get_vfat_partition(1).is_err());
+    assert!(mbr.get_vfat_partition(4).is_err());
+
+    // A device that can't be read.
+    let (mut dev, faults) = FaultyBlockDevice::new(open(&vfatfs_randompath.fs_path));
+    faults.lock().unwrap().fail_after(0);
+    assert!(matches!(
+        mbr::MasterBootRecord::try_load(&mut dev),
+        Err(VfatRsError::IoError { .. })
+    ));
+
+    // A blank sector has no signature.
+    let path = std::env::temp_dir().join(format!("vfat-mbr-{}.img", std::process::id()));
+    std::fs::write(&path, [0u8; 512]).unwrap();
+    assert!(matches!(
+        mbr::MasterBootRecord::try_load(&mut open(&path)),
+        Err(VfatRsError::Mbr {
+            error: MbrError::InvalidSignature { signature: [0, 0] }
+        })
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,15 +1,18 @@
-use chrono::{DateTime, Datelike, Local, Utc};
+use chrono::{DateTime, Datelike, Utc};
 use std::fs::OpenOptions;
 use vfat_rs::io::{SeekFrom, Write};
 
 use log::info;
 use rand::Rng;
-use serial_test::serial;
 
 use crate::common::VfatFsRandomPath;
-use block_devs::FilebackedBlockDevice;
+use block_devs::{CountingBlockDevice, FaultyBlockDevice, FilebackedBlockDevice};
+use vfat_rs::diagnostics::{Diagnostic, LfnAnomaly};
 use vfat_rs::mbr::MasterBootRecord;
-use vfat_rs::{mbr, BlockDevice, Path, SectorId, VfatFS};
+use vfat_rs::{
+    errno, mbr, BlockDevice, DiagnosticsTrait, ErrorKind, MemoryBlockDevice, Path, SectorId,
+    VfatFS, VfatRsError,
+};
 
 mod block_devs;
 mod common;
@@ -24,6 +27,12 @@ fn init() -> (FilebackedBlockDevice, MasterBootRecord, VfatFsRandomPath) {
     std::env::set_var("RUST_LOG", "debug");
     let _ = env_logger::builder().is_test(true).try_init();
     let vfatfs_randompath = common::setup();
+    let (fs, master_boot_record) = init_from(&vfatfs_randompath);
+    (fs, master_boot_record, vfatfs_randompath)
+}
+
+/// Opens an existing image.
+fn init_from(vfatfs_randompath: &VfatFsRandomPath) -> (FilebackedBlockDevice, MasterBootRecord) {
     let mut fs = FilebackedBlockDevice {
         image: OpenOptions::new()
             .read(true)
@@ -35,7 +44,7 @@ fn init() -> (FilebackedBlockDevice, MasterBootRecord, VfatFsRandomPath) {
     // MBR is always located in sector 0 of the disk
     fs.read_sector(SectorId(0), &mut buf).unwrap();
     let master_boot_record = MasterBootRecord::from(buf);
-    (fs, master_boot_record, vfatfs_randompath)
+    (fs, master_boot_record)
 }
 
 fn init_vfat() -> vfat_rs::Result<(VfatFS, VfatFsRandomPath)> {
@@ -56,7 +65,7 @@ fn random_name(prefix: &str) -> (String, String) {
 
 #[test]
 fn test_read_bios_parameter_block() {
-    let (mut dev, master_boot_record, vfatfs_randompath) = init();
+    let (mut dev, master_boot_record, _vfatfs_randompath) = init();
 
     assert_eq!(
         master_boot_record.valid_bootsector_sign,
@@ -73,7 +82,7 @@ fn test_read_bios_parameter_block() {
 
 #[test]
 fn test_read_file() -> vfat_rs::Result<()> {
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let expected_content = "Hello, Iris OS!".to_string();
     let mut file = vfat.get_path("/hello.txt".into())?.into_file().unwrap();
     let mut buf = [0; 512];
@@ -144,7 +153,7 @@ To eat the world's due, by the grave and thee.
     // Seek to 0:
     file.seek(SeekFrom::End(-(LONG_FILE.len() as i64)))?;
     // seek to -1:
-    file.seek(SeekFrom::End(-(LONG_FILE.len() as i64 + 1 as i64)))
+    file.seek(SeekFrom::End(-(LONG_FILE.len() as i64 + 1)))
         .unwrap_err();
 
     Ok(())
@@ -152,27 +161,75 @@ To eat the world's due, by the grave and thee.
 
 #[test]
 fn test_path() {
-    init();
-    let expected = "//folder/something";
     let path = Path::from("/folder/something");
+    assert_eq!(
+        path.iter().collect::<Vec<&str>>(),
+        vec!["/", "folder", "something"]
+    );
+    assert_eq!(path.join("file.txt"), "/folder/something/file.txt");
+    assert_eq!(path.parent(), Some(Path::from("/folder")));
+    assert_eq!(Path::from("/a/./b/../c.txt").normalize(), "/a/c.txt");
+}
 
-    #[cfg(feature = "std")]
-    let path_str = path
-        .iter()
-        .map(|el| el.to_str().unwrap())
-        .collect::<Vec<&str>>()
-        .join("/");
+#[test]
+fn test_path_resolution() -> vfat_rs::Result<()> {
+    use vfat_rs::VfatMetadataTrait;
 
-    #[cfg(not(feature = "std"))]
-    let path_str = path.iter().collect::<Vec<&str>>().join("/");
-    assert_eq!(expected, path_str);
+    let (vfat, _f) = init_vfat()?;
+    let nested = vfat.get_path("//folder/./some//deep/../deep/nested/".into())?;
+    assert_eq!(nested.name(), "nested");
+    assert_eq!(
+        nested.metadata.path(),
+        &Path::from("/folder/some/deep/nested")
+    );
+
+    let parent = vfat.get_path("/folder/some/deep/nested/folder/..".into())?;
+    assert_eq!(parent.name(), "nested");
+    assert_eq!(
+        parent.metadata.path(),
+        &Path::from("/folder/some/deep/nested")
+    );
+    let names = |entry: vfat_rs::VfatEntry| -> vfat_rs::Result<Vec<String>> {
+        Ok(entry
+            .into_directory()
+            .unwrap()
+            .contents()?
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect())
+    };
+    assert!(names(parent)?.contains(&"folder".to_string()));
+
+    assert_eq!(vfat.get_path("/folder/..".into())?.name(), "/");
+    assert_eq!(vfat.get_path("/..".into())?.name(), "/");
+    assert_eq!(vfat.get_path("hello.txt".into())?.name(), "hello.txt");
+    // Walking through a file fails like any other non directory component (ENOTDIR).
+    assert!(matches!(
+        vfat.get_path("/hello.txt/..".into()),
+        Err(VfatRsError::NotADirectory { .. })
+    ));
+
+    // Entries created in a new directory get the right paths, and their ".." entries point
+    // to their parent.
+    let mut outer = vfat.get_root()?.create_directory("outer".into())?;
+    assert_eq!(outer.metadata.path(), &Path::from("/outer"));
+    let mut inner = outer.create_directory("inner".into())?;
+    let file = inner.create_file("file.txt".into())?;
+    assert_eq!(inner.metadata.path(), &Path::from("/outer/inner"));
+    assert_eq!(file.metadata().path(), &Path::from("/outer/inner/file.txt"));
+    let entry = vfat.get_path("/outer/inner/../inner/./file.txt".into())?;
+    assert_eq!(entry.metadata.path(), &Path::from("/outer/inner/file.txt"));
+    let outer_again = vfat.get_path("/outer/inner/..".into())?;
+    assert_eq!(outer_again.metadata.path(), &Path::from("/outer"));
+    assert_eq!(names(outer_again)?, vec![".", "..", "inner"]);
+    Ok(())
 }
 
 #[test]
 fn test_get_path() -> vfat_rs::Result<()> {
     use vfat_rs::VfatMetadataTrait;
 
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     vfat.get_path("/not-found.txt".into()).unwrap_err();
     let file = vfat.get_path("/hello.txt".into()).unwrap();
     let local: DateTime<Utc> = Utc::now();
@@ -191,36 +248,1800 @@ fn test_get_path() -> vfat_rs::Result<()> {
         .is_ok());
     Ok(())
 }
+#[test]
+fn test_error_kinds() -> vfat_rs::Result<()> {
+    fn errno_of<T>(result: vfat_rs::Result<T>) -> i32 {
+        result.err().map(|err| err.errno()).unwrap_or(0)
+    }
+    let (vfat, f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+
+    let err = vfat.get_path("/folder/missing.txt".into()).unwrap_err();
+    assert!(matches!(err, VfatRsError::EntryNotFound { .. }));
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(err.errno(), errno::ENOENT);
+    // The context records the failed operation and its path, and it is kept when the error
+    // is turned into an I/O error.
+    let err = err.with_context("look up", "/folder/missing.txt");
+    assert_eq!(err.operation(), Some("look up"));
+    assert_eq!(err.path(), Some("/folder/missing.txt"));
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(matches!(err.cause(), VfatRsError::EntryNotFound { .. }));
+    let io_err = vfat_rs::io::Error::from(err);
+    assert_eq!(io_err.kind(), vfat_rs::io::ErrorKind::NotFound);
+    assert!(io_err.to_string().contains("/folder/missing.txt"));
+
+    let err = root.delete("folder".into()).unwrap_err();
+    assert!(matches!(err, VfatRsError::NonEmptyDirectory { .. }));
+    assert_eq!(err.errno(), errno::ENOTEMPTY);
+
+    let truncate = vfat_rs::OpenOptions {
+        truncate: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        errno_of(root.create_file("HELLO.TXT".into())),
+        errno::EEXIST
+    );
+    assert_eq!(errno_of(root.create_file("what?".into())), errno::EINVAL);
+    assert_eq!(
+        errno_of(root.create_directory("n".repeat(256))),
+        errno::ENAMETOOLONG
+    );
+    assert_eq!(
+        errno_of(root.open_at("folder".into(), truncate)),
+        errno::EISDIR
+    );
+    assert_eq!(
+        errno_of(vfat.get_path("/hello.txt/file".into())),
+        errno::ENOTDIR
+    );
+    assert!(!vfat.path_exists("/hello.txt/file".into())?);
+
+    let (dev, master_boot_record) = init_from(&f);
+    let vfat = VfatFS::new_read_only(dev, master_boot_record.partitions[0].start_sector)?;
+    let mut root = vfat.get_root()?;
+    let err = root.create_file("new.txt".into()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ReadOnly);
+    assert_eq!(err.errno(), errno::EROFS);
+    // Files written through `io::Write` report which file failed.
+    let mut file = vfat.get_path("/hello.txt".into())?.into_file().unwrap();
+    let io_err = vfat_rs::io::Write::write(&mut file, b"x").unwrap_err();
+    assert_eq!(io_err.kind(), vfat_rs::io::ErrorKind::PermissionDenied);
+    assert!(io_err.to_string().contains("/hello.txt"));
+    Ok(())
+}
+
 #[test]
 fn test_list_directory() -> vfat_rs::Result<()> {
     use vfat_rs::VfatMetadataTrait;
 
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
+    // The order depends on how the image was populated.
+    let mut names = vfat
+        .get_root()?
+        .contents()?
+        .into_iter()
+        .map(|entry| entry.name().to_string())
+        .collect::<Vec<String>>();
+    names.sort();
+    let mut expected = vec![
+        "IRISVOL",
+        "folder",
+        "MyFoLdEr",
+        "a-big-file.txt",
+        "a-very-long-file-name-entry.txt",
+        "hello.txt",
+    ]
+    .into_iter()
+    .map(Into::into)
+    .collect::<Vec<String>>();
+    expected.sort();
+    assert_eq!(names, expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_case_insensitive_lookup() -> vfat_rs::Result<()> {
+    let (vfat, _f) = init_vfat()?;
+    assert!(vfat.path_exists("/myfolder".into())?);
+    assert!(vfat.path_exists("/MYFOLDER".into())?);
+    assert!(vfat.path_exists("/HELLO.TXT".into())?);
+    assert!(vfat.path_exists("/FOLDER/Some/deep/NESTED/folder/FILE".into())?);
+
+    // Entries can also be found by their 8.3 alias:
+    let alias = vfat
+        .get_path("/a-very-long-file-name-entry.txt".into())?
+        .metadata
+        .short_name();
+    let entry = vfat.get_path(Path::from(format!("/{}", alias.to_lowercase())))?;
+    assert_eq!(entry.metadata.name(), "a-very-long-file-name-entry.txt");
+
+    let mut root = vfat.get_root()?;
+    root.create_file("README.TXT".into())?;
+    assert!(matches!(
+        root.create_file("readme.txt".into()),
+        Err(VfatRsError::NameAlreadyInUse { .. })
+    ));
+    assert!(matches!(
+        root.create_directory(alias),
+        Err(VfatRsError::NameAlreadyInUse { .. })
+    ));
+
+    // Entries sharing the same prefix get different aliases.
+    let first = root.create_file("same-prefix-first.txt".into())?;
+    let second = root.create_file("same-prefix-second.txt".into())?;
+    assert_ne!(
+        first.metadata().short_name(),
+        second.metadata().short_name()
+    );
+    root.delete("SAME-PREFIX-FIRST.TXT".into())?;
+    assert!(!vfat.path_exists("/same-prefix-first.txt".into())?);
+    assert!(vfat.path_exists("/same-prefix-second.txt".into())?);
+    Ok(())
+}
+
+/// Collects the reported diagnostics.
+#[derive(Debug, Default, Clone)]
+struct DiagnosticsCollector(std::sync::Arc<std::sync::Mutex<Vec<Diagnostic>>>);
+impl DiagnosticsTrait for DiagnosticsCollector {
+    fn report(&self, diagnostic: Diagnostic) {
+        self.0.lock().unwrap().push(diagnostic);
+    }
+}
+
+/// Returns the offset in the image of the first directory entry starting with `prefix`.
+fn find_raw_entry(image: &std::path::Path, prefix: &[u8]) -> Option<usize> {
+    let raw = std::fs::read(image).unwrap();
+    raw.chunks(32)
+        .position(|entry| entry.starts_with(prefix))
+        .map(|index| index * 32)
+}
+
+/// Returns the offset in the image of the first LFN entry (i.e. the one holding the beginning
+/// of the name) of a name starting with `prefix`.
+fn find_raw_lfn(image: &std::path::Path, prefix: &str) -> Option<usize> {
+    const LFN_ATTRIBUTES: u8 = 0x0F;
+    let prefix = prefix
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect::<Vec<u8>>();
+    let raw = std::fs::read(image).unwrap();
+    raw.chunks(32)
+        .position(|entry| {
+            entry[0] & 0x1F == 1 && entry[11] == LFN_ATTRIBUTES && entry[1..].starts_with(&prefix)
+        })
+        .map(|index| index * 32)
+}
+
+#[test]
+fn test_lfn_validation() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use vfat_rs::VfatMetadataTrait;
+
+    const CHECKSUM_OFFSET: u64 = 13;
+    let (vfat, f) = init_vfat()?;
+    vfat.get_root()?.create_file("validation-lfn.txt".into())?;
+    drop(vfat);
+
+    // Corrupt the checksum of the LFN entry preceding the regular entry.
+    let lfn_offset = find_raw_lfn(&f.fs_path, "valid").unwrap() as u64;
+    let image = OpenOptions::new().write(true).open(&f.fs_path).unwrap();
+    image
+        .write_at(&[0xAA], lfn_offset + CHECKSUM_OFFSET)
+        .unwrap();
+    drop(image);
+
+    let (dev, master_boot_record) = init_from(&f);
+    let mut vfat = VfatFS::new(dev, master_boot_record.partitions[0].start_sector)?;
+    let collector = DiagnosticsCollector::default();
+    vfat.set_diagnostics(collector.clone());
+    let mut root = vfat.get_root()?;
+    let names = root
+        .contents()?
+        .into_iter()
+        .map(|entry| entry.name().to_string())
+        .collect::<Vec<_>>();
+    // The LFN run is discarded, and the alias is used instead.
+    assert!(names.contains(&"VAL~1.TXT".to_string()));
+    assert!(!names.contains(&"validation-lfn.txt".to_string()));
+    assert!(matches!(
+        collector.0.lock().unwrap().first(),
+        Some(Diagnostic::DiscardedLfn {
+            anomaly: LfnAnomaly::ChecksumMismatch,
+            ..
+        })
+    ));
+
+    // Deleting an entry also deletes its LFN entries.
+    root.create_file("deletion-lfn.txt".into())?;
+    let regular_offset = find_raw_entry(&f.fs_path, b"DEL~1   TXT").unwrap();
+    let lfn_offset = find_raw_lfn(&f.fs_path, "delet").unwrap();
+    root.delete("deletion-lfn.txt".into())?;
+    let raw = std::fs::read(&f.fs_path).unwrap();
+    assert_eq!(raw[regular_offset], 0xE5);
+    assert_eq!(raw[lfn_offset], 0xE5);
+    Ok(())
+}
+
+#[test]
+fn test_nt_lowercase_flags() -> vfat_rs::Result<()> {
+    use vfat_rs::VfatMetadataTrait;
+
+    const NT_FLAGS_OFFSET: usize = 12;
+    let (vfat, f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+    root.create_file("notes.txt".into())?;
+    root.create_file("Mixed.txt".into())?;
+
+    // A single regular entry, flagged as lower case.
+    let regular_offset = find_raw_entry(&f.fs_path, b"NOTES   TXT").unwrap();
+    let raw = std::fs::read(&f.fs_path).unwrap();
+    assert_eq!(raw[regular_offset + NT_FLAGS_OFFSET], 0x08 | 0x10);
+    assert!(find_raw_lfn(&f.fs_path, "notes").is_none());
+    // Mixed case names still need an LFN.
+    assert!(find_raw_lfn(&f.fs_path, "Mixed").is_some());
+
+    let names = root
+        .contents()?
+        .into_iter()
+        .map(|entry| entry.name().to_string())
+        .collect::<Vec<_>>();
+    assert!(names.contains(&"notes.txt".to_string()));
+    assert!(names.contains(&"Mixed.txt".to_string()));
+    assert!(vfat.path_exists("/NOTES.TXT".into())?);
+
+    // Flags are kept when the entry is updated.
+    let mut file = vfat.get_path("/notes.txt".into())?.into_file().unwrap();
+    file.write(b"some notes")?;
+    let raw = std::fs::read(&f.fs_path).unwrap();
+    assert_eq!(raw[regular_offset + NT_FLAGS_OFFSET], 0x08 | 0x10);
     assert_eq!(
-        vfat.get_root()?
+        vfat.get_path("/notes.txt".into())?.metadata().name(),
+        "notes.txt"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_invalid_names() -> vfat_rs::Result<()> {
+    use vfat_rs::VfatMetadataTrait;
+
+    let (vfat, _f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+    let contents_before = root.contents()?.len();
+    for name in ["", "a/b", "what?", "trailing.", "aux", "LPT1.txt"] {
+        assert!(
+            matches!(
+                root.create_file(name.into()),
+                Err(VfatRsError::InvalidName { .. })
+            ),
+            "{}",
+            name
+        );
+    }
+    assert!(matches!(
+        root.create_directory("n".repeat(256)),
+        Err(VfatRsError::NameTooLong { .. })
+    ));
+    // Nothing was written.
+    assert_eq!(root.contents()?.len(), contents_before);
+
+    // The longest valid name, and a name with characters outside of ASCII.
+    let longest = "n".repeat(255);
+    root.create_file(longest.clone())?;
+    root.create_file("çà-ünïcode-😀.txt".into())?;
+    let names = root
+        .contents()?
+        .into_iter()
+        .map(|entry| entry.name().to_string())
+        .collect::<Vec<_>>();
+    assert!(names.contains(&longest));
+    assert!(names.contains(&"çà-ünïcode-😀.txt".to_string()));
+    Ok(())
+}
+
+#[test]
+fn test_directory_shrink() -> vfat_rs::Result<()> {
+    use vfat_rs::VfatMetadataTrait;
+
+    let (mut vfat, _f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+    let mut spool = root.create_directory("spool".into())?;
+    // Each file takes 3 entries: 40 files span over multiple clusters.
+    let names = (0..40)
+        .map(|i| format!("spool-file-{:02}.txt", i))
+        .collect::<Vec<_>>();
+    for name in &names {
+        spool.create_file(name.clone())?;
+    }
+    for name in names.iter().skip(1) {
+        spool.delete(name.clone())?;
+    }
+    assert!(spool.shrink()? > 0);
+    // Nothing else to free.
+    assert_eq!(spool.shrink()?, 0);
+    let contents = |dir: &vfat_rs::Directory| -> vfat_rs::Result<Vec<String>> {
+        Ok(dir
             .contents()?
             .into_iter()
             .map(|entry| entry.name().to_string())
-            .collect::<Vec<String>>(),
+            .collect())
+    };
+    assert_eq!(contents(&spool)?, vec![".", "..", "spool-file-00.txt"]);
+
+    // The directory can grow again, without stale entries showing up.
+    let mut spool = vfat.get_path("/spool".into())?.into_directory().unwrap();
+    for name in names.iter().skip(1).take(20) {
+        spool.create_file(name.clone())?;
+    }
+    let listed = contents(&spool)?;
+    assert_eq!(listed.len(), 2 + 21);
+    assert_eq!(&listed[2..], &names[..21]);
+
+    // The root cluster is never freed.
+    root.shrink()?;
+    assert!(vfat.path_exists("/hello.txt".into())?);
+
+    // Automatic mode.
+    vfat.set_auto_shrink(true);
+    let mut spool = vfat.get_path("/spool".into())?.into_directory().unwrap();
+    for name in names.iter().take(21) {
+        spool.delete(name.clone())?;
+    }
+    assert_eq!(spool.shrink()?, 0);
+    assert_eq!(contents(&spool)?, vec![".", ".."]);
+    let mut root = vfat.get_root()?;
+    root.delete("spool".into())?;
+    assert!(!vfat.path_exists("/spool".into())?);
+    Ok(())
+}
+
+#[test]
+fn test_attributes() -> vfat_rs::Result<()> {
+    use vfat_rs::{Attributes, CreateOptions, VfatMetadataTrait, VfatTimestamp};
+
+    let (vfat, _f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+
+    // New files are marked for archiving.
+    let mut file = root.create_file("attributes.txt".into())?;
+    assert!(file.metadata().attributes().is_archive());
+
+    file.set_attributes(Attributes::READ_ONLY | Attributes::HIDDEN)?;
+    let entry = vfat.get_path("/attributes.txt".into())?;
+    assert!(entry.metadata().attributes().is_read_only());
+    assert!(entry.metadata().attributes().is_hidden());
+
+    // Read-only entries can't be modified nor deleted.
+    let mut file = entry.into_file().unwrap();
+    assert!(matches!(
+        file.write(b"content"),
+        Err(VfatRsError::ReadOnlyEntry { .. })
+    ));
+    assert!(matches!(
+        file.truncate(0),
+        Err(VfatRsError::ReadOnlyEntry { .. })
+    ));
+    assert!(matches!(
+        root.delete("attributes.txt".into()),
+        Err(VfatRsError::ReadOnlyEntry { .. })
+    ));
+
+    // Once cleared, a modification sets the archive bit again.
+    file.clear_attributes(Attributes::READ_ONLY | Attributes::ARCHIVE)?;
+    assert!(!vfat
+        .get_path("/attributes.txt".into())?
+        .metadata()
+        .attributes()
+        .is_archive());
+    file.write(b"content")?;
+    file.truncate(3)?;
+    let entry = vfat.get_path("/attributes.txt".into())?;
+    assert!(entry.metadata().attributes().is_archive());
+    assert!(entry.metadata().attributes().is_hidden());
+    assert_eq!(entry.metadata().size(), 3);
+
+    // Only user attributes can be changed.
+    assert!(matches!(
+        file.set_attributes(Attributes::new_directory()),
+        Err(VfatRsError::InvalidAttributes { .. })
+    ));
+    assert!(matches!(
+        root.set_attributes(Attributes::HIDDEN),
+        Err(VfatRsError::InvalidAttributes { .. })
+    ));
+    root.delete("attributes.txt".into())?;
+
+    // Create with options.
+    let mut timestamp = VfatTimestamp::new(0);
+    timestamp
+        .set_year(2001)
+        .set_value(9u32, VfatTimestamp::MONTH)
+        .set_value(11u32, VfatTimestamp::DAY);
+    let options = CreateOptions {
+        attributes: Attributes::SYSTEM,
+        creation: Some(timestamp),
+        last_update: Some(timestamp),
+    };
+    root.create_directory_with("system-dir".into(), options)?;
+    let directory = vfat.get_path("/system-dir".into())?;
+    assert!(directory.metadata().attributes().is_system());
+    assert!(directory.metadata().attributes().is_directory());
+    assert_eq!(directory.creation().year(), 2001);
+    assert_eq!(directory.creation().day(), 11);
+    Ok(())
+}
+
+#[test]
+fn test_label_and_serial() -> vfat_rs::Result<()> {
+    let (vfat, f) = init_vfat()?;
+    assert_eq!(vfat.label()?, "IRISVOL");
+
+    vfat.set_label("provision")?;
+    assert_eq!(vfat.label()?, "PROVISION");
+    vfat.set_serial(0xDEADBEEF)?;
+    assert_eq!(vfat.serial()?, 0xDEADBEEF);
+
+    for label in [
+        "",
+        " lead",
+        "bad/label",
+        "twelve chars",
+        "ünicode",
+        "no name",
+    ] {
+        assert!(
+            matches!(vfat.set_label(label), Err(VfatRsError::InvalidLabel { .. })),
+            "{}",
+            label
+        );
+    }
+    assert_eq!(vfat.label()?, "PROVISION");
+
+    // Both the primary and the backup boot sector have been updated.
+    let (mut dev, master_boot_record) = init_from(&f);
+    let start_sector = master_boot_record
+        .get_vfat_partition(0)
+        .unwrap()
+        .start_sector;
+    let fullbpb = VfatFS::read_fullebpb(&mut dev, start_sector)?;
+    assert_eq!(&fullbpb.extended.volume_label_string, b"PROVISION  ");
+    assert_eq!(fullbpb.extended.volumeid_serial_number, 0xDEADBEEF);
+    assert_ne!(fullbpb.extended.backup_boot_sector, 0);
+    let backup = VfatFS::read_fullebpb(
+        &mut dev,
+        start_sector + fullbpb.extended.backup_boot_sector as u32,
+    )?;
+    assert_eq!(&backup.extended.volume_label_string, b"PROVISION  ");
+    assert_eq!(backup.extended.volumeid_serial_number, 0xDEADBEEF);
+
+    // And the volume entry in the root directory.
+    let vfat = VfatFS::new(dev, start_sector)?;
+    assert_eq!(vfat.label()?, "PROVISION");
+    assert_eq!(vfat.serial()?, 0xDEADBEEF);
+    let root = vfat.get_root()?;
+    assert!(root
+        .contents()?
+        .iter()
+        .all(|entry| entry.metadata.name() != "PROVISION"));
+    Ok(())
+}
+
+#[test]
+fn test_volume_stats() -> vfat_rs::Result<()> {
+    let (vfat, f) = init_vfat()?;
+    // FSInfo is only a hint: the exact scan fixes it if needed.
+    let initial = vfat.stats_exact()?;
+    assert_eq!(vfat.stats()?, initial);
+    assert_eq!(initial.fat_type, vfat_rs::FatType::Fat32);
+    assert_eq!(initial.label, "IRISVOL");
+    assert_eq!(initial.serial, vfat.serial()?);
+    assert_eq!(initial.sector_size, 512);
+    assert_eq!(initial.cluster_size, 512);
+    assert_eq!(initial.fat_amount, 2);
+    assert!(initial.reserved_sectors > 0);
+    assert!(initial.free_clusters > 0 && initial.used_clusters() > 0);
+    assert_eq!(
+        initial.total_bytes(),
+        initial.free_bytes() + initial.used_bytes()
+    );
+
+    // Allocations and deletions keep FSInfo up to date.
+    let mut root = vfat.get_root()?;
+    let (name, _) = random_name("stats");
+    let mut file = root.create_file(name.clone())?;
+    file.write(&[b'x'; 1500])?;
+    let stats = vfat.stats()?;
+    assert_eq!(stats, vfat.stats_exact()?);
+    assert!(stats.free_clusters <= initial.free_clusters - 3);
+
+    // The clusters of an open file are only freed once it's closed.
+    drop(file);
+    root.delete(name)?;
+    let after_delete = vfat.stats()?;
+    assert_eq!(after_delete, vfat.stats_exact()?);
+    // The root directory might have grown to fit the new entry.
+    assert!(after_delete.free_clusters >= stats.free_clusters + 3);
+
+    // The count is kept in memory, and written to FSInfo on unmount.
+    root.create_file(random_name("stats").0)?.write_all(b"x")?;
+    let mounted = vfat.stats()?;
+    assert!(mounted.free_clusters < after_delete.free_clusters);
+    let (dev, master_boot_record) = init_from(&f);
+    let start = master_boot_record.partitions[0].start_sector;
+    assert_eq!(VfatFS::new(dev, start)?.stats()?, after_delete);
+    drop(root);
+    vfat.unmount()?;
+    let (dev, _) = init_from(&f);
+    assert_eq!(VfatFS::new(dev, start)?.stats()?, mounted);
+    Ok(())
+}
+
+#[test]
+fn test_walk() -> vfat_rs::Result<()> {
+    use vfat_rs::{Attributes, WalkOptions, WalkOrder};
+
+    let (vfat, _f) = init_vfat()?;
+    let relative_paths = |walk: vfat_rs::Walk| -> vfat_rs::Result<Vec<String>> {
+        walk.map(|entry| entry.map(|entry| entry.relative_path))
+            .collect()
+    };
+    let position = |paths: &[String], path: &str| paths.iter().position(|p| p == path).unwrap();
+
+    let pre_order = relative_paths(vfat.walk("/".into())?)?;
+    for path in [
+        "folder",
+        "folder/some/deep/nested/folder",
+        "folder/some/deep/nested/folder/file",
+        "MyFoLdEr",
+        "hello.txt",
+    ] {
+        assert!(pre_order.contains(&path.to_string()), "{}", path);
+    }
+    assert!(!pre_order.iter().any(|p| p.ends_with('.')));
+    // The volume label is an entry of the root, but not a file.
+    assert!(!pre_order.contains(&"IRISVOL".to_string()));
+    assert!(position(&pre_order, "folder") < position(&pre_order, "folder/some"));
+
+    let options = WalkOptions {
+        order: WalkOrder::PostOrder,
+        ..Default::default()
+    };
+    let post_order = relative_paths(vfat.walk_with("/".into(), options)?)?;
+    assert_eq!(post_order.len(), pre_order.len());
+    assert!(position(&post_order, "folder") > position(&post_order, "folder/some"));
+
+    let options = WalkOptions {
+        max_depth: Some(2),
+        ..Default::default()
+    };
+    let shallow = vfat.walk_with("/folder".into(), options)?;
+    assert_eq!(relative_paths(shallow)?, vec!["some", "some/deep"]);
+
+    vfat.get_path("/hello.txt".into())?
+        .set_attributes(Attributes::HIDDEN)?;
+    let options = WalkOptions {
+        skip_hidden: true,
+        ..Default::default()
+    };
+    let visible = relative_paths(vfat.walk_with("/".into(), options)?)?;
+    assert_eq!(visible.len(), pre_order.len() - 1);
+    assert!(!visible.contains(&"hello.txt".to_string()));
+    Ok(())
+}
+
+#[test]
+fn test_walk_loop_detection() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    const CLUSTER_HIGH_OFFSET: usize = 20;
+    const CLUSTER_LOW_OFFSET: usize = 26;
+    let (vfat, f) = init_vfat()?;
+    vfat.get_root()?
+        .create_directory("LOOPA".into())?
+        .create_directory("LOOPB".into())?;
+    drop(vfat);
+
+    // Cross-link LOOPB to its parent.
+    let raw = std::fs::read(&f.fs_path).unwrap();
+    let parent = find_raw_entry(&f.fs_path, b"LOOPA      ").unwrap();
+    let child = find_raw_entry(&f.fs_path, b"LOOPB      ").unwrap();
+    let image = OpenOptions::new().write(true).open(&f.fs_path).unwrap();
+    for offset in [CLUSTER_HIGH_OFFSET, CLUSTER_LOW_OFFSET] {
+        image
+            .write_at(&raw[parent + offset..][..2], (child + offset) as u64)
+            .unwrap();
+    }
+    drop(image);
+
+    let (dev, master_boot_record) = init_from(&f);
+    let mut vfat = VfatFS::new(dev, master_boot_record.partitions[0].start_sector)?;
+    let collector = DiagnosticsCollector::default();
+    vfat.set_diagnostics(collector.clone());
+    let paths = vfat
+        .walk("/LOOPA".into())?
+        .map(|entry| entry.map(|entry| entry.relative_path))
+        .collect::<vfat_rs::Result<Vec<_>>>()?;
+    assert_eq!(paths, vec!["LOOPB"]);
+    assert!(matches!(
+        collector.0.lock().unwrap().as_slice(),
+        [Diagnostic::DirectoryLoop { .. }]
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_glob_and_du() -> vfat_rs::Result<()> {
+    use vfat_rs::VfatMetadataTrait;
+
+    let (vfat, _f) = init_vfat()?;
+    let names = |glob: vfat_rs::Glob| -> vfat_rs::Result<Vec<String>> {
+        let mut names = glob
+            .map(|entry| entry.map(|entry| entry.name().to_string()))
+            .collect::<vfat_rs::Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    };
+    assert_eq!(
+        names(vfat.glob("/*.TXT")?)?,
         vec![
-            "IRISVOL",
-            "folder",
-            "MyFoLdEr",
             "a-big-file.txt",
             "a-very-long-file-name-entry.txt",
             "hello.txt"
         ]
+    );
+    assert_eq!(names(vfat.glob("/folder/**/file")?)?, vec!["file"]);
+    assert_eq!(names(vfat.glob("/folder/*/deep")?)?, vec!["deep"]);
+    assert_eq!(names(vfat.glob("/hello.txt")?)?, vec!["hello.txt"]);
+    assert!(names(vfat.glob("/folder/*.txt")?)?.is_empty());
+    assert!(names(vfat.glob("/IRIS*")?)?.is_empty());
+
+    let usage = vfat.du("/".into())?;
+    assert_eq!(usage.files, 4);
+
+    // 5 directories of 1 cluster each, and an empty file.
+    let usage = vfat.du("/folder".into())?;
+    assert_eq!(usage.directories, 5);
+    assert_eq!(usage.files, 1);
+    assert_eq!(usage.logical_size, 0);
+    assert_eq!(usage.allocated_size, 5 * 512);
+
+    let size = vfat.get_path("/a-big-file.txt".into())?.metadata.size() as u64;
+    let usage = vfat.du("/a-big-file.txt".into())?;
+    assert_eq!(usage.files, 1);
+    assert_eq!(usage.logical_size, size);
+    assert_eq!(usage.allocated_size, size.div_ceil(512) * 512);
+    Ok(())
+}
+
+#[test]
+fn test_directory_relative_operations() -> vfat_rs::Result<()> {
+    use vfat_rs::VfatMetadataTrait;
+
+    let (vfat, _f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+    let mut work = root.create_dir_at("work".into())?;
+    root.create_dir_at("work/sub".into())?;
+    let create = vfat_rs::OpenOptions {
+        create: true,
+        ..Default::default()
+    };
+    let mut notes = work
+        .open_at("sub/../notes.txt".into(), create.clone())?
+        .into_file()
+        .unwrap();
+    assert_eq!(notes.metadata().path(), &Path::from("/work/notes.txt"));
+    notes.write(b"hello")?;
+    assert_eq!(vfat.get_path("/work/notes.txt".into())?.metadata.size(), 5);
+
+    // Opening existing entries.
+    let opened = work.open_at("notes.txt".into(), create)?;
+    assert_eq!(opened.metadata.size(), 5);
+    assert_eq!(work.open_at("..".into(), Default::default())?.name(), "/");
+    assert!(matches!(
+        work.open_at("missing.txt".into(), Default::default()),
+        Err(VfatRsError::EntryNotFound { .. })
+    ));
+    let create_new = vfat_rs::OpenOptions {
+        create_new: true,
+        ..Default::default()
+    };
+    assert!(matches!(
+        work.open_at("notes.txt".into(), create_new),
+        Err(VfatRsError::NameAlreadyInUse { .. })
+    ));
+    let truncate = vfat_rs::OpenOptions {
+        truncate: true,
+        ..Default::default()
+    };
+    assert!(matches!(
+        work.open_at("sub".into(), truncate.clone()),
+        Err(VfatRsError::IsADirectory { .. })
+    ));
+
+    // Renaming inside the same directory, then changing case.
+    work.rename("notes.txt".into(), "sub/moved.txt".into())?;
+    assert!(!vfat.path_exists("/work/notes.txt".into())?);
+    work.rename("sub/moved.txt".into(), "sub/MOVED.txt".into())?;
+    let mut moved = vfat
+        .get_path("/work/sub/MOVED.txt".into())?
+        .into_file()
+        .unwrap();
+    let mut buf = [0; 5];
+    moved.read(&mut buf)?;
+    assert_eq!(&buf, b"hello");
+    assert!(matches!(
+        work.rename("sub".into(), "sub/MOVED.txt".into()),
+        Err(VfatRsError::NameAlreadyInUse { .. })
+    ));
+
+    // Moving a directory to another directory updates its ".." entry.
+    work.rename_at("sub".into(), &mut root, "top".into())?;
+    assert!(!vfat.path_exists("/work/sub".into())?);
+    assert_eq!(vfat.get_path("/top/..".into())?.name(), "/");
+    let top_contents = vfat
+        .get_path("/top/../top".into())?
+        .into_directory()
+        .unwrap()
+        .contents()?
         .into_iter()
-        .map(Into::into)
-        .collect::<Vec<String>>()
+        .map(|entry| entry.name().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(top_contents, vec![".", "..", "MOVED.txt"]);
+    root.rename("work".into(), "top/work".into())?;
+    assert_eq!(vfat.get_path("/top/work/..".into())?.name(), "top");
+    assert!(matches!(
+        root.rename("top".into(), "top/work/top".into()),
+        Err(VfatRsError::InvalidMove { .. })
+    ));
+
+    // Truncating, then removing everything.
+    let truncated = root.open_at("top/MOVED.txt".into(), truncate)?;
+    assert_eq!(truncated.metadata.size(), 0);
+    root.remove_at("top/MOVED.txt".into())?;
+    root.remove_at("top/work".into())?;
+    root.remove_at("top".into())?;
+    assert!(!vfat.path_exists("/top".into())?);
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_handles() -> vfat_rs::Result<()> {
+    const THREADS: usize = 4;
+    const FILES_PER_THREAD: usize = 6;
+    let (vfat, _f) = init_vfat()?;
+    vfat.get_root()?.create_directory("threads".into())?;
+
+    // Writers share the same directory, and allocate clusters at the same time. Every file
+    // spans a few clusters, filled with a byte identifying it.
+    let writers = (0..THREADS).map(|thread| {
+        let vfat = vfat.clone();
+        std::thread::spawn(move || -> vfat_rs::Result<()> {
+            let mut directory = vfat
+                .get_path("/threads".into())?
+                .into_directory_or_not_found()?;
+            for i in 0..FILES_PER_THREAD {
+                let mut file = directory.create_file(format!("file-{}-{}.bin", thread, i))?;
+                file.write_all(&[(thread * FILES_PER_THREAD + i) as u8; 1500])?;
+            }
+            Ok(())
+        })
+    });
+    let writers: Vec<_> = writers.collect();
+    let reader = {
+        let vfat = vfat.clone();
+        std::thread::spawn(move || -> vfat_rs::Result<()> {
+            for _ in 0..20 {
+                vfat.get_path("/threads".into())?
+                    .into_directory_or_not_found()?
+                    .contents()?;
+            }
+            Ok(())
+        })
+    };
+    for handle in writers.into_iter().chain([reader]) {
+        handle.join().unwrap()?;
+    }
+
+    let directory = vfat
+        .get_path("/threads".into())?
+        .into_directory_or_not_found()?;
+    assert_eq!(directory.contents()?.len(), 2 + THREADS * FILES_PER_THREAD);
+    for thread in 0..THREADS {
+        for i in 0..FILES_PER_THREAD {
+            let path = format!("/threads/file-{}-{}.bin", thread, i);
+            let mut file = vfat.get_path(path.into())?.into_file().unwrap();
+            let mut buf = vec![0; 1500];
+            assert_eq!(file.read(&mut buf)?, 1500);
+            let expected = (thread * FILES_PER_THREAD + i) as u8;
+            assert!(buf.iter().all(|byte| *byte == expected));
+        }
+    }
+    assert_eq!(vfat.stats()?, vfat.stats_exact()?);
+    Ok(())
+}
+
+#[test]
+fn test_open_file_table() -> vfat_rs::Result<()> {
+    use vfat_rs::DeletePolicy;
+    let (mut vfat, _f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+
+    // Handles to the same file share size and clusters.
+    let mut writer = root.create_file("shared.txt".into())?;
+    let mut reader = vfat.get_path("/shared.txt".into())?.into_file().unwrap();
+    writer.write_all(b"hello")?;
+    assert_eq!(reader.size(), 5);
+    let mut buf = [0; 16];
+    assert_eq!(reader.read(&mut buf)?, 5);
+    assert_eq!(&buf[..5], b"hello");
+    reader.seek(SeekFrom::End(0))?;
+    reader.write_all(b" world")?;
+    writer.seek(SeekFrom::Start(0))?;
+    assert_eq!(writer.read(&mut buf)?, 11);
+    assert_eq!(&buf[..11], b"hello world");
+    assert_eq!(vfat.get_path("/shared.txt".into())?.metadata.size(), 11);
+
+    // Renaming an open file is seen by its handles.
+    root.rename("shared.txt".into(), "renamed.txt".into())?;
+    writer.write_all(b"!")?;
+    assert_eq!(writer.metadata().path(), "/renamed.txt");
+    assert_eq!(vfat.get_path("/renamed.txt".into())?.metadata.size(), 12);
+
+    // By default, an open file can be deleted: its handles keep working, and its clusters
+    // are freed once the last one is dropped.
+    root.delete("renamed.txt".into())?;
+    assert!(!vfat.path_exists("/renamed.txt".into())?);
+    writer.seek(SeekFrom::Start(0))?;
+    assert_eq!(writer.read(&mut buf)?, 12);
+    assert_eq!(&buf[..12], b"hello world!");
+    // The name can be reused right away.
+    root.create_file("renamed.txt".into())?;
+    assert_eq!(vfat.get_path("/renamed.txt".into())?.metadata.size(), 0);
+    let still_open = vfat.stats_exact()?;
+    drop(writer);
+    assert_eq!(vfat.stats_exact()?, still_open);
+    drop(reader);
+    assert_eq!(
+        vfat.stats_exact()?.free_clusters,
+        still_open.free_clusters + 1
+    );
+
+    // Otherwise, deleting it fails.
+    vfat.set_delete_policy(DeletePolicy::Deny);
+    let mut root = vfat.get_root()?;
+    let file = vfat.get_path("/renamed.txt".into())?.into_file().unwrap();
+    assert!(matches!(
+        root.delete("renamed.txt".into()),
+        Err(VfatRsError::FileInUse { .. })
+    ));
+    drop(file);
+    root.delete("renamed.txt".into())?;
+    Ok(())
+}
+
+#[test]
+fn test_check_and_repair() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use vfat_rs::{CheckOptions, Problem};
+
+    const CLUSTER_HIGH_OFFSET: usize = 20;
+    const CLUSTER_LOW_OFFSET: usize = 26;
+    const SIZE_OFFSET: usize = 28;
+    let (vfat, f) = init_vfat()?;
+    let report = vfat.check(CheckOptions::default())?;
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert!(report.files > 0 && report.directories > 0);
+
+    let mut root = vfat.get_root()?;
+    let lost_content = [b'l'; 1100];
+    for (name, content) in [
+        ("SIZED.TXT", &[b's'; 1500][..]),
+        ("LOST.BIN", &lost_content[..]),
+        ("CROSS.TXT", &[b'c'; 600][..]),
+        ("a-long-orphan-name.txt", &b"orphan"[..]),
+    ] {
+        root.create_file(name.into())?.write_all(content)?;
+    }
+    // Makes the FSInfo free count known.
+    vfat.stats_exact()?;
+    drop(root);
+    vfat.unmount()?;
+    let (mut dev, master_boot_record) = init_from(&f);
+    let start = master_boot_record.partitions[0].start_sector;
+    let ebpb = VfatFS::read_fullebpb(&mut dev, start)?;
+    let fsinfo_sector = start + ebpb.extended.fsinfo_sector as u32;
+    let second_fat = start + ebpb.bpb.reserved_sectors as u32 + ebpb.extended.sectors_per_fat;
+    let last_of_second_fat = second_fat + ebpb.extended.sectors_per_fat - 1;
+    // Every copy of the FAT is written.
+    let report = VfatFS::new(dev, start)?.check(CheckOptions::default())?;
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    // A size not matching the chain, an entry removed without freeing its clusters, a
+    // cross-linked file, an LFN run whose regular entry got an invalid name, a wrong
+    // FSInfo free count, and a FAT copy differing from the first FAT.
+    let raw = std::fs::read(&f.fs_path).unwrap();
+    let sized = find_raw_entry(&f.fs_path, b"SIZED   TXT").unwrap();
+    let lost = find_raw_entry(&f.fs_path, b"LOST    BIN").unwrap();
+    let cross = find_raw_entry(&f.fs_path, b"CROSS   TXT").unwrap();
+    let orphan = find_raw_entry(&f.fs_path, b"A-L~1   TXT").unwrap();
+    let image = OpenOptions::new().write(true).open(&f.fs_path).unwrap();
+    image
+        .write_at(&5000u32.to_le_bytes(), (sized + SIZE_OFFSET) as u64)
+        .unwrap();
+    image.write_at(&[0xE5], lost as u64).unwrap();
+    for offset in [CLUSTER_HIGH_OFFSET, CLUSTER_LOW_OFFSET] {
+        image
+            .write_at(&raw[sized + offset..][..2], (cross + offset) as u64)
+            .unwrap();
+    }
+    image.write_at(b"*", orphan as u64).unwrap();
+    image
+        .write_at(&12345u32.to_le_bytes(), fsinfo_sector as u64 * 512 + 488)
+        .unwrap();
+    image
+        .write_at(
+            &0x0FFF_FFF7u32.to_le_bytes(),
+            last_of_second_fat as u64 * 512,
+        )
+        .unwrap();
+    drop(image);
+
+    let (dev, master_boot_record) = init_from(&f);
+    let vfat = VfatFS::new(dev, master_boot_record.partitions[0].start_sector)?;
+    let report = vfat.check(CheckOptions::default())?;
+    let problems = &report.problems;
+    assert!(!report.repaired);
+    assert!(problems.contains(&Problem::SizeMismatch {
+        path: "/SIZED.TXT".into(),
+        size: 5000,
+        clusters: 3,
+    }));
+    assert!(
+        problems.iter().any(|problem| matches!(problem,
+        Problem::CrossLinked { path, other, .. } if path == "/CROSS.TXT" && other == "/SIZED.TXT"))
+    );
+    let lost_chains = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::LostChain { clusters, .. } => Some(*clusters),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(lost_chains.len(), 2, "{:?}", problems);
+    assert!(lost_chains.contains(&3) && lost_chains.contains(&2));
+    assert!(problems.iter().any(|problem| matches!(
+        problem,
+        Problem::OrphanLfn { directory, entries: 2, .. } if directory == "/"
+    )));
+    assert!(problems.contains(&Problem::InvalidShortName {
+        path: "/*-L~1.TXT".into()
+    }));
+    assert!(problems
+        .iter()
+        .any(|problem| matches!(problem, Problem::FatCopyMismatch { copy: 1, .. })));
+    assert!(problems
+        .iter()
+        .any(|problem| matches!(problem, Problem::FreeCountMismatch { stored: 12345, .. })));
+
+    let repaired = vfat.check(CheckOptions { repair: true })?;
+    assert!(repaired.repaired);
+    assert_eq!(repaired.problems, report.problems);
+    let report = vfat.check(CheckOptions::default())?;
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert_eq!(vfat.stats()?, vfat.stats_exact()?);
+
+    // The repairs are on disk, and the lost chains were saved.
+    let (dev, master_boot_record) = init_from(&f);
+    let vfat = VfatFS::new(dev, master_boot_record.partitions[0].start_sector)?;
+    assert!(vfat.check(CheckOptions::default())?.is_clean());
+    assert_eq!(vfat.get_path("/SIZED.TXT".into())?.metadata.size(), 1536);
+    assert_eq!(vfat.get_path("/CROSS.TXT".into())?.metadata.size(), 0);
+    assert!(vfat.path_exists("/_-L~1.TXT".into())?);
+    let mut recovered = (0..2)
+        .map(|number| {
+            let path = format!("/FSCK{:04}.REC", number);
+            vfat.get_path(path.as_str().into())
+                .map(|entry| entry.into_file().unwrap())
+        })
+        .collect::<vfat_rs::Result<Vec<_>>>()?;
+    let mut buf = [0; 1536];
+    let lost_file = recovered
+        .iter_mut()
+        .find(|file| file.size() == 1536)
+        .unwrap();
+    assert_eq!(lost_file.read(&mut buf)?, 1536);
+    assert_eq!(&buf[..lost_content.len()], lost_content);
+    Ok(())
+}
+
+#[test]
+fn test_corrupted_chains() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    const CLUSTER_HIGH_OFFSET: usize = 20;
+    const CLUSTER_LOW_OFFSET: usize = 26;
+    const OUT_OF_RANGE: u32 = 0x0FFF_FFE0;
+    let (vfat, f) = init_vfat()?;
+    let mut root = vfat.get_root()?;
+    root.create_file("FAR.TXT".into())?
+        .write_all(&[b'f'; 600])?;
+    root.create_file("WILD.TXT".into())?.write_all(b"wild")?;
+    root.create_file("LOOP.TXT".into())?
+        .write_all(&[b'l'; 600])?;
+    let mut directory = root.create_directory("LOOPDIR".into())?;
+    // Fill the first cluster of the directory, so that its entries don't end there.
+    for index in 0..20 {
+        directory.create_file(format!("F{}", index))?;
+    }
+    drop((root, directory, vfat));
+
+    let (mut dev, master_boot_record) = init_from(&f);
+    let start = master_boot_record.partitions[0].start_sector;
+    let reserved = VfatFS::read_fullebpb(&mut dev, start)?.bpb.reserved_sectors as u32;
+    let fat_offset = |cluster: u32| (start + reserved) as u64 * 512 + cluster as u64 * 4;
+    let raw = std::fs::read(&f.fs_path).unwrap();
+    let first_cluster = |name: &[u8]| {
+        let entry = find_raw_entry(&f.fs_path, name).unwrap();
+        let half =
+            |offset: usize| u16::from_le_bytes([raw[entry + offset], raw[entry + offset + 1]]);
+        (
+            (half(CLUSTER_HIGH_OFFSET) as u32) << 16 | half(CLUSTER_LOW_OFFSET) as u32,
+            entry,
+        )
+    };
+    let (far, _) = first_cluster(b"FAR     TXT");
+    let (_, wild) = first_cluster(b"WILD    TXT");
+    let (loop_dir, _) = first_cluster(b"LOOPDIR    ");
+    let (loop_file, _) = first_cluster(b"LOOP    TXT");
+    let image = OpenOptions::new().write(true).open(&f.fs_path).unwrap();
+    // FAR.TXT links past the last cluster, WILD.TXT starts there, LOOPDIR and LOOP.TXT loop on
+    // their first cluster.
+    image
+        .write_at(&OUT_OF_RANGE.to_le_bytes(), fat_offset(far))
+        .unwrap();
+    image
+        .write_at(
+            &((OUT_OF_RANGE >> 16) as u16).to_le_bytes(),
+            (wild + CLUSTER_HIGH_OFFSET) as u64,
+        )
+        .unwrap();
+    for cluster in [loop_dir, loop_file] {
+        image
+            .write_at(&cluster.to_le_bytes(), fat_offset(cluster))
+            .unwrap();
+    }
+    drop(image);
+
+    let (dev, master_boot_record) = init_from(&f);
+    let vfat = VfatFS::new(dev, master_boot_record.partitions[0].start_sector)?;
+    let is_corrupted =
+        |result: vfat_rs::Result<usize>| matches!(result, Err(VfatRsError::CorruptedChain { .. }));
+    let mut buf = [0; 1024];
+    let mut far_file = vfat.get_path("/FAR.TXT".into())?.into_file().unwrap();
+    assert!(is_corrupted(far_file.read(&mut buf)));
+    let mut wild_file = vfat.get_path("/WILD.TXT".into())?.into_file().unwrap();
+    assert!(is_corrupted(wild_file.read(&mut buf)));
+    drop((far_file, wild_file));
+    assert!(matches!(
+        vfat.get_root()?.delete("FAR.TXT".into()),
+        Err(VfatRsError::CorruptedChain { .. })
+    ));
+    assert!(matches!(
+        vfat.get_path("/LOOPDIR".into())?
+            .into_directory()
+            .unwrap()
+            .contents(),
+        Err(VfatRsError::CorruptedChain { .. })
+    ));
+    // Writing far enough into a looping chain fails instead of going around forever.
+    let mut loop_file = vfat.get_path("/LOOP.TXT".into())?.into_file().unwrap();
+    loop_file.seek(SeekFrom::Start(vfat.stats()?.total_bytes()))?;
+    assert!(is_corrupted(loop_file.write(b"w")));
+    Ok(())
+}
+
+#[test]
+fn test_mount_status() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use vfat_rs::{CheckOptions, MountStatus};
+
+    const CLEAN_SHUTDOWN: u32 = 1 << 27;
+    const NO_HARD_ERRORS: u32 = 1 << 26;
+    let (vfat, f) = init_vfat()?;
+    assert_eq!(vfat.mount_status(), MountStatus::default());
+    let (mut dev, master_boot_record) = init_from(&f);
+    let start = master_boot_record.partitions[0].start_sector;
+    let reserved = VfatFS::read_fullebpb(&mut dev, start)?.bpb.reserved_sectors as u32;
+    // FAT[1], in the first FAT.
+    let state_offset = (start + reserved) as u64 * 512 + 4;
+    let image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&f.fs_path)
+        .unwrap();
+    let state = || {
+        let mut raw = [0; 4];
+        image.read_exact_at(&mut raw, state_offset).unwrap();
+        u32::from_le_bytes(raw)
+    };
+    let mount = || {
+        let (dev, master_boot_record) = init_from(&f);
+        VfatFS::new(dev, master_boot_record.partitions[0].start_sector)
+    };
+
+    // Reading leaves the volume clean, the first write marks it as dirty.
+    vfat.get_root()?.contents()?;
+    assert_ne!(state() & CLEAN_SHUTDOWN, 0);
+    vfat.get_root()?.create_file("dirty.txt".into())?;
+    assert_eq!(state() & CLEAN_SHUTDOWN, 0);
+
+    // Without an unmount, the next mount finds it dirty. It stays dirty until it's checked.
+    drop(vfat);
+    let vfat = mount()?;
+    let status = vfat.mount_status();
+    assert_eq!(
+        status,
+        MountStatus {
+            was_dirty: true,
+            hard_errors: false
+        }
     );
+    assert!(status.needs_check());
+    vfat.clone().unmount()?;
+    assert_eq!(state() & CLEAN_SHUTDOWN, 0);
+    assert!(vfat.check(CheckOptions { repair: true })?.repaired);
+    vfat.unmount()?;
+    assert_ne!(state() & CLEAN_SHUTDOWN, 0);
+    let vfat = mount()?;
+    assert!(!vfat.mount_status().needs_check());
+    assert!(vfat.check(CheckOptions::default())?.is_clean());
+    drop(vfat);
+
+    // Hard errors are reported too.
+    image
+        .write_all_at(&(state() & !NO_HARD_ERRORS).to_le_bytes(), state_offset)
+        .unwrap();
+    let vfat = mount()?;
+    let status = vfat.mount_status();
+    assert!(status.hard_errors && !status.was_dirty);
+    // A clean check clears the flag on unmount, even if nothing was written.
+    assert!(vfat.check(CheckOptions::default())?.is_clean());
+    vfat.unmount()?;
+    assert_ne!(state() & NO_HARD_ERRORS, 0);
+    assert_ne!(state() & CLEAN_SHUTDOWN, 0);
+    assert_eq!(mount()?.mount_status(), MountStatus::default());
+    Ok(())
+}
 
+#[test]
+fn test_read_only_mount() -> vfat_rs::Result<()> {
+    use vfat_rs::CheckOptions;
+
+    let dev = common::SharedBlockDevice::new(MemoryBlockDevice::new(common::memory_image()));
+    let image = || dev.inner.lock().unwrap().image().to_vec();
+    let vfat = VfatFS::new(dev.clone(), common::MEMORY_PARTITION_START)?;
+    let mut root = vfat.get_root()?;
+    root.create_file("ro.txt".into())?.write_all(b"read only")?;
+    root.create_directory("ro-dir".into())?;
+    vfat.unmount()?;
+    drop(root);
+    let before = image();
+
+    let vfat = VfatFS::new_read_only(dev.clone(), common::MEMORY_PARTITION_START)?;
+    assert!(vfat.is_read_only());
+
+    let mut file = vfat.get_path("/ro.txt".into())?.into_file().unwrap();
+    let mut buf = [0; 9];
+    assert_eq!(file.read(&mut buf)?, 9);
+    assert_eq!(&buf, b"read only");
+    assert!(vfat.path_exists("/ro-dir".into())?);
+    vfat.stats_exact()?;
+    assert!(!vfat.check(CheckOptions::default())?.repaired);
+
+    let read_only = |result: vfat_rs::Result<()>| {
+        assert!(
+            matches!(result, Err(VfatRsError::ReadOnlyFilesystem)),
+            "{:?}",
+            result
+        )
+    };
+    let mut root = vfat.get_root()?;
+    read_only(file.write(b"x").map(|_| ()));
+    read_only(file.truncate(0));
+    read_only(root.create_file("new.txt".into()).map(|_| ()));
+    read_only(root.create_directory("new-dir".into()).map(|_| ()));
+    read_only(root.delete("ro.txt".into()));
+    read_only(root.rename("/ro.txt".into(), "/moved.txt".into()));
+    read_only(vfat.set_label("RO"));
+    read_only(vfat.set_serial(42));
+    read_only(vfat.check(CheckOptions { repair: true }).map(|_| ()));
+    drop(file);
+    vfat.unmount()?;
+
+    // Nothing reached the device.
+    assert!(image() == before);
+    Ok(())
+}
+
+#[test]
+fn test_memory_block_devices() -> vfat_rs::Result<()> {
+    use vfat_rs::SliceBlockDevice;
+
+    let image = common::memory_image();
+    let mut dev = MemoryBlockDevice::new(image.clone());
+    let master_boot_record = MasterBootRecord::try_load(&mut dev)?;
+    let start = master_boot_record.partitions[0].start_sector;
+
+    // Changes stay in memory.
+    let vfat = VfatFS::new(dev, start)?;
+    let mut root = vfat.get_root()?;
+    root.create_file("memory.txt".into())?
+        .write_all(b"in memory")?;
+    let mut file = vfat.get_path("/memory.txt".into())?.into_file().unwrap();
+    let mut buf = [0; 9];
+    assert_eq!(file.read(&mut buf)?, 9);
+    assert_eq!(&buf, b"in memory");
+
+    // Like an image embedded with `include_bytes!`.
+    let embedded: &'static [u8] = image.leak();
+    let vfat = VfatFS::new_read_only(SliceBlockDevice::new(embedded), start)?;
+    let mut hello = vfat.get_path("/hello.txt".into())?.into_file().unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(hello.read(&mut buf)?, 16);
+    assert_eq!(&buf, b"Hello, Iris OS!\n");
+    assert!(!vfat.path_exists("/memory.txt".into())?);
+    let report = vfat.check(Default::default())?;
+    assert!(report.is_clean(), "{:?}", report);
+    assert!(matches!(
+        vfat.get_root()?.create_file("new.txt".into()),
+        Err(VfatRsError::ReadOnlyFilesystem)
+    ));
+    // Even if the volume is not mounted read-only, the device can't be written.
+    let vfat = VfatFS::new(SliceBlockDevice::new(embedded), start)?;
+    assert!(vfat.get_root()?.create_file("new.txt".into()).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_invalid_boot_sector() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let (_vfat, f) = init_vfat()?;
+    let (_, master_boot_record) = init_from(&f);
+    let start = master_boot_record.partitions[0].start_sector;
+    let boot_sector = start as u64 * 512;
+    let image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&f.fs_path)
+        .unwrap();
+    let mut original = [0; 512];
+    image.read_exact_at(&mut original, boot_sector).unwrap();
+    // Without a backup boot sector to fall back to, the primary's errors are reported.
+    image
+        .write_all_at(&[0; 512], boot_sector + 6 * 512)
+        .unwrap();
+    let mount = || {
+        let (dev, _) = init_from(&f);
+        VfatFS::new(dev, start)
+    };
+
+    // Offset in the boot sector, corrupted value, expected error.
+    type Matcher = fn(&VfatRsError) -> bool;
+    let cases: &[(u64, &[u8], Matcher)] = &[
+        (510, &[0, 0], |e| {
+            matches!(e, VfatRsError::InvalidBootSignature { signature: 0 })
+        }),
+        (11, &1000u16.to_le_bytes(), |e| {
+            matches!(e, VfatRsError::InvalidBytesPerSector { .. })
+        }),
+        (13, &[3], |e| {
+            matches!(e, VfatRsError::InvalidSectorsPerCluster { .. })
+        }),
+        (13, &[0], |e| {
+            matches!(e, VfatRsError::InvalidSectorsPerCluster { .. })
+        }),
+        (16, &[0], |e| {
+            matches!(e, VfatRsError::InvalidFatAmount { .. })
+        }),
+        (66, &[0], |e| {
+            matches!(e, VfatRsError::InvalidVfat { target: 0 })
+        }),
+        (14, &[0, 0], |e| {
+            matches!(e, VfatRsError::InvalidVolumeLayout { .. })
+        }),
+        (36, &[0, 0, 0, 0], |e| {
+            matches!(e, VfatRsError::InvalidVolumeLayout { .. })
+        }),
+        // The 16-bit sectors per FAT, unused on FAT32.
+        (22, &1u16.to_le_bytes(), |e| {
+            matches!(e, VfatRsError::InvalidVolumeLayout { .. })
+        }),
+        (32, &60000u32.to_le_bytes(), |e| {
+            matches!(e, VfatRsError::NotFat32 { .. })
+        }),
+        (44, &[0, 0, 0, 0], |e| {
+            matches!(e, VfatRsError::InvalidRootCluster { cluster: 0, .. })
+        }),
+        (44, &0x0FFF_FFF0u32.to_le_bytes(), |e| {
+            matches!(e, VfatRsError::InvalidRootCluster { .. })
+        }),
+    ];
+    for (offset, value, matcher) in cases {
+        image.write_all_at(value, boot_sector + offset).unwrap();
+        let err = mount().unwrap_err();
+        assert!(matcher(&err), "offset {}: {:?}", offset, err);
+        image.write_all_at(&original, boot_sector).unwrap();
+    }
+
+    // Random garbage is rejected without panicking.
+    let mut rng = rand::thread_rng();
+    for _ in 0..50 {
+        let mut garbage = [0u8; 512];
+        rng.fill(&mut garbage[..]);
+        image.write_all_at(&garbage, boot_sector).unwrap();
+        assert!(mount().is_err());
+    }
+    image.write_all_at(&original, boot_sector).unwrap();
+    mount()?;
+    Ok(())
+}
+
+#[test]
+fn test_backup_boot_sector() -> vfat_rs::Result<()> {
+    use std::os::unix::fs::FileExt;
+    use vfat_rs::BootSectorCopy;
+
+    let (vfat, f) = init_vfat()?;
+    assert_eq!(vfat.boot_sector_copy(), BootSectorCopy::Primary);
+    assert_eq!(vfat.fs_info_copy(), BootSectorCopy::Primary);
+    let serial = vfat.serial()?;
+    let stats = vfat.stats()?;
+    drop(vfat);
+
+    let (mut dev, master_boot_record) = init_from(&f);
+    let start = master_boot_record.partitions[0].start_sector;
+    let ebpb = VfatFS::read_fullebpb(&mut dev, start)?;
+    let sector_offset = |sector: u32| (start + sector) as u64 * 512;
+    let boot_sector = sector_offset(0);
+    let fs_info = sector_offset(ebpb.extended.fsinfo_sector as u32);
+    let backup_boot_sector = sector_offset(ebpb.extended.backup_boot_sector as u32);
+    let image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&f.fs_path)
+        .unwrap();
+    let read_sector = |offset| {
+        let mut buf = [0; 512];
+        image.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    };
+    let (original_boot_sector, original_fs_info) = (read_sector(boot_sector), read_sector(fs_info));
+    let mount = || {
+        let (dev, _) = init_from(&f);
+        VfatFS::new(dev, start)
+    };
+
+    // The test image has no FSInfo backup (it follows the backup boot sector): add it.
+    image
+        .write_all_at(&original_fs_info, backup_boot_sector + 512)
+        .unwrap();
+
+    // Both primary copies are wiped: the backups are used.
+    image.write_all_at(&[0; 512], boot_sector).unwrap();
+    image.write_all_at(&[0xFF; 512], fs_info).unwrap();
+    let mut vfat = mount()?;
+    assert_eq!(vfat.boot_sector_copy(), BootSectorCopy::Backup);
+    assert_eq!(vfat.fs_info_copy(), BootSectorCopy::Backup);
+    assert_eq!(vfat.serial()?, serial);
+    assert_eq!(vfat.stats()?, stats);
+    assert!(vfat.path_exists("/hello.txt".into())?);
+
+    // Restoring them brings the primary copies back.
+    vfat.restore_boot_sectors()?;
+    assert_eq!(vfat.boot_sector_copy(), BootSectorCopy::Primary);
+    assert_eq!(vfat.fs_info_copy(), BootSectorCopy::Primary);
+    assert_eq!(read_sector(boot_sector), original_boot_sector);
+    assert_eq!(read_sector(fs_info), original_fs_info);
+    drop(vfat);
+    let vfat = mount()?;
+    assert_eq!(vfat.boot_sector_copy(), BootSectorCopy::Primary);
+    assert_eq!(vfat.fs_info_copy(), BootSectorCopy::Primary);
+    assert_eq!(vfat.stats()?, stats);
+    drop(vfat);
+
+    // Changes made while the backup is in use go to the backup only, until the primary copy
+    // is restored.
+    image.write_all_at(&[0; 512], boot_sector).unwrap();
+    let vfat = mount()?;
+    assert_eq!(vfat.boot_sector_copy(), BootSectorCopy::Backup);
+    vfat.set_label("BACKUP")?;
+    vfat.set_serial(serial.wrapping_add(1))?;
+    assert_eq!(read_sector(boot_sector), [0; 512]);
+    assert_eq!(&read_sector(backup_boot_sector)[71..82], b"BACKUP     ");
+    drop(vfat);
+    let mut vfat = mount()?;
+    assert_eq!(vfat.serial()?, serial.wrapping_add(1));
+    assert_eq!(vfat.label()?, "BACKUP");
+    vfat.restore_boot_sectors()?;
+    assert_eq!(read_sector(boot_sector), read_sector(backup_boot_sector));
+    drop(vfat);
+
+    // Without a valid copy, the primary boot sector's error is reported.
+    image.write_all_at(&[0; 512], boot_sector).unwrap();
+    image.write_all_at(&[0; 512], backup_boot_sector).unwrap();
+    assert!(matches!(
+        mount(),
+        Err(VfatRsError::InvalidBootSignature { signature: 0 })
+    ));
+    Ok(())
+}
+
+/// Where the FAT and the data clusters are in a test image.
+struct ImageLayout {
+    partition_start: u32,
+    fat_start: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+}
+
+impl ImageLayout {
+    const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+
+    fn read(vfatfs_randompath: &VfatFsRandomPath) -> vfat_rs::Result<Self> {
+        let (mut dev, master_boot_record) = init_from(vfatfs_randompath);
+        let partition_start = master_boot_record.partitions[0].start_sector;
+        let ebpb = VfatFS::read_fullebpb(&mut dev, partition_start)?;
+        let fat_start = partition_start + ebpb.bpb.reserved_sectors as u32;
+        Ok(Self {
+            partition_start,
+            fat_start,
+            data_start: fat_start + ebpb.bpb.fat_amount as u32 * ebpb.extended.sectors_per_fat,
+            sectors_per_cluster: ebpb.bpb.sectors_per_cluster as u32,
+        })
+    }
+
+    fn sectors(&self, cluster: u32) -> impl Iterator<Item = u32> {
+        let first = self.data_start + (cluster - 2) * self.sectors_per_cluster;
+        first..first + self.sectors_per_cluster
+    }
+
+    fn fat_entry(&self, image: &std::path::Path, cluster: u32) -> u32 {
+        use std::os::unix::fs::FileExt;
+        let mut raw = [0; 4];
+        let image = std::fs::File::open(image).unwrap();
+        image
+            .read_exact_at(&mut raw, self.fat_start as u64 * 512 + cluster as u64 * 4)
+            .unwrap();
+        u32::from_le_bytes(raw) & 0x0FFF_FFFF
+    }
+
+    fn mount_faulty(
+        &self,
+        vfatfs_randompath: &VfatFsRandomPath,
+    ) -> vfat_rs::Result<(VfatFS, std::sync::Arc<std::sync::Mutex<block_devs::Faults>>)> {
+        let (dev, _) = init_from(vfatfs_randompath);
+        let (dev, faults) = FaultyBlockDevice::new(dev);
+        Ok((VfatFS::new(dev, self.partition_start)?, faults))
+    }
+}
+
+/// The first cluster of the short entry named `name`, in the image.
+fn raw_entry_cluster(image: &std::path::Path, name: &[u8]) -> u32 {
+    let raw = std::fs::read(image).unwrap();
+    let offset = find_raw_entry(image, name).unwrap();
+    let entry = &raw[offset..offset + 32];
+    u32::from(u16::from_le_bytes([entry[20], entry[21]])) << 16
+        | u32::from(u16::from_le_bytes([entry[26], entry[27]]))
+}
+
+#[test]
+fn test_bad_clusters() -> vfat_rs::Result<()> {
+    use vfat_rs::Problem;
+
+    // The first free cluster is the one picked by the next allocation.
+    let (vfat, f) = init_vfat()?;
+    vfat.get_root()?
+        .create_file("PROBE.BIN".into())?
+        .write_all(b"probe")?;
+    let free = raw_entry_cluster(&f.fs_path, b"PROBE   BIN");
+    vfat.get_root()?.delete("PROBE.BIN".into())?;
+    drop(vfat);
+
+    let layout = ImageLayout::read(&f)?;
+    let (vfat, faults) = layout.mount_faulty(&f)?;
+    let free_clusters = vfat.stats_exact()?.free_clusters;
+    faults
+        .lock()
+        .unwrap()
+        .unreadable
+        .extend(layout.sectors(free).chain(layout.sectors(free + 3)));
+    assert_eq!(vfat.scan_surface()?, vec![free, free + 3]);
+    assert_eq!(layout.fat_entry(&f.fs_path, free), ImageLayout::BAD_CLUSTER);
+    assert_eq!(vfat.stats_exact()?.free_clusters, free_clusters - 2);
+    faults.lock().unwrap().unreadable.clear();
+
+    vfat.mark_bad(free + 2)?;
+    vfat.mark_bad(free + 2)?;
+    assert_eq!(vfat.stats_exact()?.free_clusters, free_clusters - 3);
+
+    // Bad clusters are never allocated.
+    let mut root = vfat.get_root()?;
+    for name in ["NEXT.BIN", "LAST.BIN"] {
+        root.create_file(name.into())?.write_all(b"data")?;
+    }
+    let next = raw_entry_cluster(&f.fs_path, b"NEXT    BIN");
+    let last = raw_entry_cluster(&f.fs_path, b"LAST    BIN");
+    for cluster in [next, last] {
+        assert!(cluster > free && ![free + 2, free + 3].contains(&cluster));
+    }
+    assert!(matches!(
+        vfat.mark_bad(next),
+        Err(VfatRsError::ClusterInUse { .. })
+    ));
+    for invalid in [0, 1, u32::MAX] {
+        assert!(matches!(
+            vfat.mark_bad(invalid),
+            Err(VfatRsError::InvalidCluster { .. })
+        ));
+    }
+    // Bad clusters are neither lost nor part of a chain.
+    let report = vfat.check(Default::default())?;
+    assert!(!report.problems.iter().any(|problem| matches!(
+        problem,
+        Problem::LostChain { .. } | Problem::BadCluster { .. }
+    )));
+    Ok(())
+}
+
+#[test]
+fn test_write_relocation() -> vfat_rs::Result<()> {
+    let (vfat, f) = init_vfat()?;
+    let data: Vec<u8> = (0..1536).map(|i| (i % 251) as u8).collect();
+    vfat.get_root()?
+        .create_file("RELOC.BIN".into())?
+        .write_all(&data)?;
+    drop(vfat);
+
+    let layout = ImageLayout::read(&f)?;
+    let head = raw_entry_cluster(&f.fs_path, b"RELOC   BIN");
+    let second = layout.fat_entry(&f.fs_path, head);
+    let third = layout.fat_entry(&f.fs_path, second);
+    let (vfat, faults) = layout.mount_faulty(&f)?;
+    let mut file = vfat.get_path("/RELOC.BIN".into())?.into_file().unwrap();
+
+    // The second cluster can't be written: its data moves to a new cluster.
+    faults
+        .lock()
+        .unwrap()
+        .unwritable
+        .extend(layout.sectors(second));
+    let rewritten: Vec<u8> = data.iter().rev().copied().collect();
+    file.write_all(&rewritten)?;
+    assert_eq!(
+        layout.fat_entry(&f.fs_path, second),
+        ImageLayout::BAD_CLUSTER
+    );
+    let relocated = layout.fat_entry(&f.fs_path, head);
+    assert_ne!(relocated, second);
+    assert_eq!(layout.fat_entry(&f.fs_path, relocated), third);
+
+    // So does the first one, and the entry follows it.
+    faults
+        .lock()
+        .unwrap()
+        .unwritable
+        .extend(layout.sectors(head));
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&data[..100])?;
+    assert_eq!(layout.fat_entry(&f.fs_path, head), ImageLayout::BAD_CLUSTER);
+    let new_head = raw_entry_cluster(&f.fs_path, b"RELOC   BIN");
+    assert_ne!(new_head, head);
+    assert_eq!(layout.fat_entry(&f.fs_path, new_head), relocated);
+
+    // If the cluster can't be read either, its content would be lost: the write fails, and
+    // nothing is relocated.
+    faults
+        .lock()
+        .unwrap()
+        .unreadable
+        .extend(layout.sectors(third));
+    faults
+        .lock()
+        .unwrap()
+        .unwritable
+        .extend(layout.sectors(third));
+    let free_clusters = vfat.stats_exact()?.free_clusters;
+    file.seek(SeekFrom::Start(1100))?;
+    assert!(matches!(
+        file.write(b"unreadable"),
+        Err(VfatRsError::IoError { .. })
+    ));
+    assert_eq!(layout.fat_entry(&f.fs_path, relocated), third);
+    assert_ne!(
+        layout.fat_entry(&f.fs_path, third),
+        ImageLayout::BAD_CLUSTER
+    );
+    assert_eq!(vfat.stats_exact()?.free_clusters, free_clusters);
+    faults.lock().unwrap().unreadable.clear();
+
+    // Writes fail if the new cluster can't be written either.
+    faults
+        .lock()
+        .unwrap()
+        .unwritable
+        .extend((layout.data_start..layout.data_start + 100_000).collect::<Vec<_>>());
+    file.seek(SeekFrom::Start(600))?;
+    let free_clusters = vfat.stats_exact()?.free_clusters;
+    assert!(matches!(
+        file.write(b"lost"),
+        Err(VfatRsError::IoError { .. })
+    ));
+    // The cluster picked for the relocation is marked bad too.
+    assert_eq!(vfat.stats_exact()?.free_clusters, free_clusters - 1);
+    drop(file);
+    drop(vfat);
+
+    let (vfat, _) = layout.mount_faulty(&f)?;
+    let mut file = vfat.get_path("/RELOC.BIN".into())?.into_file().unwrap();
+    let mut buf = vec![0; 1536];
+    assert_eq!(file.read(&mut buf)?, 1536);
+    assert_eq!(&buf[..100], &data[..100]);
+    assert_eq!(&buf[100..], &rewritten[100..]);
+    Ok(())
+}
+
+/// Exercises most of the API, on entries whose names end with `suffix`.
+fn device_errors_workload(vfat: &VfatFS, suffix: usize) -> vfat_rs::Result<()> {
+    let mut root = vfat.get_root()?;
+    let mut dir = root.create_directory(format!("faults-{}", suffix))?;
+    let mut file = dir.create_file(format!("file-{}.txt", suffix))?;
+    file.write_all(&[0xAB; 1500])?;
+    file.seek(SeekFrom::Start(700))?;
+    file.write_all(b"overwritten")?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = [0; 1500];
+    file.read(&mut buf)?;
+    file.truncate(300)?;
+    drop(file);
+    dir.rename(
+        format!("file-{}.txt", suffix).as_str().into(),
+        format!("renamed-{}.txt", suffix).as_str().into(),
+    )?;
+    for entry in vfat.walk(format!("/faults-{}", suffix).as_str().into())? {
+        entry?;
+    }
+    dir.delete(format!("renamed-{}.txt", suffix))?;
+    root.delete(format!("faults-{}", suffix))?;
+    root.contents()?;
+    Ok(())
+}
+
+#[test]
+fn test_device_errors() -> vfat_rs::Result<()> {
+    let image = common::memory_image();
+    let mount_faulty = |read_only: bool| {
+        let dev = MemoryBlockDevice::new(image.clone());
+        let (dev, faults) = FaultyBlockDevice::new(dev);
+        let vfat = if read_only {
+            VfatFS::new_read_only(dev, common::MEMORY_PARTITION_START)
+        } else {
+            VfatFS::new(dev, common::MEMORY_PARTITION_START)
+        };
+        vfat.map(|vfat| (vfat, faults))
+    };
+
+    // Every access fails: mounting fails.
+    let (dev, faults) = FaultyBlockDevice::new(MemoryBlockDevice::new(image.clone()));
+    faults.lock().unwrap().fail_after(0);
+    assert!(VfatFS::new(dev, common::MEMORY_PARTITION_START).is_err());
+
+    // The device fails after a growing number of accesses: operations fail without panics,
+    // until the device allows enough accesses for the whole workload. Each attempt starts from
+    // the same image: what the failed ones left behind would make the workload longer.
+    let mut accesses = 0;
+    loop {
+        let (vfat, faults) = mount_faulty(false)?;
+        faults.lock().unwrap().fail_after(accesses);
+        if device_errors_workload(&vfat, accesses).is_ok() {
+            break;
+        }
+        accesses += 1;
+        assert!(accesses < 5000);
+    }
+    assert!(accesses > 0);
+
+    // Anything else fails gracefully too.
+    let (vfat, faults) = mount_faulty(false)?;
+    faults.lock().unwrap().fail_after(0);
+    assert!(vfat.get_root().is_err());
+    assert!(vfat.get_path("/hello.txt".into()).is_err());
+    assert!(vfat.stats_exact().is_err());
+    assert!(vfat.check(Default::default()).is_err());
+    assert!(vfat.scan_surface().is_err());
+    assert!(vfat.mark_bad(100).is_err());
+    assert!(vfat.label().is_err());
+    assert!(vfat.set_label("FAULTY").is_err());
+    assert!(vfat.set_serial(1).is_err());
+    assert!(vfat.du("/".into()).is_err());
+    let (read_only, read_only_faults) = mount_faulty(true)?;
+    read_only_faults.lock().unwrap().fail_after(0);
+    assert!(read_only.stats_exact().is_err());
+
+    // And the volume is still fine.
+    faults.lock().unwrap().fail_after = None;
+    device_errors_workload(&vfat, 1000)
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_filebacked_device_errors() {
+    let path = std::env::temp_dir().join(format!("vfat-short-{}.img", std::process::id()));
+    std::fs::write(&path, [0u8; 512]).unwrap();
+    let mut dev = vfat_rs::FilebackedBlockDevice {
+        image: OpenOptions::new().read(true).open(&path).unwrap(),
+    };
+    let mut buf = [0; 512];
+    assert_eq!(dev.read_sector(SectorId(0), &mut buf).unwrap(), 512);
+    // Past the end of the image, and on a file opened without write access.
+    assert!(dev.read_sector(SectorId(4), &mut buf).is_err());
+    assert!(dev.write_sector(SectorId(0), &buf).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_multi_sector_io() -> vfat_rs::Result<()> {
+    let (vfat, f) = init_vfat()?;
+    drop(vfat);
+    let layout = ImageLayout::read(&f)?;
+    let (dev, _) = init_from(&f);
+    let (dev, requests) = CountingBlockDevice::new(dev);
+    let vfat = VfatFS::new(dev, layout.partition_start)?;
+    let cluster_size = layout.sectors_per_cluster as usize * 512;
+    let content: Vec<u8> = (0..64 * cluster_size).map(|i| (i % 251) as u8).collect();
+    let sectors = content.len() / 512;
+
+    // On a fresh volume, the clusters allocated to the file are contiguous: its content is
+    // written with a single request instead of one per sector. Other runs zero the clusters
+    // allocated to directories.
+    let mut file = vfat.get_root()?.create_file("runs.bin".into())?;
+    requests.lock().unwrap().runs.clear();
+    file.write_all(&content)?;
+    let runs = std::mem::take(&mut requests.lock().unwrap().runs);
+    assert!(runs.contains(&sectors), "{:?}", runs);
+
+    // Same for reading it back.
+    let mut file = vfat.get_path("/runs.bin".into())?.try_into_file()?;
+    requests.lock().unwrap().runs.clear();
+    let mut buf = vec![0; content.len()];
+    assert_eq!(file.read(&mut buf)?, content.len());
+    assert_eq!(buf, content);
+    let runs = std::mem::take(&mut requests.lock().unwrap().runs);
+    assert_eq!(runs, vec![sectors]);
+
+    // Unaligned reads and writes mix partial sectors and runs.
+    let mut buf = vec![0; 3 * cluster_size];
+    file.seek(SeekFrom::Start(100))?;
+    assert_eq!(file.read(&mut buf)?, buf.len());
+    assert_eq!(buf, content[100..100 + buf.len()]);
+    let patch = vec![0xAB; 2 * cluster_size + 7];
+    file.seek(SeekFrom::Start(cluster_size as u64 - 3))?;
+    file.write_all(&patch)?;
+    let mut expected = content.clone();
+    expected[cluster_size - 3..][..patch.len()].copy_from_slice(&patch);
+    let mut buf = vec![0; content.len()];
+    file.seek(SeekFrom::Start(0))?;
+    assert_eq!(file.read(&mut buf)?, content.len());
+    assert_eq!(buf, expected);
     Ok(())
 }
 
 #[test]
 fn test_get_root() -> vfat_rs::Result<()> {
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let entry = vfat.get_root().unwrap();
     assert_eq!(
         entry.metadata.path().display().to_string(),
@@ -247,7 +2068,7 @@ fn test_file_creation() -> vfat_rs::Result<()> {
     let file_name = "hello_world";
     let used_name_path = "/hello_world";
 
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let mut root = vfat.get_root()?;
 
     // 2. assert file does not exists
@@ -272,7 +2093,7 @@ fn test_file_creation() -> vfat_rs::Result<()> {
 fn test_multiple_file_creation() -> vfat_rs::Result<()> {
     // test entry creation that needs multiple clusters allocated to this directory
 
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let mut root = vfat.get_root()?;
 
     let mut files = (0..200)
@@ -297,7 +2118,7 @@ fn test_multiple_file_creation() -> vfat_rs::Result<()> {
 
 fn test_file_write(name: &str) -> vfat_rs::Result<()> {
     let (file_name, file_path) = random_name(name);
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let mut root = vfat.get_root()?;
 
     // 2. assert file does not exists
@@ -384,7 +2205,7 @@ fn test_big_write_and_read() -> vfat_rs::Result<()> {
         convert(ITERATIONS as f64 * CONTENT.len() as f64)
     );
     let (file_name, file_path) = random_name("big_write");
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let mut root = vfat.get_root()?;
 
     // 2. assert file does not exists
@@ -443,7 +2264,7 @@ fn test_create_directory_short() -> vfat_rs::Result<()> {
 
 fn test_create_directory(prefix: &str) -> vfat_rs::Result<()> {
     let (dir_name, dir_path) = random_name(prefix);
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let mut root = vfat.get_root()?;
 
     let err = format!("Directory '{}' already exists. Please delete it.", dir_path);
@@ -473,7 +2294,7 @@ fn test_create_directory(prefix: &str) -> vfat_rs::Result<()> {
 
 fn test_delete_folder_non_empty() -> vfat_rs::Result<()> {
     let (folder_name, _folder_path) = random_name("delfld");
-    let (mut vfat, _f) = init_vfat()?;
+    let (vfat, _f) = init_vfat()?;
     let mut root = vfat.get_root()?;
     let mut folder = root.create_directory(folder_name.clone())?;
     let (subfolder_name, _subfolder_path) = random_name("subfld");